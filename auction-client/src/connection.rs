@@ -0,0 +1,178 @@
+use std::collections::VecDeque;
+
+use protocol::{
+    codec::FrameReader, MarketEvent, Request, Response, ResponseFormat, ServerMessage,
+    TaggedRequest,
+};
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::{broadcast, mpsc, oneshot},
+};
+
+use crate::{Config, Error, Result};
+
+pub(crate) type Responder = oneshot::Sender<Result<Response>>;
+
+// Logged in connection, switched to the JSON format
+pub(crate) struct Connection {
+    tcp_reader: FrameReader<BufReader<OwnedReadHalf>>,
+    tcp_writer: OwnedWriteHalf,
+}
+
+impl Connection {
+    pub(crate) async fn open(addr: &str, username: &str) -> Result<Self> {
+        let (tcp_reader, tcp_writer) = TcpStream::connect(addr).await?.into_split();
+        let mut connection = Self {
+            tcp_reader: FrameReader::new(BufReader::new(tcp_reader)),
+            tcp_writer,
+        };
+
+        let greeting = connection.read_frame().await?;
+        if greeting != protocol::GREETING {
+            return Err(Error::Protocol(format!("Unexpected greeting: {greeting}")));
+        }
+        connection.write(username).await?;
+        protocol::decode_login_result(connection.read_frame().await?)
+            .map_err(|err| Error::Login(err.to_owned()))?;
+
+        connection
+            .execute(&Request::Format(ResponseFormat::Json))
+            .await?;
+        Ok(connection)
+    }
+
+    // Sends the request and waits for the response, skipping events. Used only while
+    // no other requests are in flight
+    async fn execute(&mut self, request: &Request) -> Result<Response> {
+        self.write(&request.to_string()).await?;
+        loop {
+            let frame = self.read_frame().await?;
+            // Events pushed before the session is switched to the JSON format are in the text one
+            if frame.starts_with("Event: ") {
+                continue;
+            }
+            if let ServerMessage::Response(response) = decode(frame)? {
+                return response.into_result().map_err(Error::Server);
+            }
+        }
+    }
+
+    async fn write(&mut self, line: &str) -> Result<()> {
+        self.tcp_writer
+            .write_all(format!("{line}\n").as_bytes())
+            .await?;
+        Ok(())
+    }
+
+    // Cancellation safe, so it can be used in `tokio::select!`
+    async fn read(&mut self) -> Result<ServerMessage> {
+        decode(self.read_frame().await?)
+    }
+
+    async fn read_frame(&mut self) -> Result<&str> {
+        self.tcp_reader
+            .read_frame()
+            .await?
+            .ok_or(Error::Disconnected)
+    }
+}
+
+fn decode(frame: &str) -> Result<ServerMessage> {
+    ServerMessage::from_json(frame).map_err(|err| Error::Protocol(format!("{frame}: {err}")))
+}
+
+// Background task that owns the connection. Requests are sent in the order they are received
+// and responses are matched with requests in the same order, as the server processes requests
+// of a single connection sequentially
+pub(crate) struct Worker {
+    pub(crate) addr: String,
+    pub(crate) username: String,
+    pub(crate) config: Config,
+    pub(crate) requests: mpsc::Receiver<(TaggedRequest, Responder)>,
+    pub(crate) events: broadcast::Sender<MarketEvent>,
+    // Subscription is restored after reconnect
+    pub(crate) subscription: Option<Request>,
+}
+
+impl Worker {
+    pub(crate) async fn run(mut self, mut connection: Connection) {
+        // Stops once all clients are dropped or the server is unreachable
+        while self.serve(&mut connection).await {
+            connection = match self.reconnect().await {
+                Some(connection) => connection,
+                None => return,
+            };
+        }
+    }
+
+    // Returns `true` if the connection is lost and `false` if all clients are dropped
+    async fn serve(&mut self, connection: &mut Connection) -> bool {
+        let mut in_flight = VecDeque::<Responder>::new();
+        loop {
+            tokio::select! {
+                request = self.requests.recv() => {
+                    let Some((request, responder)) = request else {
+                        return false;
+                    };
+                    match &request.request {
+                        Request::Subscribe { .. } => {
+                            self.subscription = Some(request.request.clone());
+                        }
+                        Request::Unsubscribe => self.subscription = None,
+                        _ => {}
+                    }
+                    in_flight.push_back(responder);
+                    if connection.write(&request.to_string()).await.is_err() {
+                        break;
+                    }
+                }
+                message = connection.read() => match message {
+                    Ok(ServerMessage::Event(event)) => {
+                        // Sending fails only if there are no subscribers, which is fine
+                        let _ = self.events.send(event);
+                    }
+                    Ok(ServerMessage::Response(response)) => {
+                        if let Some(responder) = in_flight.pop_front() {
+                            let _ = responder.send(response.into_result().map_err(Error::Server));
+                        }
+                    }
+                    // Malformed message is most likely a response, since events are simple
+                    Err(err @ Error::Protocol(_)) => {
+                        if let Some(responder) = in_flight.pop_front() {
+                            let _ = responder.send(Err(err));
+                        }
+                    }
+                    Err(_) => break,
+                },
+            }
+        }
+
+        for responder in in_flight {
+            let _ = responder.send(Err(Error::Disconnected));
+        }
+        true
+    }
+
+    async fn reconnect(&mut self) -> Option<Connection> {
+        let mut delay = self.config.reconnect_delay;
+        for _ in 0..self.config.reconnect_attempts {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+
+            let Ok(mut connection) = Connection::open(&self.addr, &self.username).await else {
+                continue;
+            };
+            if let Some(subscription) = &self.subscription {
+                if connection.execute(subscription).await.is_err() {
+                    continue;
+                }
+            }
+            return Some(connection);
+        }
+        None
+    }
+}
@@ -0,0 +1,40 @@
+use protocol::{ErrorCode, ErrorResponse};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Request is processed and rejected by the server
+    #[error("{0}")]
+    Server(ErrorResponse),
+    #[error("Failed to login: {0}")]
+    Login(String),
+    /// Server sent something the client doesn't understand
+    #[error("Unexpected message from the server: {0}")]
+    Protocol(String),
+    #[error("Connection error: {0}")]
+    Io(#[from] std::io::Error),
+    /// Connection is lost while the request was in flight, so it is unknown whether the request
+    /// has been processed. Following requests are sent once the client reconnects
+    #[error("Connection to the server is lost")]
+    Disconnected,
+}
+
+impl Error {
+    /// Code of the error returned by the server, if the request reached the server
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::Server(err) => Some(err.code),
+            _ => None,
+        }
+    }
+}
+
+impl From<protocol::codec::ReadError> for Error {
+    fn from(err: protocol::codec::ReadError) -> Self {
+        match err {
+            protocol::codec::ReadError::Io(err) => Self::Io(err),
+            err => Self::Protocol(err.to_string()),
+        }
+    }
+}
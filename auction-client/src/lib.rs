@@ -0,0 +1,731 @@
+//! Async client of the Auction House for bots and integration tests.
+//!
+//! The client is cheap to clone, all clones share the same connection. Requests are pipelined
+//! and the connection is restored automatically if it is lost, see [`Config`].
+//!
+//! ```no_run
+//! # async fn example() -> auction_client::Result<()> {
+//! let client = auction_client::Client::connect("localhost:3000", "Miner").await?;
+//! let mut notifications = client.notifications().await?;
+//! client.deposit("ore", 10).await?;
+//! client.sell(auction_client::SellOrderType::Auction, "ore", 10, 50).await?;
+//! while let Some(event) = notifications.next().await {
+//!     println!("{event}");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::time::Duration;
+
+use protocol::TaggedRequest;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use connection::{Connection, Responder, Worker};
+
+mod connection;
+mod error;
+
+pub use error::{Error, Result};
+pub use protocol::{
+    CatalogItem, ErrorCode, ErrorResponse, ExchangeRate, Item, ItemBalance, ItemInstance,
+    ItemStats, MarketEvent, Offer, Request, Response, SellOrder, SellOrderType, Trade, Transaction,
+    TransactionKind, Watch, WatchKind,
+};
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Number of attempts to restore a lost connection. Zero disables reconnects
+    pub reconnect_attempts: u32,
+    /// Delay before the first reconnect attempt, doubled after every failed attempt
+    pub reconnect_delay: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            reconnect_attempts: 5,
+            reconnect_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Client {
+    requests: mpsc::Sender<(TaggedRequest, Responder)>,
+    // Weak, so notifications are closed once the connection can't be restored
+    events: broadcast::WeakSender<MarketEvent>,
+}
+
+impl Client {
+    /// Connects to the server and logs in as `username`, with the default `Config`
+    pub async fn connect(addr: &str, username: &str) -> Result<Self> {
+        Self::connect_with_config(addr, username, Config::default()).await
+    }
+
+    pub async fn connect_with_config(addr: &str, username: &str, config: Config) -> Result<Self> {
+        let connection = Connection::open(addr, username).await?;
+
+        let (requests_tx, requests_rx) = mpsc::channel(64);
+        // Slow receivers will miss events once they lag behind by this many events
+        let (events, _) = broadcast::channel(1024);
+        let client = Self {
+            requests: requests_tx,
+            events: events.downgrade(),
+        };
+
+        let worker = Worker {
+            addr: addr.to_owned(),
+            username: username.to_owned(),
+            config,
+            requests: requests_rx,
+            events,
+            subscription: None,
+        };
+        tokio::spawn(worker.run(connection));
+
+        Ok(client)
+    }
+
+    pub async fn deposit(&self, item_name: &str, quantity: i64) -> Result<()> {
+        match self
+            .execute(Request::Deposit {
+                item_name: item_name.to_owned(),
+                quantity,
+            })
+            .await?
+        {
+            Response::Deposited { .. } => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn withdraw(&self, item_name: &str, quantity: i64) -> Result<()> {
+        match self
+            .execute(Request::Withdraw {
+                item_name: item_name.to_owned(),
+                quantity,
+            })
+            .await?
+        {
+            Response::Withdrawn { .. } => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Deposits a unique instance of the item with the attributes, a JSON object
+    pub async fn deposit_instance(
+        &self,
+        item_name: &str,
+        attributes: serde_json::Value,
+    ) -> Result<ItemInstance> {
+        match self
+            .execute(Request::DepositInstance {
+                item_name: item_name.to_owned(),
+                attributes,
+            })
+            .await?
+        {
+            Response::InstanceDeposited { instance } => Ok(instance),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn withdraw_instance(&self, instance_id: i64) -> Result<()> {
+        match self
+            .execute(Request::WithdrawInstance { instance_id })
+            .await?
+        {
+            Response::InstanceWithdrawn { .. } => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Unique item instances of the user
+    pub async fn instances(&self) -> Result<Vec<ItemInstance>> {
+        match self.execute(Request::ViewInstances).await? {
+            Response::Instances { instances } => Ok(instances),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Gives items to another user. The recipient is notified if connected
+    pub async fn give(&self, recipient: &str, item_name: &str, quantity: i64) -> Result<()> {
+        match self
+            .execute(Request::Give {
+                recipient: recipient.to_owned(),
+                item_name: item_name.to_owned(),
+                quantity,
+            })
+            .await?
+        {
+            Response::ItemsGiven { .. } => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Offers the recipient to exchange items. Returns the id of the placed offer
+    pub async fn offer(&self, recipient: &str, give: Vec<Item>, want: Vec<Item>) -> Result<i64> {
+        match self
+            .execute(Request::Offer {
+                recipient: recipient.to_owned(),
+                give,
+                want,
+            })
+            .await?
+        {
+            Response::OfferPlaced { offer_id } => Ok(offer_id),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn accept_offer(&self, offer_id: i64) -> Result<()> {
+        match self.execute(Request::Accept { offer_id }).await? {
+            Response::OfferAccepted { .. } => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Declines an offer sent to the current user or withdraws own offer
+    pub async fn decline_offer(&self, offer_id: i64) -> Result<()> {
+        match self.execute(Request::Decline { offer_id }).await? {
+            Response::OfferDeclined { .. } => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Offers sent by the current user and to the current user
+    pub async fn offers(&self) -> Result<Vec<Offer>> {
+        match self.execute(Request::ViewOffers).await? {
+            Response::Offers { offers } => Ok(offers),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Currencies of the server and the exchange rates between them
+    pub async fn exchange_listing(&self) -> Result<(Vec<String>, Vec<ExchangeRate>)> {
+        match self.execute(Request::ViewExchange).await? {
+            Response::Exchange { currencies, rates } => Ok((currencies, rates)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Exchanges `quantity` of the `from` currency at the listed rate.
+    /// Returns the received quantity of the `to` currency
+    pub async fn exchange(&self, quantity: i64, from: &str, to: &str) -> Result<i64> {
+        match self
+            .execute(Request::Exchange {
+                quantity,
+                from: from.to_owned(),
+                to: to.to_owned(),
+            })
+            .await?
+        {
+            Response::Exchanged { to, .. } => Ok(to.quantity),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Items given by the current user and to the current user, the latest is the last
+    pub async fn transactions(&self) -> Result<Vec<Transaction>> {
+        match self.execute(Request::ViewTransactions).await? {
+            Response::Transactions { transactions } => Ok(transactions),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Items of the current user, including funds, with quantities escrowed in bids and listed for sale
+    pub async fn items(&self) -> Result<Vec<ItemBalance>> {
+        match self.execute(Request::ViewItems).await? {
+            Response::Items { items } => Ok(items),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Places a sell order and returns its id
+    pub async fn sell(
+        &self,
+        order_type: SellOrderType,
+        item_name: &str,
+        quantity: i64,
+        price: i64,
+    ) -> Result<i64> {
+        self.barter(order_type, item_name, quantity, price, "funds")
+            .await
+    }
+
+    /// Places a sell order priced in `price_item` instead of funds and returns its id
+    pub async fn barter(
+        &self,
+        order_type: SellOrderType,
+        item_name: &str,
+        quantity: i64,
+        price: i64,
+        price_item: &str,
+    ) -> Result<i64> {
+        match self
+            .execute(Request::Sell {
+                order_type,
+                item_name: item_name.to_owned(),
+                quantity,
+                price,
+                price_item: price_item.to_owned(),
+            })
+            .await?
+        {
+            Response::SellOrderPlaced { sell_order_id, .. } => Ok(sell_order_id),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Places a sell order for the item instance priced in `price_item` and returns its id
+    pub async fn sell_instance(
+        &self,
+        order_type: SellOrderType,
+        instance_id: i64,
+        price: i64,
+        price_item: &str,
+    ) -> Result<i64> {
+        match self
+            .execute(Request::SellInstance {
+                order_type,
+                instance_id,
+                price,
+                price_item: price_item.to_owned(),
+            })
+            .await?
+        {
+            Response::SellOrderPlaced { sell_order_id, .. } => Ok(sell_order_id),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Places a sell order for several items sold together and returns its id
+    pub async fn sell_bundle(
+        &self,
+        order_type: SellOrderType,
+        items: Vec<Item>,
+        price: i64,
+        price_item: &str,
+    ) -> Result<i64> {
+        match self
+            .execute(Request::SellBundle {
+                order_type,
+                items,
+                price,
+                price_item: price_item.to_owned(),
+            })
+            .await?
+        {
+            Response::BundleSellOrderPlaced { sell_order_id, .. } => Ok(sell_order_id),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Executes an immediate sell order
+    pub async fn buy(&self, sell_order_id: i64) -> Result<()> {
+        match self.execute(Request::Buy { sell_order_id }).await? {
+            Response::SellOrderExecuted { .. } => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Places a bid on an auction sell order
+    pub async fn bid(&self, sell_order_id: i64, bid: i64) -> Result<()> {
+        match self.execute(Request::Bid { sell_order_id, bid }).await? {
+            Response::BidPlaced { .. } => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// All sell orders from all users
+    pub async fn orders(&self) -> Result<Vec<SellOrder>> {
+        self.orders_in(None).await
+    }
+
+    /// Sell orders from all users, only items of the catalog category if it's set
+    pub async fn orders_in(&self, category: Option<&str>) -> Result<Vec<SellOrder>> {
+        let request = Request::ViewSellOrders {
+            category: category.map(str::to_owned),
+        };
+        match self.execute(request).await? {
+            Response::SellOrders { sell_orders } => Ok(sell_orders),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Item types defined in the catalog of the server
+    pub async fn catalog(&self) -> Result<Vec<CatalogItem>> {
+        match self.execute(Request::ViewCatalog).await? {
+            Response::Catalog { items } => Ok(items),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Price history and market statistics of the item
+    pub async fn stats(&self, item_name: &str) -> Result<ItemStats> {
+        match self
+            .execute(Request::Stats {
+                item_name: item_name.to_owned(),
+            })
+            .await?
+        {
+            Response::Stats { stats } => Ok(stats),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Price in funds to list `quantity` of the item for, if it was traded recently
+    pub async fn suggest_price(&self, item_name: &str, quantity: i64) -> Result<Option<i64>> {
+        match self
+            .execute(Request::SuggestPrice {
+                item_name: item_name.to_owned(),
+                quantity,
+            })
+            .await?
+        {
+            Response::SuggestedPrice { price, .. } => Ok(price),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Adds a watch for new sell orders, see `MarketEvent::WatchTriggered`
+    pub async fn watch(&self, kind: WatchKind) -> Result<Watch> {
+        match self.execute(Request::Watch { kind }).await? {
+            Response::Watching { watch } => Ok(watch),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn unwatch(&self, watch_id: i64) -> Result<()> {
+        match self.execute(Request::Unwatch { watch_id }).await? {
+            Response::Unwatched { .. } => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn watchlist(&self) -> Result<Vec<Watch>> {
+        match self.execute(Request::ViewWatchlist).await? {
+            Response::Watchlist { watches } => Ok(watches),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Takes events received while the user was offline, the oldest first
+    pub async fn mailbox(&self) -> Result<Vec<MarketEvent>> {
+        match self.execute(Request::ViewMailbox).await? {
+            Response::Mailbox { events } => Ok(events),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Cancels own sell order. Items are returned, but the fee is not
+    pub async fn cancel(&self, sell_order_id: i64) -> Result<()> {
+        match self.execute(Request::Cancel { sell_order_id }).await? {
+            Response::SellOrderCancelled { .. } => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Auction sell orders where the current user is the highest bidder
+    pub async fn my_bids(&self) -> Result<Vec<SellOrder>> {
+        match self.execute(Request::MyBids).await? {
+            Response::Bids { sell_orders } => Ok(sell_orders),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Executes requests to the storage as a single unit and returns their responses in order.
+    /// If any request fails, none of them is applied and the error tells which one has failed
+    pub async fn batch(&self, requests: Vec<Request>) -> Result<Vec<Response>> {
+        match self.execute(Request::Batch(requests)).await? {
+            Response::BatchExecuted { responses } => Ok(responses),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Subscribes to changes of all sell orders and personal events of the user, e.g. received items
+    pub async fn notifications(&self) -> Result<Notifications> {
+        // Subscribe before sending the request, so no events are missed
+        let events = self
+            .events
+            .upgrade()
+            .ok_or(Error::Disconnected)?
+            .subscribe();
+        match self.execute(Request::Subscribe { item_name: None }).await? {
+            Response::Subscribed { .. } => Ok(Notifications { events }),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Sends any request and waits for its response
+    pub async fn execute(&self, request: Request) -> Result<Response> {
+        self.send(TaggedRequest { id: None, request }).await
+    }
+
+    /// Sends the request with an id. Requests that change items or sell orders are applied at most
+    /// once per id, so a request that failed with `Error::Disconnected` can be safely retried
+    /// with the same id. The retry gets the original result if the request has been processed
+    pub async fn execute_with_id(&self, request_id: &str, request: Request) -> Result<Response> {
+        self.send(TaggedRequest {
+            id: Some(request_id.to_owned()),
+            request,
+        })
+        .await
+    }
+
+    async fn send(&self, request: TaggedRequest) -> Result<Response> {
+        let (responder, response) = oneshot::channel();
+        self.requests
+            .send((request, responder))
+            .await
+            .map_err(|_| Error::Disconnected)?;
+        response.await.map_err(|_| Error::Disconnected)?
+    }
+}
+
+fn unexpected(response: Response) -> Error {
+    Error::Protocol(format!("Unexpected response: {response:?}"))
+}
+
+/// Stream of market events. Events that happen while the client is reconnecting are missed
+pub struct Notifications {
+    events: broadcast::Receiver<MarketEvent>,
+}
+
+impl Notifications {
+    /// Waits for the next event. Returns `None` once the connection is lost and can't be restored
+    pub async fn next(&mut self) -> Option<MarketEvent> {
+        loop {
+            match self.events.recv().await {
+                Ok(event) => return Some(event),
+                // Slow receiver simply misses some events
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use protocol::{
+        codec::{self, LineReader},
+        ResponseFormat,
+    };
+    use tokio::{
+        io::{AsyncWriteExt, BufReader},
+        net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    };
+
+    fn sell_order() -> SellOrder {
+        SellOrder {
+            id: 1,
+            seller_name: "seller".into(),
+            item_name: "ore".into(),
+            quantity: 10,
+            price: 50,
+            price_item: "funds".into(),
+            expiration_time: "2021-01-01 00:00:00".into(),
+            order_type: SellOrderType::Auction,
+            instance: None,
+            bundle: Vec::new(),
+        }
+    }
+
+    // Fake server that answers every request with a canned response. `withdraw` closes the
+    // connection to test reconnects. Returns the address and the number of accepted connections
+    async fn fake_server() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("localhost:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(fake_session(socket));
+            }
+        });
+        (addr, connections)
+    }
+
+    async fn fake_session(socket: TcpStream) {
+        let (tcp_reader, mut tcp_writer) = socket.into_split();
+        let mut tcp_reader = LineReader::new(BufReader::new(tcp_reader));
+        write(&mut tcp_writer, protocol::GREETING).await;
+        let username = tcp_reader.read_line().await.unwrap().unwrap().to_owned();
+        let username = String::from_utf8(username).unwrap();
+        if username.is_empty() {
+            let result = protocol::encode_login_result(Err("Username cannot be empty"));
+            write(&mut tcp_writer, &result).await;
+            return;
+        }
+        write(
+            &mut tcp_writer,
+            &protocol::encode_login_result(Ok(&username)),
+        )
+        .await;
+        // Events of the user are pushed as soon as the session is logged in, in the text format
+        if username == "recipient" {
+            let event = MarketEvent::ItemsReceived {
+                sender: "seller".into(),
+                recipient: username.clone(),
+                item_name: "ore".into(),
+                quantity: 1,
+            };
+            write(&mut tcp_writer, &ResponseFormat::Text.encode_event(&event)).await;
+        }
+
+        while let Ok(Some(line)) = tcp_reader.read_line().await {
+            let line = std::str::from_utf8(line).unwrap();
+            let request = TaggedRequest::parse(line).unwrap().request;
+            let result = match request {
+                Request::Format(format) => Ok(Response::Format { format }),
+                Request::Subscribe { item_name } => Ok(Response::Subscribed { item_name }),
+                Request::Deposit {
+                    item_name,
+                    quantity,
+                } => Ok(Response::Deposited {
+                    item_name,
+                    quantity,
+                }),
+                Request::Withdraw { .. } => return,
+                Request::ViewItems => Ok(Response::Items {
+                    items: vec![ItemBalance {
+                        name: "funds".into(),
+                        quantity: 10,
+                        in_bids: 0,
+                        listed: 0,
+                    }],
+                }),
+                Request::Sell { .. } => {
+                    let event = MarketEvent::OrderPlaced {
+                        order: sell_order(),
+                    };
+                    write(&mut tcp_writer, &ResponseFormat::Json.encode_event(&event)).await;
+                    Ok(Response::SellOrderPlaced {
+                        sell_order_id: 1,
+                        order_type: SellOrderType::Auction,
+                        item_name: "ore".into(),
+                        quantity: 10,
+                    })
+                }
+                Request::Cancel { sell_order_id } => Err(ErrorResponse {
+                    code: ErrorCode::OrderNotFound,
+                    message: format!("Sell order #{sell_order_id} doesn't exist"),
+                }),
+                _ => Ok(Response::Pong),
+            };
+            write(&mut tcp_writer, &ResponseFormat::Json.encode(result)).await;
+        }
+    }
+
+    async fn write(tcp_writer: &mut OwnedWriteHalf, payload: &str) {
+        // The client might be gone already
+        let _ = tcp_writer
+            .write_all(codec::encode_frame(payload).as_bytes())
+            .await;
+    }
+
+    #[tokio::test]
+    async fn typed_requests() {
+        let (addr, _) = fake_server().await;
+        assert!(matches!(
+            Client::connect(&addr, "").await,
+            Err(Error::Login(err)) if err == "Username cannot be empty"
+        ));
+
+        let client = Client::connect(&addr, "seller").await.unwrap();
+        let mut notifications = client.notifications().await.unwrap();
+
+        assert!(client.deposit("ore", 10).await.is_ok());
+        assert_eq!(
+            client
+                .execute_with_id(
+                    "deposit-1",
+                    Request::Deposit {
+                        item_name: "ore".into(),
+                        quantity: 5
+                    }
+                )
+                .await
+                .unwrap(),
+            Response::Deposited {
+                item_name: "ore".into(),
+                quantity: 5
+            }
+        );
+        assert_eq!(
+            client.items().await.unwrap(),
+            vec![ItemBalance {
+                name: "funds".into(),
+                quantity: 10,
+                in_bids: 0,
+                listed: 0,
+            }]
+        );
+        assert_eq!(
+            client
+                .sell(SellOrderType::Auction, "ore", 10, 50)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            notifications.next().await,
+            Some(MarketEvent::OrderPlaced {
+                order: sell_order()
+            })
+        );
+
+        let err = client.cancel(2).await.unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::OrderNotFound));
+        assert_eq!(err.to_string(), "Sell order #2 doesn't exist");
+
+        // Unexpected response type
+        assert!(matches!(client.buy(1).await, Err(Error::Protocol(_))));
+    }
+
+    #[tokio::test]
+    async fn events_during_login() {
+        let (addr, _) = fake_server().await;
+        let client = Client::connect(&addr, "recipient").await.unwrap();
+        assert!(client.deposit("ore", 1).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn reconnect() {
+        let (addr, connections) = fake_server().await;
+        let config = Config {
+            reconnect_attempts: 3,
+            reconnect_delay: Duration::from_millis(1),
+        };
+        let client = Client::connect_with_config(&addr, "seller", config)
+            .await
+            .unwrap();
+        let mut notifications = client.notifications().await.unwrap();
+
+        // The request is lost together with the connection
+        assert!(matches!(
+            client.withdraw("ore", 1).await,
+            Err(Error::Disconnected)
+        ));
+
+        // Following requests are sent via a new connection and the subscription is restored
+        assert!(client.deposit("ore", 1).await.is_ok());
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+        assert!(client
+            .sell(SellOrderType::Auction, "ore", 10, 50)
+            .await
+            .is_ok());
+        assert!(notifications.next().await.is_some());
+    }
+}
@@ -0,0 +1,175 @@
+//! Bids on auctions of a single item at the last moment, so other bidders have no time to react.
+
+use std::collections::HashMap;
+
+use anyhow::{Context as _, Result};
+use auction_client::{MarketEvent, SellOrder, SellOrderType};
+use serde::Deserialize;
+use time::{macros::format_description, PrimitiveDateTime};
+
+use crate::strategy::{Context, Strategy};
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct AuctionSniperConfig {
+    pub(crate) item_name: String,
+    /// Maximum bid per item
+    pub(crate) max_unit_bid: i64,
+    /// Bids are placed only if the auction expires within this many seconds
+    #[serde(default = "default_snipe_window_secs")]
+    pub(crate) snipe_window_secs: i64,
+    /// Bid is higher than the current price by this many funds
+    #[serde(default = "default_bid_step")]
+    pub(crate) bid_step: i64,
+}
+
+fn default_snipe_window_secs() -> i64 {
+    5
+}
+
+fn default_bid_step() -> i64 {
+    1
+}
+
+pub(crate) struct AuctionSniper {
+    config: AuctionSniperConfig,
+    // Open auctions of the item, by order id
+    auctions: HashMap<i64, SellOrder>,
+    // Last own bid, by order id
+    bids: HashMap<i64, i64>,
+}
+
+impl AuctionSniper {
+    pub(crate) fn new(config: AuctionSniperConfig) -> Self {
+        Self {
+            config,
+            auctions: HashMap::new(),
+            bids: HashMap::new(),
+        }
+    }
+
+    fn track(&mut self, order: &SellOrder, username: &str) {
+        if order.order_type == SellOrderType::Auction
+            && order.is_priced_in_funds()
+            && order.instance.is_none()
+            && !order.is_bundle()
+            && order.item_name == self.config.item_name
+            && order.seller_name != username
+        {
+            self.auctions.insert(order.id, order.clone());
+        }
+    }
+
+    fn forget(&mut self, order_id: i64) {
+        self.auctions.remove(&order_id);
+        self.bids.remove(&order_id);
+    }
+
+    // Bid to place on the auction at `unix_now`, if any
+    fn next_bid(&self, order: &SellOrder, unix_now: i64) -> Result<Option<i64>> {
+        let expires_in = unix_timestamp(&order.expiration_time)? - unix_now;
+        let max_bid = self.config.max_unit_bid * order.quantity;
+        let bid = (order.price + self.config.bid_step).min(max_bid);
+
+        // The current price is our own bid
+        let is_leading = self.bids.get(&order.id) == Some(&order.price);
+        if expires_in > self.config.snipe_window_secs || is_leading || bid <= order.price {
+            return Ok(None);
+        }
+        Ok(Some(bid))
+    }
+}
+
+impl Strategy for AuctionSniper {
+    async fn start(&mut self, ctx: &Context) -> Result<()> {
+        for order in ctx.client.orders().await? {
+            self.track(&order, &ctx.username);
+        }
+        Ok(())
+    }
+
+    async fn on_event(&mut self, ctx: &Context, event: &MarketEvent) -> Result<()> {
+        match event {
+            MarketEvent::OrderPlaced { order } | MarketEvent::BidPlaced { order } => {
+                self.track(order, &ctx.username)
+            }
+            MarketEvent::OrderBought { order }
+            | MarketEvent::OrderExpired { order }
+            | MarketEvent::OrderCancelled { order } => self.forget(order.id),
+            // Personal notifications don't affect auctions
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn on_tick(&mut self, ctx: &Context) -> Result<()> {
+        let unix_now = std::time::UNIX_EPOCH.elapsed()?.as_secs() as i64;
+        let mut bids = Vec::new();
+        for order in self.auctions.values() {
+            if let Some(bid) = self.next_bid(order, unix_now)? {
+                bids.push((order.id, bid));
+            }
+        }
+
+        for (order_id, bid) in bids {
+            ctx.client.bid(order_id, bid).await?;
+            self.bids.insert(order_id, bid);
+        }
+        Ok(())
+    }
+}
+
+// Expiration time is formatted by the server as "YYYY-MM-DD HH:MM:SS" in UTC
+fn unix_timestamp(time: &str) -> Result<i64> {
+    let format = format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+    let time = PrimitiveDateTime::parse(time, format)
+        .with_context(|| format!("Invalid expiration time '{time}'"))?;
+    Ok(time.assume_utc().unix_timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unix_timestamp() {
+        assert_eq!(unix_timestamp("1970-01-01 00:00:00").unwrap(), 0);
+        assert_eq!(unix_timestamp("2021-01-01 00:00:10").unwrap(), 1609459210);
+        assert!(unix_timestamp("2021-01-01").is_err());
+    }
+
+    #[test]
+    fn next_bid() {
+        let mut sniper = AuctionSniper::new(AuctionSniperConfig {
+            item_name: "Sword".into(),
+            max_unit_bid: 50,
+            snipe_window_secs: 5,
+            bid_step: 10,
+        });
+        let order = |quantity, price| SellOrder {
+            id: 1,
+            seller_name: "smith".into(),
+            item_name: "Sword".into(),
+            quantity,
+            price,
+            price_item: "funds".into(),
+            expiration_time: "1970-01-01 00:01:00".into(),
+            order_type: SellOrderType::Auction,
+            instance: None,
+            bundle: Vec::new(),
+        };
+
+        // Too early
+        assert_eq!(sniper.next_bid(&order(1, 20), 54).unwrap(), None);
+        assert_eq!(sniper.next_bid(&order(1, 20), 55).unwrap(), Some(30));
+        // Limited by the max bid
+        assert_eq!(sniper.next_bid(&order(1, 45), 55).unwrap(), Some(50));
+        assert_eq!(sniper.next_bid(&order(1, 50), 55).unwrap(), None);
+        assert_eq!(sniper.next_bid(&order(2, 50), 55).unwrap(), Some(60));
+
+        // Don't outbid ourselves
+        sniper.bids.insert(1, 30);
+        assert_eq!(sniper.next_bid(&order(1, 30), 55).unwrap(), None);
+        assert_eq!(sniper.next_bid(&order(1, 35), 55).unwrap(), Some(45));
+    }
+}
@@ -0,0 +1,118 @@
+//! Buys immediate sell orders that are cheaper than the configured price per item
+//! and optionally resells them with a markup.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use auction_client::{MarketEvent, SellOrder, SellOrderType};
+use serde::Deserialize;
+
+use crate::strategy::{Context, Strategy};
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BargainHunterConfig {
+    /// Maximum price of a single item the hunter is willing to pay, per item name
+    pub(crate) max_unit_prices: HashMap<String, i64>,
+    /// Bought items are put on sale with this markup, if set
+    #[serde(default)]
+    pub(crate) resell_markup_percent: Option<i64>,
+}
+
+pub(crate) struct BargainHunter {
+    config: BargainHunterConfig,
+}
+
+impl BargainHunter {
+    pub(crate) fn new(config: BargainHunterConfig) -> Self {
+        Self { config }
+    }
+
+    fn is_bargain(&self, order: &SellOrder, username: &str) -> bool {
+        order.order_type == SellOrderType::Immediate
+            && order.is_priced_in_funds()
+            && order.instance.is_none()
+            && !order.is_bundle()
+            && order.seller_name != username
+            && self
+                .config
+                .max_unit_prices
+                .get(&order.item_name)
+                .is_some_and(|max_unit_price| order.price <= max_unit_price * order.quantity)
+    }
+
+    async fn hunt(&self, ctx: &Context, order: &SellOrder) -> Result<()> {
+        if !self.is_bargain(order, &ctx.username) {
+            return Ok(());
+        }
+
+        ctx.client.buy(order.id).await?;
+        if let Some(markup_percent) = self.config.resell_markup_percent {
+            let price = order.price * (100 + markup_percent) / 100;
+            ctx.client
+                .sell(
+                    SellOrderType::Immediate,
+                    &order.item_name,
+                    order.quantity,
+                    price,
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+impl Strategy for BargainHunter {
+    // Orders placed before the hunter has started are bargains as well
+    async fn start(&mut self, ctx: &Context) -> Result<()> {
+        for order in ctx.client.orders().await? {
+            // Someone else might be faster, which shouldn't stop the bot
+            if let Err(err) = self.hunt(ctx, &order).await {
+                println!("{}: {err:#}", ctx.username);
+            }
+        }
+        Ok(())
+    }
+
+    async fn on_event(&mut self, ctx: &Context, event: &MarketEvent) -> Result<()> {
+        match event {
+            MarketEvent::OrderPlaced { order } => self.hunt(ctx, order).await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn on_tick(&mut self, _ctx: &Context) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_bargain() {
+        let hunter = BargainHunter::new(BargainHunterConfig {
+            max_unit_prices: HashMap::from([("ore".into(), 8), ("Sword".into(), 100)]),
+            resell_markup_percent: None,
+        });
+        let order = |seller_name: &str, item_name: &str, quantity, price| SellOrder {
+            id: 1,
+            seller_name: seller_name.into(),
+            item_name: item_name.into(),
+            quantity,
+            price,
+            price_item: "funds".into(),
+            expiration_time: "2021-01-01 00:00:00".into(),
+            order_type: SellOrderType::Immediate,
+            instance: None,
+            bundle: Vec::new(),
+        };
+
+        assert!(hunter.is_bargain(&order("miner", "ore", 10, 80), "hunter"));
+        assert!(!hunter.is_bargain(&order("miner", "ore", 10, 81), "hunter"));
+        assert!(hunter.is_bargain(&order("smith", "Sword", 1, 100), "hunter"));
+        assert!(!hunter.is_bargain(&order("miner", "gold", 1, 1), "hunter"));
+        assert!(!hunter.is_bargain(&order("hunter", "ore", 1, 1), "hunter"));
+    }
+}
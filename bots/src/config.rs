@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    auction_sniper::AuctionSniperConfig, bargain_hunter::BargainHunterConfig,
+    market_maker::MarketMakerConfig,
+};
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Address of the server. Example: localhost:3000
+    pub(crate) server: String,
+    #[serde(default)]
+    pub(crate) bots: Vec<BotConfig>,
+}
+
+/// Group of bots that run the same strategy
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BotConfig {
+    /// Bots are logged in as `<name>-1`, `<name>-2`, ...
+    pub(crate) name: String,
+    #[serde(default = "default_count")]
+    pub(crate) count: u32,
+    #[serde(default = "default_tick_interval_ms")]
+    pub(crate) tick_interval_ms: u64,
+    /// Items deposited by every bot once it's logged in. Example: `{ funds = 1000, ore = 10 }`
+    #[serde(default)]
+    pub(crate) deposit: BTreeMap<String, i64>,
+    pub(crate) strategy: StrategyConfig,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum StrategyConfig {
+    MarketMaker(MarketMakerConfig),
+    BargainHunter(BargainHunterConfig),
+    AuctionSniper(AuctionSniperConfig),
+}
+
+fn default_count() -> u32 {
+    1
+}
+
+fn default_tick_interval_ms() -> u64 {
+    1000
+}
+
+impl Config {
+    pub(crate) fn load(path: &str) -> Result<Self> {
+        let content =
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read {path}"))?;
+        Self::parse(&content).with_context(|| format!("Failed to parse {path}"))
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parse() {
+        let config = Config::parse(
+            r#"
+            server = "localhost:3000"
+
+            [[bots]]
+            name = "maker"
+            count = 2
+            deposit = { funds = 100, ore = 50 }
+            strategy = { type = "market_maker", item_name = "ore", fair_price = 10, spread = 2, lot_size = 5 }
+
+            [[bots]]
+            name = "hunter"
+            tick_interval_ms = 500
+            strategy = { type = "bargain_hunter", max_unit_prices = { ore = 8 }, resell_markup_percent = 20 }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                server: "localhost:3000".into(),
+                bots: vec![
+                    BotConfig {
+                        name: "maker".into(),
+                        count: 2,
+                        tick_interval_ms: 1000,
+                        deposit: BTreeMap::from([("funds".into(), 100), ("ore".into(), 50)]),
+                        strategy: StrategyConfig::MarketMaker(MarketMakerConfig {
+                            item_name: "ore".into(),
+                            fair_price: 10,
+                            spread: 2,
+                            lot_size: 5,
+                        }),
+                    },
+                    BotConfig {
+                        name: "hunter".into(),
+                        count: 1,
+                        tick_interval_ms: 500,
+                        deposit: BTreeMap::new(),
+                        strategy: StrategyConfig::BargainHunter(BargainHunterConfig {
+                            max_unit_prices: HashMap::from([("ore".into(), 8)]),
+                            resell_markup_percent: Some(20),
+                        }),
+                    },
+                ],
+            }
+        );
+
+        assert!(Config::parse("server = 'localhost:3000'\nport = 3000").is_err());
+        assert!(Config::parse(
+            "server = 'localhost:3000'\n[[bots]]\nname = 'x'\nstrategy = { type = 'unknown' }"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn example_config() {
+        let config = Config::parse(include_str!("../bots.toml")).unwrap();
+        assert_eq!(config.bots.len(), 3);
+    }
+}
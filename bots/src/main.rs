@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use auction_client::Client;
+use clap::Parser;
+use tokio::task::JoinSet;
+
+use config::{Config, StrategyConfig};
+use strategy::Context;
+
+mod auction_sniper;
+mod bargain_hunter;
+mod config;
+mod market_maker;
+mod strategy;
+
+#[derive(Parser)]
+#[command(about)]
+struct Cli {
+    /// Path to the config file. Example: bots/bots.toml
+    #[arg(short, long)]
+    config: String,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let config = Config::load(&cli.config)?;
+
+    let mut bots = JoinSet::new();
+    for bot in config.bots {
+        for i in 1..=bot.count {
+            let username = format!("{}-{i}", bot.name);
+            let ctx = Context {
+                client: Client::connect(&config.server, &username).await?,
+                username,
+            };
+            let deposit = bot.deposit.clone();
+            let tick_interval = Duration::from_millis(bot.tick_interval_ms);
+
+            // Every bot gets its own instance of the strategy
+            match &bot.strategy {
+                StrategyConfig::MarketMaker(config) => bots.spawn(strategy::run(
+                    ctx,
+                    deposit,
+                    tick_interval,
+                    market_maker::MarketMaker::new(config.clone()),
+                )),
+                StrategyConfig::BargainHunter(config) => bots.spawn(strategy::run(
+                    ctx,
+                    deposit,
+                    tick_interval,
+                    bargain_hunter::BargainHunter::new(config.clone()),
+                )),
+                StrategyConfig::AuctionSniper(config) => bots.spawn(strategy::run(
+                    ctx,
+                    deposit,
+                    tick_interval,
+                    auction_sniper::AuctionSniper::new(config.clone()),
+                )),
+            };
+        }
+    }
+    println!("Started {} bot(s)", bots.len());
+
+    while let Some(result) = bots.join_next().await {
+        if let Err(err) = result? {
+            println!("Bot stopped: {err:#}");
+        }
+    }
+    Ok(())
+}
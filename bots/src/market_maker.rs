@@ -0,0 +1,119 @@
+//! Keeps the market of a single item liquid: always has an immediate sell order slightly above
+//! the fair price and buys everything that is offered slightly below it.
+
+use anyhow::Result;
+use auction_client::{MarketEvent, SellOrder, SellOrderType};
+use serde::Deserialize;
+
+use crate::strategy::{Context, Strategy};
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct MarketMakerConfig {
+    pub(crate) item_name: String,
+    /// Fair price of a single item
+    pub(crate) fair_price: i64,
+    /// Items are sold for `fair_price + spread` and bought for `fair_price - spread` per item
+    pub(crate) spread: i64,
+    /// Number of items in the sell order
+    pub(crate) lot_size: i64,
+}
+
+pub(crate) struct MarketMaker {
+    config: MarketMakerConfig,
+    // Own sell order that is still on the market
+    order_id: Option<i64>,
+}
+
+impl MarketMaker {
+    pub(crate) fn new(config: MarketMakerConfig) -> Self {
+        Self {
+            config,
+            order_id: None,
+        }
+    }
+
+    fn is_cheap(&self, order: &SellOrder, username: &str) -> bool {
+        order.order_type == SellOrderType::Immediate
+            && order.is_priced_in_funds()
+            && order.instance.is_none()
+            && !order.is_bundle()
+            && order.item_name == self.config.item_name
+            && order.seller_name != username
+            && order.price <= (self.config.fair_price - self.config.spread) * order.quantity
+    }
+}
+
+impl Strategy for MarketMaker {
+    async fn on_event(&mut self, ctx: &Context, event: &MarketEvent) -> Result<()> {
+        match event {
+            MarketEvent::OrderPlaced { order } if self.is_cheap(order, &ctx.username) => {
+                ctx.client.buy(order.id).await?;
+            }
+            MarketEvent::OrderBought { order }
+            | MarketEvent::OrderExpired { order }
+            | MarketEvent::OrderCancelled { order }
+                if self.order_id == Some(order.id) =>
+            {
+                self.order_id = None;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn on_tick(&mut self, ctx: &Context) -> Result<()> {
+        if self.order_id.is_none() {
+            let price = (self.config.fair_price + self.config.spread) * self.config.lot_size;
+            let order_id = ctx
+                .client
+                .sell(
+                    SellOrderType::Immediate,
+                    &self.config.item_name,
+                    self.config.lot_size,
+                    price,
+                )
+                .await?;
+            self.order_id = Some(order_id);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_cheap() {
+        let market_maker = MarketMaker::new(MarketMakerConfig {
+            item_name: "ore".into(),
+            fair_price: 10,
+            spread: 2,
+            lot_size: 5,
+        });
+        let order = |seller_name: &str, item_name: &str, quantity, price, order_type| SellOrder {
+            id: 1,
+            seller_name: seller_name.into(),
+            item_name: item_name.into(),
+            quantity,
+            price,
+            price_item: "funds".into(),
+            expiration_time: "2021-01-01 00:00:00".into(),
+            order_type,
+            instance: None,
+            bundle: Vec::new(),
+        };
+
+        let immediate = SellOrderType::Immediate;
+        assert!(market_maker.is_cheap(&order("miner", "ore", 1, 8, immediate), "maker"));
+        assert!(market_maker.is_cheap(&order("miner", "ore", 10, 80, immediate), "maker"));
+        assert!(!market_maker.is_cheap(&order("miner", "ore", 10, 81, immediate), "maker"));
+        assert!(!market_maker.is_cheap(&order("miner", "gold", 1, 1, immediate), "maker"));
+        assert!(!market_maker.is_cheap(&order("maker", "ore", 1, 1, immediate), "maker"));
+        assert!(!market_maker.is_cheap(
+            &order("miner", "ore", 1, 1, SellOrderType::Auction),
+            "maker"
+        ));
+    }
+}
@@ -0,0 +1,53 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::{anyhow, Result};
+use auction_client::{Client, MarketEvent};
+
+/// Logged in bot
+pub(crate) struct Context {
+    pub(crate) client: Client,
+    pub(crate) username: String,
+}
+
+/// Trading logic of a bot. Errors returned by the callbacks are logged and the bot keeps running,
+/// since failed requests (e.g. not enough funds or an order bought by someone else) are expected
+pub(crate) trait Strategy {
+    /// Called once the bot is logged in and subscribed to market events
+    async fn start(&mut self, _ctx: &Context) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called for every market event, including events caused by the bot itself
+    async fn on_event(&mut self, ctx: &Context, event: &MarketEvent) -> Result<()>;
+
+    /// Called periodically, every `tick_interval_ms`
+    async fn on_tick(&mut self, ctx: &Context) -> Result<()>;
+}
+
+/// Runs the strategy until the connection is lost for good
+pub(crate) async fn run(
+    ctx: Context,
+    deposit: BTreeMap<String, i64>,
+    tick_interval: Duration,
+    mut strategy: impl Strategy,
+) -> Result<()> {
+    let mut notifications = ctx.client.notifications().await?;
+    for (item_name, quantity) in deposit {
+        ctx.client.deposit(&item_name, quantity).await?;
+    }
+    strategy.start(&ctx).await?;
+
+    let mut interval = tokio::time::interval(tick_interval);
+    loop {
+        let result = tokio::select! {
+            event = notifications.next() => match event {
+                Some(event) => strategy.on_event(&ctx, &event).await,
+                None => return Err(anyhow!("Connection to the server is lost")),
+            },
+            _ = interval.tick() => strategy.on_tick(&ctx).await,
+        };
+        if let Err(err) = result {
+            println!("{}: {err:#}", ctx.username);
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Tab completion of commands and their arguments. Candidates are fetched from the server
+//! over a separate connection that is logged in as the same user.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use auction_client::{Client, Response};
+use protocol::Request;
+use rustyline::{
+    completion::Completer, highlight::Highlighter, hint::Hinter, validate::Validator, Context,
+    Helper,
+};
+use tokio::runtime::Handle;
+
+// Completion blocks the input, so a slow server shouldn't freeze it for long
+const FETCH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Logged in connection used to fetch completion candidates
+#[derive(Clone)]
+pub(crate) struct Session {
+    client: Client,
+    username: String,
+    commands: Vec<String>,
+}
+
+impl Session {
+    pub(crate) async fn open(addr: &str, username: &str) -> auction_client::Result<Self> {
+        let client = Client::connect(addr, username).await?;
+        let commands = match client.execute(Request::Help).await? {
+            Response::Help { message } => parse_commands(&message),
+            _ => Vec::new(),
+        };
+        Ok(Self {
+            client,
+            username: username.to_owned(),
+            commands,
+        })
+    }
+}
+
+pub(crate) struct CommandCompleter {
+    runtime: Handle,
+    // Empty until the user is logged in
+    session: Arc<Mutex<Option<Session>>>,
+}
+
+impl CommandCompleter {
+    pub(crate) fn new(runtime: Handle) -> Self {
+        Self {
+            runtime,
+            session: Arc::default(),
+        }
+    }
+
+    pub(crate) fn session(&self) -> Arc<Mutex<Option<Session>>> {
+        self.session.clone()
+    }
+
+    fn candidates(&self, kind: Candidates) -> Vec<String> {
+        let Some(session) = self.session.lock().unwrap().clone() else {
+            return Vec::new();
+        };
+        match kind {
+            Candidates::Commands => session.commands,
+            Candidates::Keywords(keywords) => keywords.iter().map(|&k| k.to_owned()).collect(),
+            Candidates::ItemNames { order_types } => {
+                let mut candidates: Vec<String> = if order_types {
+                    vec!["immediate".into(), "auction".into()]
+                } else {
+                    Vec::new()
+                };
+                if let Some(Ok(items)) = self.fetch(session.client.items()) {
+                    candidates.extend(items.into_iter().map(|item| item.name));
+                }
+                candidates
+            }
+            Candidates::OrderIds { own } => match self.fetch(session.client.orders()) {
+                Some(Ok(orders)) => orders
+                    .into_iter()
+                    .filter(|order| (order.seller_name == session.username) == own)
+                    .map(|order| order.id.to_string())
+                    .collect(),
+                _ => Vec::new(),
+            },
+        }
+    }
+
+    fn fetch<T>(&self, future: impl std::future::Future<Output = T>) -> Option<T> {
+        // The timer has to be created within the runtime context
+        self.runtime
+            .block_on(async { tokio::time::timeout(FETCH_TIMEOUT, future).await })
+            .ok()
+    }
+}
+
+impl Completer for CommandCompleter {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let Some((start, kind)) = analyze(line) else {
+            return Ok((pos, Vec::new()));
+        };
+        let prefix = &line[start..];
+        let mut candidates: Vec<String> = self
+            .candidates(kind)
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(prefix))
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for CommandCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for CommandCompleter {}
+
+impl Validator for CommandCompleter {}
+
+impl Helper for CommandCompleter {}
+
+#[derive(Debug, PartialEq)]
+enum Candidates {
+    Commands,
+    Keywords(&'static [&'static str]),
+    /// Item names may contain spaces, so the rest of the line is completed
+    ItemNames {
+        order_types: bool,
+    },
+    /// Ids of own sell orders or sell orders of other users
+    OrderIds {
+        own: bool,
+    },
+}
+
+/// Returns the position where the completed word starts and what it should be completed with
+fn analyze(line: &str) -> Option<(usize, Candidates)> {
+    let offset = line.len() - line.trim_start().len();
+    let Some((command, args)) = line[offset..].split_once(' ') else {
+        return Some((offset, Candidates::Commands));
+    };
+    let args_start = line.len() - args.trim_start().len();
+    let args = &line[args_start..];
+
+    let kind = match command {
+        "format" => Candidates::Keywords(&["text", "json"]),
+        "subscribe" | "unsubscribe" => Candidates::Keywords(&["orders"]),
+        "batch" if !args.contains(' ') => Candidates::Keywords(&["begin", "commit", "abort"]),
+        "deposit" | "withdraw" => Candidates::ItemNames { order_types: false },
+        "sell" => match args.split_once(' ') {
+            Some(("immediate" | "auction", _)) => {
+                let item_start = args_start + args.find(' ')? + 1;
+                let item_start = line.len() - line[item_start..].trim_start().len();
+                return Some((item_start, Candidates::ItemNames { order_types: false }));
+            }
+            _ => Candidates::ItemNames { order_types: true },
+        },
+        "buy" if !args.contains(' ') => Candidates::OrderIds { own: false },
+        "cancel" if !args.contains(' ') => Candidates::OrderIds { own: true },
+        _ => return None,
+    };
+    Some((args_start, kind))
+}
+
+/// Extracts command names from the help message, where every command is described
+/// on a separate line as `- <command>: <description>`
+fn parse_commands(help: &str) -> Vec<String> {
+    help.lines()
+        .filter_map(|line| line.trim_start().strip_prefix("- ")?.split_once(':'))
+        .map(|(command, _)| command)
+        .filter(|command| {
+            !command.is_empty()
+                && command
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        })
+        .map(str::to_owned)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parameterized::parameterized;
+    use pretty_assertions::assert_eq;
+
+    #[parameterized(
+        line = {
+            "", "view", "  he", "format j", "subscribe ", "deposit ", "withdraw Long S",
+            "sell ", "sell auc", "sell auction ", "sell immediate  Sw", "buy ", "buy 1", "cancel 2",
+            "batch c",
+        },
+        expected = {
+            Some((0, Candidates::Commands)),
+            Some((0, Candidates::Commands)),
+            Some((2, Candidates::Commands)),
+            Some((7, Candidates::Keywords(&["text", "json"]))),
+            Some((10, Candidates::Keywords(&["orders"]))),
+            Some((8, Candidates::ItemNames { order_types: false })),
+            Some((9, Candidates::ItemNames { order_types: false })),
+            Some((5, Candidates::ItemNames { order_types: true })),
+            Some((5, Candidates::ItemNames { order_types: true })),
+            Some((13, Candidates::ItemNames { order_types: false })),
+            Some((16, Candidates::ItemNames { order_types: false })),
+            Some((4, Candidates::OrderIds { own: false })),
+            Some((4, Candidates::OrderIds { own: false })),
+            Some((7, Candidates::OrderIds { own: true })),
+            Some((6, Candidates::Keywords(&["begin", "commit", "abort"]))),
+        }
+    )]
+    fn analyze_line(line: &str, expected: Option<(usize, Candidates)>) {
+        // workaround for resolution ambiguity caused by `parameterized` macro
+        use pretty_assertions::assert_eq;
+        assert_eq!(analyze(line), expected);
+    }
+
+    #[parameterized(line = { "ping ", "buy 1 ", "whoami x", "unknown " })]
+    fn nothing_to_complete(line: &str) {
+        // workaround for resolution ambiguity caused by `parameterized` macro
+        use pretty_assertions::assert_eq;
+        assert_eq!(analyze(line), None);
+    }
+
+    #[test]
+    fn commands_from_help() {
+        let help = "Available commands:
+    - whoami: Displays the username of the current user
+    - format: Switches the response format for the current session. Format: 'format text|json'
+      - text - human readable responses (default)
+      - json - one JSON object per response with 'ok', 'error_code', 'error' and 'payload' fields
+
+    - view_sell_orders: Displays a list of all sell orders from all users
+    - buy: Executes immediate sell order or places a bid on a auction sell order. Format: 'buy <sell_order_id> [<bid>]'
+      - no bid - executes immediate sell order
+
+    Usage: <command> [<args>], where `[]` annotates optional argumet(s)";
+
+        assert_eq!(
+            parse_commands(help),
+            vec!["whoami", "format", "view_sell_orders", "buy"]
+        );
+    }
+}
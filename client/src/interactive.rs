@@ -0,0 +1,467 @@
+//! Interactive mode: sends every entered line to the server and prints everything the server sends back.
+//! A lost connection is restored with exponential backoff, the user is logged in again and the
+//! response format and subscription of the session are restored. Unanswered commands are repeated,
+//! state-changing ones carry a request id, so the server applies them at most once.
+
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use protocol::{codec::FrameReader, decode_login_result, Request, ServerMessage, TaggedRequest};
+use rustyline::{error::ReadlineError, history::DefaultHistory, Editor, ExternalPrinter};
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::{tcp::OwnedWriteHalf, TcpStream},
+};
+
+use crate::completion::{CommandCompleter, Session};
+
+const RECONNECT_ATTEMPTS: u32 = 8;
+// Doubled after every failed attempt, so the server has ~25 seconds to come back
+const RECONNECT_DELAY: Duration = Duration::from_millis(100);
+
+pub(crate) async fn run(
+    addr: &str,
+    username: Option<String>,
+    history: Option<PathBuf>,
+) -> Result<()> {
+    let stream = TcpStream::connect(addr).await?;
+
+    let completer = CommandCompleter::new(tokio::runtime::Handle::current());
+    let completion = completer.session();
+    let mut editor: Editor<CommandCompleter, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(completer));
+    if let Some(history) = &history {
+        // The history file doesn't exist on the first launch
+        let _ = editor.load_history(history);
+    }
+    // Responses are printed above the line that is being edited. Not supported if stdin isn't a terminal
+    let mut printer = editor.create_external_printer().ok();
+    let mut print = |message: String| match &mut printer {
+        Some(printer) => {
+            let _ = printer.print(message);
+        }
+        None => println!("{message}"),
+    };
+
+    let (cli_tx, cli_rx) = flume::bounded::<String>(1);
+
+    // Line editing blocks the thread, so it runs on a separate one. The thread exits once the user
+    // closes the input (Ctrl-D or Ctrl-C) or the next line is entered after the client has given up
+    let input_thread = std::thread::spawn(move || loop {
+        let line = match editor.readline("") {
+            Ok(line) => line,
+            Err(ReadlineError::Eof | ReadlineError::Interrupted) => break,
+            Err(err) => {
+                println!("Unable to read the input: {err}");
+                break;
+            }
+        };
+        if !line.trim().is_empty() {
+            let _ = editor.add_history_entry(line.as_str());
+            if let Some(history) = &history {
+                let _ = editor.save_history(history);
+            }
+        }
+        if cli_tx.send(line).is_err() {
+            break; // channel was closed, time to exit
+        }
+    });
+
+    // Request ids have to be unique among all sessions of the user
+    let started = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let mut state = SessionState::new(username, format!("{started:x}"));
+    let mut stream = Some(stream);
+    while let Some(connected) = stream.take() {
+        let (tcp_reader, mut tcp_writer) = connected.into_split();
+        let mut tcp_reader = FrameReader::new(BufReader::new(tcp_reader));
+
+        let requests = state.on_connect();
+        if send(&mut tcp_writer, &requests).await.is_err() {
+            stream = reconnect(addr, &mut state, &mut print).await;
+            continue;
+        }
+
+        loop {
+            tokio::select! {
+                input = cli_rx.recv_async() => {
+                    let Ok(input) = input else {
+                        // the user closed the input
+                        drop(cli_rx);
+                        let _ = input_thread.join();
+                        return Ok(());
+                    };
+                    let request = state.on_input(input);
+                    if send(&mut tcp_writer, &[request]).await.is_err() {
+                        break;
+                    }
+                }
+                frame = tcp_reader.read_frame() => match frame {
+                    Ok(Some(frame)) => {
+                        let frame = frame.to_owned();
+                        if let Some(username) = state.logged_in(&frame) {
+                            open_completion(addr, username, &completion);
+                        }
+                        if let Some(output) = state.on_frame(&frame) {
+                            print(output);
+                        }
+                    }
+                    Ok(None) | Err(_) => break,
+                },
+            }
+        }
+        stream = reconnect(addr, &mut state, &mut print).await;
+    }
+
+    print("Connection closed by server".into());
+    // The terminal is restored by the input thread once it's done with the current line
+    drop(cli_rx);
+    let _ = input_thread.join();
+    Ok(())
+}
+
+async fn send(tcp_writer: &mut OwnedWriteHalf, requests: &[String]) -> std::io::Result<()> {
+    for request in requests {
+        tcp_writer
+            .write_all(format!("{request}\n").as_bytes())
+            .await?;
+    }
+    Ok(())
+}
+
+async fn reconnect(
+    addr: &str,
+    state: &mut SessionState,
+    print: &mut impl FnMut(String),
+) -> Option<TcpStream> {
+    for message in state.on_disconnect() {
+        print(message);
+    }
+    let mut delay = RECONNECT_DELAY;
+    for _ in 0..RECONNECT_ATTEMPTS {
+        tokio::time::sleep(delay).await;
+        if let Ok(stream) = TcpStream::connect(addr).await {
+            return Some(stream);
+        }
+        delay *= 2;
+    }
+    None
+}
+
+// Completion needs a separate connection that is logged in as the same user.
+// It reconnects on its own, so it's opened only once
+fn open_completion(addr: &str, username: String, completion: &Arc<Mutex<Option<Session>>>) {
+    if completion.lock().unwrap().is_some() {
+        return;
+    }
+    let addr = addr.to_owned();
+    let completion = completion.clone();
+    tokio::spawn(async move {
+        if let Ok(opened) = Session::open(&addr, &username).await {
+            *completion.lock().unwrap() = Some(opened);
+        }
+    });
+}
+
+/// Sent request, or message the server sends on its own, that is waiting for the response
+#[derive(Debug, PartialEq)]
+enum Pending {
+    Greeting,
+    Login,
+    Command(String),
+    /// Command queued by the started batch
+    Queued(String),
+    /// Restores the session after reconnect, the response is not shown
+    Restore,
+}
+
+/// What is needed to restore the session on a new connection
+struct SessionState {
+    username: Option<String>,
+    /// Last `format` command, if any
+    format: Option<String>,
+    /// Last `subscribe` command, unless unsubscribed since then
+    subscription: Option<String>,
+    /// Responses are sent in the order of requests, so the first pending request is answered first
+    pending: VecDeque<Pending>,
+    /// Commands to repeat once the session is restored
+    replay: Vec<String>,
+    /// Whether the current connection restores a lost one, so the login is not shown again
+    resumed: bool,
+    /// State-changing commands are sent with an id, so they can be safely repeated after reconnect
+    request_id_prefix: String,
+    request_count: u64,
+    /// Whether a batch is started, so storage commands are queued until it's committed
+    batching: bool,
+}
+
+impl SessionState {
+    fn new(username: Option<String>, request_id_prefix: String) -> Self {
+        Self {
+            username,
+            format: None,
+            subscription: None,
+            pending: VecDeque::new(),
+            replay: Vec::new(),
+            resumed: false,
+            request_id_prefix,
+            request_count: 0,
+            batching: false,
+        }
+    }
+
+    /// Returns requests that have to be sent right after connecting
+    fn on_connect(&mut self) -> Vec<String> {
+        self.pending.push_back(Pending::Greeting);
+        let Some(username) = &self.username else {
+            return Vec::new();
+        };
+        self.pending.push_back(Pending::Login);
+        let mut requests = vec![username.clone()];
+        if self.resumed {
+            for request in [&self.format, &self.subscription].into_iter().flatten() {
+                self.pending.push_back(Pending::Restore);
+                requests.push(request.clone());
+            }
+            // Commands that were not answered are repeated once the session is restored
+            for command in self.replay.drain(..) {
+                self.pending.push_back(Pending::Command(command.clone()));
+                requests.push(command);
+            }
+        }
+        requests
+    }
+
+    /// Returns the request to send for the entered line
+    fn on_input(&mut self, input: String) -> String {
+        if self.username.is_none() && !self.pending.contains(&Pending::Login) {
+            // The first line answers the greeting
+            self.pending.push_back(Pending::Login);
+            return input;
+        }
+        let request = match TaggedRequest::parse(&input) {
+            // The server rejects ids of queued commands, the batch is committed with an id instead
+            Ok(TaggedRequest { request, .. }) if self.batching && request.can_be_batched() => {
+                self.pending.push_back(Pending::Queued(input.clone()));
+                return input;
+            }
+            Ok(TaggedRequest { id: None, request }) if request.changes_state() => {
+                if request == Request::BatchCommit {
+                    self.batching = false;
+                }
+                self.request_count += 1;
+                format!("@{}-{} {input}", self.request_id_prefix, self.request_count)
+            }
+            Ok(TaggedRequest { request, .. }) => {
+                match request {
+                    Request::Format(_) => self.format = Some(input.clone()),
+                    Request::Subscribe { .. } => self.subscription = Some(input.clone()),
+                    Request::Unsubscribe => self.subscription = None,
+                    Request::BatchBegin => self.batching = true,
+                    Request::BatchCommit | Request::BatchAbort => self.batching = false,
+                    _ => {}
+                }
+                input
+            }
+            Err(_) => input,
+        };
+        self.pending.push_back(Pending::Command(request.clone()));
+        request
+    }
+
+    /// Returns the username if the frame is a successful login response
+    fn logged_in(&self, frame: &str) -> Option<String> {
+        match self.pending.front() {
+            Some(Pending::Login) => decode_login_result(frame).ok().map(str::to_owned),
+            _ => None,
+        }
+    }
+
+    /// Returns what should be shown to the user
+    fn on_frame(&mut self, frame: &str) -> Option<String> {
+        if is_event(frame) {
+            return Some(format!("> {frame}"));
+        }
+        match self.pending.pop_front() {
+            Some(Pending::Greeting) | None if !self.resumed => Some(format!("> {frame}")),
+            Some(Pending::Login) => match decode_login_result(frame) {
+                Ok(username) => {
+                    self.username = Some(username.to_owned());
+                    if self.resumed {
+                        Some(format!("Reconnected as {username}"))
+                    } else {
+                        Some(format!("> {frame}"))
+                    }
+                }
+                Err(_) => {
+                    self.username = None;
+                    Some(format!("> {frame}"))
+                }
+            },
+            Some(Pending::Command(_) | Pending::Queued(_)) | None => Some(format!("> {frame}")),
+            Some(Pending::Greeting | Pending::Restore) => None,
+        }
+    }
+
+    /// Forgets the lost connection. Returns messages about commands that were not answered
+    fn on_disconnect(&mut self) -> Vec<String> {
+        let mut messages = vec!["Connection lost, reconnecting...".to_owned()];
+        self.resumed = self.username.is_some();
+        self.replay.clear();
+        // The server forgets the started batch along with the connection
+        if std::mem::take(&mut self.batching) {
+            messages.push(
+                "The started batch is discarded, start it again with 'batch begin'".to_owned(),
+            );
+        }
+        for pending in self.pending.drain(..) {
+            let Pending::Command(command) = pending else {
+                continue;
+            };
+            let tagged = TaggedRequest::parse(&command);
+            // The format and subscription of the session are restored on reconnect anyway
+            if tagged.as_ref().is_ok_and(|tagged| {
+                matches!(
+                    tagged.request,
+                    Request::Format(_) | Request::Subscribe { .. } | Request::Unsubscribe
+                )
+            }) {
+                continue;
+            }
+            // Repeating a command is only safe if it doesn't change anything or the server
+            // recognizes its id and doesn't apply it twice
+            if tagged.is_ok_and(|tagged| tagged.id.is_some() || tagged.request.is_read_only()) {
+                self.replay.push(command);
+            } else {
+                messages.push(format!(
+                    "'{command}' was not answered, it may or may not have been processed. \
+                    Check the state once reconnected before repeating it"
+                ));
+            }
+        }
+        messages
+    }
+}
+
+// Events are pushed by the server on its own and don't answer any request
+fn is_event(frame: &str) -> bool {
+    frame.starts_with("Event: ")
+        || matches!(ServerMessage::from_json(frame), Ok(ServerMessage::Event(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn login_on_greeting() {
+        let mut state = SessionState::new(None, "a1".into());
+        assert_eq!(state.on_connect(), Vec::<String>::new());
+        assert_eq!(state.on_frame("Welcome!"), Some("> Welcome!".to_owned()));
+        assert_eq!(state.on_input("Stepan".into()), "Stepan");
+        assert_eq!(
+            state.logged_in("Successfully logged in as Stepan"),
+            Some("Stepan".to_owned())
+        );
+        state.on_frame("Successfully logged in as Stepan");
+        assert_eq!(state.username.as_deref(), Some("Stepan"));
+
+        assert_eq!(state.on_input("ping".into()), "ping");
+        assert_eq!(state.logged_in("pong"), None);
+        assert_eq!(state.on_frame("pong"), Some("> pong".to_owned()));
+        assert!(state.pending.is_empty());
+    }
+
+    #[test]
+    fn resume_session() {
+        let mut state = SessionState::new(Some("Stepan".into()), "a1".into());
+        assert_eq!(state.on_connect(), vec!["Stepan"]);
+        state.on_frame("Welcome!");
+        state.on_frame("Successfully logged in as Stepan");
+
+        state.on_input("format json".into());
+        state.on_input("subscribe orders".into());
+        state.on_frame(r#"{"ok":true,"error_code":null,"error":null,"payload":{"type":"format","format":"json"}}"#);
+        state.on_input("view_items".into());
+        assert_eq!(
+            state.on_input("deposit funds 10".into()),
+            "@a1-1 deposit funds 10"
+        );
+        state.on_input("@mine buy 2".into());
+        state.on_input("view_sell_orders".into());
+
+        // The unanswered subscription is restored along with the format, so there is no warning
+        assert_eq!(
+            state.on_disconnect(),
+            vec!["Connection lost, reconnecting...".to_owned()]
+        );
+        assert_eq!(
+            state.replay,
+            vec![
+                "view_items",
+                "@a1-1 deposit funds 10",
+                "@mine buy 2",
+                "view_sell_orders"
+            ]
+        );
+
+        assert_eq!(
+            state.on_connect(),
+            vec![
+                "Stepan",
+                "format json",
+                "subscribe orders",
+                "view_items",
+                "@a1-1 deposit funds 10",
+                "@mine buy 2",
+                "view_sell_orders"
+            ]
+        );
+        assert_eq!(state.on_frame("Welcome!"), None);
+        assert_eq!(
+            state.on_frame("Successfully logged in as Stepan"),
+            Some("Reconnected as Stepan".to_owned())
+        );
+        assert_eq!(state.on_frame("format"), None);
+        assert_eq!(state.on_frame("subscribed"), None);
+        assert_eq!(state.on_frame("items"), Some("> items".to_owned()));
+        // events don't answer requests
+        assert_eq!(
+            state.on_frame("Event: order placed - #1"),
+            Some("> Event: order placed - #1".to_owned())
+        );
+        state.on_input("unsubscribe orders".into());
+        assert_eq!(state.subscription, None);
+    }
+
+    #[test]
+    fn batches() {
+        let mut state = SessionState::new(Some("Stepan".into()), "a1".into());
+        state.on_connect();
+        state.on_frame("Welcome!");
+        state.on_frame("Successfully logged in as Stepan");
+
+        assert_eq!(state.on_input("batch begin".into()), "batch begin");
+        assert_eq!(
+            state.on_input("withdraw funds 5".into()),
+            "withdraw funds 5"
+        );
+        assert_eq!(state.on_input("batch commit".into()), "@a1-1 batch commit");
+        assert_eq!(state.on_input("batch begin".into()), "batch begin");
+        assert_eq!(state.on_input("sell Sword 5".into()), "sell Sword 5");
+
+        let messages = state.on_disconnect();
+        assert_eq!(
+            messages[1],
+            "The started batch is discarded, start it again with 'batch begin'"
+        );
+        // Queued commands are lost with the batch, the committed batch is applied at most once
+        assert_eq!(state.replay, vec!["@a1-1 batch commit"]);
+        assert_eq!(state.on_input("sell Sword 5".into()), "@a1-2 sell Sword 5");
+    }
+}
@@ -1,6 +1,12 @@
+use std::path::PathBuf;
+
 use anyhow::Result;
 use clap::Parser;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+mod completion;
+mod interactive;
+mod script;
+mod tui;
 
 #[derive(Parser)]
 #[command(about)]
@@ -8,64 +14,50 @@ struct Cli {
     /// Address to connect to. Example: localhost:3000
     #[arg(value_name = "addr:port")]
     addr: String,
+    /// File to keep the history of entered commands in. Defaults to ~/.auction_history
+    #[arg(long, value_name = "path")]
+    history: Option<PathBuf>,
+    /// Logs in automatically as the user
+    #[arg(short, long, value_name = "username")]
+    user: Option<String>,
+    /// Runs the command and exits. Can be repeated, commands are executed in order and the client
+    /// exits with a non-zero code on the first failure. Example: -c "deposit funds 100"
+    #[arg(
+        short = 'c',
+        long = "command",
+        value_name = "command",
+        requires = "user"
+    )]
+    commands: Vec<String>,
+    /// Runs commands from the file, one per line, the same way as `--command`.
+    /// Empty lines and lines starting with '#' are skipped
+    #[arg(
+        long,
+        value_name = "path",
+        requires = "user",
+        conflicts_with = "commands"
+    )]
+    script: Option<String>,
+    /// Opens a full-screen dashboard with the inventory, live sell orders and notifications
+    #[arg(long, requires = "user", conflicts_with_all = ["commands", "script"])]
+    tui: bool,
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
-
-    let stream = tokio::net::TcpStream::connect(&cli.addr).await?;
-    let (mut tcp_reader, mut tcp_writer) = tokio::io::split(stream);
-
-    let tcp_reader_task = tokio::spawn(async move {
-        let mut buffer = [0; 1024];
-        loop {
-            let read_bytes = match tcp_reader.read(&mut buffer).await {
-                Ok(read_bytes) if read_bytes != 0 => read_bytes,
-                _ => {
-                    println!("Connection closed by server");
-                    break;
-                }
-            };
-            match std::str::from_utf8(&buffer[..read_bytes]) {
-                Ok(response) => println!("> {response}"),
-                Err(err) => println!("Non-utf8: {err}> {:?}", &buffer[..read_bytes]),
-            }
-        }
-    });
-
-    let (cli_tx, cli_rx) = flume::bounded(1);
-
-    let tcp_writer_task = tokio::spawn(async move {
-        loop {
-            let input: String = cli_rx
-                .recv_async()
-                .await
-                .expect("tx channel should always outlive rx");
-            if tcp_writer.write(input.as_bytes()).await.is_err() {
-                println!("Connection closed by server");
-                break;
-            }
-        }
+    if let (Some(username), true) = (&cli.user, cli.tui) {
+        return tui::run(&cli.addr, username).await;
+    }
+    if let (Some(username), Some(path)) = (&cli.user, &cli.script) {
+        return script::run(&cli.addr, username, &script::load(path)?).await;
+    }
+    if let (Some(username), false) = (&cli.user, cli.commands.is_empty()) {
+        return script::run(&cli.addr, username, &cli.commands).await;
+    }
+
+    let history = cli.history.or_else(|| {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".auction_history"))
     });
-
-    // We use a separate thread to read user input as a workaround for a know issue with blocking stdin.
-    // Once one of the tasks completes (i.e. the server closes the connection), everything will be
-    // canceled when the main task completes
-    std::thread::spawn(move || loop {
-        let mut buffer = String::new();
-        std::io::stdin().read_line(&mut buffer).unwrap();
-        if buffer.ends_with('\n') {
-            buffer.pop(); // `read_line` preserves the newline symbol that we don't need
-        }
-        if cli_tx.send(buffer).is_err() {
-            break; // channel was closed, time to exit
-        }
-    });
-
-    tokio::select! {
-        _ = tcp_reader_task => {},
-        _ = tcp_writer_task => {},
-    };
-    Ok(())
+    interactive::run(&cli.addr, cli.user, history).await
 }
@@ -0,0 +1,104 @@
+//! Non-interactive mode: runs commands one by one and stops at the first failure.
+
+use anyhow::{anyhow, Context, Result};
+use auction_client::{Client, Request, Response};
+use protocol::{ResponseFormat, TaggedRequest};
+
+/// Reads commands from the script file. Empty lines and lines starting with `#` are skipped
+pub(crate) fn load(path: &str) -> Result<Vec<String>> {
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read {path}"))?;
+    Ok(parse(&content))
+}
+
+fn parse(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Logs in as `username` and prints the response of every command
+pub(crate) async fn run(addr: &str, username: &str, commands: &[String]) -> Result<()> {
+    let client = Client::connect(addr, username).await?;
+    // The client always talks JSON to the server, the format only affects the output
+    let mut format = ResponseFormat::Text;
+
+    for command in commands {
+        let TaggedRequest { id, request } = TaggedRequest::parse(command)
+            .map_err(|err| anyhow!("Failed to process request '{command}': {err}"))?;
+        let result = match (request, id) {
+            (Request::Format(new_format), _) => {
+                format = new_format;
+                Ok(Response::Format { format })
+            }
+            (request, Some(id)) => client.execute_with_id(&id, request).await,
+            (request, None) => client.execute(request).await,
+        };
+        match result {
+            Ok(response) => println!("{}", format.encode(Ok(response))),
+            Err(err) => return Err(anyhow!("Failed to process request '{command}': {err}")),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use protocol::codec::{self, LineReader};
+    use tokio::{
+        io::{AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    #[test]
+    fn parse_script() {
+        let script = "# top up\ndeposit funds 100\n\n  view_items  \n#sell ore 10\n";
+        assert_eq!(parse(script), vec!["deposit funds 100", "view_items"]);
+    }
+
+    #[tokio::test]
+    async fn request_ids() {
+        // Fake server that records request lines and answers every one of them with a deposit
+        let listener = TcpListener::bind("localhost:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (tcp_reader, mut tcp_writer) = socket.into_split();
+            let mut tcp_reader = LineReader::new(BufReader::new(tcp_reader));
+            let mut write = async |payload: &str| {
+                let frame = codec::encode_frame(payload);
+                tcp_writer.write_all(frame.as_bytes()).await.unwrap();
+            };
+            write(protocol::GREETING).await;
+            tcp_reader.read_line().await.unwrap();
+            write(&protocol::encode_login_result(Ok("user"))).await;
+
+            let mut lines = Vec::new();
+            while let Ok(Some(line)) = tcp_reader.read_line().await {
+                lines.push(String::from_utf8(line.to_vec()).unwrap());
+                let response = Response::Deposited {
+                    item_name: "funds".into(),
+                    quantity: 10,
+                };
+                write(&ResponseFormat::Json.encode(Ok(response))).await;
+            }
+            lines
+        });
+
+        let commands = parse("@top-up-1 deposit funds 10\ndeposit funds 10\n");
+        assert!(run(&addr, "user", &commands).await.is_ok());
+        assert_eq!(
+            server.await.unwrap(),
+            vec![
+                "format json",
+                "@top-up-1 deposit funds 10",
+                "deposit funds 10"
+            ]
+        );
+    }
+}
@@ -0,0 +1,486 @@
+//! Full-screen market dashboard. Panes are loaded from the server on every market event and periodically,
+//! commands are entered in the prompt at the bottom.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use auction_client::{Client, ItemBalance, MarketEvent, Request, SellOrder};
+use protocol::ResponseFormat;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::{Style, Stylize},
+    text::Line,
+    widgets::{Block, List, ListItem, Paragraph},
+    DefaultTerminal, Frame,
+};
+use time::{macros::format_description, OffsetDateTime, PrimitiveDateTime};
+
+// Polling catches up on changes that don't produce market events, e.g. deposits
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+// Countdowns are redrawn every second
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_LOG_LINES: usize = 100;
+
+/// Logs in as `username` and runs the dashboard until the user quits with `Esc` or `Ctrl-C`
+pub(crate) async fn run(addr: &str, username: &str) -> Result<()> {
+    let client = Client::connect(addr, username).await?;
+    let mut notifications = client.notifications().await?;
+    let mut dashboard = Dashboard::new(username);
+    dashboard.refresh(&client).await;
+
+    // Reading terminal events blocks the thread. The thread is left behind once the dashboard is closed
+    let (events_tx, events_rx) = flume::unbounded();
+    std::thread::spawn(move || {
+        while let Ok(event) = event::read() {
+            if events_tx.send(event).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut terminal = ratatui::init();
+    let mut refresh = tokio::time::interval(REFRESH_INTERVAL);
+    let mut tick = tokio::time::interval(TICK_INTERVAL);
+    let result = loop {
+        if let Err(err) = draw(&mut terminal, &dashboard) {
+            break Err(err);
+        }
+        tokio::select! {
+            event = events_rx.recv_async() => match event {
+                Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                    match dashboard.on_key(key) {
+                        Action::Quit => break Ok(()),
+                        Action::Execute(command) => {
+                            dashboard.execute(&client, &command).await;
+                            dashboard.refresh(&client).await;
+                        }
+                        Action::None => {}
+                    }
+                }
+                Ok(_) => {} // e.g. resize, the dashboard is redrawn anyway
+                Err(_) => break Err(anyhow!("Unable to read terminal events")),
+            },
+            event = notifications.next() => match event {
+                Some(event) => {
+                    dashboard.on_event(&event);
+                    dashboard.refresh(&client).await;
+                }
+                None => break Err(anyhow!("Connection to the server is lost")),
+            },
+            _ = refresh.tick() => dashboard.refresh(&client).await,
+            _ = tick.tick() => {}
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+fn draw(terminal: &mut DefaultTerminal, dashboard: &Dashboard) -> Result<()> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    terminal.draw(|frame| dashboard.draw(frame, now))?;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    None,
+    Quit,
+    Execute(String),
+}
+
+struct Dashboard {
+    username: String,
+    items: Vec<ItemBalance>,
+    orders: Vec<SellOrder>,
+    /// Running auctions where the user is the highest bidder, as reported by the server
+    bids: Vec<SellOrder>,
+    /// Market events and responses to the entered commands, the latest is the last
+    log: Vec<String>,
+    input: String,
+}
+
+impl Dashboard {
+    fn new(username: &str) -> Self {
+        Self {
+            username: username.to_owned(),
+            items: Vec::new(),
+            orders: Vec::new(),
+            bids: Vec::new(),
+            log: Vec::new(),
+            input: String::new(),
+        }
+    }
+
+    async fn refresh(&mut self, client: &Client) {
+        match client.items().await {
+            Ok(items) => self.items = items,
+            Err(err) => self.push_log(format!("Failed to load items: {err}")),
+        }
+        match client.orders().await {
+            Ok(orders) => self.orders = orders,
+            Err(err) => self.push_log(format!("Failed to load sell orders: {err}")),
+        }
+        // Outbid and finished auctions drop out of the list, they are reported in the log by market events
+        match client.my_bids().await {
+            Ok(bids) => self.bids = bids,
+            Err(err) => self.push_log(format!("Failed to load bids: {err}")),
+        }
+    }
+
+    async fn execute(&mut self, client: &Client, command: &str) {
+        let request = match Request::parse(command) {
+            Ok(Request::Format(_)) => {
+                self.push_log("The format can't be changed in the dashboard".into());
+                return;
+            }
+            Ok(request) => request,
+            Err(err) => {
+                self.push_log(format!("Failed to process request: {err}"));
+                return;
+            }
+        };
+        let response = match client.execute(request).await {
+            Ok(response) => ResponseFormat::Text.encode(Ok(response)),
+            Err(auction_client::Error::Server(err)) => ResponseFormat::Text.encode(Err(err)),
+            Err(err) => format!("Failed to process request: {err}"),
+        };
+        self.push_log(format!("> {command}"));
+        self.push_log(response);
+    }
+
+    fn on_event(&mut self, event: &MarketEvent) {
+        self.push_log(ResponseFormat::Text.encode_event(event));
+    }
+
+    fn on_key(&mut self, key: KeyEvent) -> Action {
+        match key.code {
+            KeyCode::Esc => Action::Quit,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => Action::Quit,
+            KeyCode::Char(c) => {
+                self.input.push(c);
+                Action::None
+            }
+            KeyCode::Backspace => {
+                self.input.pop();
+                Action::None
+            }
+            KeyCode::Enter if !self.input.trim().is_empty() => {
+                Action::Execute(std::mem::take(&mut self.input))
+            }
+            _ => Action::None,
+        }
+    }
+
+    fn push_log(&mut self, message: String) {
+        self.log.extend(message.lines().map(str::to_owned));
+        let overflow = self.log.len().saturating_sub(MAX_LOG_LINES);
+        self.log.drain(..overflow);
+    }
+
+    fn draw(&self, frame: &mut Frame, now: i64) {
+        let [market, own, log, prompt] = Layout::vertical([
+            Constraint::Percentage(40),
+            Constraint::Percentage(25),
+            Constraint::Fill(1),
+            Constraint::Length(3),
+        ])
+        .areas(frame.area());
+        let [inventory, orders] =
+            Layout::horizontal([Constraint::Percentage(30), Constraint::Fill(1)]).areas(market);
+        let [own_orders, bids] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Fill(1)]).areas(own);
+
+        let items = self
+            .items
+            .iter()
+            .map(|item| ListItem::new(item_row_text(item)));
+        frame.render_widget(
+            List::new(items).block(Block::bordered().title(format!(" {} ", self.username))),
+            inventory,
+        );
+
+        let rows = self.orders.iter().map(|order| order_row(order, now));
+        frame.render_widget(
+            List::new(rows).block(Block::bordered().title(" Sell orders ")),
+            orders,
+        );
+
+        let rows = self
+            .orders
+            .iter()
+            .filter(|order| order.seller_name == self.username)
+            .map(|order| order_row(order, now));
+        frame.render_widget(
+            List::new(rows).block(Block::bordered().title(" My orders ")),
+            own_orders,
+        );
+
+        // The server only lists auctions the user leads, the bid is the current price
+        let rows = self.bids.iter().map(|order| {
+            ListItem::new(format!(
+                "#{} [{}] {} x{}: my bid {}, leading",
+                order.id,
+                countdown(order, now),
+                order.item_name,
+                order.quantity,
+                order.price
+            ))
+        });
+        frame.render_widget(
+            List::new(rows).block(Block::bordered().title(" My bids ")),
+            bids,
+        );
+
+        self.draw_log(frame, log);
+
+        frame.render_widget(
+            Paragraph::new(self.input.as_str())
+                .block(Block::bordered().title(" Command (Enter - execute, Esc - quit) ")),
+            prompt,
+        );
+        frame.set_cursor_position((
+            prompt.x + 1 + self.input.chars().count() as u16,
+            prompt.y + 1,
+        ));
+    }
+
+    fn draw_log(&self, frame: &mut Frame, area: Rect) {
+        // Only the latest lines that fit into the pane are shown
+        let height = area.height.saturating_sub(2) as usize;
+        let lines = self.log[self.log.len().saturating_sub(height)..]
+            .iter()
+            .map(|line| {
+                if line.starts_with("> ") {
+                    Line::styled(line.as_str(), Style::new().bold())
+                } else {
+                    Line::raw(line.as_str())
+                }
+            })
+            .collect::<Vec<_>>();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(" Notifications ")),
+            area,
+        );
+    }
+}
+
+fn item_row_text(item: &ItemBalance) -> String {
+    let mut text = format!("{}: {}", item.name, item.quantity);
+    if item.in_bids > 0 {
+        text.push_str(&format!(", {} in bids", item.in_bids));
+    }
+    if item.listed > 0 {
+        text.push_str(&format!(", {} listed", item.listed));
+    }
+    text
+}
+
+fn order_row(order: &SellOrder, now: i64) -> ListItem<'static> {
+    ListItem::new(order_row_text(order, now))
+}
+
+fn order_row_text(order: &SellOrder, now: i64) -> String {
+    let price_item = if order.is_priced_in_funds() {
+        String::new()
+    } else {
+        format!(" {}", order.price_item)
+    };
+    let items = if order.is_bundle() {
+        order
+            .bundle
+            .iter()
+            .map(|item| format!("{} x{}", item.name, item.quantity))
+            .collect::<Vec<_>>()
+            .join(", ")
+    } else if let Some(instance) = &order.instance {
+        format!("{} #{} x{}", order.item_name, instance.id, order.quantity)
+    } else {
+        format!("{} x{}", order.item_name, order.quantity)
+    };
+    format!(
+        "#{} [{}] {} {} {items} for {}{price_item}",
+        order.id,
+        countdown(order, now),
+        order.order_type,
+        order.seller_name,
+        order.price
+    )
+}
+
+fn countdown(order: &SellOrder, now: i64) -> String {
+    match unix_timestamp(&order.expiration_time) {
+        Ok(expiration_time) => format_countdown(expiration_time - now),
+        Err(_) => order.expiration_time.clone(),
+    }
+}
+
+/// Formats the time left as `mm:ss`, or `hh:mm:ss` if it's longer than an hour
+fn format_countdown(seconds: i64) -> String {
+    let seconds = seconds.max(0);
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes:02}:{seconds:02}")
+    }
+}
+
+fn unix_timestamp(time: &str) -> Result<i64> {
+    let format = format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+    let time = PrimitiveDateTime::parse(time, format)
+        .with_context(|| format!("Invalid expiration time '{time}'"))?;
+    Ok(time.assume_utc().unix_timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use auction_client::{Item, ItemBalance, SellOrderType};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn countdown() {
+        assert_eq!(format_countdown(0), "00:00");
+        assert_eq!(format_countdown(-5), "00:00");
+        assert_eq!(format_countdown(65), "01:05");
+        assert_eq!(format_countdown(3600 + 120 + 3), "1:02:03");
+    }
+
+    #[test]
+    fn order_text() {
+        let order = SellOrder {
+            id: 7,
+            seller_name: "Stepan".into(),
+            item_name: "ore".into(),
+            quantity: 10,
+            price: 50,
+            price_item: "funds".into(),
+            expiration_time: "2021-01-01 00:05:00".into(),
+            order_type: SellOrderType::Auction,
+            instance: None,
+            bundle: Vec::new(),
+        };
+        let now = unix_timestamp("2021-01-01 00:00:30").unwrap();
+        assert_eq!(
+            order_row_text(&order, now),
+            "#7 [04:30] auction Stepan ore x10 for 50"
+        );
+
+        let bundle = SellOrder {
+            bundle: vec![
+                Item {
+                    name: "ore".into(),
+                    quantity: 10,
+                },
+                Item {
+                    name: "gem".into(),
+                    quantity: 1,
+                },
+            ],
+            ..order
+        };
+        assert_eq!(
+            order_row_text(&bundle, now),
+            "#7 [04:30] auction Stepan ore x10, gem x1 for 50"
+        );
+    }
+
+    #[test]
+    fn prompt_input() {
+        let mut dashboard = Dashboard::new("Stepan");
+        let key = |code| KeyEvent::new(code, KeyModifiers::NONE);
+
+        assert_eq!(dashboard.on_key(key(KeyCode::Enter)), Action::None);
+        for c in "pinx".chars() {
+            assert_eq!(dashboard.on_key(key(KeyCode::Char(c))), Action::None);
+        }
+        dashboard.on_key(key(KeyCode::Backspace));
+        dashboard.on_key(key(KeyCode::Char('g')));
+        assert_eq!(
+            dashboard.on_key(key(KeyCode::Enter)),
+            Action::Execute("ping".into())
+        );
+        assert_eq!(dashboard.input, "");
+        assert_eq!(
+            dashboard.on_key(KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL)),
+            Action::Quit
+        );
+        assert_eq!(dashboard.on_key(key(KeyCode::Esc)), Action::Quit);
+    }
+
+    #[test]
+    fn log_is_limited() {
+        let mut dashboard = Dashboard::new("Stepan");
+        for i in 0..MAX_LOG_LINES {
+            dashboard.push_log(format!("line {i}"));
+        }
+        dashboard.push_log("multi\nline".into());
+        assert_eq!(dashboard.log.len(), MAX_LOG_LINES);
+        assert_eq!(dashboard.log[0], "line 2");
+        assert_eq!(dashboard.log[MAX_LOG_LINES - 1], "line");
+    }
+
+    #[test]
+    fn draw_panes() {
+        let order = |id, seller_name: &str, price| SellOrder {
+            id,
+            seller_name: seller_name.into(),
+            item_name: "ore".into(),
+            quantity: 5,
+            price,
+            price_item: "funds".into(),
+            expiration_time: "2021-01-01 00:05:00".into(),
+            order_type: SellOrderType::Auction,
+            instance: None,
+            bundle: Vec::new(),
+        };
+        let mut dashboard = Dashboard::new("Ann");
+        dashboard.items = vec![
+            ItemBalance {
+                name: "funds".into(),
+                quantity: 50,
+                in_bids: 50,
+                listed: 0,
+            },
+            ItemBalance {
+                name: "ore".into(),
+                quantity: 0,
+                in_bids: 0,
+                listed: 5,
+            },
+        ];
+        dashboard.orders = vec![order(1, "Bob", 50), order(2, "Ann", 10)];
+        dashboard.bids = vec![order(1, "Bob", 50)];
+        dashboard.push_log("order placed - #2".into());
+        dashboard.input = "view_items".into();
+
+        let now = unix_timestamp("2021-01-01 00:00:00").unwrap();
+        let mut terminal =
+            ratatui::Terminal::new(ratatui::backend::TestBackend::new(100, 30)).unwrap();
+        terminal.draw(|frame| dashboard.draw(frame, now)).unwrap();
+
+        let screen = terminal
+            .backend()
+            .buffer()
+            .content()
+            .chunks(100)
+            .map(|row| row.iter().map(|cell| cell.symbol()).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n");
+        for expected in [
+            "funds: 50, 50 in bids",
+            "ore: 0, 5 listed",
+            "#1 [05:00] auction Bob ore x5 for 50",
+            "#2 [05:00] auction Ann ore x5 for 10",
+            "#1 [05:00] ore x5: my bid 50, leading",
+            "order placed - #2",
+            "view_items",
+        ] {
+            assert!(
+                screen.contains(expected),
+                "'{expected}' is not drawn:\n{screen}"
+            );
+        }
+    }
+}
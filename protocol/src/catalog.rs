@@ -0,0 +1,53 @@
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+/// Item type defined by the server admin in the catalog
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CatalogItem {
+    pub name: String,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub description: String,
+    /// Maximum quantity of the item a user can hold, unlimited if not set
+    #[serde(default)]
+    pub max_stack: Option<i64>,
+    /// Whether the item can be sold, given or offered to other users
+    #[serde(default = "tradeable")]
+    pub tradeable: bool,
+    /// Alternative names the item can be referred to by
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+}
+
+fn tradeable() -> bool {
+    true
+}
+
+impl Display for CatalogItem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(display_name) = &self.display_name {
+            write!(f, " ({display_name})")?;
+        }
+        if let Some(category) = &self.category {
+            write!(f, " [{category}]")?;
+        }
+        if !self.description.is_empty() {
+            write!(f, ": {}", self.description)?;
+        }
+        if let Some(max_stack) = self.max_stack {
+            write!(f, ", at most {max_stack} per user")?;
+        }
+        if !self.tradeable {
+            write!(f, ", not tradeable")?;
+        }
+        if !self.aliases.is_empty() {
+            write!(f, ", also known as {}", self.aliases.join(", "))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+//! Line-based framing of the TCP protocol.
+//!
+//! Every request is a single line terminated by `\n` (`\r\n` sent by telnet is accepted as well).
+//! Every response consists of one or more lines, terminated by a line with a single `.` on it.
+//! Response lines that start with `.` are prefixed with an additional `.` (same as in SMTP),
+//! so the terminator can't be confused with the response payload.
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// Maximum length of a single request line, excluding the line terminator
+pub const MAX_LINE_LENGTH: usize = 4 * 1024;
+
+/// Maximum length of a single response line. Responses in the JSON format are single lines
+/// and might be much longer than requests, e.g. a list of all sell orders
+pub const MAX_RESPONSE_LINE_LENGTH: usize = 16 * 1024 * 1024;
+
+/// Line that terminates every response
+const FRAME_TERMINATOR: &str = ".";
+
+#[derive(Debug)]
+pub enum ReadError {
+    /// Line exceeds the maximum length. The whole line is discarded and
+    /// the reader is ready to read the next one.
+    TooLong {
+        max_length: usize,
+    },
+    /// Frame is not a valid utf8 string. Only `FrameReader` validates utf8, `LineReader` returns
+    /// raw bytes
+    InvalidUtf8(std::str::Utf8Error),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooLong { max_length } => write!(f, "Line is longer than {max_length} bytes"),
+            Self::InvalidUtf8(err) => write!(f, "Not a valid utf8 string: {err}"),
+            Self::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+impl From<std::io::Error> for ReadError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+// Buffered reader that splits incoming bytes into lines.
+// All the state is kept in the struct, so `read_line` is cancellation safe
+// and can be used in `tokio::select!`
+pub struct LineReader<R> {
+    reader: R,
+    max_line_length: usize,
+    line: Vec<u8>,
+    too_long: bool,
+    // The previous line was returned and should be cleared before reading the next one
+    completed: bool,
+}
+
+impl<R: AsyncBufRead + Unpin> LineReader<R> {
+    /// Creates a reader of request lines, limited by `MAX_LINE_LENGTH`
+    pub fn new(reader: R) -> Self {
+        Self::with_max_line_length(reader, MAX_LINE_LENGTH)
+    }
+
+    pub fn with_max_line_length(reader: R, max_line_length: usize) -> Self {
+        Self {
+            reader,
+            max_line_length,
+            line: Vec::new(),
+            too_long: false,
+            completed: false,
+        }
+    }
+
+    /// Reads the next line without the line terminator.
+    /// Returns `Ok(None)` once the peer has closed the connection.
+    pub async fn read_line(&mut self) -> Result<Option<&[u8]>, ReadError> {
+        if self.completed {
+            self.line.clear();
+            self.too_long = false;
+            self.completed = false;
+        }
+
+        let too_long = ReadError::TooLong {
+            max_length: self.max_line_length,
+        };
+        loop {
+            let available = self.reader.fill_buf().await?;
+            if available.is_empty() {
+                // Connection is closed. An unterminated line is still a valid request
+                if self.too_long {
+                    self.completed = true;
+                    return Err(too_long);
+                }
+                if self.line.is_empty() {
+                    return Ok(None);
+                }
+                break;
+            }
+
+            let (chunk, consumed, line_completed) = match available.iter().position(|&b| b == b'\n')
+            {
+                Some(pos) => (&available[..pos], pos + 1, true),
+                None => (available, available.len(), false),
+            };
+            // Keep consuming the rest of a too long line, but don't store it
+            if !self.too_long {
+                if self.line.len() + chunk.len() > self.max_line_length + 1 {
+                    self.too_long = true;
+                    self.line.clear();
+                } else {
+                    self.line.extend_from_slice(chunk);
+                }
+            }
+            self.reader.consume(consumed);
+
+            if line_completed {
+                break;
+            }
+        }
+
+        self.completed = true;
+        if self.line.last() == Some(&b'\r') {
+            self.line.pop();
+        }
+        if self.too_long || self.line.len() > self.max_line_length {
+            return Err(too_long);
+        }
+        Ok(Some(&self.line))
+    }
+}
+
+/// Encodes a (possibly multi-line) payload into a terminated frame
+pub fn encode_frame(payload: &str) -> String {
+    let mut frame = String::with_capacity(payload.len() + 3);
+    for line in payload.lines() {
+        if line.starts_with('.') {
+            frame.push('.');
+        }
+        frame.push_str(line);
+        frame.push('\n');
+    }
+    frame.push_str(FRAME_TERMINATOR);
+    frame.push('\n');
+    frame
+}
+
+// Client side reader that assembles response lines into frames.
+// Same as `LineReader`, it is cancellation safe
+pub struct FrameReader<R> {
+    lines: LineReader<R>,
+    frame: String,
+    completed: bool,
+}
+
+impl<R: AsyncBufRead + Unpin> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines: LineReader::with_max_line_length(reader, MAX_RESPONSE_LINE_LENGTH),
+            frame: String::new(),
+            completed: false,
+        }
+    }
+
+    /// Reads the next frame payload, without the terminator and with unescaped lines.
+    /// Returns `Ok(None)` once the peer has closed the connection.
+    pub async fn read_frame(&mut self) -> Result<Option<&str>, ReadError> {
+        if self.completed {
+            self.frame.clear();
+            self.completed = false;
+        }
+
+        loop {
+            let Some(line) = self.lines.read_line().await? else {
+                return Ok(None);
+            };
+            let line = std::str::from_utf8(line).map_err(ReadError::InvalidUtf8)?;
+            if line == FRAME_TERMINATOR {
+                self.completed = true;
+                return Ok(Some(&self.frame));
+            }
+            if !self.frame.is_empty() {
+                self.frame.push('\n');
+            }
+            self.frame.push_str(line.strip_prefix('.').unwrap_or(line));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read_all(input: &[u8]) -> Vec<Result<String, String>> {
+        let mut reader = LineReader::new(input);
+        let mut lines = Vec::new();
+        loop {
+            match reader.read_line().await {
+                Ok(Some(line)) => lines.push(Ok(String::from_utf8(line.to_vec()).unwrap())),
+                Ok(None) => break,
+                Err(err) => lines.push(Err(err.to_string())),
+            }
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn read_lines() {
+        assert_eq!(read_all(b"").await, vec![]);
+        assert_eq!(read_all(b"ping").await, vec![Ok("ping".into())]);
+        assert_eq!(read_all(b"ping\n").await, vec![Ok("ping".into())]);
+        assert_eq!(
+            read_all(b"ping\r\nwhoami\nview_items\r\n").await,
+            vec![
+                Ok("ping".into()),
+                Ok("whoami".into()),
+                Ok("view_items".into())
+            ]
+        );
+        assert_eq!(
+            read_all(b"\n\nping").await,
+            vec![Ok("".into()), Ok("".into()), Ok("ping".into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn read_long_lines() {
+        let max_line = "a".repeat(MAX_LINE_LENGTH);
+        let input = format!("{max_line}\r\n{max_line}a\nping\n{max_line}{max_line}");
+        let too_long = ReadError::TooLong {
+            max_length: MAX_LINE_LENGTH,
+        };
+        assert_eq!(
+            read_all(input.as_bytes()).await,
+            vec![
+                Ok(max_line),
+                Err(too_long.to_string()),
+                Ok("ping".into()),
+                Err(too_long.to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn read_lines_with_small_buffer() {
+        let input: &[u8] = b"deposit funds 100\r\nwithdraw funds 10\n";
+        let mut reader = LineReader::new(tokio::io::BufReader::with_capacity(3, input));
+        assert_eq!(
+            reader.read_line().await.unwrap(),
+            Some(&b"deposit funds 100"[..])
+        );
+        assert_eq!(
+            reader.read_line().await.unwrap(),
+            Some(&b"withdraw funds 10"[..])
+        );
+        assert_eq!(reader.read_line().await.unwrap(), None);
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(encode_frame("pong"), "pong\n.\n");
+        assert_eq!(encode_frame(""), ".\n");
+        assert_eq!(
+            encode_frame("Sell orders:\n- #1\n- #2"),
+            "Sell orders:\n- #1\n- #2\n.\n"
+        );
+        assert_eq!(encode_frame(".\n..x"), "..\n...x\n.\n");
+    }
+
+    #[tokio::test]
+    async fn read_frames() {
+        let payloads = ["pong", "", "Sell orders:\n- #1\n- #2", ".\n..x"];
+        let input = payloads.map(encode_frame).concat();
+
+        let mut reader = FrameReader::new(input.as_bytes());
+        for payload in payloads {
+            assert_eq!(reader.read_frame().await.unwrap(), Some(payload));
+        }
+        assert_eq!(reader.read_frame().await.unwrap(), None);
+
+        let mut reader = FrameReader::new(&b"\xffpong\n.\n"[..]);
+        assert!(matches!(
+            reader.read_frame().await,
+            Err(ReadError::InvalidUtf8(_))
+        ));
+    }
+}
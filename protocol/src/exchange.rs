@@ -0,0 +1,70 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ParseError;
+
+/// Rate of the exchange listing configured by the server admin: 1 `from` currency is exchanged
+/// for `rate` of the `to` currency
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExchangeRate {
+    pub from: String,
+    pub to: String,
+    pub rate: i64,
+}
+
+impl Display for ExchangeRate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "1 {} = {} {}(s)", self.from, self.rate, self.to)
+    }
+}
+
+// Parses "<from>/<to>=<rate>", e.g. "gem/gold=100"
+impl FromStr for ExchangeRate {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || {
+            ParseError(format!(
+                "Unable to parse exchange rate '{s}'. Expected: '<currency>/<currency>=<rate>'"
+            ))
+        };
+        let (pair, rate) = s.rsplit_once('=').ok_or_else(error)?;
+        let (from, to) = pair.split_once('/').ok_or_else(error)?;
+        let (from, to) = (from.trim(), to.trim());
+        if from.is_empty() || to.is_empty() {
+            return Err(error());
+        }
+        Ok(Self {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            rate: rate.trim().parse().map_err(|_| error())?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parse() {
+        let rate: ExchangeRate = "premium gem/gold=100".parse().unwrap();
+        assert_eq!(
+            rate,
+            ExchangeRate {
+                from: "premium gem".into(),
+                to: "gold".into(),
+                rate: 100
+            }
+        );
+        assert_eq!(rate.to_string(), "1 premium gem = 100 gold(s)");
+
+        assert!("gem=100".parse::<ExchangeRate>().is_err());
+        assert!("gem/gold".parse::<ExchangeRate>().is_err());
+        assert!("/gold=1".parse::<ExchangeRate>().is_err());
+        assert!("gem/gold=x".parse::<ExchangeRate>().is_err());
+    }
+}
@@ -0,0 +1,19 @@
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+/// Unique item with its own attributes, e.g. a sword with durability and enchantments.
+/// Instances are never merged with other items of the same name
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ItemInstance {
+    pub id: i64,
+    pub item_name: String,
+    /// JSON object with attributes defined by the game
+    pub attributes: serde_json::Value,
+}
+
+impl Display for ItemInstance {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} #{} {}", self.item_name, self.id, self.attributes)
+    }
+}
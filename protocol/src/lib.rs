@@ -0,0 +1,31 @@
+//! Protocol of the Auction House, shared by the `server` and all clients.
+//!
+//! - [`codec`] - line-based framing of requests and responses
+//! - [`Request`] - typed commands with parsing from and encoding into request lines
+//! - [`Response`] and [`MarketEvent`] - typed responses and pushed events in text and JSON formats
+
+mod catalog;
+pub mod codec;
+mod exchange;
+mod instance;
+mod login;
+mod offer;
+mod order;
+mod request;
+mod response;
+mod stats;
+mod watch;
+
+pub use catalog::CatalogItem;
+pub use exchange::ExchangeRate;
+pub use instance::ItemInstance;
+pub use login::{decode_login_result, encode_login_result, GREETING};
+pub use offer::Offer;
+pub use order::{MarketEvent, SellOrder, SellOrderType};
+pub use request::{ParseError, Request, TaggedRequest};
+pub use response::{
+    ErrorCode, ErrorResponse, Item, ItemBalance, JsonResponse, Response, ResponseFormat,
+    ServerMessage, Transaction, TransactionKind,
+};
+pub use stats::{ItemStats, Trade};
+pub use watch::{Watch, WatchKind};
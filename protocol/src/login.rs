@@ -0,0 +1,48 @@
+//! Login handshake of the TCP protocol. Once connected, the server sends `GREETING`,
+//! the client replies with a username and the server answers with the login result.
+
+/// First frame sent by the server to every new connection
+pub const GREETING: &str = "Welcome to Sundris Auction House, stranger! How can I call you?";
+
+const LOGIN_SUCCEEDED_PREFIX: &str = "Successfully logged in as ";
+const LOGIN_FAILED_PREFIX: &str = "Failed to login: ";
+
+/// Encodes the login result, where `Ok` is the username and `Err` is the error message
+pub fn encode_login_result(result: Result<&str, &str>) -> String {
+    match result {
+        Ok(username) => format!("{LOGIN_SUCCEEDED_PREFIX}{username}"),
+        Err(err) => format!("{LOGIN_FAILED_PREFIX}{err}"),
+    }
+}
+
+/// Decodes the login result encoded by `encode_login_result`.
+/// Unexpected payloads are returned as errors as is
+pub fn decode_login_result(payload: &str) -> Result<&str, &str> {
+    if let Some(username) = payload.strip_prefix(LOGIN_SUCCEEDED_PREFIX) {
+        Ok(username)
+    } else {
+        Err(payload.strip_prefix(LOGIN_FAILED_PREFIX).unwrap_or(payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn login_result() {
+        assert_eq!(
+            encode_login_result(Ok("seller")),
+            "Successfully logged in as seller"
+        );
+        assert_eq!(
+            encode_login_result(Err("Username cannot be empty")),
+            "Failed to login: Username cannot be empty"
+        );
+
+        for result in [Ok("holy seller"), Err("Username cannot be empty")] {
+            assert_eq!(decode_login_result(&encode_login_result(result)), result);
+        }
+        assert_eq!(decode_login_result("pong"), Err("pong"));
+    }
+}
@@ -0,0 +1,51 @@
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Item;
+
+/// Trade offer from one user to another. Items the sender gives are held by the server until
+/// the offer is accepted, declined or expired
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Offer {
+    pub id: i64,
+    pub sender: String,
+    pub recipient: String,
+    pub give: Vec<Item>,
+    pub want: Vec<Item>,
+    pub expiration_time: String,
+}
+
+impl Display for Offer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "#{}: {} offers {} to {} for {} until {}",
+            self.id,
+            self.sender,
+            ItemList(&self.give),
+            self.recipient,
+            ItemList(&self.want),
+            self.expiration_time
+        )
+    }
+}
+
+/// Formats items as "Sword, 20 arrow(s)"
+pub(crate) struct ItemList<'a>(pub(crate) &'a [Item]);
+
+impl Display for ItemList<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, item) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            if item.quantity == 1 {
+                write!(f, "{}", item.name)?;
+            } else {
+                write!(f, "{} {}(s)", item.quantity, item.name)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,265 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::offer::ItemList;
+use crate::{Item, ItemInstance, Offer, ParseError, Watch};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SellOrderType {
+    // Order will be immediately executed if there is a matching buy order
+    Immediate,
+    // Order will be executed only after the auction is over
+    Auction,
+}
+
+impl Display for SellOrderType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Immediate => write!(f, "immediate"),
+            Self::Auction => write!(f, "auction"),
+        }
+    }
+}
+
+impl FromStr for SellOrderType {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "immediate" => Ok(Self::Immediate),
+            "auction" => Ok(Self::Auction),
+            _ => Err(ParseError(format!(
+                "Unknown sell order type '{s}'. Expected: 'immediate' or 'auction'"
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SellOrder {
+    pub id: i64,
+    pub seller_name: String,
+    pub item_name: String,
+    pub quantity: i64,
+    pub price: i64,
+    /// Item the price is paid in: funds, another currency or any item for barter orders
+    #[serde(default = "funds")]
+    pub price_item: String,
+    pub expiration_time: String,
+    pub order_type: SellOrderType,
+    /// Unique item instance the order sells, if any. The quantity of such orders is 1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<ItemInstance>,
+    /// All items of a bundle order, sold together as a single lot. `item_name` and `quantity` are
+    /// the first of them. Empty for orders of a single item
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bundle: Vec<Item>,
+}
+
+impl SellOrder {
+    /// Orders priced in funds, as opposed to other currencies and barter orders
+    pub fn is_priced_in_funds(&self) -> bool {
+        self.price_item == "funds"
+    }
+
+    /// Bundle orders sell several different items together
+    pub fn is_bundle(&self) -> bool {
+        !self.bundle.is_empty()
+    }
+
+    fn price_str(&self) -> String {
+        if self.is_priced_in_funds() {
+            format!("{} funds", self.price)
+        } else {
+            format!("{} {}(s)", self.price, self.price_item)
+        }
+    }
+}
+
+fn funds() -> String {
+    "funds".into()
+}
+
+impl Display for SellOrder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let order_type_str = match self.order_type {
+            SellOrderType::Auction => "on auction ",
+            SellOrderType::Immediate => "",
+        };
+
+        if let Some(instance) = &self.instance {
+            write!(
+                f,
+                "#{}: {} is selling {} for {} {}until {}",
+                self.id,
+                self.seller_name,
+                instance,
+                self.price_str(),
+                order_type_str,
+                self.expiration_time
+            )
+        } else if self.is_bundle() {
+            write!(
+                f,
+                "#{}: {} is selling a bundle of {} for {} {}until {}",
+                self.id,
+                self.seller_name,
+                ItemList(&self.bundle),
+                self.price_str(),
+                order_type_str,
+                self.expiration_time
+            )
+        } else if self.quantity == 1 {
+            write!(
+                f,
+                "#{}: {} is selling a {} for {} {}until {}",
+                self.id,
+                self.seller_name,
+                self.item_name,
+                self.price_str(),
+                order_type_str,
+                self.expiration_time
+            )
+        } else {
+            write!(
+                f,
+                "#{}: {} is selling {} {}(s) for {} {}until {}",
+                self.id,
+                self.seller_name,
+                self.quantity,
+                self.item_name,
+                self.price_str(),
+                order_type_str,
+                self.expiration_time
+            )
+        }
+    }
+}
+
+/// Changes of the market, pushed once the change is committed. Changes of sell orders are pushed
+/// to all subscribers, personal events only to the user they are addressed to
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum MarketEvent {
+    OrderPlaced {
+        order: SellOrder,
+    },
+    // `order.price` is the new bid
+    BidPlaced {
+        order: SellOrder,
+    },
+    OrderBought {
+        order: SellOrder,
+    },
+    // Expired order is either executed (auction with a bid) or returned to the seller
+    OrderExpired {
+        order: SellOrder,
+    },
+    // Order is cancelled by the seller and items are returned to the seller
+    OrderCancelled {
+        order: SellOrder,
+    },
+    // Personal events are pushed to the user they are addressed to even without a subscription
+    ItemsReceived {
+        sender: String,
+        recipient: String,
+        item_name: String,
+        quantity: i64,
+    },
+    // Addressed to the recipient of the offer
+    OfferReceived {
+        offer: Offer,
+    },
+    // Addressed to the sender of the offer
+    OfferAccepted {
+        offer: Offer,
+    },
+    // Addressed to the other party of the offer
+    OfferDeclined {
+        offer: Offer,
+        declined_by: String,
+    },
+    // Addressed to the sender of the offer, items are returned to the sender
+    OfferExpired {
+        offer: Offer,
+    },
+    // Addressed to the watcher, the order is placed and matches the watch
+    WatchTriggered {
+        watcher: String,
+        watch: Watch,
+        order: SellOrder,
+    },
+}
+
+impl MarketEvent {
+    /// Sell order the event is about, if any
+    pub fn order(&self) -> Option<&SellOrder> {
+        match self {
+            Self::OrderPlaced { order }
+            | Self::BidPlaced { order }
+            | Self::OrderBought { order }
+            | Self::OrderExpired { order }
+            | Self::OrderCancelled { order } => Some(order),
+            Self::ItemsReceived { .. }
+            | Self::OfferReceived { .. }
+            | Self::OfferAccepted { .. }
+            | Self::OfferDeclined { .. }
+            | Self::OfferExpired { .. }
+            | Self::WatchTriggered { .. } => None,
+        }
+    }
+
+    /// User the event is addressed to, if it's personal
+    pub fn recipient(&self) -> Option<&str> {
+        match self {
+            Self::ItemsReceived { recipient, .. } => Some(recipient),
+            Self::OfferReceived { offer } => Some(&offer.recipient),
+            Self::OfferAccepted { offer } | Self::OfferExpired { offer } => Some(&offer.sender),
+            Self::OfferDeclined { offer, declined_by } if *declined_by == offer.sender => {
+                Some(&offer.recipient)
+            }
+            Self::OfferDeclined { offer, .. } => Some(&offer.sender),
+            Self::WatchTriggered { watcher, .. } => Some(watcher),
+            Self::OrderPlaced { .. }
+            | Self::BidPlaced { .. }
+            | Self::OrderBought { .. }
+            | Self::OrderExpired { .. }
+            | Self::OrderCancelled { .. } => None,
+        }
+    }
+}
+
+impl Display for MarketEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OrderPlaced { order } => write!(f, "order placed - {order}"),
+            Self::BidPlaced { order } => write!(f, "bid placed - {order}"),
+            Self::OrderBought { order } => write!(f, "order bought - {order}"),
+            Self::OrderExpired { order } => write!(f, "order expired - {order}"),
+            Self::OrderCancelled { order } => write!(f, "order cancelled - {order}"),
+            Self::ItemsReceived {
+                sender,
+                recipient: _,
+                item_name,
+                quantity,
+            } => write!(
+                f,
+                "items received - {sender} gave you {quantity} {item_name}(s)"
+            ),
+            Self::OfferReceived { offer } => write!(f, "offer received - {offer}"),
+            Self::OfferAccepted { offer } => write!(f, "offer accepted - {offer}"),
+            Self::OfferDeclined { offer, declined_by } => {
+                write!(f, "offer declined by {declined_by} - {offer}")
+            }
+            Self::OfferExpired { offer } => write!(f, "offer expired - {offer}"),
+            Self::WatchTriggered {
+                watcher: _,
+                watch,
+                order,
+            } => write!(f, "watched {} - {order}", watch.kind),
+        }
+    }
+}
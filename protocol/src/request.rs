@@ -0,0 +1,1505 @@
+use std::fmt::{Display, Formatter};
+
+use crate::{Item, ResponseFormat, SellOrderType, WatchKind};
+
+/// Request line that can't be parsed into a `Request`
+#[derive(Debug, PartialEq)]
+pub struct ParseError(pub(crate) String);
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    Ping,
+    Whoami,
+    Help,
+    Format(ResponseFormat),
+    /// Subscribes to all sell orders or only to orders of the specified item
+    Subscribe {
+        item_name: Option<String>,
+    },
+    Unsubscribe,
+
+    ViewItems,
+    Deposit {
+        item_name: String,
+        quantity: i64,
+    },
+    Withdraw {
+        item_name: String,
+        quantity: i64,
+    },
+    /// Unique item instances of the user with their attributes
+    ViewInstances,
+    /// Deposits a new instance of the item with attributes, a JSON object
+    DepositInstance {
+        item_name: String,
+        attributes: serde_json::Value,
+    },
+    WithdrawInstance {
+        instance_id: i64,
+    },
+    Give {
+        recipient: String,
+        item_name: String,
+        quantity: i64,
+    },
+    ViewTransactions,
+
+    /// Sell orders of all users, only items of the category if it's set
+    ViewSellOrders {
+        category: Option<String>,
+    },
+    Sell {
+        order_type: SellOrderType,
+        item_name: String,
+        quantity: i64,
+        price: i64,
+        /// Item the price is paid in, "funds" unless it's a barter
+        price_item: String,
+    },
+    /// Sells the unique item instance, buyers see its attributes
+    SellInstance {
+        order_type: SellOrderType,
+        instance_id: i64,
+        price: i64,
+        price_item: String,
+    },
+    /// Sells several different items together as a single lot
+    SellBundle {
+        order_type: SellOrderType,
+        items: Vec<Item>,
+        price: i64,
+        price_item: String,
+    },
+    Buy {
+        sell_order_id: i64,
+    },
+    Bid {
+        sell_order_id: i64,
+        bid: i64,
+    },
+    Cancel {
+        sell_order_id: i64,
+    },
+    /// Auction sell orders where the user is the current highest bidder
+    MyBids,
+    /// Price history and market statistics of the item
+    Stats {
+        item_name: String,
+    },
+    /// Recommends a price to list `quantity` of the item for, based on recent trades
+    SuggestPrice {
+        item_name: String,
+        quantity: i64,
+    },
+
+    /// Notifies the user about new sell orders of the kind, offline users find them in the mailbox
+    Watch {
+        kind: WatchKind,
+    },
+    Unwatch {
+        watch_id: i64,
+    },
+    ViewWatchlist,
+    /// Notifications received while the user was offline, removed once viewed
+    ViewMailbox,
+
+    /// Offers `give` items to the recipient in exchange for `want` items
+    Offer {
+        recipient: String,
+        give: Vec<Item>,
+        want: Vec<Item>,
+    },
+    Accept {
+        offer_id: i64,
+    },
+    Decline {
+        offer_id: i64,
+    },
+    ViewOffers,
+
+    /// Currencies and the exchange listing
+    ViewExchange,
+    /// Item types defined by the server admin
+    ViewCatalog,
+    /// Exchanges `quantity` of the `from` currency at the listed rate
+    Exchange {
+        quantity: i64,
+        from: String,
+        to: String,
+    },
+
+    /// Storage commands that are executed as a single unit: either all of them succeed,
+    /// or none of them is applied
+    Batch(Vec<Request>),
+    /// Following commands of the session are queued until the batch is committed or aborted
+    BatchBegin,
+    BatchCommit,
+    BatchAbort,
+}
+
+impl Request {
+    /// Parses a request line in the format "<command> [<args>]"
+    pub fn parse(line: &str) -> Result<Self, ParseError> {
+        let line = line.trim();
+        let (command, args) = match line.split_once(' ') {
+            Some((command, args)) => (command, args.trim()),
+            None => (line, ""),
+        };
+
+        match command {
+            "ping" => Ok(Self::Ping),
+            "whoami" => Ok(Self::Whoami),
+            "help" => Ok(Self::Help),
+            "format" => args.parse().map(Self::Format),
+            "subscribe" => parse_subscribe(args),
+            "unsubscribe" if args == "orders" => Ok(Self::Unsubscribe),
+            "unsubscribe" => Err(ParseError(
+                "Unable to parse subscription. Expected: 'unsubscribe orders'".into(),
+            )),
+
+            "view_items" => Ok(Self::ViewItems),
+            "deposit" | "withdraw" if args.is_empty() => Err(ParseError(format!(
+                "Argument is required. Format: '{command} <item name> [<quantity>]'"
+            ))),
+            "deposit" => parse_deposit(args),
+            "withdraw" if args.starts_with('#') => {
+                parse_instance_id(args).map(|instance_id| Self::WithdrawInstance { instance_id })
+            }
+            "withdraw" => {
+                let (item_name, quantity) = parse_item_name_and_quantity(args);
+                Ok(Self::Withdraw {
+                    item_name: item_name.to_owned(),
+                    quantity,
+                })
+            }
+            "view_instances" => Ok(Self::ViewInstances),
+            "give" => parse_give(args),
+            "view_transactions" => Ok(Self::ViewTransactions),
+
+            "view_sell_orders" => Ok(Self::ViewSellOrders {
+                category: (!args.is_empty()).then(|| args.to_owned()),
+            }),
+            "sell" => parse_sell(args),
+            "buy" => parse_buy(args),
+            "cancel" => args
+                .parse::<i64>()
+                .map(|sell_order_id| Self::Cancel { sell_order_id })
+                .map_err(|err| ParseError(format!("Unable to parse sell order id: {err}"))),
+            "my_bids" => Ok(Self::MyBids),
+            "stats" | "suggest_price" if args.is_empty() => Err(ParseError(format!(
+                "Argument is required. Format: '{command} <item name>'"
+            ))),
+            "stats" => Ok(Self::Stats {
+                item_name: args.to_owned(),
+            }),
+            "suggest_price" => {
+                let (item_name, quantity) = parse_item_name_and_quantity(args);
+                Ok(Self::SuggestPrice {
+                    item_name: item_name.to_owned(),
+                    quantity,
+                })
+            }
+
+            "watch" => parse_watch(args),
+            "unwatch" => args
+                .parse::<i64>()
+                .map(|watch_id| Self::Unwatch { watch_id })
+                .map_err(|err| ParseError(format!("Unable to parse watch id: {err}"))),
+            "view_watchlist" => Ok(Self::ViewWatchlist),
+            "view_mailbox" => Ok(Self::ViewMailbox),
+
+            "batch" if args == "begin" => Ok(Self::BatchBegin),
+            "batch" if args == "commit" => Ok(Self::BatchCommit),
+            "batch" if args == "abort" => Ok(Self::BatchAbort),
+            "batch" => parse_batch(args),
+
+            "offer" => parse_offer(args),
+            "accept" => parse_offer_id(args).map(|offer_id| Self::Accept { offer_id }),
+            "decline" => parse_offer_id(args).map(|offer_id| Self::Decline { offer_id }),
+            "view_offers" => Ok(Self::ViewOffers),
+
+            "view_exchange" => Ok(Self::ViewExchange),
+            "view_catalog" => Ok(Self::ViewCatalog),
+            "exchange" => parse_exchange(args),
+            _ => Err(ParseError(format!("Unknown command '{command}'"))),
+        }
+    }
+
+    /// Whether the request doesn't change any state on the server, so it is always safe to repeat it
+    pub fn is_read_only(&self) -> bool {
+        !self.changes_state() && !self.changes_session()
+    }
+
+    // Whether the request changes only the current session, like its format or the queued batch
+    fn changes_session(&self) -> bool {
+        matches!(
+            self,
+            Self::Format(_)
+                | Self::Subscribe { .. }
+                | Self::Unsubscribe
+                | Self::BatchBegin
+                | Self::BatchAbort
+        )
+    }
+
+    /// Whether the request changes the state stored on the server, e.g. items or sell orders,
+    /// unlike the requests that only change the current session
+    pub fn changes_state(&self) -> bool {
+        match self {
+            Self::Deposit { .. }
+            | Self::Withdraw { .. }
+            | Self::DepositInstance { .. }
+            | Self::WithdrawInstance { .. }
+            | Self::Give { .. }
+            | Self::Sell { .. }
+            | Self::SellInstance { .. }
+            | Self::SellBundle { .. }
+            | Self::Buy { .. }
+            | Self::Bid { .. }
+            | Self::Cancel { .. }
+            | Self::Offer { .. }
+            | Self::Accept { .. }
+            | Self::Decline { .. }
+            | Self::Exchange { .. }
+            | Self::Watch { .. }
+            | Self::Unwatch { .. }
+            | Self::ViewMailbox
+            | Self::BatchCommit => true,
+            Self::Batch(requests) => requests.iter().any(Self::changes_state),
+            Self::Ping
+            | Self::Whoami
+            | Self::Help
+            | Self::Format(_)
+            | Self::Subscribe { .. }
+            | Self::Unsubscribe
+            | Self::ViewItems
+            | Self::ViewInstances
+            | Self::ViewTransactions
+            | Self::ViewSellOrders { .. }
+            | Self::MyBids
+            | Self::ViewOffers
+            | Self::ViewExchange
+            | Self::ViewCatalog
+            | Self::Stats { .. }
+            | Self::SuggestPrice { .. }
+            | Self::ViewWatchlist
+            | Self::BatchBegin
+            | Self::BatchAbort => false,
+        }
+    }
+
+    /// Whether the request can be a step of a batch. Only requests to the storage can,
+    /// session commands and batches themselves can't
+    pub fn can_be_batched(&self) -> bool {
+        match self {
+            Self::ViewItems
+            | Self::Deposit { .. }
+            | Self::Withdraw { .. }
+            | Self::ViewInstances
+            | Self::DepositInstance { .. }
+            | Self::WithdrawInstance { .. }
+            | Self::Give { .. }
+            | Self::ViewTransactions
+            | Self::ViewSellOrders { .. }
+            | Self::Sell { .. }
+            | Self::SellInstance { .. }
+            | Self::SellBundle { .. }
+            | Self::Buy { .. }
+            | Self::Bid { .. }
+            | Self::Cancel { .. }
+            | Self::MyBids
+            | Self::Offer { .. }
+            | Self::Accept { .. }
+            | Self::Decline { .. }
+            | Self::ViewOffers
+            | Self::ViewExchange
+            | Self::ViewCatalog
+            | Self::Stats { .. }
+            | Self::SuggestPrice { .. }
+            | Self::Exchange { .. }
+            | Self::Watch { .. }
+            | Self::Unwatch { .. }
+            | Self::ViewWatchlist
+            | Self::ViewMailbox => true,
+            Self::Ping
+            | Self::Whoami
+            | Self::Help
+            | Self::Format(_)
+            | Self::Subscribe { .. }
+            | Self::Unsubscribe
+            | Self::Batch(_)
+            | Self::BatchBegin
+            | Self::BatchCommit
+            | Self::BatchAbort => false,
+        }
+    }
+}
+
+/// Request with an optional id chosen by the client. Requests that change the stored state are
+/// applied at most once per id and user, a retry with the same id gets the original result.
+/// Format: "[@<request id> ]<command> [<args>]"
+#[derive(Clone, Debug, PartialEq)]
+pub struct TaggedRequest {
+    pub id: Option<String>,
+    pub request: Request,
+}
+
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+impl TaggedRequest {
+    pub fn parse(line: &str) -> Result<Self, ParseError> {
+        let line = line.trim();
+        let Some(tagged) = line.strip_prefix('@') else {
+            return Ok(Self {
+                id: None,
+                request: Request::parse(line)?,
+            });
+        };
+        let (id, request) = tagged.split_once(' ').unwrap_or((tagged, ""));
+        Self::validate_id(id)?;
+        Ok(Self {
+            id: Some(id.to_owned()),
+            request: Request::parse(request)?,
+        })
+    }
+
+    /// Checks that the request id is 1 to 64 letters, digits, '-' or '_'. Other transports, e.g.
+    /// the `Idempotency-Key` header of the HTTP API, accept the same ids
+    pub fn validate_id(id: &str) -> Result<(), ParseError> {
+        if id.is_empty() || id.len() > MAX_REQUEST_ID_LENGTH {
+            return Err(ParseError(format!(
+                "Request id must be from 1 to {MAX_REQUEST_ID_LENGTH} characters long"
+            )));
+        }
+        if !id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(ParseError(format!(
+                "Request id '{id}' may contain only letters, digits, '-' and '_'"
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Display for TaggedRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.id {
+            Some(id) => write!(f, "@{id} {}", self.request),
+            None => write!(f, "{}", self.request),
+        }
+    }
+}
+
+/// Encodes the request into a request line, without the line terminator
+impl Display for Request {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ping => write!(f, "ping"),
+            Self::Whoami => write!(f, "whoami"),
+            Self::Help => write!(f, "help"),
+            Self::Format(format) => write!(f, "format {format}"),
+            Self::Subscribe { item_name: None } => write!(f, "subscribe orders"),
+            Self::Subscribe {
+                item_name: Some(item_name),
+            } => write!(f, "subscribe orders {item_name}"),
+            Self::Unsubscribe => write!(f, "unsubscribe orders"),
+            Self::ViewItems => write!(f, "view_items"),
+            Self::Deposit {
+                item_name,
+                quantity,
+            } => write!(f, "deposit {item_name} {quantity}"),
+            Self::Withdraw {
+                item_name,
+                quantity,
+            } => write!(f, "withdraw {item_name} {quantity}"),
+            Self::ViewInstances => write!(f, "view_instances"),
+            Self::DepositInstance {
+                item_name,
+                attributes,
+            } => write!(f, "deposit {item_name} with {attributes}"),
+            Self::WithdrawInstance { instance_id } => write!(f, "withdraw #{instance_id}"),
+            Self::Give {
+                recipient,
+                item_name,
+                quantity,
+            } => write!(f, "give {recipient} {item_name} {quantity}"),
+            Self::ViewTransactions => write!(f, "view_transactions"),
+            Self::ViewSellOrders { category: None } => write!(f, "view_sell_orders"),
+            Self::ViewSellOrders {
+                category: Some(category),
+            } => write!(f, "view_sell_orders {category}"),
+            Self::Sell {
+                order_type,
+                item_name,
+                quantity,
+                price,
+                price_item,
+            } if price_item == "funds" => {
+                write!(f, "sell {order_type} {item_name} {quantity} {price}")
+            }
+            Self::Sell {
+                order_type,
+                item_name,
+                quantity,
+                price,
+                price_item,
+            } => write!(
+                f,
+                "sell {order_type} {item_name} {quantity} price {price} {price_item}"
+            ),
+            Self::SellInstance {
+                order_type,
+                instance_id,
+                price,
+                price_item,
+            } if price_item == "funds" => write!(f, "sell {order_type} #{instance_id} {price}"),
+            Self::SellInstance {
+                order_type,
+                instance_id,
+                price,
+                price_item,
+            } => write!(
+                f,
+                "sell {order_type} #{instance_id} price {price} {price_item}"
+            ),
+            Self::SellBundle {
+                order_type,
+                items,
+                price,
+                price_item,
+            } if price_item == "funds" => {
+                write!(f, "sell {order_type} {} {price}", item_list(items))
+            }
+            Self::SellBundle {
+                order_type,
+                items,
+                price,
+                price_item,
+            } => write!(
+                f,
+                "sell {order_type} {} price {price} {price_item}",
+                item_list(items)
+            ),
+            Self::Buy { sell_order_id } => write!(f, "buy {sell_order_id}"),
+            Self::Bid { sell_order_id, bid } => write!(f, "buy {sell_order_id} {bid}"),
+            Self::Cancel { sell_order_id } => write!(f, "cancel {sell_order_id}"),
+            Self::MyBids => write!(f, "my_bids"),
+            Self::Stats { item_name } => write!(f, "stats {item_name}"),
+            Self::SuggestPrice {
+                item_name,
+                quantity,
+            } => write!(f, "suggest_price {item_name} {quantity}"),
+            Self::Watch {
+                kind: WatchKind::ItemBelow { item_name, price },
+            } => write!(f, "watch {item_name} below {price}"),
+            Self::Watch {
+                kind: WatchKind::Seller { seller_name },
+            } => write!(f, "watch seller {seller_name}"),
+            Self::Unwatch { watch_id } => write!(f, "unwatch {watch_id}"),
+            Self::ViewWatchlist => write!(f, "view_watchlist"),
+            Self::ViewMailbox => write!(f, "view_mailbox"),
+            Self::Offer {
+                recipient,
+                give,
+                want,
+            } => write!(
+                f,
+                "offer {recipient} give {} want {}",
+                item_list(give),
+                item_list(want)
+            ),
+            Self::Accept { offer_id } => write!(f, "accept {offer_id}"),
+            Self::Decline { offer_id } => write!(f, "decline {offer_id}"),
+            Self::ViewOffers => write!(f, "view_offers"),
+            Self::ViewExchange => write!(f, "view_exchange"),
+            Self::ViewCatalog => write!(f, "view_catalog"),
+            Self::Exchange { quantity, from, to } => {
+                write!(f, "exchange {quantity} {from} to {to}")
+            }
+            Self::Batch(requests) => {
+                write!(f, "batch")?;
+                for (i, request) in requests.iter().enumerate() {
+                    let separator = if i == 0 { " " } else { "; " };
+                    write!(f, "{separator}{request}")?;
+                }
+                Ok(())
+            }
+            Self::BatchBegin => write!(f, "batch begin"),
+            Self::BatchCommit => write!(f, "batch commit"),
+            Self::BatchAbort => write!(f, "batch abort"),
+        }
+    }
+}
+
+// args should be in the format "orders [<item name>]"
+fn parse_subscribe(args: &str) -> Result<Request, ParseError> {
+    match args.split_once(' ') {
+        Some(("orders", item_name)) => Ok(Request::Subscribe {
+            item_name: Some(item_name.trim().to_owned()),
+        }),
+        None if args == "orders" => Ok(Request::Subscribe { item_name: None }),
+        _ => Err(ParseError(
+            "Unable to parse subscription. Expected: 'subscribe orders [<item name>]'".into(),
+        )),
+    }
+}
+
+// args should be in the format "<username> <item name> [<quantity>]", usernames can't contain spaces
+fn parse_give(args: &str) -> Result<Request, ParseError> {
+    let Some((recipient, args)) = args.split_once(' ') else {
+        return Err(ParseError(
+            "Unable to parse transfer. Expected: 'give <username> <item name> [<quantity>]'".into(),
+        ));
+    };
+    let (item_name, quantity) = parse_item_name_and_quantity(args.trim());
+    Ok(Request::Give {
+        recipient: recipient.to_owned(),
+        item_name: item_name.to_owned(),
+        quantity,
+    })
+}
+
+// args should be in the format "<username> give <items> want <items>", where items are separated by
+// commas and every item is "<item name> [<quantity>]", e.g. "Ivan give Sword want arrow 20, funds 50"
+fn parse_offer(args: &str) -> Result<Request, ParseError> {
+    let error = || {
+        ParseError(
+            "Unable to parse offer. Expected: 'offer <username> give <item name> [<quantity>], ... \
+            want <item name> [<quantity>], ...'"
+                .into(),
+        )
+    };
+    let (recipient, args) = args.split_once(' ').ok_or_else(error)?;
+    let args = args.trim_start().strip_prefix("give ").ok_or_else(error)?;
+    let (give, want) = args.split_once(" want ").ok_or_else(error)?;
+    let items = |items: &str| {
+        items
+            .split(',')
+            .map(|item| match parse_item_name_and_quantity(item.trim()) {
+                ("", _) => Err(error()),
+                (name, quantity) => Ok(Item {
+                    name: name.to_owned(),
+                    quantity,
+                }),
+            })
+            .collect::<Result<Vec<_>, _>>()
+    };
+    Ok(Request::Offer {
+        recipient: recipient.to_owned(),
+        give: items(give)?,
+        want: items(want)?,
+    })
+}
+
+// args should be in the format "<quantity> <currency> to <currency>", e.g. "2 premium gem to gold"
+fn parse_exchange(args: &str) -> Result<Request, ParseError> {
+    let error = || {
+        ParseError(
+            "Unable to parse exchange. Expected: 'exchange <quantity> <currency> to <currency>'"
+                .into(),
+        )
+    };
+    let (quantity, args) = args.split_once(' ').ok_or_else(error)?;
+    let quantity = quantity.parse::<i64>().map_err(|_| error())?;
+    let (from, to) = args.split_once(" to ").ok_or_else(error)?;
+    let (from, to) = (from.trim(), to.trim());
+    if from.is_empty() || to.is_empty() {
+        return Err(error());
+    }
+    Ok(Request::Exchange {
+        quantity,
+        from: from.to_owned(),
+        to: to.to_owned(),
+    })
+}
+
+fn parse_offer_id(args: &str) -> Result<i64, ParseError> {
+    args.parse::<i64>()
+        .map_err(|err| ParseError(format!("Unable to parse offer id: {err}")))
+}
+
+// args should be in the format "<item name> below <price>" or "seller <username>"
+fn parse_watch(args: &str) -> Result<Request, ParseError> {
+    if let Some((item_name, price)) = args.rsplit_once(" below ") {
+        let price = price
+            .trim()
+            .parse::<i64>()
+            .map_err(|err| ParseError(format!("Unable to parse watched price: {err}")))?;
+        return Ok(Request::Watch {
+            kind: WatchKind::ItemBelow {
+                item_name: item_name.trim().to_owned(),
+                price,
+            },
+        });
+    }
+    match args.strip_prefix("seller ") {
+        Some(seller_name) if !seller_name.trim().is_empty() => Ok(Request::Watch {
+            kind: WatchKind::Seller {
+                seller_name: seller_name.trim().to_owned(),
+            },
+        }),
+        _ => Err(ParseError(
+            "Unable to parse watch. Expected: 'watch <item name> below <price>' or 'watch seller <username>'"
+                .into(),
+        )),
+    }
+}
+
+// args should be in the format "<command>; <command>; ...", e.g. "withdraw funds 10; sell Sword 150"
+fn parse_batch(args: &str) -> Result<Request, ParseError> {
+    let requests = split_batch_steps(args)
+        .into_iter()
+        .map(str::trim)
+        .filter(|step| !step.is_empty())
+        .enumerate()
+        .map(|(i, step)| {
+            let request = Request::parse(step).map_err(|err| {
+                ParseError(format!(
+                    "Unable to parse step {} of the batch: {err}",
+                    i + 1
+                ))
+            })?;
+            if !request.can_be_batched() {
+                return Err(ParseError(format!(
+                    "Step {} of the batch: '{step}' can't be a part of a batch",
+                    i + 1
+                )));
+            }
+            Ok(request)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if requests.is_empty() {
+        return Err(ParseError(
+            "Batch is empty. Expected: 'batch <command>; <command>; ...' or 'batch begin|commit|abort'"
+                .into(),
+        ));
+    }
+    Ok(Request::Batch(requests))
+}
+
+// Splits batch steps on ';', skipping separators inside JSON objects (instance attributes),
+// so attributes like {"note": "a;b"} stay within their step.
+fn split_batch_steps(args: &str) -> Vec<&str> {
+    let mut steps = Vec::new();
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in args.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' if depth > 0 => in_string = true,
+            '{' | '[' => depth += 1,
+            '}' | ']' => depth = depth.saturating_sub(1),
+            ';' if depth == 0 => {
+                steps.push(&args[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    steps.push(&args[start..]);
+    steps
+}
+
+// args should be in the format "[immediate|auction] <item_name> [quantity] <price>"
+// or "[immediate|auction] <item_name> [quantity] price <price> <price item name>" for barter orders.
+// Price is mandatory, quantity is optional and defaults to 1. Several items separated by commas
+// are sold as a bundle.
+// Examples:
+// - "arrow 5 10" -> {"arrow", .quantity=5, .price=10, .type=Immediate}
+// - "holy sword 1 100" -> {"holy sword", .quantity=1, .price=100, .type=Immediate}
+// - "arrow 10" -> {"arrow", .quantity=1, .price=10, .type=Immediate}
+// - "immidiate arrow 10 5" -> {"arrow", .quantity=10, .price=5, .type=Immediate}
+// - "auction arrow 10 5" -> {"arrow", .quantity=10, .price=5, .type=Auction}
+// - "ore 100 price 3 gem" -> {"ore", .quantity=100, .price=3, .price_item="gem", .type=Immediate}
+// - "old price tag 5" -> {"old price tag", .quantity=1, .price=5, .type=Immediate}
+// - "Sword, arrow 50 100" -> {["Sword" 1, "arrow" 50], .price=100, .type=Immediate}
+fn parse_sell(args: &str) -> Result<Request, ParseError> {
+    let (order_type, args) = args
+        .find(' ')
+        .and_then(|pos| {
+            args[..pos]
+                .parse::<SellOrderType>()
+                .ok()
+                .map(|order_type| (order_type, &args[pos + 1..]))
+        })
+        .unwrap_or((SellOrderType::Immediate, args));
+
+    // Item names may contain " price ", so only a number after it makes the order a barter one
+    for (index, separator) in args.rmatch_indices(" price ") {
+        let price = args[index + separator.len()..].trim();
+        let (price, price_item) = price.split_once(' ').unwrap_or((price, ""));
+        let Ok(price) = price.parse::<i64>() else {
+            continue;
+        };
+        let price_item = price_item.trim();
+        if price_item.is_empty() {
+            return Err(ParseError(
+                "Unable to parse barter order. \
+                Expected: 'sell [immediate|auction] <item_name> [<quantity>] price <price> <item_name>'"
+                    .into(),
+            ));
+        }
+        return sell_request(order_type, &args[..index], price, price_item);
+    }
+
+    let (price, args) = args
+        .rfind(' ')
+        .and_then(|pos| {
+            args[pos + 1..]
+                .parse::<i64>()
+                .ok()
+                .map(|price| (price, &args[..pos]))
+        })
+        .ok_or(ParseError(
+            "Unable to parse order. \
+            Expected: 'sell [immediate|auction] <item_name> [<quantity>] <price>'. \
+            Default type is 'immediate' and default quantity is 1"
+                .into(),
+        ))?;
+
+    sell_request(order_type, args, price, "funds")
+}
+
+// Item names in the format "#<instance id>" refer to unique item instances, several items
+// separated by commas are a bundle
+fn sell_request(
+    order_type: SellOrderType,
+    items: &str,
+    price: i64,
+    price_item: &str,
+) -> Result<Request, ParseError> {
+    if items.contains(',') {
+        let items = items
+            .split(',')
+            .map(|item| match parse_item_name_and_quantity(item.trim()) {
+                ("", _) => Err(ParseError(
+                    "Unable to parse bundle. \
+                    Expected: 'sell [immediate|auction] <item_name> [<quantity>], ... <price>'"
+                        .into(),
+                )),
+                (name, quantity) => Ok(Item {
+                    name: name.to_owned(),
+                    quantity,
+                }),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        return Ok(Request::SellBundle {
+            order_type,
+            items,
+            price,
+            price_item: price_item.to_owned(),
+        });
+    }
+
+    let (item_name, quantity) = parse_item_name_and_quantity(items);
+    if !item_name.starts_with('#') {
+        return Ok(Request::Sell {
+            order_type,
+            item_name: item_name.to_owned(),
+            quantity,
+            price,
+            price_item: price_item.to_owned(),
+        });
+    }
+    if quantity != 1 {
+        return Err(ParseError(
+            "Item instance is unique, only one can be sold by an order".into(),
+        ));
+    }
+    Ok(Request::SellInstance {
+        order_type,
+        instance_id: parse_instance_id(item_name)?,
+        price,
+        price_item: price_item.to_owned(),
+    })
+}
+
+// args should be in the format "<item name> [<quantity>]" or "<item name> with <JSON object>".
+// Item names may contain " with ", so only a JSON object after it makes the deposit an instance one
+fn parse_deposit(args: &str) -> Result<Request, ParseError> {
+    let mut has_attributes = false;
+    for (index, separator) in args.match_indices(" with ") {
+        let attributes = args[index + separator.len()..].trim();
+        if !attributes.starts_with('{') {
+            continue;
+        }
+        has_attributes = true;
+        if let Some(attributes) = serde_json::from_str::<serde_json::Value>(attributes)
+            .ok()
+            .filter(serde_json::Value::is_object)
+        {
+            return Ok(Request::DepositInstance {
+                item_name: args[..index].trim().to_owned(),
+                attributes,
+            });
+        }
+    }
+    if has_attributes {
+        return Err(ParseError(
+            "Unable to parse item attributes. \
+            Expected: 'deposit <item name> with <JSON object>'"
+                .into(),
+        ));
+    }
+    let (item_name, quantity) = parse_item_name_and_quantity(args);
+    Ok(Request::Deposit {
+        item_name: item_name.to_owned(),
+        quantity,
+    })
+}
+
+// args should be in the format "#<instance id>"
+fn parse_instance_id(args: &str) -> Result<i64, ParseError> {
+    args.strip_prefix('#')
+        .ok_or(ParseError("Item instance id must start with '#'".into()))?
+        .parse::<i64>()
+        .map_err(|err| ParseError(format!("Unable to parse item instance id: {err}")))
+}
+
+// args should be in the format "<sell_order_id> [<bid>]"
+// if bid provided - try to make a bid on the auction sell order
+// otherwise - try to execute the immediate sell order
+fn parse_buy(args: &str) -> Result<Request, ParseError> {
+    let (bid, args) = args
+        .rfind(' ')
+        .and_then(|pos| {
+            args[pos + 1..]
+                .parse::<i64>()
+                .ok()
+                .map(|bid| (Some(bid), &args[..pos]))
+        })
+        .unwrap_or((None, args));
+
+    let sell_order_id = args
+        .parse::<i64>()
+        .map_err(|err| ParseError(format!("Unable to parse sell order id: {err}")))?;
+
+    Ok(match bid {
+        Some(bid) => Request::Bid { sell_order_id, bid },
+        None => Request::Buy { sell_order_id },
+    })
+}
+
+// Parses the last word as a quantity and if failed - uses the whole string as an item name
+// Examples:
+// - "arrow 5" -> {"arrow", 5}
+// - "holy sword 1" -> {"holy sword", 1}
+// - "arrow" -> {"arrow", 1}
+// - "holy sword" -> {"holy sword", 1}
+fn parse_item_name_and_quantity(args: &str) -> (&str, i64) {
+    if let Some(pos) = args.rfind(' ') {
+        if let Ok(quantity) = args[pos + 1..].parse::<i64>() {
+            return (&args[..pos], quantity);
+        }
+    }
+    (args, 1)
+}
+
+// Formats items as "Sword 1, arrow 20", the way they are parsed
+fn item_list(items: &[Item]) -> String {
+    items
+        .iter()
+        .map(|item| format!("{} {}", item.name, item.quantity))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_item_name_and_quantity() {
+        assert_eq!(parse_item_name_and_quantity("arrow 5"), ("arrow", 5));
+        assert_eq!(
+            parse_item_name_and_quantity("holy sword 1"),
+            ("holy sword", 1)
+        );
+        assert_eq!(parse_item_name_and_quantity("arrow"), ("arrow", 1));
+        assert_eq!(
+            parse_item_name_and_quantity("holy sword"),
+            ("holy sword", 1)
+        );
+        assert_eq!(parse_item_name_and_quantity(""), ("", 1));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Request::parse("ping"), Ok(Request::Ping));
+        assert_eq!(Request::parse(" whoami "), Ok(Request::Whoami));
+        assert_eq!(
+            Request::parse("format json"),
+            Ok(Request::Format(ResponseFormat::Json))
+        );
+        assert_eq!(
+            Request::parse("subscribe orders holy sword"),
+            Ok(Request::Subscribe {
+                item_name: Some("holy sword".into())
+            })
+        );
+        assert_eq!(
+            Request::parse("deposit funds 100"),
+            Ok(Request::Deposit {
+                item_name: "funds".into(),
+                quantity: 100
+            })
+        );
+        assert_eq!(
+            Request::parse("withdraw holy sword"),
+            Ok(Request::Withdraw {
+                item_name: "holy sword".into(),
+                quantity: 1
+            })
+        );
+        assert_eq!(
+            Request::parse("give Ivan holy sword 2"),
+            Ok(Request::Give {
+                recipient: "Ivan".into(),
+                item_name: "holy sword".into(),
+                quantity: 2
+            })
+        );
+        assert_eq!(
+            Request::parse("offer Ivan give holy sword want arrow 20,funds 50"),
+            Ok(Request::Offer {
+                recipient: "Ivan".into(),
+                give: vec![Item {
+                    name: "holy sword".into(),
+                    quantity: 1
+                }],
+                want: vec![
+                    Item {
+                        name: "arrow".into(),
+                        quantity: 20
+                    },
+                    Item {
+                        name: "funds".into(),
+                        quantity: 50
+                    }
+                ]
+            })
+        );
+        assert_eq!(
+            Request::parse("decline 3"),
+            Ok(Request::Decline { offer_id: 3 })
+        );
+        assert_eq!(
+            Request::parse("sell holy sword 1 100"),
+            Ok(Request::Sell {
+                order_type: SellOrderType::Immediate,
+                item_name: "holy sword".into(),
+                quantity: 1,
+                price: 100,
+                price_item: "funds".into()
+            })
+        );
+        assert_eq!(
+            Request::parse("sell auction arrow 10"),
+            Ok(Request::Sell {
+                order_type: SellOrderType::Auction,
+                item_name: "arrow".into(),
+                quantity: 1,
+                price: 10,
+                price_item: "funds".into()
+            })
+        );
+        assert_eq!(
+            Request::parse("sell auction ore 100 price 3 blue gem"),
+            Ok(Request::Sell {
+                order_type: SellOrderType::Auction,
+                item_name: "ore".into(),
+                quantity: 100,
+                price: 3,
+                price_item: "blue gem".into()
+            })
+        );
+        assert_eq!(
+            Request::parse("sell old price tag 5"),
+            Ok(Request::Sell {
+                order_type: SellOrderType::Immediate,
+                item_name: "old price tag".into(),
+                quantity: 1,
+                price: 5,
+                price_item: "funds".into()
+            })
+        );
+        assert_eq!(
+            Request::parse("sell old price tag 2 price 3 gem"),
+            Ok(Request::Sell {
+                order_type: SellOrderType::Immediate,
+                item_name: "old price tag".into(),
+                quantity: 2,
+                price: 3,
+                price_item: "gem".into()
+            })
+        );
+        assert_eq!(
+            Request::parse("buy 20"),
+            Ok(Request::Buy { sell_order_id: 20 })
+        );
+        assert_eq!(
+            Request::parse("buy 20 200"),
+            Ok(Request::Bid {
+                sell_order_id: 20,
+                bid: 200
+            })
+        );
+        assert_eq!(
+            Request::parse("cancel 20"),
+            Ok(Request::Cancel { sell_order_id: 20 })
+        );
+        assert_eq!(
+            Request::parse("stats holy sword"),
+            Ok(Request::Stats {
+                item_name: "holy sword".into()
+            })
+        );
+        assert_eq!(
+            Request::parse("watch holy sword below 100"),
+            Ok(Request::Watch {
+                kind: WatchKind::ItemBelow {
+                    item_name: "holy sword".into(),
+                    price: 100
+                }
+            })
+        );
+        assert_eq!(
+            Request::parse("watch seller Ivan"),
+            Ok(Request::Watch {
+                kind: WatchKind::Seller {
+                    seller_name: "Ivan".into()
+                }
+            })
+        );
+        assert_eq!(
+            Request::parse("suggest_price arrow"),
+            Ok(Request::SuggestPrice {
+                item_name: "arrow".into(),
+                quantity: 1
+            })
+        );
+        assert_eq!(
+            Request::parse(r#"deposit holy sword with {"durability": 80}"#),
+            Ok(Request::DepositInstance {
+                item_name: "holy sword".into(),
+                attributes: serde_json::json!({"durability": 80})
+            })
+        );
+        assert_eq!(
+            Request::parse("withdraw #12"),
+            Ok(Request::WithdrawInstance { instance_id: 12 })
+        );
+        assert_eq!(
+            Request::parse("sell auction #12 100"),
+            Ok(Request::SellInstance {
+                order_type: SellOrderType::Auction,
+                instance_id: 12,
+                price: 100,
+                price_item: "funds".into()
+            })
+        );
+        assert_eq!(
+            Request::parse("sell Sword, Shield, arrow 50 100"),
+            Ok(Request::SellBundle {
+                order_type: SellOrderType::Immediate,
+                items: vec![
+                    Item {
+                        name: "Sword".into(),
+                        quantity: 1
+                    },
+                    Item {
+                        name: "Shield".into(),
+                        quantity: 1
+                    },
+                    Item {
+                        name: "arrow".into(),
+                        quantity: 50
+                    }
+                ],
+                price: 100,
+                price_item: "funds".into()
+            })
+        );
+
+        assert!(Request::parse("").is_err());
+        assert!(Request::parse("pong").is_err());
+        assert!(Request::parse("format xml").is_err());
+        assert!(Request::parse("deposit").is_err());
+        assert!(Request::parse("give Ivan").is_err());
+        assert!(Request::parse("offer Ivan give Sword").is_err());
+        assert!(Request::parse("offer Ivan give Sword want ").is_err());
+        assert!(Request::parse("offer Ivan give , want arrow").is_err());
+        assert!(Request::parse("accept x").is_err());
+        assert!(Request::parse("exchange gem to gold").is_err());
+        assert!(Request::parse("exchange 2 gem").is_err());
+        assert!(Request::parse("sell arrow").is_err());
+        assert!(Request::parse("sell ore 100 price 3").is_err());
+        assert!(Request::parse("sell ore 100 price gem").is_err());
+        assert!(Request::parse("sell Sword, 100").is_err());
+        assert!(Request::parse("buy arrow").is_err());
+        assert!(Request::parse("subscribe items").is_err());
+        assert!(Request::parse("cancel").is_err());
+        assert!(Request::parse("stats").is_err());
+        assert!(Request::parse("watch Sword").is_err());
+        assert!(Request::parse("watch Sword below x").is_err());
+        assert!(Request::parse("watch seller ").is_err());
+        assert!(Request::parse("suggest_price").is_err());
+        assert!(Request::parse("deposit Sword with {durability}").is_err());
+        // " with " is a part of the item name unless a JSON object follows it
+        assert_eq!(
+            Request::parse("deposit Sword with 80"),
+            Ok(Request::Deposit {
+                item_name: "Sword with".into(),
+                quantity: 80
+            })
+        );
+        assert_eq!(
+            Request::parse("deposit bread with butter 3"),
+            Ok(Request::Deposit {
+                item_name: "bread with butter".into(),
+                quantity: 3
+            })
+        );
+        assert_eq!(
+            Request::parse(r#"deposit bread with butter with {"fresh": "with jam"}"#),
+            Ok(Request::DepositInstance {
+                item_name: "bread with butter".into(),
+                attributes: serde_json::json!({"fresh": "with jam"})
+            })
+        );
+        assert!(Request::parse("withdraw #x").is_err());
+        assert!(Request::parse("sell #12 2 100").is_err());
+    }
+
+    #[test]
+    fn parse_batch() {
+        assert_eq!(
+            Request::parse("batch withdraw funds 10; sell holy sword 150 ;cancel 2;"),
+            Ok(Request::Batch(vec![
+                Request::Withdraw {
+                    item_name: "funds".into(),
+                    quantity: 10
+                },
+                Request::Sell {
+                    order_type: SellOrderType::Immediate,
+                    item_name: "holy sword".into(),
+                    quantity: 1,
+                    price: 150,
+                    price_item: "funds".into()
+                },
+                Request::Cancel { sell_order_id: 2 }
+            ]))
+        );
+        assert_eq!(Request::parse("batch begin"), Ok(Request::BatchBegin));
+        assert_eq!(Request::parse("batch commit"), Ok(Request::BatchCommit));
+        assert_eq!(Request::parse("batch abort"), Ok(Request::BatchAbort));
+
+        assert_eq!(
+            Request::parse("batch view_items; buy x"),
+            Err(ParseError(
+                "Unable to parse step 2 of the batch: Unable to parse sell order id: \
+                invalid digit found in string"
+                    .into()
+            ))
+        );
+        assert_eq!(
+            Request::parse("batch deposit funds 10; format json"),
+            Err(ParseError(
+                "Step 2 of the batch: 'format json' can't be a part of a batch".into()
+            ))
+        );
+        assert_eq!(
+            Request::parse(r#"batch deposit Sword with {"note": "a;b}"}; view_items"#),
+            Ok(Request::Batch(vec![
+                Request::DepositInstance {
+                    item_name: "Sword".into(),
+                    attributes: serde_json::json!({"note": "a;b}"})
+                },
+                Request::ViewItems
+            ]))
+        );
+        assert!(Request::parse("batch").is_err());
+        assert!(Request::parse("batch ; ").is_err());
+        assert!(Request::parse("batch batch view_items").is_err());
+    }
+
+    #[test]
+    fn encode_parse_roundtrip() {
+        let requests = [
+            Request::Ping,
+            Request::Whoami,
+            Request::Help,
+            Request::Format(ResponseFormat::Text),
+            Request::Subscribe { item_name: None },
+            Request::Subscribe {
+                item_name: Some("holy sword".into()),
+            },
+            Request::Unsubscribe,
+            Request::ViewItems,
+            Request::Deposit {
+                item_name: "holy sword".into(),
+                quantity: 2,
+            },
+            Request::Withdraw {
+                item_name: "funds".into(),
+                quantity: 100,
+            },
+            Request::Give {
+                recipient: "Ivan".into(),
+                item_name: "holy sword".into(),
+                quantity: 1,
+            },
+            Request::ViewTransactions,
+            Request::ViewSellOrders { category: None },
+            Request::ViewSellOrders {
+                category: Some("melee weapons".into()),
+            },
+            Request::Sell {
+                order_type: SellOrderType::Auction,
+                item_name: "holy sword".into(),
+                quantity: 1,
+                price: 100,
+                price_item: "funds".into(),
+            },
+            Request::Sell {
+                order_type: SellOrderType::Immediate,
+                item_name: "iron ore".into(),
+                quantity: 100,
+                price: 3,
+                price_item: "blue gem".into(),
+            },
+            Request::Buy { sell_order_id: 1 },
+            Request::Bid {
+                sell_order_id: 1,
+                bid: 120,
+            },
+            Request::Cancel { sell_order_id: 1 },
+            Request::MyBids,
+            Request::Stats {
+                item_name: "holy sword".into(),
+            },
+            Request::SuggestPrice {
+                item_name: "arrow".into(),
+                quantity: 50,
+            },
+            Request::Watch {
+                kind: WatchKind::ItemBelow {
+                    item_name: "holy sword".into(),
+                    price: 100,
+                },
+            },
+            Request::Watch {
+                kind: WatchKind::Seller {
+                    seller_name: "Ivan".into(),
+                },
+            },
+            Request::Unwatch { watch_id: 1 },
+            Request::ViewWatchlist,
+            Request::ViewMailbox,
+            Request::ViewInstances,
+            Request::DepositInstance {
+                item_name: "holy sword".into(),
+                attributes: serde_json::json!({"durability": 80, "enchantments": ["fire"]}),
+            },
+            Request::WithdrawInstance { instance_id: 12 },
+            Request::SellInstance {
+                order_type: SellOrderType::Immediate,
+                instance_id: 12,
+                price: 100,
+                price_item: "funds".into(),
+            },
+            Request::SellInstance {
+                order_type: SellOrderType::Auction,
+                instance_id: 12,
+                price: 3,
+                price_item: "blue gem".into(),
+            },
+            Request::SellBundle {
+                order_type: SellOrderType::Auction,
+                items: vec![
+                    Item {
+                        name: "holy sword".into(),
+                        quantity: 1,
+                    },
+                    Item {
+                        name: "arrow".into(),
+                        quantity: 50,
+                    },
+                ],
+                price: 100,
+                price_item: "funds".into(),
+            },
+            Request::SellBundle {
+                order_type: SellOrderType::Immediate,
+                items: vec![
+                    Item {
+                        name: "Sword".into(),
+                        quantity: 1,
+                    },
+                    Item {
+                        name: "Shield".into(),
+                        quantity: 2,
+                    },
+                ],
+                price: 3,
+                price_item: "blue gem".into(),
+            },
+            Request::Offer {
+                recipient: "Ivan".into(),
+                give: vec![
+                    Item {
+                        name: "holy sword".into(),
+                        quantity: 1,
+                    },
+                    Item {
+                        name: "funds".into(),
+                        quantity: 10,
+                    },
+                ],
+                want: vec![Item {
+                    name: "arrow".into(),
+                    quantity: 20,
+                }],
+            },
+            Request::Accept { offer_id: 1 },
+            Request::Decline { offer_id: 1 },
+            Request::ViewOffers,
+            Request::ViewExchange,
+            Request::ViewCatalog,
+            Request::Exchange {
+                quantity: 2,
+                from: "premium gem".into(),
+                to: "gold".into(),
+            },
+            Request::Batch(vec![
+                Request::Deposit {
+                    item_name: "funds".into(),
+                    quantity: 100,
+                },
+                Request::ViewItems,
+            ]),
+            Request::BatchBegin,
+            Request::BatchCommit,
+            Request::BatchAbort,
+        ];
+        for request in requests {
+            assert_eq!(Request::parse(&request.to_string()), Ok(request));
+        }
+    }
+
+    #[test]
+    fn classification() {
+        // (command, is_read_only, changes_state, can_be_batched) for every kind of request
+        let requests = [
+            ("ping", true, false, false),
+            ("whoami", true, false, false),
+            ("help", true, false, false),
+            ("format json", false, false, false),
+            ("subscribe orders", false, false, false),
+            ("unsubscribe orders", false, false, false),
+            ("view_items", true, false, true),
+            ("deposit funds 10", false, true, true),
+            ("withdraw funds 10", false, true, true),
+            ("view_instances", true, false, true),
+            (
+                r#"deposit Sword with {"durability": 80}"#,
+                false,
+                true,
+                true,
+            ),
+            ("withdraw #1", false, true, true),
+            ("give Ivan Sword", false, true, true),
+            ("view_transactions", true, false, true),
+            ("view_sell_orders", true, false, true),
+            ("sell Sword 100", false, true, true),
+            ("sell #1 100", false, true, true),
+            ("sell Sword, Shield 100", false, true, true),
+            ("buy 1", false, true, true),
+            ("buy 1 100", false, true, true),
+            ("cancel 1", false, true, true),
+            ("my_bids", true, false, true),
+            ("stats Sword", true, false, true),
+            ("suggest_price Sword", true, false, true),
+            ("watch Sword below 100", false, true, true),
+            ("unwatch 1", false, true, true),
+            ("view_watchlist", true, false, true),
+            // Taking the mailbox empties it
+            ("view_mailbox", false, true, true),
+            ("offer Ivan give Sword want funds 100", false, true, true),
+            ("accept 1", false, true, true),
+            ("decline 1", false, true, true),
+            ("view_offers", true, false, true),
+            ("view_exchange", true, false, true),
+            ("view_catalog", true, false, true),
+            ("exchange 2 gem to gold", false, true, true),
+            ("batch view_items; view_offers", true, false, false),
+            ("batch view_items; buy 1", false, true, false),
+            ("batch begin", false, false, false),
+            ("batch commit", false, true, false),
+            ("batch abort", false, false, false),
+        ];
+        for (command, is_read_only, changes_state, can_be_batched) in requests {
+            let request = Request::parse(command).unwrap();
+            assert_eq!(
+                (
+                    request.is_read_only(),
+                    request.changes_state(),
+                    request.can_be_batched()
+                ),
+                (is_read_only, changes_state, can_be_batched),
+                "{command}"
+            );
+        }
+    }
+
+    #[test]
+    fn tagged_request() {
+        assert_eq!(
+            TaggedRequest::parse("buy 20"),
+            Ok(TaggedRequest {
+                id: None,
+                request: Request::Buy { sell_order_id: 20 }
+            })
+        );
+        let tagged = TaggedRequest::parse(" @bot-1_42 deposit funds 100").unwrap();
+        assert_eq!(
+            tagged,
+            TaggedRequest {
+                id: Some("bot-1_42".into()),
+                request: Request::Deposit {
+                    item_name: "funds".into(),
+                    quantity: 100
+                }
+            }
+        );
+        assert_eq!(tagged.to_string(), "@bot-1_42 deposit funds 100");
+        assert_eq!(TaggedRequest::parse(&tagged.to_string()), Ok(tagged));
+
+        assert!(TaggedRequest::parse("@ ping").is_err());
+        assert!(TaggedRequest::parse("@id").is_err());
+        assert!(TaggedRequest::parse("@i.d ping").is_err());
+        assert!(TaggedRequest::parse(&format!("@{} ping", "a".repeat(65))).is_err());
+        assert!(TaggedRequest::parse(&format!("@{} ping", "a".repeat(64))).is_ok());
+    }
+}
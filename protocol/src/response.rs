@@ -0,0 +1,1019 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::offer::ItemList;
+use crate::{
+    CatalogItem, ExchangeRate, ItemInstance, ItemStats, MarketEvent, Offer, ParseError, SellOrder,
+    SellOrderType, Watch,
+};
+
+/// Stable numeric code of a failed request. Codes are never reused, even if the error is removed.
+/// Codes below 100 are reserved for protocol-level errors, the rest are returned by the storage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "u32", into = "u32")]
+pub enum ErrorCode {
+    // Request failed before reaching the storage, e.g. an unknown command or malformed arguments
+    BadRequest,
+    Database,
+    EmptyUsername,
+    EmptyItemName,
+    InvalidQuantity,
+    InvalidPrice,
+    ItemNotFound,
+    InsufficientFunds,
+    InsufficientItems,
+    FundsNotTradable,
+    OrderNotFound,
+    NotImmediateOrder,
+    NotAuctionOrder,
+    OwnOrder,
+    BidTooLow,
+    InvalidToken,
+    NotOwnOrder,
+    OrderHasBid,
+    UserNotFound,
+    SelfTransfer,
+    DailyLimitExceeded,
+    EmptyOffer,
+    OfferNotFound,
+    NotOfferRecipient,
+    SamePriceItem,
+    NotCurrency,
+    ExchangeNotListed,
+    UnknownItem,
+    StackLimitExceeded,
+    NotTradeable,
+    AliasTaken,
+    InstanceNotFound,
+    InvalidAttributes,
+    WatchNotFound,
+    EmptyBundle,
+    // Code introduced by a newer server
+    Unknown(u32),
+}
+
+impl From<ErrorCode> for u32 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::BadRequest => 1,
+            ErrorCode::Database => 100,
+            ErrorCode::EmptyUsername => 101,
+            ErrorCode::EmptyItemName => 102,
+            ErrorCode::InvalidQuantity => 103,
+            ErrorCode::InvalidPrice => 104,
+            ErrorCode::ItemNotFound => 105,
+            ErrorCode::InsufficientFunds => 106,
+            ErrorCode::InsufficientItems => 107,
+            ErrorCode::FundsNotTradable => 108,
+            ErrorCode::OrderNotFound => 109,
+            ErrorCode::NotImmediateOrder => 110,
+            ErrorCode::NotAuctionOrder => 111,
+            ErrorCode::OwnOrder => 112,
+            ErrorCode::BidTooLow => 113,
+            ErrorCode::InvalidToken => 114,
+            ErrorCode::NotOwnOrder => 115,
+            ErrorCode::OrderHasBid => 116,
+            ErrorCode::UserNotFound => 117,
+            ErrorCode::SelfTransfer => 118,
+            ErrorCode::DailyLimitExceeded => 119,
+            ErrorCode::EmptyOffer => 120,
+            ErrorCode::OfferNotFound => 121,
+            ErrorCode::NotOfferRecipient => 122,
+            ErrorCode::SamePriceItem => 123,
+            ErrorCode::NotCurrency => 124,
+            ErrorCode::ExchangeNotListed => 125,
+            ErrorCode::UnknownItem => 126,
+            ErrorCode::StackLimitExceeded => 127,
+            ErrorCode::NotTradeable => 128,
+            ErrorCode::AliasTaken => 129,
+            ErrorCode::InstanceNotFound => 130,
+            ErrorCode::InvalidAttributes => 131,
+            ErrorCode::WatchNotFound => 132,
+            ErrorCode::EmptyBundle => 133,
+            ErrorCode::Unknown(code) => code,
+        }
+    }
+}
+
+impl From<u32> for ErrorCode {
+    fn from(code: u32) -> Self {
+        match code {
+            1 => Self::BadRequest,
+            100 => Self::Database,
+            101 => Self::EmptyUsername,
+            102 => Self::EmptyItemName,
+            103 => Self::InvalidQuantity,
+            104 => Self::InvalidPrice,
+            105 => Self::ItemNotFound,
+            106 => Self::InsufficientFunds,
+            107 => Self::InsufficientItems,
+            108 => Self::FundsNotTradable,
+            109 => Self::OrderNotFound,
+            110 => Self::NotImmediateOrder,
+            111 => Self::NotAuctionOrder,
+            112 => Self::OwnOrder,
+            113 => Self::BidTooLow,
+            114 => Self::InvalidToken,
+            115 => Self::NotOwnOrder,
+            116 => Self::OrderHasBid,
+            117 => Self::UserNotFound,
+            118 => Self::SelfTransfer,
+            119 => Self::DailyLimitExceeded,
+            120 => Self::EmptyOffer,
+            121 => Self::OfferNotFound,
+            122 => Self::NotOfferRecipient,
+            123 => Self::SamePriceItem,
+            124 => Self::NotCurrency,
+            125 => Self::ExchangeNotListed,
+            126 => Self::UnknownItem,
+            127 => Self::StackLimitExceeded,
+            128 => Self::NotTradeable,
+            129 => Self::AliasTaken,
+            130 => Self::InstanceNotFound,
+            131 => Self::InvalidAttributes,
+            132 => Self::WatchNotFound,
+            133 => Self::EmptyBundle,
+            code => Self::Unknown(code),
+        }
+    }
+}
+
+/// Failed result of a request processing
+#[derive(Clone, Debug, PartialEq)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl Display for ErrorResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ErrorResponse {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    // Human readable responses, suitable for telnet
+    Text,
+    // Structured responses for bots, one JSON object per response
+    Json,
+}
+
+impl Display for ResponseFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Text => write!(f, "text"),
+            Self::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl FromStr for ResponseFormat {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(ParseError(format!(
+                "Unknown format '{s}'. Expected: 'format text|json'"
+            ))),
+        }
+    }
+}
+
+impl ResponseFormat {
+    /// Encodes the result of a request processing into the response payload
+    pub fn encode(&self, result: Result<Response, ErrorResponse>) -> String {
+        match self {
+            Self::Text => match result {
+                Ok(response) => response.to_string(),
+                Err(err) => format!("Failed to process request: {err}"),
+            },
+            Self::Json => serde_json::to_string(&JsonResponse::from(result))
+                .expect("Response should always be serializable to JSON"),
+        }
+    }
+
+    /// Encodes a market event pushed to subscribers. Events are distinguishable from responses
+    /// by the `Event:` prefix in the text format and by the `event` field in the JSON format
+    pub fn encode_event(&self, event: &MarketEvent) -> String {
+        match self {
+            Self::Text => format!("Event: {event}"),
+            Self::Json => serde_json::to_string(event)
+                .expect("Market event should always be serializable to JSON"),
+        }
+    }
+}
+
+/// Envelope of every response in the JSON format
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JsonResponse {
+    pub ok: bool,
+    pub error_code: Option<ErrorCode>,
+    pub error: Option<String>,
+    pub payload: Option<Response>,
+}
+
+impl From<Result<Response, ErrorResponse>> for JsonResponse {
+    fn from(result: Result<Response, ErrorResponse>) -> Self {
+        match result {
+            Ok(response) => Self {
+                ok: true,
+                error_code: None,
+                error: None,
+                payload: Some(response),
+            },
+            Err(err) => Self {
+                ok: false,
+                error_code: Some(err.code),
+                error: Some(err.message),
+                payload: None,
+            },
+        }
+    }
+}
+
+impl JsonResponse {
+    pub fn into_result(self) -> Result<Response, ErrorResponse> {
+        match (self.ok, self.payload) {
+            (true, Some(response)) => Ok(response),
+            (true, None) => Err(ErrorResponse {
+                code: ErrorCode::BadRequest,
+                message: "Successful response without a payload".into(),
+            }),
+            (false, _) => Err(ErrorResponse {
+                code: self.error_code.unwrap_or(ErrorCode::Unknown(0)),
+                message: self.error.unwrap_or_default(),
+            }),
+        }
+    }
+}
+
+/// Any frame sent by the server after login in the JSON format
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum ServerMessage {
+    Event(MarketEvent),
+    Response(JsonResponse),
+}
+
+impl ServerMessage {
+    pub fn from_json(payload: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(payload)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Item {
+    pub name: String,
+    pub quantity: i64,
+}
+
+/// Item of a user with the quantities held by the market besides the available one
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ItemBalance {
+    pub name: String,
+    /// Available quantity, i.e. items that can be withdrawn, sold or given
+    pub quantity: i64,
+    /// Escrowed by the user's highest bids on auctions until they are outbid or win
+    #[serde(default)]
+    pub in_bids: i64,
+    /// Held by the user's sell orders until they are bought, cancelled or expire
+    #[serde(default)]
+    pub listed: i64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionKind {
+    // Items are given by one user to another
+    Give,
+    // Items are exchanged by an accepted offer, every side of the exchange is a separate transaction
+    Trade,
+}
+
+/// Entry of the transaction history of a user
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Transaction {
+    pub kind: TransactionKind,
+    pub sender: String,
+    pub recipient: String,
+    pub item_name: String,
+    pub quantity: i64,
+    pub time: String,
+}
+
+impl Display for Transaction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let action = match self.kind {
+            TransactionKind::Give => "gave",
+            TransactionKind::Trade => "traded",
+        };
+        write!(
+            f,
+            "{}: {} {action} {} {}(s) to {}",
+            self.time, self.sender, self.quantity, self.item_name, self.recipient
+        )
+    }
+}
+
+// Successful result of a command processing
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Pong,
+    LoggedIn {
+        username: String,
+        token: String,
+    },
+    LoggedOut,
+    Whoami {
+        username: String,
+    },
+    Help {
+        message: String,
+    },
+    Format {
+        format: ResponseFormat,
+    },
+    Subscribed {
+        item_name: Option<String>,
+    },
+    Unsubscribed,
+    Items {
+        items: Vec<ItemBalance>,
+    },
+    Deposited {
+        item_name: String,
+        quantity: i64,
+    },
+    Withdrawn {
+        item_name: String,
+        quantity: i64,
+    },
+    Instances {
+        instances: Vec<ItemInstance>,
+    },
+    InstanceDeposited {
+        instance: ItemInstance,
+    },
+    InstanceWithdrawn {
+        instance_id: i64,
+    },
+    ItemsGiven {
+        recipient: String,
+        item_name: String,
+        quantity: i64,
+    },
+    /// Transactions of the user, the latest is the last
+    Transactions {
+        transactions: Vec<Transaction>,
+    },
+    SellOrders {
+        sell_orders: Vec<SellOrder>,
+    },
+    /// Auction sell orders where the user is the highest bidder, the bid is the order price
+    Bids {
+        sell_orders: Vec<SellOrder>,
+    },
+    SellOrderPlaced {
+        sell_order_id: i64,
+        order_type: SellOrderType,
+        item_name: String,
+        quantity: i64,
+    },
+    BundleSellOrderPlaced {
+        sell_order_id: i64,
+        order_type: SellOrderType,
+        items: Vec<Item>,
+    },
+    BidPlaced {
+        sell_order_id: i64,
+        bid: i64,
+    },
+    SellOrderExecuted {
+        sell_order_id: i64,
+    },
+    SellOrderCancelled {
+        sell_order_id: i64,
+    },
+    OfferPlaced {
+        offer_id: i64,
+    },
+    OfferAccepted {
+        offer_id: i64,
+    },
+    OfferDeclined {
+        offer_id: i64,
+    },
+    /// Offers sent by the current user and to the current user
+    Offers {
+        offers: Vec<Offer>,
+    },
+    /// Currencies of the server and the exchange listing between them
+    Exchange {
+        currencies: Vec<String>,
+        rates: Vec<ExchangeRate>,
+    },
+    Exchanged {
+        from: Item,
+        to: Item,
+    },
+    Catalog {
+        items: Vec<CatalogItem>,
+    },
+    Stats {
+        stats: ItemStats,
+    },
+    /// Price is not suggested if the item has no recent trades
+    SuggestedPrice {
+        item_name: String,
+        quantity: i64,
+        price: Option<i64>,
+    },
+    Watching {
+        watch: Watch,
+    },
+    Unwatched {
+        watch_id: i64,
+    },
+    Watchlist {
+        watches: Vec<Watch>,
+    },
+    /// Events received while the user was offline, the oldest first
+    Mailbox {
+        events: Vec<MarketEvent>,
+    },
+    BatchStarted,
+    /// Command is queued as a step of the started batch
+    BatchQueued {
+        step: usize,
+    },
+    /// Responses of all steps of the batch, in order
+    BatchExecuted {
+        responses: Vec<Response>,
+    },
+    BatchAborted,
+}
+
+impl Display for Response {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Pong => write!(f, "pong"),
+            Self::LoggedIn { username, token: _ } => {
+                write!(f, "Successfully logged in as {username}")
+            }
+            Self::LoggedOut => write!(f, "Successfully logged out"),
+            Self::Whoami { username } => write!(f, "{username}"),
+            Self::Help { message } => write!(f, "{message}"),
+            Self::Format { format } => write!(f, "Successfully switched to {format} format"),
+            Self::Subscribed { item_name: None } => write!(f, "Successfully subscribed to orders"),
+            Self::Subscribed {
+                item_name: Some(item_name),
+            } => write!(f, "Successfully subscribed to {item_name} orders"),
+            Self::Unsubscribed => write!(f, "Successfully unsubscribed from orders"),
+            Self::Items { items } => {
+                let available = items
+                    .iter()
+                    .map(|item| (&item.name, item.quantity))
+                    .collect::<Vec<_>>();
+                write!(f, "Items: {available:?}")?;
+                let in_bids = items
+                    .iter()
+                    .filter(|item| item.in_bids > 0)
+                    .map(|item| (&item.name, item.in_bids))
+                    .collect::<Vec<_>>();
+                if !in_bids.is_empty() {
+                    write!(f, "\nIn bids: {in_bids:?}")?;
+                }
+                let listed = items
+                    .iter()
+                    .filter(|item| item.listed > 0)
+                    .map(|item| (&item.name, item.listed))
+                    .collect::<Vec<_>>();
+                if !listed.is_empty() {
+                    write!(f, "\nListed for sale: {listed:?}")?;
+                }
+                Ok(())
+            }
+            Self::Deposited {
+                item_name,
+                quantity,
+            } => write!(f, "Successfully deposited {quantity} {item_name}(s)"),
+            Self::Withdrawn {
+                item_name,
+                quantity,
+            } => write!(f, "Successfully withdrawed {quantity} {item_name}(s)"),
+            Self::Instances { instances } => {
+                write!(f, "Item instances:")?;
+                for instance in instances {
+                    write!(f, "\n- {instance}")?;
+                }
+                Ok(())
+            }
+            Self::InstanceDeposited { instance } => {
+                write!(f, "Successfully deposited {instance}")
+            }
+            Self::InstanceWithdrawn { instance_id } => {
+                write!(f, "Successfully withdrawed item instance #{instance_id}")
+            }
+            Self::ItemsGiven {
+                recipient,
+                item_name,
+                quantity,
+            } => write!(
+                f,
+                "Successfully gave {quantity} {item_name}(s) to {recipient}"
+            ),
+            Self::Transactions { transactions } => {
+                write!(f, "Transactions:")?;
+                for transaction in transactions {
+                    write!(f, "\n- {transaction}")?;
+                }
+                Ok(())
+            }
+            Self::SellOrders { sell_orders } => {
+                write!(f, "Sell orders:")?;
+                for order in sell_orders {
+                    write!(f, "\n- {order}")?;
+                }
+                Ok(())
+            }
+            Self::Bids { sell_orders } => {
+                write!(f, "Your bids:")?;
+                for order in sell_orders {
+                    write!(f, "\n- {order}")?;
+                }
+                Ok(())
+            }
+            Self::SellOrderPlaced {
+                sell_order_id: _,
+                order_type,
+                item_name,
+                quantity,
+            } => write!(
+                f,
+                "Successfully placed {order_type} sell order for {quantity} {item_name}(s)"
+            ),
+            Self::BundleSellOrderPlaced {
+                sell_order_id: _,
+                order_type,
+                items,
+            } => write!(
+                f,
+                "Successfully placed {order_type} sell order for a bundle of {}",
+                ItemList(items)
+            ),
+            Self::BidPlaced {
+                sell_order_id,
+                bid: _,
+            } => write!(f, "Successfully placed bid on sell order #{sell_order_id}"),
+            Self::SellOrderExecuted { sell_order_id } => write!(
+                f,
+                "Successfully executed immediate sell order #{sell_order_id}"
+            ),
+            Self::SellOrderCancelled { sell_order_id } => {
+                write!(f, "Successfully cancelled sell order #{sell_order_id}")
+            }
+            Self::OfferPlaced { offer_id } => write!(f, "Successfully placed offer #{offer_id}"),
+            Self::OfferAccepted { offer_id } => {
+                write!(f, "Successfully accepted offer #{offer_id}")
+            }
+            Self::OfferDeclined { offer_id } => {
+                write!(f, "Successfully declined offer #{offer_id}")
+            }
+            Self::Offers { offers } => {
+                write!(f, "Offers:")?;
+                for offer in offers {
+                    write!(f, "\n- {offer}")?;
+                }
+                Ok(())
+            }
+            Self::Exchange { currencies, rates } => {
+                write!(f, "Currencies: {}", currencies.join(", "))?;
+                write!(f, "\nExchange rates:")?;
+                for rate in rates {
+                    write!(f, "\n- {rate}")?;
+                }
+                Ok(())
+            }
+            Self::Exchanged { from, to } => write!(
+                f,
+                "Successfully exchanged {} {}(s) for {} {}(s)",
+                from.quantity, from.name, to.quantity, to.name
+            ),
+            Self::Catalog { items } => {
+                write!(f, "Catalog:")?;
+                for item in items {
+                    write!(f, "\n- {item}")?;
+                }
+                Ok(())
+            }
+            Self::Stats { stats } => write!(f, "{stats}"),
+            Self::SuggestedPrice {
+                item_name,
+                quantity,
+                price: Some(price),
+            } => write!(
+                f,
+                "Suggested price for {quantity} {item_name}(s) is {price} funds"
+            ),
+            Self::SuggestedPrice {
+                item_name,
+                quantity: _,
+                price: None,
+            } => write!(f, "No recent trades of {item_name} to suggest a price"),
+            Self::Watching { watch } => write!(f, "Watching {}, watch #{}", watch.kind, watch.id),
+            Self::Unwatched { watch_id } => write!(f, "Successfully removed watch #{watch_id}"),
+            Self::Watchlist { watches } => {
+                write!(f, "Watchlist:")?;
+                for watch in watches {
+                    write!(f, "\n- {watch}")?;
+                }
+                Ok(())
+            }
+            Self::Mailbox { events } => {
+                write!(f, "Mailbox:")?;
+                for event in events {
+                    write!(f, "\n- {event}")?;
+                }
+                Ok(())
+            }
+            Self::BatchStarted => write!(
+                f,
+                "Batch started, following commands are queued until 'batch commit' or 'batch abort'"
+            ),
+            Self::BatchQueued { step } => write!(f, "Queued as step {step} of the batch"),
+            Self::BatchExecuted { responses } => {
+                write!(
+                    f,
+                    "Successfully executed batch of {} command(s):",
+                    responses.len()
+                )?;
+                for response in responses {
+                    write!(f, "\n- {response}")?;
+                }
+                Ok(())
+            }
+            Self::BatchAborted => write!(f, "Batch aborted, queued commands are discarded"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Trade;
+    use pretty_assertions::assert_eq;
+
+    fn sell_order() -> SellOrder {
+        SellOrder {
+            id: 1,
+            seller_name: "seller".into(),
+            item_name: "Sword".into(),
+            quantity: 1,
+            price: 100,
+            price_item: "funds".into(),
+            expiration_time: "2021-01-01 00:00:00".into(),
+            order_type: SellOrderType::Auction,
+            instance: None,
+            bundle: Vec::new(),
+        }
+    }
+
+    fn instance() -> ItemInstance {
+        ItemInstance {
+            id: 12,
+            item_name: "Sword".into(),
+            attributes: serde_json::json!({"durability": 80}),
+        }
+    }
+
+    #[test]
+    fn text_format() {
+        let format = ResponseFormat::Text;
+        assert_eq!(format.encode(Ok(Response::Pong)), "pong");
+        assert_eq!(
+            format.encode(Ok(Response::Items {
+                items: vec![
+                    ItemBalance {
+                        name: "funds".into(),
+                        quantity: 100,
+                        in_bids: 0,
+                        listed: 0,
+                    },
+                    ItemBalance {
+                        name: "Sword".into(),
+                        quantity: 1,
+                        in_bids: 0,
+                        listed: 0,
+                    }
+                ]
+            })),
+            r#"Items: [("funds", 100), ("Sword", 1)]"#
+        );
+        assert_eq!(
+            format.encode(Ok(Response::Items {
+                items: vec![
+                    ItemBalance {
+                        name: "funds".into(),
+                        quantity: 50,
+                        in_bids: 120,
+                        listed: 0,
+                    },
+                    ItemBalance {
+                        name: "Sword".into(),
+                        quantity: 0,
+                        in_bids: 0,
+                        listed: 2,
+                    }
+                ]
+            })),
+            "Items: [(\"funds\", 50), (\"Sword\", 0)]\nIn bids: [(\"funds\", 120)]\nListed for sale: [(\"Sword\", 2)]"
+        );
+        assert_eq!(
+            format.encode(Ok(Response::Bids {
+                sell_orders: vec![sell_order()]
+            })),
+            "Your bids:\n- #1: seller is selling a Sword for 100 funds on auction until 2021-01-01 00:00:00"
+        );
+        assert_eq!(
+            format.encode(Ok(Response::SellOrders {
+                sell_orders: vec![sell_order()]
+            })),
+            "Sell orders:\n- #1: seller is selling a Sword for 100 funds on auction until 2021-01-01 00:00:00"
+        );
+        assert_eq!(
+            format.encode(Ok(Response::SellOrders {
+                sell_orders: vec![SellOrder {
+                    instance: Some(instance()),
+                    ..sell_order()
+                }]
+            })),
+            r#"Sell orders:
+- #1: seller is selling Sword #12 {"durability":80} for 100 funds on auction until 2021-01-01 00:00:00"#
+        );
+        let bundle = vec![
+            Item {
+                name: "Sword".into(),
+                quantity: 1,
+            },
+            Item {
+                name: "arrow".into(),
+                quantity: 50,
+            },
+        ];
+        assert_eq!(
+            format.encode(Ok(Response::SellOrders {
+                sell_orders: vec![SellOrder {
+                    bundle: bundle.clone(),
+                    ..sell_order()
+                }]
+            })),
+            "Sell orders:\n- #1: seller is selling a bundle of Sword, 50 arrow(s) for 100 funds on auction until 2021-01-01 00:00:00"
+        );
+        assert_eq!(
+            format.encode(Ok(Response::BundleSellOrderPlaced {
+                sell_order_id: 1,
+                order_type: SellOrderType::Auction,
+                items: bundle,
+            })),
+            "Successfully placed auction sell order for a bundle of Sword, 50 arrow(s)"
+        );
+        assert_eq!(
+            format.encode(Ok(Response::Stats {
+                stats: ItemStats {
+                    item_name: "arrow".into(),
+                    last_trade: Some(Trade {
+                        quantity: 10,
+                        price: 25,
+                        time: "2021-01-01 00:00:00".into(),
+                    }),
+                    volume_24h: 10,
+                    volume_7d: 30,
+                    vwap_7d: Some(2.5),
+                    min_price_7d: Some(2.0),
+                    max_price_7d: Some(3.0),
+                    active_listings: 4,
+                }
+            })),
+            "Stats of arrow:
+- last trade: 10 for 25 funds at 2021-01-01 00:00:00
+- volume: 10 in 24h, 30 in 7d
+- price in 7d: 2.50 average, 2.00 min, 3.00 max funds per item
+- active listings: 4"
+        );
+        assert_eq!(
+            format.encode(Ok(Response::SuggestedPrice {
+                item_name: "arrow".into(),
+                quantity: 10,
+                price: None,
+            })),
+            "No recent trades of arrow to suggest a price"
+        );
+        assert_eq!(
+            format.encode(Ok(Response::Instances {
+                instances: vec![instance()]
+            })),
+            "Item instances:\n- Sword #12 {\"durability\":80}"
+        );
+        assert_eq!(
+            format.encode(Err(ErrorResponse {
+                code: ErrorCode::BadRequest,
+                message: "Unknown command 'pong'".into()
+            })),
+            "Failed to process request: Unknown command 'pong'"
+        );
+        assert_eq!(
+            format.encode(Ok(Response::BatchExecuted {
+                responses: vec![
+                    Response::Withdrawn {
+                        item_name: "funds".into(),
+                        quantity: 10
+                    },
+                    Response::SellOrderCancelled { sell_order_id: 2 }
+                ]
+            })),
+            "Successfully executed batch of 2 command(s):\n\
+            - Successfully withdrawed 10 funds(s)\n\
+            - Successfully cancelled sell order #2"
+        );
+        assert_eq!(
+            format.encode_event(&MarketEvent::BidPlaced {
+                order: sell_order()
+            }),
+            "Event: bid placed - #1: seller is selling a Sword for 100 funds on auction until 2021-01-01 00:00:00"
+        );
+        assert_eq!(
+            format.encode_event(&MarketEvent::ItemsReceived {
+                sender: "seller".into(),
+                recipient: "buyer".into(),
+                item_name: "Sword".into(),
+                quantity: 2
+            }),
+            "Event: items received - seller gave you 2 Sword(s)"
+        );
+        assert_eq!(
+            format.encode(Ok(Response::Offers {
+                offers: vec![Offer {
+                    id: 1,
+                    sender: "seller".into(),
+                    recipient: "buyer".into(),
+                    give: vec![Item {
+                        name: "Sword".into(),
+                        quantity: 1
+                    }],
+                    want: vec![
+                        Item {
+                            name: "arrow".into(),
+                            quantity: 20
+                        },
+                        Item {
+                            name: "funds".into(),
+                            quantity: 50
+                        }
+                    ],
+                    expiration_time: "2021-01-01 00:00:00".into()
+                }]
+            })),
+            "Offers:\n- #1: seller offers Sword to buyer for 20 arrow(s), 50 funds(s) until 2021-01-01 00:00:00"
+        );
+        assert_eq!(
+            format.encode(Ok(Response::Exchange {
+                currencies: vec!["funds".into(), "gold".into(), "gem".into()],
+                rates: vec![ExchangeRate {
+                    from: "gem".into(),
+                    to: "gold".into(),
+                    rate: 100
+                }]
+            })),
+            "Currencies: funds, gold, gem\nExchange rates:\n- 1 gem = 100 gold(s)"
+        );
+        assert_eq!(
+            format.encode(Ok(Response::Catalog {
+                items: vec![
+                    CatalogItem {
+                        name: "Sword".into(),
+                        display_name: Some("Iron Sword".into()),
+                        category: Some("weapons".into()),
+                        description: "Sharp enough".into(),
+                        max_stack: Some(1),
+                        tradeable: false,
+                        aliases: vec!["blade".into()]
+                    },
+                    CatalogItem {
+                        name: "ore".into(),
+                        display_name: None,
+                        category: None,
+                        description: String::new(),
+                        max_stack: None,
+                        tradeable: true,
+                        aliases: Vec::new()
+                    }
+                ]
+            })),
+            "Catalog:\n- Sword (Iron Sword) [weapons]: Sharp enough, at most 1 per user, not tradeable, also known as blade\n- ore"
+        );
+        assert_eq!(
+            format.encode(Ok(Response::Transactions {
+                transactions: vec![Transaction {
+                    kind: TransactionKind::Give,
+                    sender: "seller".into(),
+                    recipient: "buyer".into(),
+                    item_name: "Sword".into(),
+                    quantity: 2,
+                    time: "2021-01-01 00:00:00".into()
+                }]
+            })),
+            "Transactions:\n- 2021-01-01 00:00:00: seller gave 2 Sword(s) to buyer"
+        );
+    }
+
+    #[test]
+    fn json_format() {
+        let format = ResponseFormat::Json;
+        assert_eq!(
+            format.encode(Ok(Response::Pong)),
+            r#"{"ok":true,"error_code":null,"error":null,"payload":{"type":"pong"}}"#
+        );
+        assert_eq!(
+            format.encode(Ok(Response::Items {
+                items: vec![ItemBalance {
+                    name: "funds".into(),
+                    quantity: 100,
+                    in_bids: 20,
+                    listed: 0,
+                }]
+            })),
+            r#"{"ok":true,"error_code":null,"error":null,"payload":{"type":"items","items":[{"name":"funds","quantity":100,"in_bids":20,"listed":0}]}}"#
+        );
+        assert_eq!(
+            format.encode(Ok(Response::SellOrders {
+                sell_orders: vec![sell_order()]
+            })),
+            r#"{"ok":true,"error_code":null,"error":null,"payload":{"type":"sell_orders","sell_orders":[{"id":1,"seller_name":"seller","item_name":"Sword","quantity":1,"price":100,"price_item":"funds","expiration_time":"2021-01-01 00:00:00","order_type":"auction"}]}}"#
+        );
+        assert_eq!(
+            format.encode(Err(ErrorResponse {
+                code: ErrorCode::InsufficientFunds,
+                message: "Failed to executed immediate sell order #1: Not enough funds: 100 required, but only 10 available".into()
+            })),
+            r#"{"ok":false,"error_code":106,"error":"Failed to executed immediate sell order #1: Not enough funds: 100 required, but only 10 available","payload":null}"#
+        );
+        assert_eq!(
+            format.encode_event(&MarketEvent::OrderPlaced {
+                order: sell_order()
+            }),
+            r#"{"event":"order_placed","order":{"id":1,"seller_name":"seller","item_name":"Sword","quantity":1,"price":100,"price_item":"funds","expiration_time":"2021-01-01 00:00:00","order_type":"auction"}}"#
+        );
+        assert_eq!(
+            format.encode(Ok(Response::InstanceDeposited {
+                instance: instance()
+            })),
+            r#"{"ok":true,"error_code":null,"error":null,"payload":{"type":"instance_deposited","instance":{"id":12,"item_name":"Sword","attributes":{"durability":80}}}}"#
+        );
+    }
+
+    #[test]
+    fn decode_server_messages() {
+        let results = [
+            Ok(Response::Pong),
+            Ok(Response::SellOrderPlaced {
+                sell_order_id: 1,
+                order_type: SellOrderType::Immediate,
+                item_name: "Sword".into(),
+                quantity: 2,
+            }),
+            Ok(Response::Subscribed { item_name: None }),
+            Err(ErrorResponse {
+                code: ErrorCode::OrderNotFound,
+                message: "Sell order #2 doesn't exist".into(),
+            }),
+            Err(ErrorResponse {
+                code: ErrorCode::Unknown(999),
+                message: "Something new".into(),
+            }),
+        ];
+        for result in results {
+            let payload = ResponseFormat::Json.encode(result.clone());
+            match ServerMessage::from_json(&payload).unwrap() {
+                ServerMessage::Response(response) => assert_eq!(response.into_result(), result),
+                ServerMessage::Event(event) => panic!("Unexpected event: {event:?}"),
+            }
+        }
+
+        let event = MarketEvent::OrderExpired {
+            order: sell_order(),
+        };
+        assert_eq!(
+            ServerMessage::from_json(&ResponseFormat::Json.encode_event(&event)).unwrap(),
+            ServerMessage::Event(event)
+        );
+    }
+}
@@ -0,0 +1,63 @@
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+/// Settled sell order of an item, priced in funds
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Trade {
+    pub quantity: i64,
+    /// Total price of the `quantity` items
+    pub price: i64,
+    pub time: String,
+}
+
+impl Display for Trade {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} for {} funds at {}",
+            self.quantity, self.price, self.time
+        )
+    }
+}
+
+/// Market statistics of an item. Only trades priced in funds are counted, prices are per item
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ItemStats {
+    pub item_name: String,
+    pub last_trade: Option<Trade>,
+    /// Quantity traded in the last 24 hours
+    pub volume_24h: i64,
+    /// Quantity traded in the last 7 days
+    pub volume_7d: i64,
+    /// Volume-weighted average price of the last 7 days
+    pub vwap_7d: Option<f64>,
+    pub min_price_7d: Option<f64>,
+    pub max_price_7d: Option<f64>,
+    /// Sell orders of the item currently on the market, including bundles
+    pub active_listings: i64,
+}
+
+impl Display for ItemStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Stats of {}:", self.item_name)?;
+        match &self.last_trade {
+            Some(trade) => write!(f, "\n- last trade: {trade}")?,
+            None => write!(f, "\n- last trade: none")?,
+        }
+        write!(
+            f,
+            "\n- volume: {} in 24h, {} in 7d",
+            self.volume_24h, self.volume_7d
+        )?;
+        if let (Some(vwap), Some(min), Some(max)) =
+            (self.vwap_7d, self.min_price_7d, self.max_price_7d)
+        {
+            write!(
+                f,
+                "\n- price in 7d: {vwap:.2} average, {min:.2} min, {max:.2} max funds per item"
+            )?;
+        }
+        write!(f, "\n- active listings: {}", self.active_listings)
+    }
+}
@@ -0,0 +1,36 @@
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+/// Kind of sell orders a user is notified about once they are placed
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum WatchKind {
+    /// Orders of the item priced in funds below `price` per item
+    ItemBelow { item_name: String, price: i64 },
+    /// All orders of the seller
+    Seller { seller_name: String },
+}
+
+impl Display for WatchKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ItemBelow { item_name, price } => write!(f, "{item_name} below {price} funds"),
+            Self::Seller { seller_name } => write!(f, "seller {seller_name}"),
+        }
+    }
+}
+
+/// Entry of a user's watchlist
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Watch {
+    pub id: i64,
+    #[serde(flatten)]
+    pub kind: WatchKind,
+}
+
+impl Display for Watch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{}: {}", self.id, self.kind)
+    }
+}
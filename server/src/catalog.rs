@@ -0,0 +1,87 @@
+//! Catalog of item types defined by the server admin in a TOML file:
+//!
+//! ```toml
+//! [[items]]
+//! name = "Sword"
+//! display_name = "Iron Sword"
+//! category = "weapons"
+//! description = "Sharp enough"
+//! max_stack = 1
+//! tradeable = true
+//! aliases = ["blade"]
+//! ```
+
+use anyhow::{Context, Result};
+use protocol::CatalogItem;
+use serde::Deserialize;
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Catalog {
+    #[serde(default)]
+    items: Vec<CatalogItem>,
+}
+
+pub(crate) fn load(path: &str) -> Result<Vec<CatalogItem>> {
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read {path}"))?;
+    parse(&content).with_context(|| format!("Failed to parse {path}"))
+}
+
+fn parse(content: &str) -> Result<Vec<CatalogItem>> {
+    let catalog: Catalog = toml::from_str(content)?;
+    Ok(catalog.items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parse_catalog() {
+        let content = r#"
+            [[items]]
+            name = "Sword"
+            display_name = "Iron Sword"
+            category = "weapons"
+            description = "Sharp enough"
+            max_stack = 1
+            tradeable = false
+            aliases = ["blade", "iron sword"]
+
+            [[items]]
+            name = "ore"
+        "#;
+        assert_eq!(
+            parse(content).unwrap(),
+            vec![
+                CatalogItem {
+                    name: "Sword".into(),
+                    display_name: Some("Iron Sword".into()),
+                    category: Some("weapons".into()),
+                    description: "Sharp enough".into(),
+                    max_stack: Some(1),
+                    tradeable: false,
+                    aliases: vec!["blade".into(), "iron sword".into()],
+                },
+                CatalogItem {
+                    name: "ore".into(),
+                    display_name: None,
+                    category: None,
+                    description: String::new(),
+                    max_stack: None,
+                    tradeable: true,
+                    aliases: Vec::new(),
+                },
+            ]
+        );
+        assert!(parse("[[items]]\ndescription = \"no name\"").is_err());
+    }
+
+    #[test]
+    fn example_catalog() {
+        let items = parse(include_str!("../catalog.toml")).unwrap();
+        assert_eq!(items.len(), 4);
+    }
+}
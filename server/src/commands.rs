@@ -1,266 +1,1388 @@
 use std::sync::Arc;
 
-use anyhow::{anyhow, Context, Result};
-use tokio::sync::Mutex;
+use anyhow::{bail, Context, Result};
+use protocol::{
+    ErrorCode, ErrorResponse, Item, ItemBalance, JsonResponse, MarketEvent, Request, Response,
+    ResponseFormat, SellOrderType, TaggedRequest, WatchKind,
+};
+use tokio::sync::{broadcast, mpsc, Mutex};
 
-use crate::storage::{SellOrderType, Storage, User};
+use crate::storage::{self, Storage, User};
 
 pub(crate) struct CommandsProcessor {
     user: User,
     storage: Arc<Mutex<Storage>>,
+    format: ResponseFormat,
+    // Created once the session waits for events, the user is offline until then
+    events: Option<Events>,
+    subscription: Option<Subscription>,
+    /// Steps of the batch started with `batch begin`
+    batch: Option<Vec<Request>>,
 }
 
+struct Events {
+    // All market events, filtered by the session
+    market: broadcast::Receiver<MarketEvent>,
+    // Personal events of the user
+    personal: mpsc::UnboundedReceiver<MarketEvent>,
+}
+
+struct Subscription {
+    // Only events about orders of this item are delivered, if set
+    item_name: Option<String>,
+}
+
+// Keeps a single batch from holding the storage lock for too long
+const MAX_BATCH_STEPS: usize = 100;
+
 const HELP_MESSAGE: &str =
     "Available commands:
     - whoami: Displays the username of the current user
     - ping: Replies 'pong'
     - help: Prints this help message about all available commands
+    - format: Switches the response format for the current session. Format: 'format text|json'
+      - text - human readable responses (default)
+      - json - one JSON object per response with 'ok', 'error_code', 'error' and 'payload' fields
+    - subscribe: Subscribes to live updates of sell orders. Format: 'subscribe orders [<item name>]'
+      Example: 'subscribe orders' - all orders, 'subscribe orders Sword' - only orders that sell Swords
+    - unsubscribe: Stops live updates of sell orders. Format: 'unsubscribe orders'
 
     - deposit: Deposits a specified amount into the user's account. Format: 'deposit <item name> [<quantity>]'.
       'fund' is a special item name that can be used to deposit funds into the user's account
       Example: 'deposit funds 100' - deposits 100 funds, 'deposit Sword' - deposits 1 Sword
     - withdraw: Withdraws a specified amount from the user's account. Format: 'withdraw <item name> [<quantity>]'
       Example: 'withdraw arrow 5' - withdraws 5 arrows, 'withdraw Sword' - withdraws 1 Sword
-    - view_items: Displays a list items for the current user
+    - view_items: Displays a list items for the current user: available quantities, and quantities escrowed
+      in bids on auctions and listed for sale, if any
+    - deposit with attributes: Deposits a unique item instance that is never merged with other items.
+      Format: 'deposit <item name> with <attributes>', where <attributes> is a JSON object
+      Example: 'deposit Sword with {\"durability\": 80}'. 'withdraw #<instance_id>' withdraws the instance
+    - view_instances: Displays unique item instances of the current user with their attributes
+    - view_catalog: Displays item types defined by the server with their category, description and limits.
+      The server may only accept deposits of these items
+    - give: Gives items to another user. Format: 'give <username> <item name> [<quantity>]'
+      Example: 'give Ivan Sword' - gives 1 Sword, 'give Ivan funds 100' - gives 100 funds.
+      The server may limit the quantity of every item a user can give within a day
+    - view_transactions: Displays items given and traded by the current user and to the current user
+    - offer: Offers another user to exchange items. Format: 'offer <username> give <items> want <items>'
+      where <items> is a comma separated list of '<item name> [<quantity>]'.
+      Offered items are held until the offer is accepted, declined or expired in 1 hour
+      Example: 'offer Ivan give Sword want funds 100, arrow 20'
+    - accept: Accepts an offer sent to the current user and exchanges the items. Format: 'accept <offer_id>'
+    - decline: Declines an offer sent to the current user or withdraws own offer. Format: 'decline <offer_id>'
+    - view_offers: Displays offers sent by the current user and to the current user
+
+    - view_exchange: Displays currencies and exchange rates between them listed by the server
+    - exchange: Exchanges a currency at the listed rate. Format: 'exchange <quantity> <currency> to <currency>'
+      Example: 'exchange 2 gem to gold'
 
-    - view_sell_orders: Displays a list of all sell orders from all users
+    - view_sell_orders: Displays a list of all sell orders from all users. Format: 'view_sell_orders [<category>]'
+      Example: 'view_sell_orders weapons' - only orders that sell items of the 'weapons' catalog category
     - sell: Places an item for sale at a specified price. Format: 'sell [immediate|auction] <item_name> [<quantity>] <price>'
+      Use 'price <price> <item name>' instead of '<price>' to sell for another currency or item instead of funds,
+      e.g. 'sell ore 100 price 3 gem'. The fee is taken in the currency of the order, or 1 funds for other items
+      Use '#<instance_id>' instead of '<item_name> [<quantity>]' to sell a unique item instance, e.g. 'sell #12 100'
+      List several items separated by commas to sell them together as a bundle, e.g. 'sell Sword, Shield, arrow 50 100'
       - immediate sell order - will be executed immediately once someone buys it. Otherwise it will expire in 5 minutes
         and items will be returned to the seller, but not the fee, which is `5% of the price + 1` funds
       - auction sell order - will be executed once it expires if someone placed a bid on it
     - buy: Executes immediate sell order or places a bid on a auction sell order. Format: 'buy <sell_order_id> [<bid>]'
       - no bid - executes immediate sell order
       - bid - places a bid on a auction sell order
+    - cancel: Cancels own sell order and returns items, but not the fee. Format: 'cancel <sell_order_id>'
+      Auction sell orders can't be cancelled once someone placed a bid on them
+    - my_bids: Displays auction sell orders where the current user is the highest bidder
+    - stats: Displays the last trade, volume, average, min and max price and active listings of an item.
+      Format: 'stats <item name>'. Only items sold alone for funds are counted
+    - suggest_price: Suggests a price in funds based on recent trades. Format: 'suggest_price <item name> [<quantity>]'
+    - watch: Notifies about new sell orders of an item below a price per item in funds or of a seller.
+      Format: 'watch <item name> below <price>' or 'watch seller <username>'. Example: 'watch Sword below 100'
+      Notifications received while offline are kept in the mailbox
+    - unwatch: Removes a watch. Format: 'unwatch <watch_id>'
+    - view_watchlist: Displays watches of the current user
+    - view_mailbox: Displays notifications received while offline and removes them from the mailbox
 
-    Usage: <command> [<args>], where `[]` annotates optional argumet(s)";
+    - batch: Executes several commands as a single unit: if any of them fails, none of them is applied.
+      Format: 'batch <command>; <command>; ...'. Only commands that view or change items and sell orders are allowed
+      Example: 'batch withdraw funds 10; sell Sword 150'
+      'batch begin' queues following commands until 'batch commit' executes them or 'batch abort' discards them
+
+    Usage: <command> [<args>], where `[]` annotates optional argumet(s)
+    Commands that change items or sell orders can be prefixed with a request id: '@<request id> <command>'.
+      Retrying a command with the same id returns the original result instead of applying it twice
+      Example: '@order-20 buy 20'";
 
 impl CommandsProcessor {
     pub(crate) fn new(user: User, storage: Arc<Mutex<Storage>>) -> Self {
-        Self { user, storage }
+        Self {
+            user,
+            storage,
+            format: ResponseFormat::Text,
+            events: None,
+            subscription: None,
+            batch: None,
+        }
     }
 
-    pub(crate) async fn process_request(&self, request: &str) -> Result<String> {
-        let (command, args) = if let Some(pos) = request.find(' ') {
-            (&request[..pos], request[pos + 1..].trim())
-        } else {
-            (request, "")
-        };
+    /// Waits for the next market event the session is subscribed to, or a personal event of the user,
+    /// and encodes it using the format of the current session. Cancellation safe
+    pub(crate) async fn next_event(&mut self) -> String {
+        loop {
+            let events = match &mut self.events {
+                Some(events) => events,
+                None => {
+                    let storage = self.storage.lock().await;
+                    self.events.insert(Events {
+                        market: storage.subscribe(),
+                        personal: storage.connect(self.user.id),
+                    })
+                }
+            };
+            let event = tokio::select! {
+                Some(event) = events.personal.recv() => event,
+                event = events.market.recv() => match event {
+                    Ok(event) => event,
+                    // Slow subscriber simply misses some market events
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return std::future::pending().await,
+                },
+            };
+            if self.is_delivered(&event) {
+                return self.format.encode_event(&event);
+            }
+        }
+    }
 
-        match command {
-            "ping" => Ok("pong".to_string()),
-            "whoami" => Ok(self.user.username.clone()),
-            "help" => Ok(HELP_MESSAGE.to_string()),
+    fn is_delivered(&self, event: &MarketEvent) -> bool {
+        if let Some(recipient) = event.recipient() {
+            return recipient == self.user.username;
+        }
+        match (&self.subscription, event.order()) {
+            (Some(subscription), Some(order)) => {
+                subscription.item_name.as_ref().is_none_or(|name| {
+                    *name == order.item_name || order.bundle.iter().any(|item| item.name == *name)
+                })
+            }
+            _ => false,
+        }
+    }
 
-            "view_items" => self.view_items().await,
-            "deposit" => self.deposit(args).await,
-            "withdraw" => self.withdraw(args).await,
+    /// Encodes the result of a request processing using the format of the current session
+    pub(crate) fn encode(&self, result: Result<Response>) -> String {
+        self.format
+            .encode(result.map_err(|err| error_response(&err)))
+    }
 
-            "view_sell_orders" => self.view_sell_orders().await,
-            "sell" => self.sell(args).await,
-            "buy" => self.buy(args).await,
-            _ => Err(anyhow!("Unknown command '{command}'")),
+    pub(crate) async fn process_request(&mut self, request: &str) -> Result<Response> {
+        let TaggedRequest { id, request } = TaggedRequest::parse(request)?;
+        self.execute(id.as_deref(), request).await
+    }
+
+    /// Executes the request. Requests that change the stored state are applied at most once
+    /// per `request_id`, other requests ignore it
+    pub(crate) async fn execute(
+        &mut self,
+        request_id: Option<&str>,
+        request: Request,
+    ) -> Result<Response> {
+        if let (Some(batch), true) = (&mut self.batch, request.can_be_batched()) {
+            if request_id.is_some() {
+                bail!(
+                    "Queued commands can't have request ids, pass it with 'batch commit' instead"
+                );
+            }
+            if batch.len() >= MAX_BATCH_STEPS {
+                bail!("Batch is too long: at most {MAX_BATCH_STEPS} commands are allowed");
+            }
+            batch.push(request);
+            return Ok(Response::BatchQueued { step: batch.len() });
         }
+
+        match request {
+            Request::Ping => Ok(Response::Pong),
+            Request::Whoami => Ok(Response::Whoami {
+                username: self.user.username.clone(),
+            }),
+            Request::Help => Ok(Response::Help {
+                message: HELP_MESSAGE.to_string(),
+            }),
+            Request::Format(format) => {
+                self.format = format;
+                Ok(Response::Format { format })
+            }
+            Request::Subscribe { item_name } => self.subscribe(item_name).await,
+            Request::Unsubscribe => {
+                self.subscription = None;
+                Ok(Response::Unsubscribed)
+            }
+
+            Request::ViewItems => self.view_items().await,
+            Request::Deposit {
+                item_name,
+                quantity,
+            } => self.deposit_item(request_id, &item_name, quantity).await,
+            Request::Withdraw {
+                item_name,
+                quantity,
+            } => self.withdraw_item(request_id, &item_name, quantity).await,
+            Request::ViewInstances => self.view_instances().await,
+            Request::DepositInstance {
+                item_name,
+                attributes,
+            } => {
+                self.deposit_item_instance(request_id, &item_name, &attributes)
+                    .await
+            }
+            Request::WithdrawInstance { instance_id } => {
+                self.withdraw_item_instance(request_id, instance_id).await
+            }
+            Request::Give {
+                recipient,
+                item_name,
+                quantity,
+            } => {
+                self.give_item(request_id, &recipient, &item_name, quantity)
+                    .await
+            }
+            Request::ViewTransactions => self.view_transactions().await,
+            Request::Offer {
+                recipient,
+                give,
+                want,
+            } => self.place_offer(request_id, &recipient, &give, &want).await,
+            Request::Accept { offer_id } => self.accept_offer(request_id, offer_id).await,
+            Request::Decline { offer_id } => self.decline_offer(request_id, offer_id).await,
+            Request::ViewOffers => self.view_offers().await,
+
+            Request::Watch { kind } => self.add_watch(request_id, &kind).await,
+            Request::Unwatch { watch_id } => self.remove_watch(request_id, watch_id).await,
+            Request::ViewWatchlist => self.view_watchlist().await,
+            Request::ViewMailbox => self.view_mailbox(request_id).await,
+
+            Request::ViewExchange => self.view_exchange().await,
+            Request::ViewCatalog => self.view_catalog().await,
+            Request::Exchange { quantity, from, to } => {
+                self.exchange_currency(request_id, quantity, &from, &to)
+                    .await
+            }
+
+            Request::ViewSellOrders { category } => {
+                self.view_sell_orders(category.as_deref()).await
+            }
+            Request::Sell {
+                order_type,
+                item_name,
+                quantity,
+                price,
+                price_item,
+            } => {
+                self.place_sell_order(
+                    request_id,
+                    order_type,
+                    &item_name,
+                    quantity,
+                    price,
+                    &price_item,
+                )
+                .await
+            }
+            Request::SellInstance {
+                order_type,
+                instance_id,
+                price,
+                price_item,
+            } => {
+                self.place_instance_sell_order(
+                    request_id,
+                    order_type,
+                    instance_id,
+                    price,
+                    &price_item,
+                )
+                .await
+            }
+            Request::SellBundle {
+                order_type,
+                items,
+                price,
+                price_item,
+            } => {
+                self.place_bundle_sell_order(request_id, order_type, &items, price, &price_item)
+                    .await
+            }
+            Request::Buy { sell_order_id } => {
+                self.execute_immediate_sell_order(request_id, sell_order_id)
+                    .await
+            }
+            Request::Bid { sell_order_id, bid } => {
+                self.place_bid(request_id, sell_order_id, bid).await
+            }
+            Request::Cancel { sell_order_id } => {
+                self.cancel_sell_order(request_id, sell_order_id).await
+            }
+            Request::MyBids => self.view_bids().await,
+            Request::Stats { item_name } => self.view_item_stats(&item_name).await,
+            Request::SuggestPrice {
+                item_name,
+                quantity,
+            } => self.suggest_price(&item_name, quantity).await,
+
+            Request::Batch(requests) => self.execute_batch(request_id, requests).await,
+            Request::BatchBegin => {
+                if self.batch.is_some() {
+                    bail!(
+                        "Batch is already started, finish it with 'batch commit' or 'batch abort'"
+                    );
+                }
+                self.batch = Some(Vec::new());
+                Ok(Response::BatchStarted)
+            }
+            Request::BatchCommit => match self.batch.take() {
+                Some(requests) => self.execute_batch(request_id, requests).await,
+                None => {
+                    // The batch may have been committed before the connection was lost
+                    if let Some(request_id) = request_id {
+                        let storage = self.storage.lock().await;
+                        if let Some(result) = self.stored_result(&storage, request_id)? {
+                            return result;
+                        }
+                    }
+                    bail!("No batch is started, start it with 'batch begin'")
+                }
+            },
+            Request::BatchAbort => match self.batch.take() {
+                Some(_) => Ok(Response::BatchAborted),
+                None => bail!("No batch is started, start it with 'batch begin'"),
+            },
+        }
+    }
+
+    async fn subscribe(&mut self, item_name: Option<String>) -> Result<Response> {
+        // Events carry the name the item is stored with, whatever spelling or alias is subscribed to
+        let item_name = match item_name {
+            Some(item_name) => Some(
+                self.storage
+                    .lock()
+                    .await
+                    .resolve_item_name(&item_name)
+                    .with_context(|| format!("Failed to subscribe to orders of '{item_name}'"))?,
+            ),
+            None => None,
+        };
+        self.subscription = Some(Subscription {
+            item_name: item_name.clone(),
+        });
+        Ok(Response::Subscribed { item_name })
+    }
+
+    pub(crate) async fn view_items(&self) -> Result<Response> {
+        self.items(&*self.storage.lock().await)
+    }
+
+    pub(crate) async fn deposit_item(
+        &self,
+        request_id: Option<&str>,
+        item_name: &str,
+        quantity: i64,
+    ) -> Result<Response> {
+        self.apply(request_id, |storage| {
+            self.deposit(storage, item_name, quantity)
+        })
+        .await
+    }
+
+    pub(crate) async fn withdraw_item(
+        &self,
+        request_id: Option<&str>,
+        item_name: &str,
+        quantity: i64,
+    ) -> Result<Response> {
+        self.apply(request_id, |storage| {
+            self.withdraw(storage, item_name, quantity)
+        })
+        .await
+    }
+
+    pub(crate) async fn view_instances(&self) -> Result<Response> {
+        self.instances(&*self.storage.lock().await)
     }
 
-    async fn view_items(&self) -> Result<String> {
-        self.storage
-            .lock()
+    pub(crate) async fn deposit_item_instance(
+        &self,
+        request_id: Option<&str>,
+        item_name: &str,
+        attributes: &serde_json::Value,
+    ) -> Result<Response> {
+        self.apply(request_id, |storage| {
+            self.deposit_instance(storage, item_name, attributes)
+        })
+        .await
+    }
+
+    pub(crate) async fn withdraw_item_instance(
+        &self,
+        request_id: Option<&str>,
+        instance_id: i64,
+    ) -> Result<Response> {
+        self.apply(request_id, |storage| {
+            self.withdraw_instance(storage, instance_id)
+        })
+        .await
+    }
+
+    pub(crate) async fn give_item(
+        &self,
+        request_id: Option<&str>,
+        recipient: &str,
+        item_name: &str,
+        quantity: i64,
+    ) -> Result<Response> {
+        self.apply(request_id, |storage| {
+            self.give(storage, recipient, item_name, quantity)
+        })
+        .await
+    }
+
+    pub(crate) async fn view_transactions(&self) -> Result<Response> {
+        self.transactions(&*self.storage.lock().await)
+    }
+
+    pub(crate) async fn place_offer(
+        &self,
+        request_id: Option<&str>,
+        recipient: &str,
+        give: &[Item],
+        want: &[Item],
+    ) -> Result<Response> {
+        self.apply(request_id, |storage| {
+            self.offer(storage, recipient, give, want)
+        })
+        .await
+    }
+
+    pub(crate) async fn accept_offer(
+        &self,
+        request_id: Option<&str>,
+        offer_id: i64,
+    ) -> Result<Response> {
+        self.apply(request_id, |storage| self.accept(storage, offer_id))
             .await
-            .view_items(self.user.id)
-            .map(|items| format!("Items: {items:?}"))
     }
 
-    async fn deposit(&self, args: &str) -> Result<String> {
-        if args.is_empty() {
-            return Err(anyhow!(
-                "Argument is required. Format: 'deposit <item name> [<quantity>]'"
-            ));
-        }
+    pub(crate) async fn decline_offer(
+        &self,
+        request_id: Option<&str>,
+        offer_id: i64,
+    ) -> Result<Response> {
+        self.apply(request_id, |storage| self.decline(storage, offer_id))
+            .await
+    }
 
-        let (item_name, quantity) = parse_item_name_and_quantity(args);
-        self.storage
-            .lock()
+    pub(crate) async fn add_watch(
+        &self,
+        request_id: Option<&str>,
+        kind: &WatchKind,
+    ) -> Result<Response> {
+        self.apply(request_id, |storage| self.watch(storage, kind))
             .await
-            .deposit(self.user.id, item_name, quantity)
-            .with_context(|| format!("Failed to deposit {quantity} {item_name}(s)"))
-            .map(|()| format!("Successfully deposited {quantity} {item_name}(s)"))
     }
 
-    async fn withdraw(&self, args: &str) -> Result<String> {
-        if args.is_empty() {
-            return Err(anyhow!(
-                "Argument is required. Format: 'withdraw <item name> [<quantity>]'"
-            ));
-        }
+    pub(crate) async fn remove_watch(
+        &self,
+        request_id: Option<&str>,
+        watch_id: i64,
+    ) -> Result<Response> {
+        self.apply(request_id, |storage| self.unwatch(storage, watch_id))
+            .await
+    }
+
+    pub(crate) async fn view_watchlist(&self) -> Result<Response> {
+        self.watchlist(&*self.storage.lock().await)
+    }
 
-        let (item_name, quantity) = parse_item_name_and_quantity(args);
-        self.storage
-            .lock()
+    pub(crate) async fn view_mailbox(&self, request_id: Option<&str>) -> Result<Response> {
+        self.apply(request_id, |storage| self.mailbox(storage))
             .await
-            .withdraw(self.user.id, item_name, quantity)
-            .with_context(|| format!("Failed to withdraw {quantity} {item_name}(s)"))
-            .map(|()| format!("Successfully withdrawed {quantity} {item_name}(s)"))
     }
 
-    async fn view_sell_orders(&self) -> Result<String> {
-        let orders = self.storage.lock().await.view_sell_orders()?;
-        let mut result = String::from("Sell orders:");
-        for order in orders {
-            let order_type_str = match order.order_type {
-                SellOrderType::Auction => "on auction ",
-                SellOrderType::Immediate => "",
-            };
+    pub(crate) async fn view_offers(&self) -> Result<Response> {
+        self.offers(&*self.storage.lock().await)
+    }
+
+    pub(crate) async fn view_exchange(&self) -> Result<Response> {
+        exchange_listing(&*self.storage.lock().await)
+    }
+
+    pub(crate) async fn exchange_currency(
+        &self,
+        request_id: Option<&str>,
+        quantity: i64,
+        from: &str,
+        to: &str,
+    ) -> Result<Response> {
+        self.apply(request_id, |storage| {
+            self.exchange(storage, quantity, from, to)
+        })
+        .await
+    }
+
+    pub(crate) async fn view_sell_orders(&self, category: Option<&str>) -> Result<Response> {
+        sell_orders(&*self.storage.lock().await, category)
+    }
+
+    pub(crate) async fn view_bids(&self) -> Result<Response> {
+        self.bids(&*self.storage.lock().await)
+    }
+
+    pub(crate) async fn view_item_stats(&self, item_name: &str) -> Result<Response> {
+        item_stats(&*self.storage.lock().await, item_name)
+    }
+
+    pub(crate) async fn suggest_price(&self, item_name: &str, quantity: i64) -> Result<Response> {
+        suggested_price(&*self.storage.lock().await, item_name, quantity)
+    }
+
+    pub(crate) async fn view_catalog(&self) -> Result<Response> {
+        let items = self.storage.lock().await.view_catalog()?;
+        Ok(Response::Catalog { items })
+    }
+
+    pub(crate) async fn place_sell_order(
+        &self,
+        request_id: Option<&str>,
+        order_type: SellOrderType,
+        item_name: &str,
+        quantity: i64,
+        price: i64,
+        price_item: &str,
+    ) -> Result<Response> {
+        self.apply(request_id, |storage| {
+            self.sell(storage, order_type, item_name, quantity, price, price_item)
+        })
+        .await
+    }
+
+    pub(crate) async fn place_instance_sell_order(
+        &self,
+        request_id: Option<&str>,
+        order_type: SellOrderType,
+        instance_id: i64,
+        price: i64,
+        price_item: &str,
+    ) -> Result<Response> {
+        self.apply(request_id, |storage| {
+            self.sell_instance(storage, order_type, instance_id, price, price_item)
+        })
+        .await
+    }
+
+    pub(crate) async fn place_bundle_sell_order(
+        &self,
+        request_id: Option<&str>,
+        order_type: SellOrderType,
+        items: &[Item],
+        price: i64,
+        price_item: &str,
+    ) -> Result<Response> {
+        self.apply(request_id, |storage| {
+            self.sell_bundle(storage, order_type, items, price, price_item)
+        })
+        .await
+    }
+
+    pub(crate) async fn place_bid(
+        &self,
+        request_id: Option<&str>,
+        sell_order_id: i64,
+        bid: i64,
+    ) -> Result<Response> {
+        self.apply(request_id, |storage| self.bid(storage, sell_order_id, bid))
+            .await
+    }
+
+    pub(crate) async fn execute_immediate_sell_order(
+        &self,
+        request_id: Option<&str>,
+        sell_order_id: i64,
+    ) -> Result<Response> {
+        self.apply(request_id, |storage| self.buy(storage, sell_order_id))
+            .await
+    }
+
+    pub(crate) async fn cancel_sell_order(
+        &self,
+        request_id: Option<&str>,
+        sell_order_id: i64,
+    ) -> Result<Response> {
+        self.apply(request_id, |storage| self.cancel(storage, sell_order_id))
+            .await
+    }
+
+    /// Executes all steps in a single transaction. The first failed step rolls back all the changes
+    /// and the error reports which step has failed
+    async fn execute_batch(
+        &self,
+        request_id: Option<&str>,
+        requests: Vec<Request>,
+    ) -> Result<Response> {
+        if requests.len() > MAX_BATCH_STEPS {
+            bail!("Batch is too long: at most {MAX_BATCH_STEPS} commands are allowed");
+        }
+        self.apply(request_id, |storage| {
+            let savepoint = storage.savepoint()?;
+            let responses = requests
+                .iter()
+                .enumerate()
+                .map(|(i, request)| {
+                    self.execute_step(storage, request).with_context(|| {
+                        format!("Batch rolled back, step {} '{request}' failed", i + 1)
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            savepoint.commit()?;
+            Ok(Response::BatchExecuted { responses })
+        })
+        .await
+    }
 
-            if order.quantity == 1 {
-                result.push_str(&format!(
-                    "\n- #{}: {} is selling a {} for {} funds {}until {}",
-                    order.id,
-                    order.seller_name,
-                    order.item_name,
-                    order.price,
-                    order_type_str,
-                    order.expiration_time
-                ));
-            } else {
-                result.push_str(&format!(
-                    "\n- #{}: {} is selling {} {}(s) for {} funds {}until {}",
-                    order.id,
-                    order.seller_name,
-                    order.quantity,
-                    order.item_name,
-                    order.price,
-                    order_type_str,
-                    order.expiration_time
-                ));
+    fn execute_step(&self, storage: &Storage, request: &Request) -> Result<Response> {
+        match request {
+            Request::ViewItems => self.items(storage),
+            Request::Deposit {
+                item_name,
+                quantity,
+            } => self.deposit(storage, item_name, *quantity),
+            Request::Withdraw {
+                item_name,
+                quantity,
+            } => self.withdraw(storage, item_name, *quantity),
+            Request::ViewInstances => self.instances(storage),
+            Request::DepositInstance {
+                item_name,
+                attributes,
+            } => self.deposit_instance(storage, item_name, attributes),
+            Request::WithdrawInstance { instance_id } => {
+                self.withdraw_instance(storage, *instance_id)
             }
+            Request::Give {
+                recipient,
+                item_name,
+                quantity,
+            } => self.give(storage, recipient, item_name, *quantity),
+            Request::ViewTransactions => self.transactions(storage),
+            Request::Offer {
+                recipient,
+                give,
+                want,
+            } => self.offer(storage, recipient, give, want),
+            Request::Accept { offer_id } => self.accept(storage, *offer_id),
+            Request::Decline { offer_id } => self.decline(storage, *offer_id),
+            Request::ViewOffers => self.offers(storage),
+            Request::Watch { kind } => self.watch(storage, kind),
+            Request::Unwatch { watch_id } => self.unwatch(storage, *watch_id),
+            Request::ViewWatchlist => self.watchlist(storage),
+            Request::ViewMailbox => self.mailbox(storage),
+            Request::ViewExchange => exchange_listing(storage),
+            Request::ViewCatalog => Ok(Response::Catalog {
+                items: storage.view_catalog()?,
+            }),
+            Request::Exchange { quantity, from, to } => self.exchange(storage, *quantity, from, to),
+            Request::ViewSellOrders { category } => sell_orders(storage, category.as_deref()),
+            Request::Stats { item_name } => item_stats(storage, item_name),
+            Request::SuggestPrice {
+                item_name,
+                quantity,
+            } => suggested_price(storage, item_name, *quantity),
+            Request::Sell {
+                order_type,
+                item_name,
+                quantity,
+                price,
+                price_item,
+            } => self.sell(
+                storage,
+                *order_type,
+                item_name,
+                *quantity,
+                *price,
+                price_item,
+            ),
+            Request::SellInstance {
+                order_type,
+                instance_id,
+                price,
+                price_item,
+            } => self.sell_instance(storage, *order_type, *instance_id, *price, price_item),
+            Request::SellBundle {
+                order_type,
+                items,
+                price,
+                price_item,
+            } => self.sell_bundle(storage, *order_type, items, *price, price_item),
+            Request::Buy { sell_order_id } => self.buy(storage, *sell_order_id),
+            Request::Bid { sell_order_id, bid } => self.bid(storage, *sell_order_id, *bid),
+            Request::Cancel { sell_order_id } => self.cancel(storage, *sell_order_id),
+            Request::MyBids => self.bids(storage),
+            request => bail!("'{request}' can't be a part of a batch"),
         }
-        Ok(result)
-    }
-
-    // args should be in the format "[immediate|auction] <item_name> [quantity] <price>".
-    // Price is mandatory, quantity is optional and defaults to 1.
-    // Examples:
-    // - "arrow 5 10" -> {"arrow", .quantity=5, .price=10, .type=Immediate}
-    // - "holy sword 1 100" -> {"holy sword", .quantity=1, .price=100, .type=Immediate}
-    // - "arrow 10" -> {"arrow", .quantity=1, .price=10, .type=Immediate}
-    // - "immidiate arrow 10 5" -> {"arrow", .quantity=10, .price=5, .type=Immediate}
-    // - "auction arrow 10 5" -> {"arrow", .quantity=10, .price=5, .type=Auction}
-    async fn sell(&self, args: &str) -> Result<String> {
-        let (order_type, args) = args
-            .find(' ')
-            .and_then(|pos| {
-                SellOrderType::from_str(&args[..pos])
-                    .map(|order_type| (order_type, &args[pos + 1..]))
-            })
-            .unwrap_or((SellOrderType::Immediate, args));
-
-        let (price, args) = args
-            .rfind(' ')
-            .and_then(|pos| {
-                args[pos + 1..]
-                    .parse::<i64>()
-                    .ok()
-                    .map(|price| (price, &args[..pos]))
+    }
+
+    fn items(&self, storage: &Storage) -> Result<Response> {
+        let mut items: Vec<ItemBalance> = storage
+            .view_items(self.user.id)?
+            .into_iter()
+            .map(|(name, quantity)| ItemBalance {
+                name,
+                quantity,
+                in_bids: 0,
+                listed: 0,
             })
-            .ok_or(anyhow!(
-                "Unable to parse order. \
-                Expected: 'sell [immediate|auction] <item_name> [<quantity>] <price>'. \
-                Default type is 'immediate' and default quantity is 1"
-            ))?;
+            .collect();
+        // Items can be entirely held by the market, then they are listed after the available ones
+        for (name, in_bids, listed) in storage.view_reserved_items(self.user.id)? {
+            match items.iter_mut().find(|item| item.name == name) {
+                Some(item) => {
+                    item.in_bids = in_bids;
+                    item.listed = listed;
+                }
+                None => items.push(ItemBalance {
+                    name,
+                    quantity: 0,
+                    in_bids,
+                    listed,
+                }),
+            }
+        }
+        Ok(Response::Items { items })
+    }
 
-        let (item_name, quantity) = parse_item_name_and_quantity(args);
+    fn deposit(&self, storage: &Storage, item_name: &str, quantity: i64) -> Result<Response> {
+        storage
+            .deposit(self.user.id, item_name, quantity)
+            .with_context(|| format!("Failed to deposit {quantity} {item_name}(s)"))?;
+        Ok(Response::Deposited {
+            item_name: item_name.to_owned(),
+            quantity,
+        })
+    }
 
-        let order_lifetime_seconds = 5 * 60; // 5 min
-        let unix_now = std::time::UNIX_EPOCH.elapsed()?.as_secs() as i64;
+    fn withdraw(&self, storage: &Storage, item_name: &str, quantity: i64) -> Result<Response> {
+        storage
+            .withdraw(self.user.id, item_name, quantity)
+            .with_context(|| format!("Failed to withdraw {quantity} {item_name}(s)"))?;
+        Ok(Response::Withdrawn {
+            item_name: item_name.to_owned(),
+            quantity,
+        })
+    }
 
-        self.storage
-            .lock()
-            .await
+    fn instances(&self, storage: &Storage) -> Result<Response> {
+        let instances = storage.view_instances(self.user.id)?;
+        Ok(Response::Instances { instances })
+    }
+
+    fn deposit_instance(
+        &self,
+        storage: &Storage,
+        item_name: &str,
+        attributes: &serde_json::Value,
+    ) -> Result<Response> {
+        let instance = storage
+            .deposit_instance(self.user.id, item_name, attributes)
+            .with_context(|| format!("Failed to deposit {item_name} with {attributes}"))?;
+        Ok(Response::InstanceDeposited { instance })
+    }
+
+    fn withdraw_instance(&self, storage: &Storage, instance_id: i64) -> Result<Response> {
+        storage
+            .withdraw_instance(self.user.id, instance_id)
+            .with_context(|| format!("Failed to withdraw item instance #{instance_id}"))?;
+        Ok(Response::InstanceWithdrawn { instance_id })
+    }
+
+    fn give(
+        &self,
+        storage: &Storage,
+        recipient: &str,
+        item_name: &str,
+        quantity: i64,
+    ) -> Result<Response> {
+        storage
+            .give(self.user.id, recipient, item_name, quantity, unix_now()?)
+            .with_context(|| format!("Failed to give {quantity} {item_name}(s) to {recipient}"))?;
+        Ok(Response::ItemsGiven {
+            recipient: recipient.to_owned(),
+            item_name: item_name.to_owned(),
+            quantity,
+        })
+    }
+
+    fn transactions(&self, storage: &Storage) -> Result<Response> {
+        let transactions = storage.view_transactions(self.user.id)?;
+        Ok(Response::Transactions { transactions })
+    }
+
+    fn offer(
+        &self,
+        storage: &Storage,
+        recipient: &str,
+        give: &[Item],
+        want: &[Item],
+    ) -> Result<Response> {
+        let offer_lifetime_seconds = 60 * 60; // 1 hour
+        let offer_id = storage
+            .place_offer(
+                self.user.id,
+                recipient,
+                give,
+                want,
+                unix_now()? + offer_lifetime_seconds,
+            )
+            .with_context(|| format!("Failed to place offer to {recipient}"))?;
+        Ok(Response::OfferPlaced { offer_id })
+    }
+
+    fn accept(&self, storage: &Storage, offer_id: i64) -> Result<Response> {
+        storage
+            .accept_offer(self.user.id, offer_id, unix_now()?)
+            .with_context(|| format!("Failed to accept offer #{offer_id}"))?;
+        Ok(Response::OfferAccepted { offer_id })
+    }
+
+    fn decline(&self, storage: &Storage, offer_id: i64) -> Result<Response> {
+        storage
+            .decline_offer(self.user.id, offer_id)
+            .with_context(|| format!("Failed to decline offer #{offer_id}"))?;
+        Ok(Response::OfferDeclined { offer_id })
+    }
+
+    fn watch(&self, storage: &Storage, kind: &WatchKind) -> Result<Response> {
+        let watch = storage
+            .add_watch(self.user.id, kind)
+            .with_context(|| format!("Failed to watch {kind}"))?;
+        Ok(Response::Watching { watch })
+    }
+
+    fn unwatch(&self, storage: &Storage, watch_id: i64) -> Result<Response> {
+        storage
+            .remove_watch(self.user.id, watch_id)
+            .with_context(|| format!("Failed to remove watch #{watch_id}"))?;
+        Ok(Response::Unwatched { watch_id })
+    }
+
+    fn watchlist(&self, storage: &Storage) -> Result<Response> {
+        let watches = storage.view_watchlist(self.user.id)?;
+        Ok(Response::Watchlist { watches })
+    }
+
+    fn mailbox(&self, storage: &Storage) -> Result<Response> {
+        let events = storage.view_mailbox(self.user.id)?;
+        Ok(Response::Mailbox { events })
+    }
+
+    fn offers(&self, storage: &Storage) -> Result<Response> {
+        let offers = storage.view_offers(self.user.id)?;
+        Ok(Response::Offers { offers })
+    }
+
+    fn exchange(&self, storage: &Storage, quantity: i64, from: &str, to: &str) -> Result<Response> {
+        let received = storage
+            .exchange(self.user.id, quantity, from, to)
+            .with_context(|| format!("Failed to exchange {quantity} {from}(s) to {to}"))?;
+        Ok(Response::Exchanged {
+            from: Item {
+                name: from.to_owned(),
+                quantity,
+            },
+            to: Item {
+                name: to.to_owned(),
+                quantity: received,
+            },
+        })
+    }
+
+    fn sell(
+        &self,
+        storage: &Storage,
+        order_type: SellOrderType,
+        item_name: &str,
+        quantity: i64,
+        price: i64,
+        price_item: &str,
+    ) -> Result<Response> {
+        let order_lifetime_seconds = 5 * 60; // 5 min
+        let sell_order_id = storage
             .place_sell_order(
                 order_type,
                 self.user.id,
                 item_name,
                 quantity,
                 price,
-                unix_now + order_lifetime_seconds,
+                price_item,
+                unix_now()? + order_lifetime_seconds,
             )
             .with_context(|| {
                 format!("Failed to place {order_type} sell order for {quantity} {item_name}(s)")
-            })
-            .map(|()| {
-                format!("Successfully placed {order_type} sell order for {quantity} {item_name}(s)")
-            })
+            })?;
+        Ok(Response::SellOrderPlaced {
+            sell_order_id,
+            order_type,
+            item_name: item_name.to_owned(),
+            quantity,
+        })
     }
 
-    // args should be in the format "<sell_order_id> [<bid>]"
-    // if bid provided - try to make a bid on the auction sell order
-    // otherwise - try to execute the immediate sell order
-    async fn buy(&self, args: &str) -> Result<String> {
-        let (bid, args) = args
-            .rfind(' ')
-            .and_then(|pos| {
-                args[pos + 1..]
-                    .parse::<i64>()
-                    .ok()
-                    .map(|bid| (Some(bid), &args[..pos]))
-            })
-            .unwrap_or((None, args));
-
-        let sell_order_id = args
-            .parse::<i64>()
-            .with_context(|| "Unable to parse sell order id")?;
+    fn sell_instance(
+        &self,
+        storage: &Storage,
+        order_type: SellOrderType,
+        instance_id: i64,
+        price: i64,
+        price_item: &str,
+    ) -> Result<Response> {
+        let order_lifetime_seconds = 5 * 60; // 5 min
+        let sell_order_id = storage
+            .place_instance_sell_order(
+                order_type,
+                self.user.id,
+                instance_id,
+                price,
+                price_item,
+                unix_now()? + order_lifetime_seconds,
+            )
+            .with_context(|| {
+                format!("Failed to place {order_type} sell order for item instance #{instance_id}")
+            })?;
+        let order = storage.get_sell_order(sell_order_id)?;
+        Ok(Response::SellOrderPlaced {
+            sell_order_id,
+            order_type,
+            item_name: order.item_name,
+            quantity: order.quantity,
+        })
+    }
 
-        if let Some(bid) = bid {
-            self.storage
-                .lock()
-                .await
-                .place_bid_on_auction_sell_order(self.user.id, sell_order_id, bid)
-                .with_context(|| format!("Failed to place bid on sell order #{sell_order_id}"))
-                .map(|()| format!("Successfully placed bid on sell order #{sell_order_id}"))
+    fn sell_bundle(
+        &self,
+        storage: &Storage,
+        order_type: SellOrderType,
+        items: &[Item],
+        price: i64,
+        price_item: &str,
+    ) -> Result<Response> {
+        let order_lifetime_seconds = 5 * 60; // 5 min
+        let sell_order_id = storage
+            .place_bundle_sell_order(
+                order_type,
+                self.user.id,
+                items,
+                price,
+                price_item,
+                unix_now()? + order_lifetime_seconds,
+            )
+            .with_context(|| format!("Failed to place {order_type} sell order for a bundle"))?;
+        // Repeated items are merged by the storage
+        let order = storage.get_sell_order(sell_order_id)?;
+        let items = if order.is_bundle() {
+            order.bundle
         } else {
-            self.storage
-                .lock()
-                .await
-                .execute_immediate_sell_order(self.user.id, sell_order_id)
-                .with_context(|| {
-                    format!("Failed to executed immediate sell order #{sell_order_id}")
-                })
-                .map(|()| format!("Successfully executed immediate sell order #{sell_order_id}"))
+            vec![Item {
+                name: order.item_name,
+                quantity: order.quantity,
+            }]
+        };
+        Ok(Response::BundleSellOrderPlaced {
+            sell_order_id,
+            order_type,
+            items,
+        })
+    }
+
+    fn bid(&self, storage: &Storage, sell_order_id: i64, bid: i64) -> Result<Response> {
+        storage
+            .place_bid_on_auction_sell_order(self.user.id, sell_order_id, bid)
+            .with_context(|| format!("Failed to place bid on sell order #{sell_order_id}"))?;
+        Ok(Response::BidPlaced { sell_order_id, bid })
+    }
+
+    fn buy(&self, storage: &Storage, sell_order_id: i64) -> Result<Response> {
+        storage
+            .execute_immediate_sell_order(self.user.id, sell_order_id, unix_now()?)
+            .with_context(|| format!("Failed to executed immediate sell order #{sell_order_id}"))?;
+        Ok(Response::SellOrderExecuted { sell_order_id })
+    }
+
+    fn bids(&self, storage: &Storage) -> Result<Response> {
+        let sell_orders = storage.view_bids(self.user.id)?;
+        Ok(Response::Bids { sell_orders })
+    }
+
+    fn cancel(&self, storage: &Storage, sell_order_id: i64) -> Result<Response> {
+        storage
+            .cancel_sell_order(self.user.id, sell_order_id)
+            .with_context(|| format!("Failed to cancel sell order #{sell_order_id}"))?;
+        Ok(Response::SellOrderCancelled { sell_order_id })
+    }
+
+    /// Applies the operation that changes the stored state. Operations with a request id are applied
+    /// at most once per user, retries get the result of the first attempt, even if it has failed
+    async fn apply(
+        &self,
+        request_id: Option<&str>,
+        operation: impl FnOnce(&Storage) -> Result<Response>,
+    ) -> Result<Response> {
+        let storage = self.storage.lock().await;
+        let Some(request_id) = request_id else {
+            return operation(&storage);
+        };
+
+        // The result is stored atomically with the changes made by the operation
+        let savepoint = storage.savepoint()?;
+        if let Some(result) = self.stored_result(&storage, request_id)? {
+            return result;
         }
+
+        let result = operation(&storage);
+        let stored = result.as_ref().map(Clone::clone).map_err(error_response);
+        // Database errors are not a result of the request, so it should be possible to retry it
+        if stored
+            .as_ref()
+            .is_err_and(|err| err.code == ErrorCode::Database)
+        {
+            return result;
+        }
+        let stored = serde_json::to_string(&JsonResponse::from(stored))?;
+        storage.save_request_result(self.user.id, request_id, &stored, unix_now()?)?;
+        savepoint.commit()?;
+        result
+    }
+
+    /// Result of the request with this id, if it has already been applied
+    fn stored_result(
+        &self,
+        storage: &Storage,
+        request_id: &str,
+    ) -> Result<Option<Result<Response>>> {
+        let Some(result) = storage.get_request_result(self.user.id, request_id)? else {
+            return Ok(None);
+        };
+        let result: JsonResponse = serde_json::from_str(&result)
+            .with_context(|| format!("Corrupted result of the request '{request_id}'"))?;
+        Ok(Some(result.into_result().map_err(anyhow::Error::new)))
     }
 }
 
-// Parses the last word as a quantity and if failed - uses the whole string as an item name
-// Examples:
-// - "arrow 5" -> {"arrow", 5}
-// - "holy sword 1" -> {"holy sword", 1}
-// - "arrow" -> {"arrow", 1}
-// - "holy sword" -> {"holy sword", 1}
-fn parse_item_name_and_quantity(args: &str) -> (&str, i64) {
-    if let Some(pos) = args.rfind(' ') {
-        if let Ok(quantity) = args[pos + 1..].parse::<i64>() {
-            return (&args[..pos], quantity);
-        }
+fn sell_orders(storage: &Storage, category: Option<&str>) -> Result<Response> {
+    let sell_orders = storage.view_sell_orders(category)?;
+    Ok(Response::SellOrders { sell_orders })
+}
+
+fn item_stats(storage: &Storage, item_name: &str) -> Result<Response> {
+    let stats = storage
+        .item_stats(item_name, unix_now()?)
+        .with_context(|| format!("Failed to get stats of {item_name}"))?;
+    Ok(Response::Stats { stats })
+}
+
+fn suggested_price(storage: &Storage, item_name: &str, quantity: i64) -> Result<Response> {
+    let price = storage
+        .suggest_price(item_name, quantity, unix_now()?)
+        .with_context(|| format!("Failed to suggest price for {quantity} {item_name}(s)"))?;
+    Ok(Response::SuggestedPrice {
+        item_name: item_name.to_owned(),
+        quantity,
+        price,
+    })
+}
+
+fn exchange_listing(storage: &Storage) -> Result<Response> {
+    Ok(Response::Exchange {
+        currencies: storage.view_currencies()?,
+        rates: storage.view_exchange_rates(),
+    })
+}
+
+pub(crate) fn unix_now() -> Result<i64> {
+    Ok(std::time::UNIX_EPOCH.elapsed()?.as_secs() as i64)
+}
+
+/// Converts an error of a request processing into the response reported to the client
+pub(crate) fn error_response(err: &anyhow::Error) -> ErrorResponse {
+    // Stored result of a retried request
+    if let Some(err) = err.downcast_ref::<ErrorResponse>() {
+        return err.clone();
+    }
+    ErrorResponse {
+        code: err
+            .downcast_ref::<storage::Error>()
+            .map_or(ErrorCode::BadRequest, storage::Error::code),
+        message: format!("{err:#}"),
     }
-    (args, 1)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
 
     #[test]
-    fn test_parse_item_name_and_quantity() {
-        assert_eq!(parse_item_name_and_quantity("arrow 5"), ("arrow", 5));
+    fn test_error_response() {
+        assert_eq!(
+            error_response(&anyhow::anyhow!("Unknown command 'pong'")),
+            ErrorResponse {
+                code: ErrorCode::BadRequest,
+                message: "Unknown command 'pong'".into()
+            }
+        );
+        assert_eq!(
+            error_response(
+                &anyhow::Error::new(storage::Error::InsufficientFunds {
+                    required: 100,
+                    available: 10
+                })
+                .context("Failed to executed immediate sell order #1")
+            ),
+            ErrorResponse {
+                code: ErrorCode::InsufficientFunds,
+                message: "Failed to executed immediate sell order #1: \
+                    Not enough funds: 100 required, but only 10 available"
+                    .into()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn retried_requests() {
+        let storage = Storage::open(":memory:").unwrap();
+        let user = storage.login("buyer").unwrap();
+        let mut processor = CommandsProcessor::new(user, Arc::new(Mutex::new(storage)));
+        let mut process = async |request: &str| {
+            let result = processor.process_request(request).await;
+            processor.encode(result)
+        };
+
+        assert_eq!(
+            process("@1 deposit funds 100").await,
+            "Successfully deposited 100 funds(s)"
+        );
+        assert_eq!(
+            process("@1 deposit funds 100").await,
+            "Successfully deposited 100 funds(s)"
+        );
+        assert_eq!(
+            process("@2 withdraw funds 150").await,
+            "Failed to process request: Failed to withdraw 150 funds(s): \
+            Not enough funds: 150 required, but only 100 available"
+        );
+        assert_eq!(
+            process("deposit funds 100").await,
+            "Successfully deposited 100 funds(s)"
+        );
+        // The original outcome is returned, even though the request would succeed now
+        assert_eq!(
+            process("@2 withdraw funds 150").await,
+            "Failed to process request: Failed to withdraw 150 funds(s): \
+            Not enough funds: 150 required, but only 100 available"
+        );
+        // Read-only requests ignore ids
+        assert_eq!(process("@1 view_items").await, r#"Items: [("funds", 200)]"#);
+        assert_eq!(
+            process("@1. ping").await,
+            "Failed to process request: Request id '1.' may contain only letters, digits, '-' and '_'"
+        );
+    }
+
+    #[tokio::test]
+    async fn batches() {
+        let storage = Storage::open(":memory:").unwrap();
+        let user = storage.login("seller").unwrap();
+        let storage = Arc::new(Mutex::new(storage));
+        let mut events = storage.lock().await.subscribe();
+        let mut processor = CommandsProcessor::new(user, storage);
+        let mut process = async |request: &str| {
+            let result = processor.process_request(request).await;
+            processor.encode(result)
+        };
+
+        assert_eq!(
+            process("batch deposit funds 10; deposit Sword 2; sell Sword 100; view_items").await,
+            "Successfully executed batch of 4 command(s):\n\
+            - Successfully deposited 10 funds(s)\n\
+            - Successfully deposited 2 Sword(s)\n\
+            - Successfully placed immediate sell order for 1 Sword(s)\n\
+            - Items: [(\"funds\", 4), (\"Sword\", 1)]\n\
+            Listed for sale: [(\"Sword\", 1)]"
+        );
+        assert!(matches!(
+            events.try_recv(),
+            Ok(MarketEvent::OrderPlaced { .. })
+        ));
+
+        // The fee is paid, but the next step fails, so the order is not placed
+        assert_eq!(
+            process("batch sell Sword 50; withdraw Sword").await,
+            "Failed to process request: Batch rolled back, step 2 'withdraw Sword 1' failed: \
+            Failed to withdraw 1 Sword(s): Not enough Sword(s): 1 required, but only 0 available"
+        );
+        assert_eq!(
+            process("view_items").await,
+            "Items: [(\"funds\", 4), (\"Sword\", 1)]\nListed for sale: [(\"Sword\", 1)]"
+        );
+        assert!(events.try_recv().is_err());
+
+        assert_eq!(
+            process("batch begin").await,
+            "Batch started, following commands are queued until 'batch commit' or 'batch abort'"
+        );
+        assert_eq!(
+            process("withdraw funds 4").await,
+            "Queued as step 1 of the batch"
+        );
+        // Session commands are executed immediately
+        assert_eq!(process("ping").await, "pong");
+        assert_eq!(
+            process("withdraw Sword").await,
+            "Queued as step 2 of the batch"
+        );
+        assert_eq!(
+            process("@5 batch commit").await,
+            "Successfully executed batch of 2 command(s):\n\
+            - Successfully withdrawed 4 funds(s)\n\
+            - Successfully withdrawed 1 Sword(s)"
+        );
+        // The retry gets the original result, though the batch is already finished
+        assert_eq!(
+            process("@5 batch commit").await,
+            "Successfully executed batch of 2 command(s):\n\
+            - Successfully withdrawed 4 funds(s)\n\
+            - Successfully withdrawed 1 Sword(s)"
+        );
+        assert_eq!(
+            process("batch commit").await,
+            "Failed to process request: No batch is started, start it with 'batch begin'"
+        );
+
+        process("batch begin").await;
+        process("deposit funds 1").await;
+        assert_eq!(
+            process("batch abort").await,
+            "Batch aborted, queued commands are discarded"
+        );
+        // The listed Sword is still shown, though none is available
+        assert_eq!(
+            process("view_items").await,
+            "Items: [(\"funds\", 0), (\"Sword\", 0)]\nListed for sale: [(\"Sword\", 1)]"
+        );
+    }
+
+    #[tokio::test]
+    async fn personal_events() {
+        let storage = Storage::open(":memory:").unwrap();
+        let sender = storage.login("sender").unwrap();
+        let recipient = storage.login("recipient").unwrap();
+        let bystander = storage.login("bystander").unwrap();
+        let storage = Arc::new(Mutex::new(storage));
+
+        let mut sender = CommandsProcessor::new(sender, storage.clone());
+        let mut recipient = CommandsProcessor::new(recipient, storage.clone());
+        let mut bystander = CommandsProcessor::new(bystander, storage);
+        // Sessions start receiving events once they wait for them
+        for processor in [&mut recipient, &mut bystander] {
+            let event = tokio::time::timeout(Duration::from_millis(10), processor.next_event());
+            assert!(event.await.is_err());
+        }
+        bystander.process_request("subscribe orders").await.unwrap();
+
+        sender.process_request("deposit Sword 2").await.unwrap();
+        let result = sender.process_request("give recipient Sword 2").await;
+        assert_eq!(
+            sender.encode(result),
+            "Successfully gave 2 Sword(s) to recipient"
+        );
+        assert_eq!(
+            recipient.next_event().await,
+            "Event: items received - sender gave you 2 Sword(s)"
+        );
+        // Personal events are not delivered to other users, even if they are subscribed
+        let event = tokio::time::timeout(Duration::from_millis(10), bystander.next_event());
+        assert!(event.await.is_err());
+
+        // Personal events are not lost when the session lags behind the market events
+        sender.process_request("deposit Shield 1").await.unwrap();
+        sender
+            .process_request("give recipient Shield")
+            .await
+            .unwrap();
+        sender.process_request("deposit arrow 2000").await.unwrap();
+        sender.process_request("deposit funds 4000").await.unwrap();
+        for _ in 0..2000 {
+            sender.process_request("sell arrow 1 1").await.unwrap();
+        }
+        assert_eq!(
+            recipient.next_event().await,
+            "Event: items received - sender gave you 1 Shield(s)"
+        );
+    }
+
+    #[tokio::test]
+    async fn subscriptions() {
+        let mut storage = Storage::open(":memory:").unwrap();
+        let catalog = [protocol::CatalogItem {
+            name: "Iron Sword".into(),
+            display_name: None,
+            category: None,
+            description: String::new(),
+            max_stack: None,
+            tradeable: true,
+            aliases: vec!["blade".into()],
+        }];
+        storage.set_catalog(&catalog, false).unwrap();
+        let seller = storage.login("seller").unwrap();
+        let by_name = storage.login("by_name").unwrap();
+        let by_alias = storage.login("by_alias").unwrap();
+        let storage = Arc::new(Mutex::new(storage));
+
+        let mut seller = CommandsProcessor::new(seller, storage.clone());
+        let mut by_name = CommandsProcessor::new(by_name, storage.clone());
+        let mut by_alias = CommandsProcessor::new(by_alias, storage);
+        let result = by_name.process_request("subscribe orders iron swrod").await;
+        assert_eq!(
+            by_name.encode(result),
+            "Failed to process request: Failed to subscribe to orders of 'iron swrod': \
+            No such item 'iron swrod', did you mean 'Iron Sword'?"
+        );
+        // Subscriptions refer to the item whatever spelling or alias is used
+        let result = by_name
+            .process_request("subscribe orders IRON  sword")
+            .await;
         assert_eq!(
-            parse_item_name_and_quantity("holy sword 1"),
-            ("holy sword", 1)
+            by_name.encode(result),
+            "Successfully subscribed to Iron Sword orders"
         );
-        assert_eq!(parse_item_name_and_quantity("arrow"), ("arrow", 1));
+        let result = by_alias.process_request("subscribe orders Blade").await;
         assert_eq!(
-            parse_item_name_and_quantity("holy sword"),
-            ("holy sword", 1)
+            by_alias.encode(result),
+            "Successfully subscribed to Iron Sword orders"
         );
-        assert_eq!(parse_item_name_and_quantity(""), ("", 1));
+        for processor in [&mut by_name, &mut by_alias] {
+            let event = tokio::time::timeout(Duration::from_millis(10), processor.next_event());
+            assert!(event.await.is_err());
+        }
+
+        seller.process_request("deposit funds 10").await.unwrap();
+        seller.process_request("deposit Shield 1").await.unwrap();
+        seller
+            .process_request("deposit iron sword 1")
+            .await
+            .unwrap();
+        seller.process_request("sell Shield 10").await.unwrap();
+        seller.process_request("sell blade 20").await.unwrap();
+        // The Shield order #1 is skipped
+        for processor in [&mut by_name, &mut by_alias] {
+            assert!(processor.next_event().await.starts_with(
+                "Event: order placed - #2: seller is selling a Iron Sword for 20 funds"
+            ));
+        }
     }
 }
@@ -0,0 +1,1024 @@
+//! HTTP/JSON API, served alongside the TCP protocol and backed by the same `Storage`.
+//!
+//! Every response uses the same JSON envelope as the `format json` mode of the TCP protocol.
+//! All endpoints except `POST /login` require an `Authorization: Bearer <token>` header
+//! (or the `token` query parameter), where the token is obtained from `POST /login`. Tokens expire
+//! after `SESSION_LIFETIME_SECONDS` or once `POST /logout` is called with them.
+
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use axum::{
+    extract::{FromRequest, FromRequestParts, Path, Query, Request, State},
+    http::{header, request::Parts, StatusCode},
+    response::IntoResponse,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use protocol::{
+    ErrorCode, Item, Response, ResponseFormat, SellOrderType, TaggedRequest, WatchKind,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    commands::{self, CommandsProcessor},
+    storage::{self, Storage, User},
+    websocket,
+};
+
+pub(crate) type SharedStorage = Arc<Mutex<Storage>>;
+
+const SESSION_LIFETIME_SECONDS: i64 = 30 * 24 * 60 * 60;
+
+pub(crate) fn router(storage: SharedStorage) -> Router {
+    Router::new()
+        .route("/login", post(login))
+        .route("/logout", post(logout))
+        .route("/orders", get(view_sell_orders).post(place_sell_order))
+        .route("/orders/bundles", post(place_bundle_sell_order))
+        .route("/orders/{id}", delete(cancel_sell_order))
+        .route("/orders/{id}/buy", post(execute_immediate_sell_order))
+        .route("/orders/{id}/bids", post(place_bid))
+        .route("/me/items", get(view_items))
+        .route("/me/bids", get(view_bids))
+        .route("/me/deposits", post(deposit))
+        .route("/me/withdrawals", post(withdraw))
+        .route("/me/instances", get(view_instances).post(deposit_instance))
+        .route("/me/instances/{id}", delete(withdraw_instance))
+        .route("/me/instances/{id}/orders", post(place_instance_sell_order))
+        .route("/me/transfers", post(give))
+        .route("/me/transactions", get(view_transactions))
+        .route("/me/offers", get(view_offers))
+        .route("/me/watchlist", get(view_watchlist).post(add_watch))
+        .route("/me/watchlist/{id}", delete(remove_watch))
+        .route("/me/mailbox", delete(take_mailbox))
+        .route("/offers", post(place_offer))
+        .route("/offers/{id}/accept", post(accept_offer))
+        .route("/offers/{id}/decline", post(decline_offer))
+        .route("/exchange", get(view_exchange))
+        .route("/items/{name}/stats", get(view_item_stats))
+        .route("/items/{name}/suggested_price", get(suggest_price))
+        .route("/catalog", get(view_catalog))
+        .route("/me/exchanges", post(exchange))
+        .route("/ws", get(websocket::handler))
+        .with_state(storage)
+}
+
+// Result of a request processing, encoded into the JSON envelope
+pub(crate) struct ApiResponse(anyhow::Result<Response>);
+
+impl IntoResponse for ApiResponse {
+    fn into_response(self) -> axum::response::Response {
+        // Mapped by the error code, so retries replaying a stored error get the same status
+        let status = match &self.0 {
+            Ok(_) => StatusCode::OK,
+            Err(err) => match commands::error_response(err).code {
+                ErrorCode::BadRequest
+                | ErrorCode::EmptyUsername
+                | ErrorCode::EmptyItemName
+                | ErrorCode::InvalidQuantity
+                | ErrorCode::InvalidPrice
+                | ErrorCode::EmptyOffer
+                | ErrorCode::EmptyBundle
+                | ErrorCode::InvalidAttributes => StatusCode::BAD_REQUEST,
+                ErrorCode::InvalidToken => StatusCode::UNAUTHORIZED,
+                ErrorCode::ItemNotFound
+                | ErrorCode::OrderNotFound
+                | ErrorCode::UserNotFound
+                | ErrorCode::OfferNotFound
+                | ErrorCode::InstanceNotFound
+                | ErrorCode::WatchNotFound => StatusCode::NOT_FOUND,
+                ErrorCode::Database => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::UNPROCESSABLE_ENTITY,
+            },
+        };
+        (
+            status,
+            [(header::CONTENT_TYPE, "application/json")],
+            ResponseFormat::Json.encode(self.0.map_err(|err| commands::error_response(&err))),
+        )
+            .into_response()
+    }
+}
+
+// Session token from the `Authorization: Bearer <token>` header or the `token` query parameter
+struct SessionToken(String);
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: String,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for SessionToken {
+    type Rejection = ApiResponse;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let query_token = Query::<TokenQuery>::try_from_uri(&parts.uri)
+            .ok()
+            .map(|Query(query)| query.token);
+        parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_owned)
+            .or(query_token)
+            .map(|token| Self(token.trim().to_owned()))
+            .ok_or(ApiResponse(Err(storage::Error::InvalidToken.into())))
+    }
+}
+
+// User authenticated by a session token that hasn't expired
+pub(crate) struct AuthUser(pub(crate) User);
+
+impl FromRequestParts<SharedStorage> for AuthUser {
+    type Rejection = ApiResponse;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        storage: &SharedStorage,
+    ) -> Result<Self, Self::Rejection> {
+        let SessionToken(token) = SessionToken::from_request_parts(parts, storage).await?;
+        let unix_now = commands::unix_now().map_err(|err| ApiResponse(Err(err)))?;
+        storage
+            .lock()
+            .await
+            .get_session_user(&token, unix_now)
+            .map(AuthUser)
+            .map_err(|err| ApiResponse(Err(err.into())))
+    }
+}
+
+// Id of the request from the optional `Idempotency-Key` header. Requests that change the state
+// are applied at most once per key, retries get the result of the first attempt
+struct IdempotencyKey(Option<String>);
+
+impl<S: Send + Sync> FromRequestParts<S> for IdempotencyKey {
+    type Rejection = ApiResponse;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts.headers.get("idempotency-key") else {
+            return Ok(Self(None));
+        };
+        let key = value
+            .to_str()
+            .map_err(|err| ApiResponse(Err(anyhow!("Invalid Idempotency-Key header: {err}"))))?;
+        TaggedRequest::validate_id(key).map_err(|err| ApiResponse(Err(err.into())))?;
+        Ok(Self(Some(key.to_owned())))
+    }
+}
+
+// Same as `axum::Json`, but reports malformed bodies using the JSON envelope
+struct JsonBody<T>(T);
+
+impl<S: Send + Sync, T: DeserializeOwned> FromRequest<S> for JsonBody<T> {
+    type Rejection = ApiResponse;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(req, state).await {
+            Ok(Json(value)) => Ok(Self(value)),
+            Err(rejection) => Err(ApiResponse(Err(anyhow!(rejection.body_text())))),
+        }
+    }
+}
+
+// Same as `axum::extract::Path`, but reports malformed path parameters using the JSON envelope
+struct PathParam<T>(T);
+
+impl<S: Send + Sync, T: DeserializeOwned + Send> FromRequestParts<S> for PathParam<T> {
+    type Rejection = ApiResponse;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match Path::<T>::from_request_parts(parts, state).await {
+            Ok(Path(value)) => Ok(Self(value)),
+            Err(rejection) => Err(ApiResponse(Err(anyhow!(rejection.body_text())))),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginRequest {
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct SellOrdersQuery {
+    category: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SuggestedPriceQuery {
+    #[serde(default = "default_quantity")]
+    quantity: i64,
+}
+
+#[derive(Deserialize)]
+struct ItemRequest {
+    item_name: String,
+    #[serde(default = "default_quantity")]
+    quantity: i64,
+}
+
+#[derive(Deserialize)]
+struct InstanceRequest {
+    item_name: String,
+    attributes: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct TransferRequest {
+    recipient: String,
+    item_name: String,
+    #[serde(default = "default_quantity")]
+    quantity: i64,
+}
+
+#[derive(Deserialize)]
+struct OfferRequest {
+    recipient: String,
+    give: Vec<Item>,
+    want: Vec<Item>,
+}
+
+#[derive(Deserialize)]
+struct ExchangeRequest {
+    quantity: i64,
+    from: String,
+    to: String,
+}
+
+#[derive(Deserialize)]
+struct SellOrderRequest {
+    #[serde(default = "default_order_type")]
+    order_type: SellOrderType,
+    item_name: String,
+    #[serde(default = "default_quantity")]
+    quantity: i64,
+    price: i64,
+    #[serde(default = "default_price_item")]
+    price_item: String,
+}
+
+#[derive(Deserialize)]
+struct BundleSellOrderRequest {
+    #[serde(default = "default_order_type")]
+    order_type: SellOrderType,
+    items: Vec<Item>,
+    price: i64,
+    #[serde(default = "default_price_item")]
+    price_item: String,
+}
+
+#[derive(Deserialize)]
+struct InstanceSellOrderRequest {
+    #[serde(default = "default_order_type")]
+    order_type: SellOrderType,
+    price: i64,
+    #[serde(default = "default_price_item")]
+    price_item: String,
+}
+
+#[derive(Deserialize)]
+struct BidRequest {
+    bid: i64,
+}
+
+fn default_quantity() -> i64 {
+    1
+}
+
+fn default_price_item() -> String {
+    "funds".into()
+}
+
+fn default_order_type() -> SellOrderType {
+    SellOrderType::Immediate
+}
+
+async fn login(
+    State(storage): State<SharedStorage>,
+    JsonBody(request): JsonBody<LoginRequest>,
+) -> ApiResponse {
+    let unix_expiration_time = match commands::unix_now() {
+        Ok(unix_now) => unix_now + SESSION_LIFETIME_SECONDS,
+        Err(err) => return ApiResponse(Err(err)),
+    };
+    let storage = storage.lock().await;
+    let result = storage.login(request.username.trim()).and_then(|user| {
+        storage
+            .create_session(user.id, unix_expiration_time)
+            .map(|token| Response::LoggedIn {
+                username: user.username,
+                token,
+            })
+    });
+    ApiResponse(result.map_err(|err| anyhow::Error::new(err).context("Failed to login")))
+}
+
+async fn logout(
+    State(storage): State<SharedStorage>,
+    SessionToken(token): SessionToken,
+) -> ApiResponse {
+    let result = storage.lock().await.delete_session(&token);
+    ApiResponse(
+        result
+            .map(|()| Response::LoggedOut)
+            .map_err(|err| anyhow::Error::new(err).context("Failed to logout")),
+    )
+}
+
+async fn view_sell_orders(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    Query(query): Query<SellOrdersQuery>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .view_sell_orders(query.category.as_deref())
+            .await,
+    )
+}
+
+async fn view_item_stats(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    PathParam(item_name): PathParam<String>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .view_item_stats(&item_name)
+            .await,
+    )
+}
+
+async fn suggest_price(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    PathParam(item_name): PathParam<String>,
+    Query(query): Query<SuggestedPriceQuery>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .suggest_price(&item_name, query.quantity)
+            .await,
+    )
+}
+
+async fn view_catalog(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+) -> ApiResponse {
+    ApiResponse(CommandsProcessor::new(user, storage).view_catalog().await)
+}
+
+async fn place_sell_order(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    IdempotencyKey(request_id): IdempotencyKey,
+    JsonBody(request): JsonBody<SellOrderRequest>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .place_sell_order(
+                request_id.as_deref(),
+                request.order_type,
+                &request.item_name,
+                request.quantity,
+                request.price,
+                &request.price_item,
+            )
+            .await,
+    )
+}
+
+async fn place_bundle_sell_order(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    IdempotencyKey(request_id): IdempotencyKey,
+    JsonBody(request): JsonBody<BundleSellOrderRequest>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .place_bundle_sell_order(
+                request_id.as_deref(),
+                request.order_type,
+                &request.items,
+                request.price,
+                &request.price_item,
+            )
+            .await,
+    )
+}
+
+async fn execute_immediate_sell_order(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    IdempotencyKey(request_id): IdempotencyKey,
+    PathParam(sell_order_id): PathParam<i64>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .execute_immediate_sell_order(request_id.as_deref(), sell_order_id)
+            .await,
+    )
+}
+
+async fn place_bid(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    IdempotencyKey(request_id): IdempotencyKey,
+    PathParam(sell_order_id): PathParam<i64>,
+    JsonBody(request): JsonBody<BidRequest>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .place_bid(request_id.as_deref(), sell_order_id, request.bid)
+            .await,
+    )
+}
+
+async fn cancel_sell_order(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    IdempotencyKey(request_id): IdempotencyKey,
+    PathParam(sell_order_id): PathParam<i64>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .cancel_sell_order(request_id.as_deref(), sell_order_id)
+            .await,
+    )
+}
+
+async fn view_items(State(storage): State<SharedStorage>, AuthUser(user): AuthUser) -> ApiResponse {
+    ApiResponse(CommandsProcessor::new(user, storage).view_items().await)
+}
+
+async fn view_bids(State(storage): State<SharedStorage>, AuthUser(user): AuthUser) -> ApiResponse {
+    ApiResponse(CommandsProcessor::new(user, storage).view_bids().await)
+}
+
+async fn deposit(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    IdempotencyKey(request_id): IdempotencyKey,
+    JsonBody(request): JsonBody<ItemRequest>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .deposit_item(request_id.as_deref(), &request.item_name, request.quantity)
+            .await,
+    )
+}
+
+async fn withdraw(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    IdempotencyKey(request_id): IdempotencyKey,
+    JsonBody(request): JsonBody<ItemRequest>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .withdraw_item(request_id.as_deref(), &request.item_name, request.quantity)
+            .await,
+    )
+}
+
+async fn view_instances(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+) -> ApiResponse {
+    ApiResponse(CommandsProcessor::new(user, storage).view_instances().await)
+}
+
+async fn deposit_instance(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    IdempotencyKey(request_id): IdempotencyKey,
+    JsonBody(request): JsonBody<InstanceRequest>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .deposit_item_instance(
+                request_id.as_deref(),
+                &request.item_name,
+                &request.attributes,
+            )
+            .await,
+    )
+}
+
+async fn withdraw_instance(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    IdempotencyKey(request_id): IdempotencyKey,
+    PathParam(instance_id): PathParam<i64>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .withdraw_item_instance(request_id.as_deref(), instance_id)
+            .await,
+    )
+}
+
+async fn place_instance_sell_order(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    IdempotencyKey(request_id): IdempotencyKey,
+    PathParam(instance_id): PathParam<i64>,
+    JsonBody(request): JsonBody<InstanceSellOrderRequest>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .place_instance_sell_order(
+                request_id.as_deref(),
+                request.order_type,
+                instance_id,
+                request.price,
+                &request.price_item,
+            )
+            .await,
+    )
+}
+
+async fn give(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    IdempotencyKey(request_id): IdempotencyKey,
+    JsonBody(request): JsonBody<TransferRequest>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .give_item(
+                request_id.as_deref(),
+                &request.recipient,
+                &request.item_name,
+                request.quantity,
+            )
+            .await,
+    )
+}
+
+async fn view_transactions(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .view_transactions()
+            .await,
+    )
+}
+
+async fn view_exchange(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+) -> ApiResponse {
+    ApiResponse(CommandsProcessor::new(user, storage).view_exchange().await)
+}
+
+async fn exchange(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    IdempotencyKey(request_id): IdempotencyKey,
+    JsonBody(request): JsonBody<ExchangeRequest>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .exchange_currency(
+                request_id.as_deref(),
+                request.quantity,
+                &request.from,
+                &request.to,
+            )
+            .await,
+    )
+}
+
+async fn view_watchlist(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+) -> ApiResponse {
+    ApiResponse(CommandsProcessor::new(user, storage).view_watchlist().await)
+}
+
+async fn add_watch(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    IdempotencyKey(request_id): IdempotencyKey,
+    JsonBody(kind): JsonBody<WatchKind>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .add_watch(request_id.as_deref(), &kind)
+            .await,
+    )
+}
+
+async fn remove_watch(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    IdempotencyKey(request_id): IdempotencyKey,
+    PathParam(watch_id): PathParam<i64>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .remove_watch(request_id.as_deref(), watch_id)
+            .await,
+    )
+}
+
+// Events are removed from the mailbox once they are returned
+async fn take_mailbox(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    IdempotencyKey(request_id): IdempotencyKey,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .view_mailbox(request_id.as_deref())
+            .await,
+    )
+}
+
+async fn view_offers(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+) -> ApiResponse {
+    ApiResponse(CommandsProcessor::new(user, storage).view_offers().await)
+}
+
+async fn place_offer(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    IdempotencyKey(request_id): IdempotencyKey,
+    JsonBody(request): JsonBody<OfferRequest>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .place_offer(
+                request_id.as_deref(),
+                &request.recipient,
+                &request.give,
+                &request.want,
+            )
+            .await,
+    )
+}
+
+async fn accept_offer(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    IdempotencyKey(request_id): IdempotencyKey,
+    PathParam(offer_id): PathParam<i64>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .accept_offer(request_id.as_deref(), offer_id)
+            .await,
+    )
+}
+
+async fn decline_offer(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    IdempotencyKey(request_id): IdempotencyKey,
+    PathParam(offer_id): PathParam<i64>,
+) -> ApiResponse {
+    ApiResponse(
+        CommandsProcessor::new(user, storage)
+            .decline_offer(request_id.as_deref(), offer_id)
+            .await,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use pretty_assertions::assert_eq;
+    use tower::ServiceExt;
+
+    async fn send(
+        router: &Router,
+        method: &str,
+        uri: &str,
+        token: Option<&str>,
+        body: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        let headers = token
+            .map(|token| (header::AUTHORIZATION.as_str(), format!("Bearer {token}")))
+            .into_iter()
+            .collect::<Vec<_>>();
+        send_with_headers(router, method, uri, &headers, body).await
+    }
+
+    async fn send_with_headers(
+        router: &Router,
+        method: &str,
+        uri: &str,
+        headers: &[(&str, String)],
+        body: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json");
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        let response = router
+            .clone()
+            .oneshot(request.body(Body::from(body.to_string())).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn api() {
+        let storage = Arc::new(Mutex::new(Storage::open(":memory:").unwrap()));
+        let router = router(storage.clone());
+        let null = serde_json::Value::Null;
+
+        // Unauthorized requests are rejected
+        let (status, body) = send(&router, "GET", "/me/items", None, null.clone()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error_code"], 114);
+        let (status, _) = send(&router, "GET", "/me/items", Some("invalid"), null.clone()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = send(
+            &router,
+            "POST",
+            "/login",
+            None,
+            serde_json::json!({"username": "seller"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let seller_token = body["payload"]["token"].as_str().unwrap().to_owned();
+
+        // Tokens can't be used once the session is ended
+        let (_, body) = send(
+            &router,
+            "POST",
+            "/login",
+            None,
+            serde_json::json!({"username": "seller"}),
+        )
+        .await;
+        let ended_token = body["payload"]["token"].as_str().unwrap().to_owned();
+        let (status, body) =
+            send(&router, "POST", "/logout", Some(&ended_token), null.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["payload"]["type"], "logged_out");
+        for (method, uri) in [("GET", "/me/items"), ("POST", "/logout")] {
+            let (status, body) = send(&router, method, uri, Some(&ended_token), null.clone()).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(body["error_code"], 114);
+        }
+
+        let (status, body) = send(
+            &router,
+            "POST",
+            "/me/deposits",
+            Some(&seller_token),
+            serde_json::json!({"item_name": "funds", "quantity": 10}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["payload"]["type"], "deposited");
+
+        // Items deposited via the HTTP API are visible to the same user logged in via TCP
+        let seller = storage.lock().await.login("seller").unwrap();
+        assert!(storage.lock().await.deposit(seller.id, "Sword", 1).is_ok());
+        let (_, body) = send(
+            &router,
+            "GET",
+            "/me/items",
+            Some(&seller_token),
+            null.clone(),
+        )
+        .await;
+        assert_eq!(
+            body["payload"]["items"],
+            serde_json::json!([
+                {"name": "funds", "quantity": 10, "in_bids": 0, "listed": 0},
+                {"name": "Sword", "quantity": 1, "in_bids": 0, "listed": 0}
+            ])
+        );
+
+        let (status, body) = send(
+            &router,
+            "POST",
+            "/orders",
+            Some(&seller_token),
+            serde_json::json!({"order_type": "auction", "item_name": "Sword", "price": 20}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["payload"]["sell_order_id"], 1);
+
+        // Malformed body
+        let (status, body) = send(
+            &router,
+            "POST",
+            "/orders",
+            Some(&seller_token),
+            serde_json::json!({"item_name": "Sword"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error_code"], 1);
+
+        // Malformed path
+        let (status, body) = send(
+            &router,
+            "DELETE",
+            "/orders/abc",
+            Some(&seller_token),
+            null.clone(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["ok"], false);
+        assert_eq!(body["error_code"], 1);
+
+        let (_, body) = send(
+            &router,
+            "POST",
+            "/login",
+            None,
+            serde_json::json!({"username": "buyer"}),
+        )
+        .await;
+        let buyer_token = body["payload"]["token"].as_str().unwrap().to_owned();
+
+        let (status, body) = send(
+            &router,
+            "POST",
+            "/orders/1/bids",
+            Some(&buyer_token),
+            serde_json::json!({"bid": 30}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error_code"], 106);
+
+        let (status, body) =
+            send(&router, "GET", "/me/bids", Some(&buyer_token), null.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["payload"]["type"], "bids");
+        assert_eq!(body["payload"]["sell_orders"], serde_json::json!([]));
+
+        let (status, body) = send(
+            &router,
+            "POST",
+            "/orders/2/buy",
+            Some(&buyer_token),
+            null.clone(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error_code"], 109);
+
+        let (status, body) =
+            send(&router, "GET", "/orders", Some(&buyer_token), null.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["payload"]["sell_orders"][0]["seller_name"], "seller");
+
+        let (status, body) = send(
+            &router,
+            "POST",
+            "/me/transfers",
+            Some(&seller_token),
+            serde_json::json!({"recipient": "nobody", "item_name": "funds"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error_code"], 117);
+
+        let (status, body) = send(
+            &router,
+            "POST",
+            "/me/transfers",
+            Some(&seller_token),
+            serde_json::json!({"recipient": "buyer", "item_name": "funds", "quantity": 5}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["payload"]["type"], "items_given");
+
+        let (_, body) = send(&router, "GET", "/me/transactions", Some(&buyer_token), null).await;
+        assert_eq!(body["payload"]["transactions"][0]["sender"], "seller");
+        assert_eq!(body["payload"]["transactions"][0]["quantity"], 5);
+    }
+
+    #[tokio::test]
+    async fn idempotency_key() {
+        let storage = Arc::new(Mutex::new(Storage::open(":memory:").unwrap()));
+        let router = router(storage.clone());
+        let (_, body) = send(
+            &router,
+            "POST",
+            "/login",
+            None,
+            serde_json::json!({"username": "seller"}),
+        )
+        .await;
+        let token = body["payload"]["token"].as_str().unwrap().to_owned();
+        let headers = |key: &str| {
+            vec![
+                ("authorization", format!("Bearer {token}")),
+                ("idempotency-key", key.to_owned()),
+            ]
+        };
+        let deposit = serde_json::json!({"item_name": "funds", "quantity": 100});
+        let withdraw = serde_json::json!({"item_name": "funds", "quantity": 500});
+
+        // a retried request is applied once and gets the original result
+        for _ in 0..2 {
+            let (status, body) = send_with_headers(
+                &router,
+                "POST",
+                "/me/deposits",
+                &headers("deposit-1"),
+                deposit.clone(),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["payload"]["type"], "deposited");
+        }
+        let (_, body) = send(
+            &router,
+            "GET",
+            "/me/items",
+            Some(&token),
+            serde_json::Value::Null,
+        )
+        .await;
+        assert_eq!(body["payload"]["items"][0]["quantity"], 100);
+
+        // failures are replayed with the same status, even if the request would succeed now
+        let (status, failed) = send_with_headers(
+            &router,
+            "POST",
+            "/me/withdrawals",
+            &headers("withdraw-1"),
+            withdraw.clone(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(send_with_headers(
+            &router,
+            "POST",
+            "/me/deposits",
+            &headers("deposit-2"),
+            deposit.clone(),
+        )
+        .await
+        .0
+        .is_success());
+        for _ in 0..4 {
+            send(
+                &router,
+                "POST",
+                "/me/deposits",
+                Some(&token),
+                deposit.clone(),
+            )
+            .await;
+        }
+        assert_eq!(
+            send_with_headers(
+                &router,
+                "POST",
+                "/me/withdrawals",
+                &headers("withdraw-1"),
+                withdraw.clone(),
+            )
+            .await,
+            (StatusCode::UNPROCESSABLE_ENTITY, failed)
+        );
+
+        let (status, body) = send_with_headers(
+            &router,
+            "POST",
+            "/me/deposits",
+            &headers("bad key"),
+            deposit,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["error"],
+            "Request id 'bad key' may contain only letters, digits, '-' and '_'"
+        );
+    }
+}
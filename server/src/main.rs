@@ -3,38 +3,25 @@ use std::sync::Arc;
 use anyhow::{anyhow, Context, Result};
 use clap::Parser;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncWriteExt, BufReader, ReadHalf, WriteHalf},
     net::{TcpListener, TcpStream},
     sync::Mutex,
 };
 
+use protocol::{
+    codec::{self, LineReader},
+    ExchangeRate,
+};
 use storage::Storage;
 
+mod catalog;
 mod commands;
+mod http;
 mod storage;
+mod websocket;
 
-// TcpStream reader half wrapper with buffer
-struct TcpReader {
-    stream: tokio::io::ReadHalf<TcpStream>,
-    buf: [u8; 256],
-}
-
-impl TcpReader {
-    fn new(stream_reader: tokio::io::ReadHalf<TcpStream>) -> Self {
-        Self {
-            stream: stream_reader,
-            buf: [0; 256],
-        }
-    }
-
-    async fn read(&mut self) -> Result<&[u8]> {
-        let read_bytes = self.stream.read(&mut self.buf).await?;
-        if read_bytes == 0 {
-            return Err(anyhow!("Client disconnected"));
-        }
-        Ok(&self.buf[..read_bytes])
-    }
-}
+type TcpReader = LineReader<BufReader<ReadHalf<TcpStream>>>;
+type TcpWriter = WriteHalf<TcpStream>;
 
 #[derive(Parser)]
 #[command(about)]
@@ -46,18 +33,71 @@ struct Cli {
     /// Path to the database file. Example: db.sqlite
     #[arg(short, long)]
     db: String,
+
+    /// Port to serve the HTTP/JSON API on. The API is disabled if not specified
+    #[arg(long)]
+    http_port: Option<u16>,
+
+    /// Maximum quantity of every item a user can give to other users within 24 hours.
+    /// Unlimited if not specified
+    #[arg(long, value_name = "quantity")]
+    daily_give_limit: Option<i64>,
+
+    /// Item that is used as a currency in addition to funds: orders can be priced in it and
+    /// the fee is taken in it. Can be repeated
+    #[arg(long = "currency", value_name = "name")]
+    currencies: Vec<String>,
+
+    /// Rate of exchanging one currency into another. Can be repeated.
+    /// Example: gem/gold=100 - 1 gem is exchanged for 100 gold
+    #[arg(long = "exchange-rate", value_name = "from/to=rate")]
+    exchange_rates: Vec<ExchangeRate>,
+
+    /// Path to the TOML file with the catalog of item types. Example: catalog.toml
+    #[arg(long, value_name = "path")]
+    catalog: Option<String>,
+
+    /// Rejects deposits of items that are not in the catalog
+    #[arg(long, requires = "catalog")]
+    strict_catalog: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    let storage = Arc::new(Mutex::new(Storage::open(&cli.db)?));
+    let mut storage = Storage::open(&cli.db)?;
+    storage.set_daily_give_limit(cli.daily_give_limit);
+    storage
+        .set_currencies(&cli.currencies)
+        .context("Failed to set currencies")?;
+    storage
+        .set_exchange_rates(cli.exchange_rates)
+        .context("Failed to set exchange rates")?;
+    let catalog = match &cli.catalog {
+        Some(path) => catalog::load(path)?,
+        None => Vec::new(),
+    };
+    storage
+        .set_catalog(&catalog, cli.strict_catalog)
+        .context("Failed to set the catalog")?;
+    let storage = Arc::new(Mutex::new(storage));
 
     let listener = TcpListener::bind(("localhost", cli.port)).await?;
     println!("Listening on port {}", cli.port);
 
-    // launch a periodic task to process sell orders
+    if let Some(http_port) = cli.http_port {
+        let http_listener = TcpListener::bind(("localhost", http_port)).await?;
+        println!("Serving HTTP API on port {http_port}");
+        let router = http::router(storage.clone());
+        tokio::spawn(async move {
+            if let Err(err) = axum::serve(http_listener, router).await {
+                println!("HTTP API server failed: {err:#}");
+            }
+        });
+    }
+
+    // launch a periodic task to process expired sell orders and offers
     let storage_clone = storage.clone();
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
@@ -76,6 +116,16 @@ async fn main() -> Result<()> {
             {
                 println!("Failed to process sell orders at {unix_now} unix time: {err:#}");
             }
+            if let Err(err) = storage_clone.lock().await.process_expired_offers(unix_now) {
+                println!("Failed to process offers at {unix_now} unix time: {err:#}");
+            }
+            if let Err(err) = storage_clone
+                .lock()
+                .await
+                .process_expired_sessions(unix_now)
+            {
+                println!("Failed to process sessions at {unix_now} unix time: {err:#}");
+            }
         }
     });
 
@@ -85,7 +135,7 @@ async fn main() -> Result<()> {
 
         tokio::spawn(async move {
             let (tcp_reader, mut tcp_writer) = tokio::io::split(socket);
-            let mut tcp_reader = TcpReader::new(tcp_reader);
+            let mut tcp_reader = LineReader::new(BufReader::new(tcp_reader));
 
             let user = match process_client_login(&mut tcp_reader, &mut tcp_writer, &storage).await
             {
@@ -99,24 +149,31 @@ async fn main() -> Result<()> {
                 }
             };
 
-            let processor = commands::CommandsProcessor::new(user.clone(), storage);
+            let mut processor = commands::CommandsProcessor::new(user.clone(), storage.clone());
 
             loop {
-                let request = match tcp_reader.read().await {
-                    Ok(request) => request,
-                    _ => {
-                        println!("Connection with {user:?} closed by client");
-                        break;
+                // Personal events and market events the session is subscribed to are pushed in between
+                // responses
+                let response = tokio::select! {
+                    request = tcp_reader.read_line() => {
+                        let response = match request {
+                            Ok(Some(request)) => match std::str::from_utf8(request) {
+                                Err(err) => Err(anyhow!("{request:?} is not a valid utf8 string: {err}")),
+                                Ok(request) => processor.process_request(request).await,
+                            },
+                            Ok(None) | Err(codec::ReadError::Io(_)) => {
+                                println!("Connection with {user:?} closed by client");
+                                break;
+                            }
+                            // Too long line is skipped, the next one can be read
+                            Err(err) => Err(err.into()),
+                        };
+                        processor.encode(response)
                     }
+                    event = processor.next_event() => event,
                 };
 
-                let response = match std::str::from_utf8(request) {
-                    Err(err) => Err(anyhow!("{request:?} is not a valid utf8 string: {err}")),
-                    Ok(request) => processor.process_request(request).await,
-                }
-                .unwrap_or_else(|err| format!("Failed to process request: {err:#}"));
-
-                if tcp_writer.write(response.as_bytes()).await.is_err() {
+                if write_response(&mut tcp_writer, &response).await.is_err() {
                     println!("Connection with {user:?} closed by client");
                     break;
                 }
@@ -138,27 +195,39 @@ async fn try_login(storage: &Mutex<Storage>, username: &[u8]) -> Result<storage:
 
 async fn process_client_login(
     tcp_reader: &mut TcpReader,
-    tcp_writer: &mut tokio::io::WriteHalf<TcpStream>,
+    tcp_writer: &mut TcpWriter,
     storage: &Mutex<Storage>,
 ) -> Result<storage::User> {
-    tcp_writer
-        .write(b"Welcome to Sundris Auction House, stranger! How can I call you?")
-        .await?;
+    write_response(tcp_writer, protocol::GREETING).await?;
 
-    let response = tcp_reader.read().await?;
+    let response = tcp_reader
+        .read_line()
+        .await?
+        .ok_or(anyhow!("Client disconnected"))?;
     match try_login(storage, response).await {
         Ok(user) => {
-            tcp_writer
-                .write(format!("Successfully logged in as {}", user.username).as_bytes())
-                .await?;
+            write_response(
+                tcp_writer,
+                &protocol::encode_login_result(Ok(&user.username)),
+            )
+            .await?;
             Ok(user)
         }
         Err(err) => {
             // ignore write errors, we're already in a bad state
-            let _ = tcp_writer
-                .write(format!("Failed to login: {err:#}").as_bytes())
-                .await;
+            let _ = write_response(
+                tcp_writer,
+                &protocol::encode_login_result(Err(&format!("{err:#}"))),
+            )
+            .await;
             Err(err)
         }
     }
 }
+
+async fn write_response(tcp_writer: &mut TcpWriter, response: &str) -> Result<()> {
+    tcp_writer
+        .write_all(codec::encode_frame(response).as_bytes())
+        .await?;
+    Ok(())
+}
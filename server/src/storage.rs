@@ -1,6 +1,145 @@
-use std::fmt::{Display, Formatter};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+
+use protocol::{
+    CatalogItem, ErrorCode, ExchangeRate, Item, ItemInstance, ItemStats, MarketEvent, Offer,
+    SellOrder, SellOrderType, Trade, Transaction, TransactionKind, Watch, WatchKind,
+};
+use tokio::sync::{broadcast, mpsc};
+use unicode_normalization::UnicodeNormalization;
+
+pub(crate) type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors returned by the storage. Every error has a stable numeric code, exposed to clients
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum Error {
+    #[error("Username cannot be empty")]
+    EmptyUsername,
+    #[error("Item name cannot be empty")]
+    EmptyItemName,
+    #[error("Quantity must be positive")]
+    InvalidQuantity,
+    #[error("Price must be positive")]
+    InvalidPrice,
+    #[error("No such item '{item_name}'{}", did_you_mean(.suggestions))]
+    ItemNotFound {
+        item_name: String,
+        suggestions: Vec<String>,
+    },
+    #[error("Not enough funds: {required} required, but only {available} available")]
+    InsufficientFunds { required: i64, available: i64 },
+    #[error("Not enough {item_name}(s): {required} required, but only {available} available")]
+    InsufficientItems {
+        item_name: String,
+        required: i64,
+        available: i64,
+    },
+    #[error("Cannot sell funds for funds, it's a speculation!")]
+    FundsNotTradable,
+    #[error("Cannot sell {0}(s) for {0}(s)")]
+    SamePriceItem(String),
+    #[error("Sell order #{0} doesn't exist")]
+    OrderNotFound(i64),
+    #[error("Order #{0} is not an immediate order")]
+    NotImmediateOrder(i64),
+    #[error("Order #{0} is not an auction order")]
+    NotAuctionOrder(i64),
+    #[error("You can't buy your own items")]
+    OwnOrder,
+    #[error("Bid must be higher than the current price of {current_price} {price_item}(s)")]
+    BidTooLow {
+        current_price: i64,
+        price_item: String,
+    },
+    #[error("Invalid or expired session token")]
+    InvalidToken,
+    #[error("Sell order #{0} is placed by another user")]
+    NotOwnOrder(i64),
+    #[error("Sell order #{0} already has a bid")]
+    OrderHasBid(i64),
+    #[error("User '{0}' doesn't exist")]
+    UserNotFound(String),
+    #[error("You can't give items to yourself")]
+    SelfTransfer,
+    #[error(
+        "Daily limit of {limit} {item_name}(s) to give is exceeded, only {remaining} more can be given today"
+    )]
+    DailyLimitExceeded {
+        item_name: String,
+        limit: i64,
+        remaining: i64,
+    },
+    #[error("Offer must give and want at least one item")]
+    EmptyOffer,
+    #[error("Offer #{0} doesn't exist")]
+    OfferNotFound(i64),
+    #[error("Offer #{0} is addressed to another user")]
+    NotOfferRecipient(i64),
+    #[error("'{0}' is not a currency")]
+    NotCurrency(String),
+    #[error("Exchange of {from} to {to} is not listed")]
+    ExchangeNotListed { from: String, to: String },
+    #[error("Item '{0}' is not in the catalog")]
+    UnknownItem(String),
+    #[error("At most {max_stack} {item_name}(s) can be held by a user")]
+    StackLimitExceeded { item_name: String, max_stack: i64 },
+    #[error("{0}(s) can't be traded")]
+    NotTradeable(String),
+    #[error("Alias '{alias}' is already used by item '{item_name}'")]
+    AliasTaken { alias: String, item_name: String },
+    #[error("Item instance #{0} doesn't exist")]
+    InstanceNotFound(i64),
+    #[error("Item attributes must be a JSON object")]
+    InvalidAttributes,
+    #[error("Watch #{0} doesn't exist")]
+    WatchNotFound(i64),
+    #[error("Bundle must contain at least one item")]
+    EmptyBundle,
+    #[error("Database error: {0}")]
+    Database(#[from] rusqlite::Error),
+}
 
-use anyhow::Result;
+impl Error {
+    /// Code of the error reported to clients, see `protocol::ErrorCode`
+    pub(crate) fn code(&self) -> ErrorCode {
+        match self {
+            Self::Database(_) => ErrorCode::Database,
+            Self::EmptyUsername => ErrorCode::EmptyUsername,
+            Self::EmptyItemName => ErrorCode::EmptyItemName,
+            Self::InvalidQuantity => ErrorCode::InvalidQuantity,
+            Self::InvalidPrice => ErrorCode::InvalidPrice,
+            Self::ItemNotFound { .. } => ErrorCode::ItemNotFound,
+            Self::InsufficientFunds { .. } => ErrorCode::InsufficientFunds,
+            Self::InsufficientItems { .. } => ErrorCode::InsufficientItems,
+            Self::FundsNotTradable => ErrorCode::FundsNotTradable,
+            Self::SamePriceItem(_) => ErrorCode::SamePriceItem,
+            Self::NotCurrency(_) => ErrorCode::NotCurrency,
+            Self::ExchangeNotListed { .. } => ErrorCode::ExchangeNotListed,
+            Self::UnknownItem(_) => ErrorCode::UnknownItem,
+            Self::StackLimitExceeded { .. } => ErrorCode::StackLimitExceeded,
+            Self::NotTradeable(_) => ErrorCode::NotTradeable,
+            Self::AliasTaken { .. } => ErrorCode::AliasTaken,
+            Self::InstanceNotFound(_) => ErrorCode::InstanceNotFound,
+            Self::InvalidAttributes => ErrorCode::InvalidAttributes,
+            Self::WatchNotFound(_) => ErrorCode::WatchNotFound,
+            Self::OrderNotFound(_) => ErrorCode::OrderNotFound,
+            Self::NotImmediateOrder(_) => ErrorCode::NotImmediateOrder,
+            Self::NotAuctionOrder(_) => ErrorCode::NotAuctionOrder,
+            Self::OwnOrder => ErrorCode::OwnOrder,
+            Self::BidTooLow { .. } => ErrorCode::BidTooLow,
+            Self::InvalidToken => ErrorCode::InvalidToken,
+            Self::NotOwnOrder(_) => ErrorCode::NotOwnOrder,
+            Self::OrderHasBid(_) => ErrorCode::OrderHasBid,
+            Self::UserNotFound(_) => ErrorCode::UserNotFound,
+            Self::SelfTransfer => ErrorCode::SelfTransfer,
+            Self::DailyLimitExceeded { .. } => ErrorCode::DailyLimitExceeded,
+            Self::EmptyOffer => ErrorCode::EmptyOffer,
+            Self::EmptyBundle => ErrorCode::EmptyBundle,
+            Self::OfferNotFound(_) => ErrorCode::OfferNotFound,
+            Self::NotOfferRecipient(_) => ErrorCode::NotOfferRecipient,
+        }
+    }
+}
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub(crate) struct UserId(i64);
@@ -11,49 +150,13 @@ pub(crate) struct User {
     pub(crate) username: String,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub(crate) enum SellOrderType {
-    // Order will be immediately executed if there is a matching buy order
-    Immediate,
-    // Order will be executed only after the auction is over
-    Auction,
-}
-
-impl Display for SellOrderType {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Immediate => write!(f, "immediate"),
-            Self::Auction => write!(f, "auction"),
-        }
-    }
-}
-
-impl SellOrderType {
-    pub(crate) fn from_str(s: &str) -> Option<Self> {
-        match s {
-            "immediate" => Some(Self::Immediate),
-            "auction" => Some(Self::Auction),
-            _ => None,
-        }
-    }
-}
-
-#[derive(Debug, PartialEq)]
-pub(crate) struct SellOrder {
-    pub(crate) id: i64,
-    pub(crate) seller_name: String,
-    pub(crate) item_name: String,
-    pub(crate) quantity: i64,
-    pub(crate) price: i64,
-    pub(crate) expiration_time: String,
-    pub(crate) order_type: SellOrderType,
-}
-
 struct SellOrderEntry {
     seller_id: UserId,
-    item_id: i64,
-    quantity: i64,
+    // (item_id, quantity) pairs held by the order, a single line for item instances
+    lines: Vec<(i64, i64)>,
     price: i64,
+    price_item_id: i64,
+    instance_id: Option<i64>,
     buyer_id: Option<UserId>,
 }
 
@@ -67,13 +170,95 @@ impl SellOrderEntry {
     }
 }
 
+// Catalog rules of an item type. Items not in the catalog have no limits
+struct ItemRules {
+    name: String,
+    is_currency: bool,
+    in_catalog: bool,
+    max_stack: Option<i64>,
+    tradeable: bool,
+}
+
+struct OfferEntry {
+    sender_id: UserId,
+    recipient_id: UserId,
+    // (item_id, quantity) pairs
+    give: Vec<(i64, i64)>,
+    want: Vec<(i64, i64)>,
+    expiration_time: i64,
+}
+
+struct WatchEntry {
+    user_id: UserId,
+    username: String,
+    watch: Watch,
+}
+
+// Results of requests with ids are kept for a day, retries are expected to happen much sooner
+const REQUEST_RESULT_LIFETIME_SECONDS: i64 = 24 * 60 * 60;
+
+// Daily limits are applied to a sliding window rather than to a calendar day
+const DAY_SECONDS: i64 = 24 * 60 * 60;
+
 pub(crate) struct Storage {
     db: rusqlite::Connection,
     funds_item_id: i64,
+    // Market events, broadcast to all sessions
+    events: broadcast::Sender<MarketEvent>,
+    // Number of open savepoints. Events are delivered once the outermost savepoint is committed
+    savepoint_depth: Cell<usize>,
+    // Events with the user they are addressed to, if they are personal
+    pending_events: RefCell<Vec<(Option<UserId>, MarketEvent)>>,
+    // Personal events of every user are sent to each of the user's connected sessions. Users
+    // without connected sessions are offline, their personal events go to their mailbox
+    sessions: RefCell<HashMap<i64, Vec<mpsc::UnboundedSender<MarketEvent>>>>,
+    // Maximum quantity of every item a user can give to others within a day, unlimited if not set
+    daily_give_limit: Option<i64>,
+    // Exchange listing configured by the admin, every currency is flagged in the items table
+    exchange_rates: Vec<ExchangeRate>,
+    // Only items from the catalog and currencies can be deposited
+    strict_catalog: bool,
+}
+
+/// Changes made after the savepoint is created are rolled back, unless it's committed.
+/// Savepoints can be nested, changes are persisted once the outermost one is committed
+#[must_use]
+pub(crate) struct Savepoint<'a> {
+    storage: &'a Storage,
+    // Events notified within the savepoint are dropped on rollback
+    pending_events: usize,
+    committed: bool,
+}
+
+impl Savepoint<'_> {
+    pub(crate) fn commit(mut self) -> Result<()> {
+        self.storage.db.execute_batch("RELEASE storage")?;
+        self.committed = true;
+        self.storage.close_savepoint();
+        Ok(())
+    }
+}
+
+impl Drop for Savepoint<'_> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        // Fails only if the transaction is already rolled back by SQLite, e.g. due to I/O errors
+        let _ = self
+            .storage
+            .db
+            .execute_batch("ROLLBACK TO storage; RELEASE storage");
+        self.storage
+            .pending_events
+            .borrow_mut()
+            .truncate(self.pending_events);
+        self.storage.close_savepoint();
+    }
 }
 
 impl Storage {
-    pub(crate) fn open(path: &str) -> Result<Self> {
+    pub(crate) fn open(path: &str) -> anyhow::Result<Self> {
         let db = rusqlite::Connection::open(path)?;
 
         // Enable Write-Ahead Logging (WAL) mode for better performance and to enable concurrent reads and writes.
@@ -108,16 +293,72 @@ impl Storage {
             (),
         )?;
 
+        // normalized_name - key the item is looked up by, see `normalize_item_name`
+        // is_currency - 1 for items that orders can be priced in with a percentage fee
+        // in_catalog - 1 for items defined by the admin in the catalog, the rest of columns
+        // are the catalog metadata
         db.execute(
             "CREATE TABLE IF NOT EXISTS items (
                 id INTEGER PRIMARY KEY,
-                name TEXT NOT NULL UNIQUE
+                name TEXT NOT NULL UNIQUE,
+                normalized_name TEXT NOT NULL DEFAULT '',
+                is_currency INTEGER NOT NULL DEFAULT 0,
+                in_catalog INTEGER NOT NULL DEFAULT 0,
+                display_name TEXT,
+                category TEXT,
+                description TEXT NOT NULL DEFAULT '',
+                max_stack INTEGER,
+                tradeable INTEGER NOT NULL DEFAULT 1
+            ) STRICT",
+            (),
+        )?;
+        // Databases created before currencies were introduced have funds as the only currency
+        add_column_if_missing(&db, "items", "is_currency", "INTEGER NOT NULL DEFAULT 0")?;
+        // Databases created before the catalog was introduced have no items in it
+        add_column_if_missing(&db, "items", "in_catalog", "INTEGER NOT NULL DEFAULT 0")?;
+        add_column_if_missing(&db, "items", "display_name", "TEXT")?;
+        add_column_if_missing(&db, "items", "category", "TEXT")?;
+        add_column_if_missing(&db, "items", "description", "TEXT NOT NULL DEFAULT ''")?;
+        add_column_if_missing(&db, "items", "max_stack", "INTEGER")?;
+        add_column_if_missing(&db, "items", "tradeable", "INTEGER NOT NULL DEFAULT 1")?;
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS items_category ON items (category)",
+            (),
+        )?;
+        // Databases created before names were normalized can have several items with the same
+        // normalized name, they are merged by `merge_duplicate_items` once all tables exist
+        add_column_if_missing(&db, "items", "normalized_name", "TEXT NOT NULL DEFAULT ''")?;
+        {
+            let mut select = db.prepare("SELECT id, name FROM items WHERE normalized_name = ''")?;
+            let mut update = db.prepare("UPDATE items SET normalized_name = ?2 WHERE id = ?1")?;
+            let items = select
+                .query_map([], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            for (item_id, name) in items {
+                update.execute((item_id, normalize_item_name(&name)))?;
+            }
+        }
+
+        // Alternative names of items defined by the admin in the catalog
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS item_aliases (
+                id INTEGER PRIMARY KEY,
+                item_id INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                normalized_name TEXT NOT NULL UNIQUE,
+                FOREIGN KEY (item_id) REFERENCES items (id)
             ) STRICT",
             (),
         )?;
 
         // We store balance in funds as a separate item to simplify the code
-        db.execute("INSERT OR IGNORE INTO items (name) VALUES ('funds')", ())?;
+        db.execute(
+            "INSERT INTO items (name, normalized_name, is_currency) VALUES ('funds', 'funds', 1)
+            ON CONFLICT (name) DO UPDATE SET is_currency = 1",
+            (),
+        )?;
         let funds_item_id =
             db.query_row("SELECT id FROM items WHERE name = 'funds'", [], |row| {
                 row.get(0)
@@ -135,7 +376,28 @@ impl Storage {
             (),
         )?;
 
+        // Unique items that are never merged with others of the same item, e.g. a sword with durability.
+        // owner_id - NULL while the instance is held by a sell order
+        // attributes - JSON object defined by the game
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS item_instances (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                item_id INTEGER NOT NULL,
+                owner_id INTEGER,
+                attributes TEXT NOT NULL CHECK(json_valid(attributes)),
+                FOREIGN KEY (item_id) REFERENCES items (id),
+                FOREIGN KEY (owner_id) REFERENCES users (id)
+            ) STRICT",
+            (),
+        )?;
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS item_instances_owner_id ON item_instances (owner_id, item_id)",
+            (),
+        )?;
+
         // expiration_time - Unix timestamp in seconds
+        // price_item_id - item the price is paid in, funds unless it's a barter order
+        // instance_id - item instance sold by the order, quantity of such orders is 1
         // buyer_id stores either NULL or user_id:
         // - equal to the seller_id for immediate orders
         // - NULL for aution orders without bid
@@ -147,23 +409,408 @@ impl Storage {
                 item_id INTEGER NOT NULL,
                 quantity INTEGER NOT NULL CHECK(quantity > 0),
                 price INTEGER NOT NULL CHECK(price > 0),
+                price_item_id INTEGER NOT NULL,
+                instance_id INTEGER,
                 expiration_time INTEGER NOT NULL,
                 buyer_id INTEGER,
                 FOREIGN KEY (seller_id) REFERENCES users (id),
                 FOREIGN KEY (buyer_id) REFERENCES users (id),
-                FOREIGN KEY (item_id) REFERENCES items (id)
+                FOREIGN KEY (item_id) REFERENCES items (id),
+                FOREIGN KEY (price_item_id) REFERENCES items (id),
+                FOREIGN KEY (instance_id) REFERENCES item_instances (id)
             ) STRICT",
             (),
         )?;
+        // Databases created before barter orders were introduced have all orders priced in funds.
+        // SQLite can't add a column referencing another table with a non-NULL default
+        add_column_if_missing(
+            &db,
+            "sell_orders",
+            "price_item_id",
+            &format!("INTEGER NOT NULL DEFAULT {funds_item_id}"),
+        )?;
+        add_column_if_missing(
+            &db,
+            "sell_orders",
+            "instance_id",
+            "INTEGER REFERENCES item_instances (id)",
+        )?;
         // Speed up filtering by expiration_time
         db.execute("CREATE INDEX IF NOT EXISTS sell_orders_expiration_time ON sell_orders (expiration_time)", ())?;
 
-        Ok(Self { db, funds_item_id })
+        // Items held by sell orders, several lines for bundles. The first line is also stored in the
+        // sell_orders table. Orders of item instances have no lines, the instance is held instead
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS sell_order_items (
+                sell_order_id INTEGER NOT NULL,
+                item_id INTEGER NOT NULL,
+                quantity INTEGER NOT NULL CHECK(quantity > 0),
+                FOREIGN KEY (sell_order_id) REFERENCES sell_orders (id),
+                FOREIGN KEY (item_id) REFERENCES items (id),
+                PRIMARY KEY (sell_order_id, item_id)
+            ) STRICT",
+            (),
+        )?;
+        // Orders placed before bundles were introduced hold a single line
+        db.execute(
+            "INSERT INTO sell_order_items (sell_order_id, item_id, quantity)
+            SELECT id, item_id, quantity FROM sell_orders
+            WHERE instance_id IS NULL
+              AND id NOT IN (SELECT sell_order_id FROM sell_order_items)",
+            (),
+        )?;
+
+        // Session tokens used to authenticate HTTP API requests
+        // expiration_time - Unix timestamp in seconds
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                token TEXT PRIMARY KEY,
+                user_id INTEGER NOT NULL,
+                expiration_time INTEGER NOT NULL DEFAULT 0,
+                FOREIGN KEY (user_id) REFERENCES users (id)
+            ) STRICT",
+            (),
+        )?;
+        // Sessions created before tokens expired are expired, their users log in again
+        add_column_if_missing(
+            &db,
+            "sessions",
+            "expiration_time",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+
+        // Results of state changing requests sent with ids, to apply every such request only once.
+        // created_at - Unix timestamp in seconds
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS request_results (
+                user_id INTEGER NOT NULL,
+                request_id TEXT NOT NULL,
+                result TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                FOREIGN KEY (user_id) REFERENCES users (id),
+                PRIMARY KEY (user_id, request_id)
+            ) STRICT",
+            (),
+        )?;
+        // Speed up removal of old results
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS request_results_created_at ON request_results (created_at)",
+            (),
+        )?;
+
+        // History of transactions between users.
+        // kind - 'give' for items given by the sender to the recipient, 'trade' for items exchanged by an offer
+        // created_at - Unix timestamp in seconds
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS transactions (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                kind TEXT NOT NULL,
+                sender_id INTEGER NOT NULL,
+                recipient_id INTEGER NOT NULL,
+                item_id INTEGER NOT NULL,
+                quantity INTEGER NOT NULL CHECK(quantity > 0),
+                created_at INTEGER NOT NULL,
+                FOREIGN KEY (sender_id) REFERENCES users (id),
+                FOREIGN KEY (recipient_id) REFERENCES users (id),
+                FOREIGN KEY (item_id) REFERENCES items (id)
+            ) STRICT",
+            (),
+        )?;
+        // Speed up daily limit checks
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS transactions_sender_id ON transactions (sender_id, created_at)",
+            (),
+        )?;
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS transactions_recipient_id ON transactions (recipient_id)",
+            (),
+        )?;
+
+        // Price history of settled sell orders
+        // price - total price of `quantity` items, paid in `price_item_id`
+        // bundle - set for every item line of a bundle, with the price of the whole bundle. Such trades
+        //   are left out of per-item stats and VWAP, as their price isn't per item
+        // created_at - Unix timestamp in seconds
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS trades (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                item_id INTEGER NOT NULL,
+                quantity INTEGER NOT NULL CHECK(quantity > 0),
+                price INTEGER NOT NULL CHECK(price > 0),
+                price_item_id INTEGER NOT NULL,
+                bundle INTEGER NOT NULL DEFAULT 0,
+                created_at INTEGER NOT NULL,
+                FOREIGN KEY (item_id) REFERENCES items (id),
+                FOREIGN KEY (price_item_id) REFERENCES items (id)
+            ) STRICT",
+            (),
+        )?;
+        // Databases created before bundle settlements were recorded have no bundle trades
+        add_column_if_missing(&db, "trades", "bundle", "INTEGER NOT NULL DEFAULT 0")?;
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS trades_item_id ON trades (item_id, created_at)",
+            (),
+        )?;
+
+        // Items offered by the sender are held in the offer until it's accepted, declined or expired.
+        // expiration_time - Unix timestamp in seconds
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS offers (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                sender_id INTEGER NOT NULL,
+                recipient_id INTEGER NOT NULL,
+                expiration_time INTEGER NOT NULL,
+                FOREIGN KEY (sender_id) REFERENCES users (id),
+                FOREIGN KEY (recipient_id) REFERENCES users (id)
+            ) STRICT",
+            (),
+        )?;
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS offers_expiration_time ON offers (expiration_time)",
+            (),
+        )?;
+        // side - 'give' for items the sender gives, 'want' for items the sender wants in exchange
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS offer_items (
+                offer_id INTEGER NOT NULL,
+                side TEXT NOT NULL CHECK(side IN ('give', 'want')),
+                item_id INTEGER NOT NULL,
+                quantity INTEGER NOT NULL CHECK(quantity > 0),
+                FOREIGN KEY (offer_id) REFERENCES offers (id),
+                FOREIGN KEY (item_id) REFERENCES items (id),
+                PRIMARY KEY (offer_id, side, item_id)
+            ) STRICT",
+            (),
+        )?;
+
+        // Sell orders users are notified about once they are placed, see `protocol::WatchKind`
+        // item_id, price - orders of the item priced in funds below the price per item
+        // seller_id - all orders of the seller
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS watches (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER NOT NULL,
+                item_id INTEGER,
+                price INTEGER CHECK(price > 0),
+                seller_id INTEGER,
+                FOREIGN KEY (user_id) REFERENCES users (id),
+                FOREIGN KEY (item_id) REFERENCES items (id),
+                FOREIGN KEY (seller_id) REFERENCES users (id),
+                CHECK((item_id IS NULL) != (seller_id IS NULL))
+            ) STRICT",
+            (),
+        )?;
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS watches_item_id ON watches (item_id)",
+            (),
+        )?;
+        db.execute(
+            "CREATE INDEX IF NOT EXISTS watches_seller_id ON watches (seller_id)",
+            (),
+        )?;
+
+        // Personal events addressed to users while they were offline, as JSON
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS mailbox (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER NOT NULL,
+                event TEXT NOT NULL CHECK(json_valid(event)),
+                FOREIGN KEY (user_id) REFERENCES users (id)
+            ) STRICT",
+            (),
+        )?;
+
+        merge_duplicate_items(&db)?;
+        // Replaces the index created while duplicates were still possible
+        db.execute("DROP INDEX IF EXISTS items_normalized_name", ())?;
+        db.execute(
+            "CREATE UNIQUE INDEX IF NOT EXISTS items_normalized_name_key ON items (normalized_name)",
+            (),
+        )?;
+
+        // Slow subscribers will miss market events once they lag behind by this many events
+        let (events, _) = broadcast::channel(1024);
+
+        Ok(Self {
+            db,
+            funds_item_id,
+            events,
+            savepoint_depth: Cell::new(0),
+            pending_events: RefCell::new(Vec::new()),
+            sessions: RefCell::new(HashMap::new()),
+            daily_give_limit: None,
+            exchange_rates: Vec::new(),
+            strict_catalog: false,
+        })
+    }
+
+    /// Limits the quantity of every item a user can give to others within 24 hours
+    pub(crate) fn set_daily_give_limit(&mut self, limit: Option<i64>) {
+        self.daily_give_limit = limit;
+    }
+
+    /// Flags the items as currencies in addition to funds, creating them if needed.
+    /// Items that were flagged before but aren't listed are not currencies anymore
+    pub(crate) fn set_currencies(&self, names: &[String]) -> Result<()> {
+        let savepoint = self.savepoint()?;
+        self.db.execute(
+            "UPDATE items SET is_currency = 0 WHERE id != ?1",
+            [self.funds_item_id],
+        )?;
+        for name in names {
+            let item_id = self.get_or_create_item(name)?;
+            self.db
+                .execute("UPDATE items SET is_currency = 1 WHERE id = ?1", [item_id])?;
+        }
+        savepoint.commit()
+    }
+
+    /// Sets the exchange listing. Both sides of every rate must be currencies
+    pub(crate) fn set_exchange_rates(&mut self, rates: Vec<ExchangeRate>) -> Result<()> {
+        for rate in &rates {
+            if rate.rate <= 0 {
+                return Err(Error::InvalidPrice);
+            }
+            for name in [&rate.from, &rate.to] {
+                if !self.is_currency(self.get_item_id(name)?)? {
+                    return Err(Error::NotCurrency(name.clone()));
+                }
+            }
+        }
+        self.exchange_rates = rates;
+        Ok(())
+    }
+
+    /// Replaces the catalog of items and their aliases. In strict mode only items from the catalog
+    /// and currencies can be deposited
+    pub(crate) fn set_catalog(&mut self, items: &[CatalogItem], strict: bool) -> Result<()> {
+        let savepoint = self.savepoint()?;
+        self.db.execute("DELETE FROM item_aliases", ())?;
+        self.db.execute(
+            "UPDATE items SET
+                in_catalog = 0,
+                display_name = NULL,
+                category = NULL,
+                description = '',
+                max_stack = NULL,
+                tradeable = 1",
+            (),
+        )?;
+        let mut item_ids = Vec::with_capacity(items.len());
+        for item in items {
+            if item.max_stack.is_some_and(|max_stack| max_stack <= 0) {
+                return Err(Error::InvalidQuantity);
+            }
+            let item_id = self.get_or_create_item(&item.name)?;
+            self.db.execute(
+                "UPDATE items SET
+                    in_catalog = 1,
+                    display_name = ?2,
+                    category = ?3,
+                    description = ?4,
+                    max_stack = ?5,
+                    tradeable = ?6
+                WHERE id = ?1",
+                (
+                    item_id,
+                    &item.display_name,
+                    &item.category,
+                    &item.description,
+                    item.max_stack,
+                    item.tradeable,
+                ),
+            )?;
+            item_ids.push(item_id);
+        }
+        // Aliases are added once all items exist, so that an alias can't shadow an item name
+        for (item, item_id) in items.iter().zip(item_ids) {
+            for alias in &item.aliases {
+                let name = tidy_item_name(alias);
+                if name.is_empty() {
+                    return Err(Error::EmptyItemName);
+                }
+                match self.get_item_id(&name) {
+                    Ok(other_id) if other_id != item_id => {
+                        return Err(Error::AliasTaken {
+                            alias: name,
+                            item_name: self.get_item_name(other_id)?,
+                        })
+                    }
+                    Ok(_) | Err(Error::ItemNotFound { .. }) => {}
+                    Err(err) => return Err(err),
+                }
+                self.db.execute(
+                    "INSERT INTO item_aliases (item_id, name, normalized_name) VALUES (?1, ?2, ?3)
+                    ON CONFLICT (normalized_name) DO NOTHING",
+                    (item_id, &name, normalize_item_name(&name)),
+                )?;
+            }
+        }
+        savepoint.commit()?;
+        self.strict_catalog = strict;
+        Ok(())
+    }
+
+    /// Starts a transaction, or a nested one if there is a transaction already
+    pub(crate) fn savepoint(&self) -> Result<Savepoint<'_>> {
+        self.db.execute_batch("SAVEPOINT storage")?;
+        self.savepoint_depth.set(self.savepoint_depth.get() + 1);
+        Ok(Savepoint {
+            storage: self,
+            pending_events: self.pending_events.borrow().len(),
+            committed: false,
+        })
+    }
+
+    fn close_savepoint(&self) {
+        let depth = self.savepoint_depth.get() - 1;
+        self.savepoint_depth.set(depth);
+        if depth == 0 {
+            for (user_id, event) in self.pending_events.take() {
+                self.send_event(user_id, event);
+            }
+        }
+    }
+
+    /// Returns the result stored for the request, if the request has been processed already
+    pub(crate) fn get_request_result(
+        &self,
+        user_id: UserId,
+        request_id: &str,
+    ) -> Result<Option<String>> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT result FROM request_results WHERE user_id = ?1 AND request_id = ?2")?;
+        match stmt.query_row((user_id.0, request_id), |row| row.get(0)) {
+            Ok(result) => Ok(Some(result)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Stores the result of the request and forgets results stored more than a day ago
+    pub(crate) fn save_request_result(
+        &self,
+        user_id: UserId,
+        request_id: &str,
+        result: &str,
+        unix_now: i64,
+    ) -> Result<()> {
+        self.db.execute(
+            "DELETE FROM request_results WHERE created_at <= ?1",
+            [unix_now - REQUEST_RESULT_LIFETIME_SECONDS],
+        )?;
+        self.db.execute(
+            "INSERT INTO request_results (user_id, request_id, result, created_at)
+            VALUES (?1, ?2, ?3, ?4)",
+            (user_id.0, request_id, result, unix_now),
+        )?;
+        Ok(())
     }
 
     pub(crate) fn login(&self, username: &str) -> Result<User> {
         if username.is_empty() {
-            Err(anyhow::anyhow!("Username cannot be empty"))?;
+            return Err(Error::EmptyUsername);
         }
 
         let mut stmt = self
@@ -193,6 +840,52 @@ impl Storage {
         })
     }
 
+    /// Creates a new random session token for the user, valid until the expiration time
+    pub(crate) fn create_session(
+        &self,
+        user_id: UserId,
+        unix_expiration_time: i64,
+    ) -> Result<String> {
+        let token = self.db.query_row(
+            "INSERT INTO sessions (token, user_id, expiration_time)
+            VALUES (lower(hex(randomblob(16))), ?1, ?2)
+            RETURNING token",
+            [user_id.0, unix_expiration_time],
+            |row| row.get(0),
+        )?;
+        Ok(token)
+    }
+
+    pub(crate) fn get_session_user(&self, token: &str, unix_now: i64) -> Result<User> {
+        let mut stmt = self.db.prepare(
+            "SELECT users.id, users.username
+            FROM sessions
+            INNER JOIN users ON sessions.user_id = users.id
+            WHERE sessions.token = ?1 AND sessions.expiration_time > ?2",
+        )?;
+        match stmt.query_row((token, unix_now), |row| {
+            Ok(User {
+                id: UserId(row.get(0)?),
+                username: row.get(1)?,
+            })
+        }) {
+            Ok(user) => Ok(user),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Err(Error::InvalidToken),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Ends the session, so its token can't be used anymore
+    pub(crate) fn delete_session(&self, token: &str) -> Result<()> {
+        let deleted = self
+            .db
+            .execute("DELETE FROM sessions WHERE token = ?1", [token])?;
+        if deleted == 0 {
+            return Err(Error::InvalidToken);
+        }
+        Ok(())
+    }
+
     pub(crate) fn view_items(&self, user_id: UserId) -> Result<Vec<(String, i64)>> {
         let mut stmt = self.db.prepare(
             "SELECT items.name, user_items.quantity
@@ -206,79 +899,659 @@ impl Storage {
         Ok(items)
     }
 
+    /// Items of the user held by the market as (name, in bids, listed for sale) tuples: funds or
+    /// other price items of the auctions the user is the highest bidder on and items of the user's
+    /// sell orders. Item instances aren't counted
+    pub(crate) fn view_reserved_items(&self, user_id: UserId) -> Result<Vec<(String, i64, i64)>> {
+        let mut stmt = self.db.prepare(
+            "WITH reserved_items AS (
+              SELECT price_item_id AS item_id, price AS in_bids, 0 AS listed
+              FROM sell_orders
+              WHERE buyer_id = ?1 AND seller_id != ?1
+              UNION ALL
+              SELECT sell_order_items.item_id, 0, sell_order_items.quantity
+              FROM sell_order_items
+              INNER JOIN sell_orders ON sell_order_items.sell_order_id = sell_orders.id
+              WHERE sell_orders.seller_id = ?1
+            )
+            SELECT items.name, SUM(reserved_items.in_bids), SUM(reserved_items.listed)
+            FROM reserved_items
+            INNER JOIN items ON reserved_items.item_id = items.id
+            GROUP BY items.id
+            ORDER BY items.id",
+        )?;
+        let items = stmt
+            .query_map([user_id.0], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(items)
+    }
+
+    /// Auction sell orders where the user is the highest bidder
+    pub(crate) fn view_bids(&self, user_id: UserId) -> Result<Vec<SellOrder>> {
+        self.query_sell_orders(
+            "WHERE sell_orders.buyer_id = ?1 AND sell_orders.seller_id != ?1
+            ORDER BY sell_orders.expiration_time, sell_orders.id",
+            [user_id.0],
+        )
+    }
+
     pub(crate) fn deposit(&self, user_id: UserId, item_name: &str, quantity: i64) -> Result<()> {
-        if item_name.is_empty() {
-            return Err(anyhow::anyhow!("Item name cannot be empty"));
-        }
+        check_item_name(item_name)?;
         if quantity <= 0 {
-            return Err(anyhow::anyhow!("Quantity must be positive"));
+            return Err(Error::InvalidQuantity);
         }
 
-        self.get_item_id(item_name)
-            .or_else(|err| match err {
-                rusqlite::Error::QueryReturnedNoRows => {
-                    self.db
-                        .execute("INSERT INTO items (name) VALUES (?1)", [item_name])?;
-                    Ok(self.db.last_insert_rowid())
-                }
-                err => Err(err),
-            })
-            .and_then(|item_id| self.deposit_inner(user_id, item_id, quantity))
-            .map_err(anyhow::Error::msg)
+        let item_id = self.get_depositable_item_id(item_name)?;
+        self.check_stack(user_id, item_id, quantity)?;
+        self.deposit_inner(user_id, item_id, quantity)
     }
 
     pub(crate) fn withdraw(&self, user_id: UserId, item_name: &str, quantity: i64) -> Result<()> {
-        if item_name.is_empty() {
-            return Err(anyhow::anyhow!("Item name cannot be empty"));
+        check_item_name(item_name)?;
+        if quantity <= 0 {
+            return Err(Error::InvalidQuantity);
+        }
+
+        let item_id = self.find_item_id(item_name)?;
+        self.withdraw_inner(user_id, item_id, quantity)
+    }
+
+    /// Item instances held by the user, the oldest first. Instances held by sell orders are not
+    /// included
+    pub(crate) fn view_instances(&self, user_id: UserId) -> Result<Vec<ItemInstance>> {
+        self.query_instances("WHERE item_instances.owner_id = ?1", [user_id.0])
+    }
+
+    /// Deposits a new unique instance of the item with the attributes, which must be a JSON object
+    pub(crate) fn deposit_instance(
+        &self,
+        user_id: UserId,
+        item_name: &str,
+        attributes: &serde_json::Value,
+    ) -> Result<ItemInstance> {
+        check_item_name(item_name)?;
+        if !attributes.is_object() {
+            return Err(Error::InvalidAttributes);
         }
+
+        let item_id = self.get_depositable_item_id(item_name)?;
+        self.check_stack(user_id, item_id, 1)?;
+        self.db.execute(
+            "INSERT INTO item_instances (item_id, owner_id, attributes) VALUES (?1, ?2, ?3)",
+            (item_id, user_id.0, attributes),
+        )?;
+        self.get_instance(self.db.last_insert_rowid())
+    }
+
+    pub(crate) fn withdraw_instance(&self, user_id: UserId, instance_id: i64) -> Result<()> {
+        self.get_owned_instance_item_id(user_id, instance_id)?;
+        self.db
+            .execute("DELETE FROM item_instances WHERE id = ?1", [instance_id])?;
+        Ok(())
+    }
+
+    /// Moves items from the sender to the recipient and records the transaction
+    pub(crate) fn give(
+        &self,
+        sender_id: UserId,
+        recipient_name: &str,
+        item_name: &str,
+        quantity: i64,
+        unix_now: i64,
+    ) -> Result<()> {
+        check_item_name(item_name)?;
         if quantity <= 0 {
-            return Err(anyhow::anyhow!("Quantity must be positive"));
+            return Err(Error::InvalidQuantity);
+        }
+        let recipient_id = self.get_user_id(recipient_name)?;
+        if recipient_id == sender_id {
+            return Err(Error::SelfTransfer);
+        }
+        let item_id = self.get_item_id(item_name)?;
+        self.check_tradeable(item_id)?;
+        self.check_stack(recipient_id, item_id, quantity)?;
+
+        if let Some(limit) = self.daily_give_limit {
+            let given: i64 = self.db.query_row(
+                "SELECT IFNULL(SUM(quantity), 0) FROM transactions
+                WHERE kind = 'give' AND sender_id = ?1 AND item_id = ?2 AND created_at > ?3",
+                (sender_id.0, item_id, unix_now - DAY_SECONDS),
+                |row| row.get(0),
+            )?;
+            if given + quantity > limit {
+                return Err(Error::DailyLimitExceeded {
+                    item_name: item_name.to_owned(),
+                    limit,
+                    remaining: (limit - given).max(0),
+                });
+            }
         }
 
-        self.get_item_id(item_name)
-            .map_err(|err| anyhow::anyhow!("no such item: {err}"))
-            .and_then(|item_id| self.withdraw_inner(user_id, item_id, quantity))
-            .map_err(|_| anyhow::anyhow!("Not enough {}(s) to withdraw", item_name))
+        let savepoint = self.savepoint()?;
+        self.withdraw_inner(sender_id, item_id, quantity)?;
+        self.deposit_inner(recipient_id, item_id, quantity)?;
+        self.db.execute(
+            "INSERT INTO transactions (kind, sender_id, recipient_id, item_id, quantity, created_at)
+            VALUES ('give', ?1, ?2, ?3, ?4, ?5)",
+            (sender_id.0, recipient_id.0, item_id, quantity, unix_now),
+        )?;
+        let sender: String = self.db.query_row(
+            "SELECT username FROM users WHERE id = ?1",
+            [sender_id.0],
+            |row| row.get(0),
+        )?;
+        self.notify_user(
+            recipient_id,
+            MarketEvent::ItemsReceived {
+                sender,
+                recipient: recipient_name.to_owned(),
+                item_name: self.get_item_name(item_id)?,
+                quantity,
+            },
+        )?;
+        savepoint.commit()
     }
 
-    pub(crate) fn view_sell_orders(&self) -> Result<Vec<SellOrder>> {
+    /// Transactions the user took part in, the latest is the last
+    pub(crate) fn view_transactions(&self, user_id: UserId) -> Result<Vec<Transaction>> {
         let mut stmt = self.db.prepare(
             "SELECT
-                sell_orders.id,
-                users.username,
+                transactions.kind,
+                senders.username,
+                recipients.username,
                 items.name,
-                sell_orders.quantity,
-                sell_orders.price,
-                DATETIME(sell_orders.expiration_time, 'unixepoch'),
-                sell_orders.seller_id,
-                sell_orders.buyer_id
-            FROM sell_orders
-            INNER JOIN users ON sell_orders.seller_id = users.id
-            INNER JOIN items ON sell_orders.item_id = items.id",
+                transactions.quantity,
+                DATETIME(transactions.created_at, 'unixepoch')
+            FROM transactions
+            INNER JOIN users AS senders ON transactions.sender_id = senders.id
+            INNER JOIN users AS recipients ON transactions.recipient_id = recipients.id
+            INNER JOIN items ON transactions.item_id = items.id
+            WHERE transactions.sender_id = ?1 OR transactions.recipient_id = ?1
+            ORDER BY transactions.id",
         )?;
-        let orders = stmt
-            .query_map([], |row| {
-                let seller_id: i64 = row.get(6)?;
-                let buyer_id: Option<i64> = row.get(7)?;
-                Ok(SellOrder {
-                    id: row.get(0)?,
-                    seller_name: row.get(1)?,
-                    item_name: row.get(2)?,
-                    quantity: row.get(3)?,
-                    price: row.get(4)?,
-                    expiration_time: row.get(5)?,
-                    order_type: if buyer_id == Some(seller_id) {
-                        SellOrderType::Immediate
-                    } else {
-                        SellOrderType::Auction
+        let transactions = stmt
+            .query_map([user_id.0], |row| {
+                let kind: String = row.get(0)?;
+                Ok(Transaction {
+                    kind: match kind.as_str() {
+                        "trade" => TransactionKind::Trade,
+                        _ => TransactionKind::Give,
                     },
+                    sender: row.get(1)?,
+                    recipient: row.get(2)?,
+                    item_name: row.get(3)?,
+                    quantity: row.get(4)?,
+                    time: row.get(5)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(transactions)
+    }
+
+    /// Places an offer to the recipient and holds the given items until the offer is finished
+    pub(crate) fn place_offer(
+        &self,
+        sender_id: UserId,
+        recipient_name: &str,
+        give: &[Item],
+        want: &[Item],
+        unix_expiration_time: i64,
+    ) -> Result<i64> {
+        if give.is_empty() || want.is_empty() {
+            return Err(Error::EmptyOffer);
+        }
+        for item in give.iter().chain(want) {
+            check_item_name(&item.name)?;
+            if item.quantity <= 0 {
+                return Err(Error::InvalidQuantity);
+            }
+        }
+        let recipient_id = self.get_user_id(recipient_name)?;
+        if recipient_id == sender_id {
+            return Err(Error::SelfTransfer);
+        }
+
+        let savepoint = self.savepoint()?;
+        self.db.execute(
+            "INSERT INTO offers (sender_id, recipient_id, expiration_time) VALUES (?1, ?2, ?3)",
+            (sender_id.0, recipient_id.0, unix_expiration_time),
+        )?;
+        let offer_id = self.db.last_insert_rowid();
+        for (side, items) in [("give", give), ("want", want)] {
+            for item in items {
+                let item_id = self.get_item_id(&item.name)?;
+                self.check_tradeable(item_id)?;
+                if side == "give" {
+                    self.withdraw_inner(sender_id, item_id, item.quantity)?;
+                }
+                // The same item mentioned twice is merged
+                self.db.execute(
+                    "INSERT INTO offer_items (offer_id, side, item_id, quantity)
+                    VALUES (?1, ?2, ?3, ?4)
+                    ON CONFLICT (offer_id, side, item_id) DO UPDATE SET quantity = quantity + ?4",
+                    (offer_id, side, item_id, item.quantity),
+                )?;
+            }
+        }
+        // Wanted items are paid on acceptance, so they must fit while the sender can still react
+        for (item_id, quantity) in self.get_offer_entry(offer_id)?.want {
+            self.check_stack(sender_id, item_id, quantity)?;
+        }
+        let offer = self.get_offer(offer_id)?;
+        self.notify_user(recipient_id, MarketEvent::OfferReceived { offer })?;
+        savepoint.commit()?;
+        Ok(offer_id)
+    }
+
+    /// Exchanges the items of the offer. Fails if the recipient doesn't have the wanted items
+    pub(crate) fn accept_offer(&self, user_id: UserId, offer_id: i64, unix_now: i64) -> Result<()> {
+        let entry = self.get_offer_entry(offer_id)?;
+        // Expired offers are only waiting for the items to be returned to the sender
+        if unix_now >= entry.expiration_time {
+            return Err(Error::OfferNotFound(offer_id));
+        }
+        if entry.recipient_id != user_id {
+            return Err(Error::NotOfferRecipient(offer_id));
+        }
+
+        for &(item_id, quantity) in &entry.want {
+            self.check_stack(entry.sender_id, item_id, quantity)?;
+        }
+
+        let savepoint = self.savepoint()?;
+        for &(item_id, quantity) in &entry.want {
+            self.withdraw_inner(entry.recipient_id, item_id, quantity)?;
+            self.deposit_inner(entry.sender_id, item_id, quantity)?;
+            self.record_transaction(
+                TransactionKind::Trade,
+                entry.recipient_id,
+                entry.sender_id,
+                item_id,
+                quantity,
+                unix_now,
+            )?;
+        }
+        // Given items are already withdrawn from the sender
+        for &(item_id, quantity) in &entry.give {
+            self.check_stack(entry.recipient_id, item_id, quantity)?;
+            self.deposit_inner(entry.recipient_id, item_id, quantity)?;
+            self.record_transaction(
+                TransactionKind::Trade,
+                entry.sender_id,
+                entry.recipient_id,
+                item_id,
+                quantity,
+                unix_now,
+            )?;
+        }
+        let offer = self.get_offer(offer_id)?;
+        self.delete_offer(offer_id)?;
+        self.notify_user(entry.sender_id, MarketEvent::OfferAccepted { offer })?;
+        savepoint.commit()
+    }
+
+    /// Declines the offer addressed to the user, or withdraws the offer sent by the user.
+    /// Given items are returned to the sender
+    pub(crate) fn decline_offer(&self, user_id: UserId, offer_id: i64) -> Result<()> {
+        let entry = self.get_offer_entry(offer_id)?;
+        if entry.recipient_id != user_id && entry.sender_id != user_id {
+            return Err(Error::NotOfferRecipient(offer_id));
+        }
+
+        let savepoint = self.savepoint()?;
+        for &(item_id, quantity) in &entry.give {
+            self.deposit_inner(entry.sender_id, item_id, quantity)?;
+        }
+        let offer = self.get_offer(offer_id)?;
+        let declined_by = self.get_username(user_id)?;
+        self.delete_offer(offer_id)?;
+        // The other side of the offer is notified
+        let notified_id = if user_id == entry.sender_id {
+            entry.recipient_id
+        } else {
+            entry.sender_id
+        };
+        self.notify_user(
+            notified_id,
+            MarketEvent::OfferDeclined { offer, declined_by },
+        )?;
+        savepoint.commit()
+    }
+
+    /// Offers sent by the user and to the user
+    pub(crate) fn view_offers(&self, user_id: UserId) -> Result<Vec<Offer>> {
+        self.query_offers(
+            "WHERE offers.sender_id = ?1 OR offers.recipient_id = ?1",
+            [user_id.0],
+        )
+    }
+
+    /// Names of all currencies, funds are the first
+    pub(crate) fn view_currencies(&self) -> Result<Vec<String>> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT name FROM items WHERE is_currency = 1 ORDER BY id")?;
+        let currencies = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(currencies)
+    }
+
+    pub(crate) fn view_exchange_rates(&self) -> Vec<ExchangeRate> {
+        self.exchange_rates.clone()
+    }
+
+    /// Exchanges `quantity` of the `from` currency at the listed rate. Currencies are matched by
+    /// item, so any spelling or alias of their names is accepted.
+    /// Returns the received quantity of the `to` currency
+    pub(crate) fn exchange(
+        &self,
+        user_id: UserId,
+        quantity: i64,
+        from: &str,
+        to: &str,
+    ) -> Result<i64> {
+        if quantity <= 0 {
+            return Err(Error::InvalidQuantity);
+        }
+        let not_listed = || Error::ExchangeNotListed {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        };
+        let (from_id, to_id) = match (self.get_item_id(from), self.get_item_id(to)) {
+            (Ok(from_id), Ok(to_id)) => (from_id, to_id),
+            (Err(Error::ItemNotFound { .. }), _) | (_, Err(Error::ItemNotFound { .. })) => {
+                return Err(not_listed())
+            }
+            (Err(err), _) | (_, Err(err)) => return Err(err),
+        };
+        let mut rate = None;
+        for listed in &self.exchange_rates {
+            if self.get_item_id(&listed.from)? == from_id && self.get_item_id(&listed.to)? == to_id
+            {
+                rate = Some(listed.rate);
+                break;
+            }
+        }
+        let rate = rate.ok_or_else(not_listed)?;
+        let received = quantity.checked_mul(rate).ok_or(Error::InvalidQuantity)?;
+
+        let savepoint = self.savepoint()?;
+        self.withdraw_inner(user_id, from_id, quantity)?;
+        self.check_stack(user_id, to_id, received)?;
+        self.deposit_inner(user_id, to_id, received)?;
+        savepoint.commit()?;
+        Ok(received)
+    }
+
+    /// Sell orders of all users, only orders with items of the category if it's set
+    pub(crate) fn view_sell_orders(&self, category: Option<&str>) -> Result<Vec<SellOrder>> {
+        match category {
+            Some(category) => self.query_sell_orders(
+                "WHERE items.category = ?1 OR EXISTS (
+                  SELECT 1 FROM sell_order_items
+                  INNER JOIN items AS bundle_items ON sell_order_items.item_id = bundle_items.id
+                  WHERE sell_order_items.sell_order_id = sell_orders.id
+                    AND bundle_items.category = ?1
+                )",
+                [category],
+            ),
+            None => self.query_sell_orders("", []),
+        }
+    }
+
+    /// Name of the item referred by `item_name` or its alias, suggesting similar names if there is
+    /// no such item
+    pub(crate) fn resolve_item_name(&self, item_name: &str) -> Result<String> {
+        self.get_item_name(self.find_item_id(item_name)?)
+    }
+
+    /// Items defined in the catalog
+    pub(crate) fn view_catalog(&self) -> Result<Vec<CatalogItem>> {
+        let mut stmt = self.db.prepare(
+            "SELECT id, name, display_name, category, description, max_stack, tradeable
+            FROM items
+            WHERE in_catalog = 1
+            ORDER BY id",
+        )?;
+        let mut aliases_stmt = self
+            .db
+            .prepare("SELECT name FROM item_aliases WHERE item_id = ?1 ORDER BY id")?;
+        let items = stmt
+            .query_map([], |row| {
+                let item_id: i64 = row.get(0)?;
+                Ok(CatalogItem {
+                    name: row.get(1)?,
+                    display_name: row.get(2)?,
+                    category: row.get(3)?,
+                    description: row.get(4)?,
+                    max_stack: row.get(5)?,
+                    tradeable: row.get(6)?,
+                    aliases: aliases_stmt
+                        .query_map([item_id], |row| row.get(0))?
+                        .collect::<Result<Vec<_>, _>>()?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(items)
+    }
+
+    /// Price history and market statistics of the item. Only trades of the item alone priced in
+    /// funds are counted: bundles are recorded, but the price of a bundle says nothing about the
+    /// price of its items
+    pub(crate) fn item_stats(&self, item_name: &str, unix_now: i64) -> Result<ItemStats> {
+        let item_id = self.find_item_id(item_name)?;
+        let last_trade = self.db.query_row(
+            "SELECT quantity, price, DATETIME(created_at, 'unixepoch')
+            FROM trades
+            WHERE item_id = ?1 AND price_item_id = ?2 AND bundle = 0
+            ORDER BY created_at DESC, id DESC
+            LIMIT 1",
+            [item_id, self.funds_item_id],
+            |row| {
+                Ok(Trade {
+                    quantity: row.get(0)?,
+                    price: row.get(1)?,
+                    time: row.get(2)?,
+                })
+            },
+        );
+        let last_trade = match last_trade {
+            Ok(trade) => Some(trade),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(err) => return Err(err.into()),
+        };
+
+        // Prices are per item, the average is weighted by the traded quantity
+        let (volume_24h, volume_7d, vwap_7d, min_price_7d, max_price_7d) = self.db.query_row(
+            "SELECT
+                IFNULL(SUM(CASE WHEN created_at > ?3 THEN quantity END), 0),
+                IFNULL(SUM(quantity), 0),
+                CAST(SUM(price) AS REAL) / SUM(quantity),
+                MIN(CAST(price AS REAL) / quantity),
+                MAX(CAST(price AS REAL) / quantity)
+            FROM trades
+            WHERE item_id = ?1 AND price_item_id = ?2 AND bundle = 0 AND created_at > ?4",
+            [
+                item_id,
+                self.funds_item_id,
+                unix_now - DAY_SECONDS,
+                unix_now - 7 * DAY_SECONDS,
+            ],
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            },
+        )?;
+
+        let active_listings = self.db.query_row(
+            "SELECT COUNT(*) FROM sell_orders
+            WHERE item_id = ?1
+              OR id IN (SELECT sell_order_id FROM sell_order_items WHERE item_id = ?1)",
+            [item_id],
+            |row| row.get(0),
+        )?;
+
+        Ok(ItemStats {
+            item_name: self.get_item_name(item_id)?,
+            last_trade,
+            volume_24h,
+            volume_7d,
+            vwap_7d,
+            min_price_7d,
+            max_price_7d,
+            active_listings,
+        })
+    }
+
+    /// Suggests a price in funds to list `quantity` of the item for: the median price per item of
+    /// its recent trades. Nothing is suggested if the item wasn't sold alone for funds in the last
+    /// 7 days, bundles aren't counted the same way as in `item_stats`
+    pub(crate) fn suggest_price(
+        &self,
+        item_name: &str,
+        quantity: i64,
+        unix_now: i64,
+    ) -> Result<Option<i64>> {
+        const RECENT_TRADES: i64 = 20;
+
+        if quantity <= 0 {
+            return Err(Error::InvalidQuantity);
+        }
+        let item_id = self.find_item_id(item_name)?;
+        let mut stmt = self.db.prepare(
+            "SELECT CAST(price AS REAL) / quantity
+            FROM trades
+            WHERE item_id = ?1 AND price_item_id = ?2 AND bundle = 0 AND created_at > ?3
+            ORDER BY created_at DESC, id DESC
+            LIMIT ?4",
+        )?;
+        let mut prices = stmt
+            .query_map(
+                [
+                    item_id,
+                    self.funds_item_id,
+                    unix_now - 7 * DAY_SECONDS,
+                    RECENT_TRADES,
+                ],
+                |row| row.get::<_, f64>(0),
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        if prices.is_empty() {
+            return Ok(None);
+        }
+
+        prices.sort_by(f64::total_cmp);
+        let middle = prices.len() / 2;
+        let median = if prices.len() % 2 == 0 {
+            (prices[middle - 1] + prices[middle]) / 2.0
+        } else {
+            prices[middle]
+        };
+        Ok(Some(((median * quantity as f64).round() as i64).max(1)))
+    }
+
+    /// Adds the watch to the user's watchlist
+    pub(crate) fn add_watch(&self, user_id: UserId, kind: &WatchKind) -> Result<Watch> {
+        match kind {
+            WatchKind::ItemBelow { item_name, price } => {
+                if *price <= 0 {
+                    return Err(Error::InvalidPrice);
+                }
+                let item_id = self.find_item_id(item_name)?;
+                self.db.execute(
+                    "INSERT INTO watches (user_id, item_id, price) VALUES (?1, ?2, ?3)",
+                    [user_id.0, item_id, *price],
+                )?;
+            }
+            WatchKind::Seller { seller_name } => {
+                let seller_id = self.get_user_id(seller_name)?;
+                self.db.execute(
+                    "INSERT INTO watches (user_id, seller_id) VALUES (?1, ?2)",
+                    [user_id.0, seller_id.0],
+                )?;
+            }
+        }
+        let watch_id = self.db.last_insert_rowid();
+        self.query_watches("WHERE watches.id = ?1", [watch_id])?
+            .pop()
+            .map(|entry| entry.watch)
+            .ok_or(Error::WatchNotFound(watch_id))
+    }
+
+    /// Removes the watch from the user's watchlist. Watches of other users are reported as not
+    /// existing
+    pub(crate) fn remove_watch(&self, user_id: UserId, watch_id: i64) -> Result<()> {
+        let removed = self.db.execute(
+            "DELETE FROM watches WHERE id = ?1 AND user_id = ?2",
+            [watch_id, user_id.0],
+        )?;
+        if removed == 0 {
+            return Err(Error::WatchNotFound(watch_id));
+        }
+        Ok(())
+    }
+
+    /// Watches of the user, the oldest first
+    pub(crate) fn view_watchlist(&self, user_id: UserId) -> Result<Vec<Watch>> {
+        let watches = self
+            .query_watches("WHERE watches.user_id = ?1", [user_id.0])?
+            .into_iter()
+            .map(|entry| entry.watch)
+            .collect();
+        Ok(watches)
+    }
+
+    /// Takes events received by the user while they were offline, the oldest first
+    pub(crate) fn view_mailbox(&self, user_id: UserId) -> Result<Vec<MarketEvent>> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT event FROM mailbox WHERE user_id = ?1 ORDER BY id")?;
+        let events = stmt
+            .query_map([user_id.0], |row| {
+                let event: String = row.get(0)?;
+                serde_json::from_str(&event).map_err(|err| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        0,
+                        rusqlite::types::Type::Text,
+                        err.into(),
+                    )
                 })
             })?
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(anyhow::Error::msg);
-        orders
+            .collect::<Result<Vec<_>, _>>()?;
+        self.db
+            .execute("DELETE FROM mailbox WHERE user_id = ?1", [user_id.0])?;
+        Ok(events)
+    }
+
+    /// Connects a session of the user. Personal events that would otherwise go to the mailbox are
+    /// sent to the session until the returned receiver is dropped. Unlike market events, personal
+    /// events are never dropped for slow sessions
+    pub(crate) fn connect(&self, user_id: UserId) -> mpsc::UnboundedReceiver<MarketEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.sessions
+            .borrow_mut()
+            .entry(user_id.0)
+            .or_default()
+            .push(sender);
+        receiver
+    }
+
+    /// Subscribes to all market changes that happen after this call
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<MarketEvent> {
+        self.events.subscribe()
     }
 
+    /// Places an order to sell items for the price paid in `price_item_name`, which is a currency
+    /// for regular orders, e.g. funds, and any other item for barter orders
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn place_sell_order(
         &self,
         order_type: SellOrderType,
@@ -286,37 +1559,140 @@ impl Storage {
         item_name: &str,
         quantity: i64,
         price: i64,
+        price_item_name: &str,
         unix_expiration_time: i64,
-    ) -> Result<()> {
-        if quantity < 0 {
-            Err(anyhow::anyhow!("Cannot sell negative amount"))?;
+    ) -> Result<i64> {
+        if quantity <= 0 {
+            return Err(Error::InvalidQuantity);
         }
-        if price < 0 {
-            Err(anyhow::anyhow!("Cannot sell for negative price"))?;
+        if price <= 0 {
+            return Err(Error::InvalidPrice);
         }
-        if item_name == "funds" {
-            Err(anyhow::anyhow!(
-                "Cannot sell funds for funds, it's a speculation!"
-            ))?;
+
+        let item_id = self.find_item_id(item_name)?;
+        self.insert_sell_order(
+            order_type,
+            seller_id,
+            &[(item_id, quantity)],
+            None,
+            price,
+            price_item_name,
+            unix_expiration_time,
+        )
+    }
+
+    /// Places an order to sell several different items as a single lot. Items are held by the order
+    /// and bought together
+    pub(crate) fn place_bundle_sell_order(
+        &self,
+        order_type: SellOrderType,
+        seller_id: UserId,
+        items: &[Item],
+        price: i64,
+        price_item_name: &str,
+        unix_expiration_time: i64,
+    ) -> Result<i64> {
+        if items.is_empty() {
+            return Err(Error::EmptyBundle);
+        }
+        if items.iter().any(|item| item.quantity <= 0) {
+            return Err(Error::InvalidQuantity);
+        }
+        if price <= 0 {
+            return Err(Error::InvalidPrice);
+        }
+
+        // The same item listed twice is a single line
+        let mut lines: Vec<(i64, i64)> = Vec::with_capacity(items.len());
+        for item in items {
+            let item_id = self.find_item_id(&item.name)?;
+            match lines.iter_mut().find(|(id, _)| *id == item_id) {
+                Some((_, quantity)) => *quantity += item.quantity,
+                None => lines.push((item_id, item.quantity)),
+            }
         }
+        self.insert_sell_order(
+            order_type,
+            seller_id,
+            &lines,
+            None,
+            price,
+            price_item_name,
+            unix_expiration_time,
+        )
+    }
 
-        let transaction_guard = self.db.unchecked_transaction()?;
+    /// Places an order to sell the item instance, buyers see its attributes
+    pub(crate) fn place_instance_sell_order(
+        &self,
+        order_type: SellOrderType,
+        seller_id: UserId,
+        instance_id: i64,
+        price: i64,
+        price_item_name: &str,
+        unix_expiration_time: i64,
+    ) -> Result<i64> {
+        if price <= 0 {
+            return Err(Error::InvalidPrice);
+        }
 
-        let item_id = self
-            .get_item_id(item_name)
-            .map_err(|err| anyhow::anyhow!("no such item: {err}"))
-            .and_then(|item_id| {
-                self.withdraw_inner(seller_id, item_id, quantity)
-                    .map(|()| item_id)
-            })
-            .map_err(|_| anyhow::anyhow!("Not enough {item_name}(s) to sell"))?;
+        let item_id = self.get_owned_instance_item_id(seller_id, instance_id)?;
+        self.insert_sell_order(
+            order_type,
+            seller_id,
+            &[(item_id, 1)],
+            Some(instance_id),
+            price,
+            price_item_name,
+            unix_expiration_time,
+        )
+    }
 
-        // Fee is 5% of the price + 1 funds
-        let fee = price / 20 + 1;
-        self.withdraw_inner(seller_id, self.funds_item_id, fee)
-            .map_err(|_err| {
-                anyhow::anyhow!("Not enough funds to pay {fee} funds fee (which is 5% + 1)")
-            })?;
+    // Takes (item_id, quantity) `lines` from the seller, or the instance if it's set, and places
+    // the order. The first line is the item the order is shown and filtered by
+    #[allow(clippy::too_many_arguments)]
+    fn insert_sell_order(
+        &self,
+        order_type: SellOrderType,
+        seller_id: UserId,
+        lines: &[(i64, i64)],
+        instance_id: Option<i64>,
+        price: i64,
+        price_item_name: &str,
+        unix_expiration_time: i64,
+    ) -> Result<i64> {
+        let price_item_id = self.find_item_id(price_item_name)?;
+        for &(item_id, _) in lines {
+            if item_id == price_item_id {
+                return Err(if item_id == self.funds_item_id {
+                    Error::FundsNotTradable
+                } else {
+                    Error::SamePriceItem(self.get_item_name(item_id)?)
+                });
+            }
+        }
+
+        let savepoint = self.savepoint()?;
+        for &(item_id, _) in lines {
+            self.check_tradeable(item_id)?;
+        }
+        self.check_tradeable(price_item_id)?;
+        match instance_id {
+            Some(instance_id) => self.move_instance(instance_id, None)?,
+            None => {
+                for &(item_id, quantity) in lines {
+                    self.withdraw_inner(seller_id, item_id, quantity)?;
+                }
+            }
+        }
+
+        // Fee is 5% of the price + 1, taken in the currency of the order, funds or another one.
+        // The price of barter orders isn't in a currency, so only 1 funds is taken
+        if self.is_currency(price_item_id)? {
+            self.withdraw_inner(seller_id, price_item_id, price / 20 + 1)?;
+        } else {
+            self.withdraw_inner(seller_id, self.funds_item_id, 1)?;
+        }
 
         // For immediate orders, buyer_id is equal to the seller_id.
         // For auction orders, buyer_id is null untill someone places a bid.
@@ -325,43 +1701,62 @@ impl Storage {
             SellOrderType::Auction => None,
         };
 
+        let (item_id, quantity) = lines[0];
         self.db.execute(
-            "INSERT INTO sell_orders (seller_id, item_id, quantity, price, expiration_time, buyer_id)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-            (seller_id.0, item_id, quantity, price, unix_expiration_time, buyer_id)
+            "INSERT INTO sell_orders (seller_id, item_id, quantity, price, price_item_id, instance_id, expiration_time, buyer_id)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            (seller_id.0, item_id, quantity, price, price_item_id, instance_id, unix_expiration_time, buyer_id)
         )?;
-        transaction_guard.commit()?;
-        Ok(())
+        let sell_order_id = self.db.last_insert_rowid();
+        if instance_id.is_none() {
+            for &(item_id, quantity) in lines {
+                self.db.execute(
+                    "INSERT INTO sell_order_items (sell_order_id, item_id, quantity) VALUES (?1, ?2, ?3)",
+                    [sell_order_id, item_id, quantity],
+                )?;
+            }
+        }
+        let order = self.get_sell_order(sell_order_id)?;
+        self.notify(MarketEvent::OrderPlaced {
+            order: order.clone(),
+        });
+        self.notify_watchers(&order, seller_id, lines, price_item_id)?;
+        savepoint.commit()?;
+
+        Ok(sell_order_id)
     }
 
     pub(crate) fn execute_immediate_sell_order(
         &self,
         buyer_id: UserId,
         order_id: i64,
+        unix_now: i64,
     ) -> Result<()> {
-        let order = self
-            .get_sell_oder_entry(order_id)
-            .map_err(|_| anyhow::anyhow!("Immediate sell order #{order_id} doesn't exist"))?;
+        let order = self.get_sell_oder_entry(order_id)?;
         if order.order_type() != SellOrderType::Immediate {
-            return Err(anyhow::anyhow!(
-                "Order #{order_id} is not an immediate order"
-            ));
+            return Err(Error::NotImmediateOrder(order_id));
         }
         if buyer_id == order.seller_id {
-            return Err(anyhow::anyhow!("You can't buy your own items"));
+            return Err(Error::OwnOrder);
+        }
+        for &(item_id, quantity) in &order.lines {
+            self.check_stack(buyer_id, item_id, quantity)?;
         }
 
-        let transaction_guard = self.db.unchecked_transaction()?;
-        // deduce funds from the buyer
-        self.withdraw_inner(buyer_id, self.funds_item_id, order.price)?;
-        // add funds to the seller
-        self.deposit_inner(order.seller_id, self.funds_item_id, order.price)?;
-        // transfer item to the buyer
-        self.deposit_inner(buyer_id, order.item_id, order.quantity)?;
+        let savepoint = self.savepoint()?;
+        // deduce the price from the buyer
+        self.withdraw_inner(buyer_id, order.price_item_id, order.price)?;
+        // pay the price to the seller
+        self.deposit_inner(order.seller_id, order.price_item_id, order.price)?;
+        // transfer items to the buyer
+        self.deposit_order_items(&order, buyer_id)?;
+        self.record_trade(&order, unix_now)?;
         // delete the order
-        self.db
-            .execute("DELETE FROM sell_orders WHERE id = ?1", [order_id])?;
-        transaction_guard.commit()?;
+        let sell_order = self.get_sell_order(order_id)?;
+        self.delete_sell_order(order_id)?;
+        savepoint.commit()?;
+
+        self.notify(MarketEvent::OrderBought { order: sell_order });
         Ok(())
     }
 
@@ -371,67 +1766,105 @@ impl Storage {
         sell_order_id: i64,
         bid: i64,
     ) -> Result<()> {
-        let order = self
-            .get_sell_oder_entry(sell_order_id)
-            .map_err(|_| anyhow::anyhow!("Auction sell order #{sell_order_id} doesn't exist"))?;
+        let order = self.get_sell_oder_entry(sell_order_id)?;
         if order.order_type() != SellOrderType::Auction {
-            return Err(anyhow::anyhow!(
-                "Order #{sell_order_id} is not an auction order"
-            ));
+            return Err(Error::NotAuctionOrder(sell_order_id));
         }
         if buyer_id == order.seller_id {
-            return Err(anyhow::anyhow!("You can't buy your own items"));
+            return Err(Error::OwnOrder);
         }
         if bid <= order.price {
-            return Err(anyhow::anyhow!("Bid must be higher than the current price"));
+            return Err(Error::BidTooLow {
+                current_price: order.price,
+                price_item: self.get_item_name(order.price_item_id)?,
+            });
+        }
+        // The winner gets the items once the auction expires, so they must fit already
+        for &(item_id, quantity) in &order.lines {
+            self.check_stack(buyer_id, item_id, quantity)?;
         }
 
-        let transaction_guard = self.db.unchecked_transaction()?;
+        let savepoint = self.savepoint()?;
         if let Some(buyer_id) = order.buyer_id {
-            // return funds to the previous buyer if any
-            self.deposit_inner(buyer_id, self.funds_item_id, order.price)?;
+            // return the bid to the previous buyer if any
+            self.deposit_inner(buyer_id, order.price_item_id, order.price)?;
         }
 
-        // deduce funds from the buyer
-        self.withdraw_inner(buyer_id, self.funds_item_id, bid)?;
+        // deduce the bid from the buyer
+        self.withdraw_inner(buyer_id, order.price_item_id, bid)?;
 
         // update the order
         self.db.execute(
             "UPDATE sell_orders SET price = ?1, buyer_id = ?2 WHERE id = ?3",
             (bid, buyer_id.0, sell_order_id),
         )?;
-        transaction_guard.commit()?;
+        let order = self.get_sell_order(sell_order_id)?;
+        savepoint.commit()?;
+
+        self.notify(MarketEvent::BidPlaced { order });
+        Ok(())
+    }
+
+    /// Cancels the order and returns items to the seller. The fee is not returned.
+    /// Auction orders can't be cancelled once someone placed a bid
+    pub(crate) fn cancel_sell_order(&self, seller_id: UserId, order_id: i64) -> Result<()> {
+        let order = self.get_sell_oder_entry(order_id)?;
+        if order.seller_id != seller_id {
+            return Err(Error::NotOwnOrder(order_id));
+        }
+        if order.buyer_id.is_some() && order.order_type() == SellOrderType::Auction {
+            return Err(Error::OrderHasBid(order_id));
+        }
+
+        let savepoint = self.savepoint()?;
+        self.deposit_order_items(&order, seller_id)?;
+        let sell_order = self.get_sell_order(order_id)?;
+        self.delete_sell_order(order_id)?;
+        savepoint.commit()?;
+
+        self.notify(MarketEvent::OrderCancelled { order: sell_order });
         Ok(())
     }
 
     pub(crate) fn process_expired_sell_orders(&self, unix_now: i64) -> Result<()> {
-        let transaction_guard = self.db.unchecked_transaction()?;
+        let savepoint = self.savepoint()?;
 
-        // 1. Aggregate orders that sells the same item to the same user into `aggregated_orders`
+        let expired_orders =
+            self.query_sell_orders("WHERE sell_orders.expiration_time <= ?1", [unix_now])?;
+        if expired_orders.is_empty() {
+            return Ok(());
+        }
+
+        // 1. Collect item lines of the orders into `moved_items`, item instances are moved separately
         //   - for for immediate order and auction order without bid we return items to the seller
         //   - for auction order with bid we move items to the buyer
-        // 2. Add funds as payment to the `aggregated_orders` via UNION ALL for all auction orders with bid
-        // 3. Insert or update user_items with the aggregated orders
+        // 2. Add the price as payment to the `moved_items` via UNION ALL for all auction orders with bid
+        // 3. Aggregate items moved to the same user into `aggregated_orders`, so every user item is updated once
+        // 4. Insert or update user_items with the aggregated orders
         self.db.execute(
-            "WITH aggregated_orders AS (
+            "WITH moved_items AS (
               SELECT
                 CASE
                   WHEN buyer_id IS NULL OR buyer_id = seller_id THEN seller_id
                   ELSE buyer_id
                 END as user_id,
-                item_id,
-                SUM(quantity) as total_quantity
-              FROM sell_orders
+                sell_order_items.item_id,
+                sell_order_items.quantity
+              FROM sell_order_items
+              INNER JOIN sell_orders ON sell_order_items.sell_order_id = sell_orders.id
               WHERE sell_orders.expiration_time <= ?1
-              GROUP BY user_id, item_id
               UNION ALL
               SELECT
                 seller_id as user_id,
-                ?2 as item_id,
-                SUM(price) as total_quantity
+                price_item_id as item_id,
+                price as quantity
               FROM sell_orders
               WHERE sell_orders.expiration_time <= ?1 AND buyer_id IS NOT NULL AND buyer_id != seller_id
-              GROUP BY seller_id
+            ),
+            aggregated_orders AS (
+              SELECT user_id, item_id, SUM(quantity) as total_quantity
+              FROM moved_items
+              GROUP BY user_id, item_id
             )
             INSERT OR REPLACE INTO user_items (user_id, item_id, quantity)
             SELECT
@@ -441,1113 +1874,4294 @@ impl Storage {
             FROM aggregated_orders
             LEFT JOIN user_items ON user_items.user_id = aggregated_orders.user_id
               AND user_items.item_id = aggregated_orders.item_id",
-            (unix_now, self.funds_item_id),
+            [unix_now],
+        )?;
+
+        // Auctions with a bid are settled at their expiration time. Bundles are recorded per item
+        // line with the price of the whole bundle and flagged, like in `record_trade`
+        self.db.execute(
+            "WITH settled_orders AS (
+              SELECT
+                id,
+                item_id,
+                quantity,
+                price,
+                price_item_id,
+                expiration_time,
+                (SELECT COUNT(*) FROM sell_order_items WHERE sell_order_id = sell_orders.id) > 1
+                  AS bundle
+              FROM sell_orders
+              WHERE expiration_time <= ?1 AND buyer_id IS NOT NULL AND buyer_id != seller_id
+            )
+            INSERT INTO trades (item_id, quantity, price, price_item_id, bundle, created_at)
+            SELECT item_id, quantity, price, price_item_id, 0, expiration_time
+            FROM settled_orders
+            WHERE NOT bundle
+            UNION ALL
+            SELECT
+              sell_order_items.item_id,
+              sell_order_items.quantity,
+              price,
+              price_item_id,
+              1,
+              expiration_time
+            FROM settled_orders
+            INNER JOIN sell_order_items ON sell_order_items.sell_order_id = settled_orders.id
+            WHERE bundle
+            ORDER BY expiration_time",
+            [unix_now],
+        )?;
+
+        self.db.execute(
+            "UPDATE item_instances SET owner_id = (
+              SELECT
+                CASE
+                  WHEN buyer_id IS NULL OR buyer_id = seller_id THEN seller_id
+                  ELSE buyer_id
+                END
+              FROM sell_orders
+              WHERE sell_orders.instance_id = item_instances.id
+            )
+            WHERE id IN (SELECT instance_id FROM sell_orders WHERE expiration_time <= ?1)",
+            [unix_now],
         )?;
 
+        self.db.execute(
+            "DELETE FROM sell_order_items WHERE sell_order_id IN (
+              SELECT id FROM sell_orders WHERE expiration_time <= ?1
+            )",
+            [unix_now],
+        )?;
         self.db.execute(
             "DELETE FROM sell_orders WHERE expiration_time <= ?1",
             [unix_now],
         )?;
 
-        transaction_guard.commit()?;
+        savepoint.commit()?;
+
+        for order in expired_orders {
+            self.notify(MarketEvent::OrderExpired { order });
+        }
+        Ok(())
+    }
+
+    /// Returns the given items of expired offers to their senders
+    pub(crate) fn process_expired_offers(&self, unix_now: i64) -> Result<()> {
+        let savepoint = self.savepoint()?;
+
+        let expired_offers = self.query_offers("WHERE offers.expiration_time <= ?1", [unix_now])?;
+        for offer in expired_offers {
+            let entry = self.get_offer_entry(offer.id)?;
+            for (item_id, quantity) in entry.give {
+                self.deposit_inner(entry.sender_id, item_id, quantity)?;
+            }
+            self.delete_offer(offer.id)?;
+            self.notify_user(entry.sender_id, MarketEvent::OfferExpired { offer })?;
+        }
+
+        savepoint.commit()?;
         Ok(())
     }
 
-    fn get_item_id(&self, item_name: &str) -> Result<i64, rusqlite::Error> {
-        let mut stmt = self.db.prepare("SELECT id FROM items WHERE name = ?1")?;
-        stmt.query_row([item_name], |row| row.get(0))
+    /// Removes sessions whose tokens have expired
+    pub(crate) fn process_expired_sessions(&self, unix_now: i64) -> Result<()> {
+        self.db.execute(
+            "DELETE FROM sessions WHERE expiration_time <= ?1",
+            [unix_now],
+        )?;
+        Ok(())
     }
 
-    fn get_user_item_quantity(&self, user_id: UserId, item_id: i64) -> Result<i64> {
-        let mut stmt = self
-            .db
-            .prepare("SELECT quantity FROM user_items WHERE user_id = ?1 AND item_id = ?2")?;
-        let quantity = match stmt.query_row([user_id.0, item_id], |row| row.get(0)) {
-            Ok(quantity) => quantity,
-            Err(rusqlite::Error::QueryReturnedNoRows) => 0,
-            Err(err) => Err(err)?,
+    fn notify(&self, event: MarketEvent) {
+        self.send_event(None, event);
+    }
+
+    // Sends the personal event to the sessions of the user if it's set, or broadcasts the market
+    // event otherwise
+    fn send_event(&self, user_id: Option<UserId>, event: MarketEvent) {
+        if self.savepoint_depth.get() > 0 {
+            // Delivered once the changes are committed
+            self.pending_events.borrow_mut().push((user_id, event));
+            return;
+        }
+        let Some(user_id) = user_id else {
+            // Sending fails only if there are no subscribers, which is fine
+            let _ = self.events.send(event);
+            return;
         };
-        Ok(quantity)
+        let mut sessions = self.sessions.borrow_mut();
+        let senders = sessions.entry(user_id.0).or_default();
+        senders.retain(|sender| sender.send(event.clone()).is_ok());
+        if senders.is_empty() {
+            sessions.remove(&user_id.0);
+            drop(sessions);
+            // All sessions of the user have been closed since the event was addressed. Fails only
+            // if the database is broken, the event has nowhere else to go then
+            let _ = self.store_in_mailbox(user_id, &event);
+        }
+    }
+
+    pub(crate) fn get_sell_order(&self, order_id: i64) -> Result<SellOrder> {
+        self.query_sell_orders("WHERE sell_orders.id = ?1", [order_id])?
+            .pop()
+            .ok_or(Error::OrderNotFound(order_id))
     }
 
-    fn deposit_inner(
+    // Queries sell orders with all the details, filtered by the `condition`
+    fn query_sell_orders(
         &self,
-        user_id: UserId,
-        item_id: i64,
-        quantity: i64,
-    ) -> Result<(), rusqlite::Error> {
-        self.db
-            .execute(
-                "INSERT INTO user_items (user_id, item_id, quantity)
-                VALUES (?1, ?2, ?3)
-                ON CONFLICT (user_id, item_id) DO UPDATE SET quantity = quantity + ?3",
-                [user_id.0, item_id, quantity],
-            )
-            .map(|_| ())
+        condition: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<SellOrder>> {
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT
+                sell_orders.id,
+                users.username,
+                items.name,
+                sell_orders.quantity,
+                sell_orders.price,
+                DATETIME(sell_orders.expiration_time, 'unixepoch'),
+                sell_orders.seller_id,
+                sell_orders.buyer_id,
+                price_items.name,
+                item_instances.id,
+                item_instances.attributes
+            FROM sell_orders
+            INNER JOIN users ON sell_orders.seller_id = users.id
+            INNER JOIN items ON sell_orders.item_id = items.id
+            INNER JOIN items AS price_items ON sell_orders.price_item_id = price_items.id
+            LEFT JOIN item_instances ON sell_orders.instance_id = item_instances.id
+            {condition}"
+        ))?;
+        let mut orders = stmt
+            .query_map(params, |row| {
+                let seller_id: i64 = row.get(6)?;
+                let buyer_id: Option<i64> = row.get(7)?;
+                let item_name: String = row.get(2)?;
+                let instance = match row.get::<_, Option<i64>>(9)? {
+                    Some(instance_id) => Some(ItemInstance {
+                        id: instance_id,
+                        item_name: item_name.clone(),
+                        attributes: row.get(10)?,
+                    }),
+                    None => None,
+                };
+                Ok(SellOrder {
+                    id: row.get(0)?,
+                    seller_name: row.get(1)?,
+                    item_name,
+                    quantity: row.get(3)?,
+                    price: row.get(4)?,
+                    price_item: row.get(8)?,
+                    expiration_time: row.get(5)?,
+                    order_type: if buyer_id == Some(seller_id) {
+                        SellOrderType::Immediate
+                    } else {
+                        SellOrderType::Auction
+                    },
+                    instance,
+                    bundle: Vec::new(),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut stmt = self.db.prepare(
+            "SELECT items.name, sell_order_items.quantity
+            FROM sell_order_items
+            INNER JOIN items ON sell_order_items.item_id = items.id
+            WHERE sell_order_items.sell_order_id = ?1
+            ORDER BY sell_order_items.rowid",
+        )?;
+        for order in &mut orders {
+            let items = stmt
+                .query_map([order.id], |row| {
+                    Ok(Item {
+                        name: row.get(0)?,
+                        quantity: row.get(1)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            if items.len() > 1 {
+                order.bundle = items;
+            }
+        }
+        Ok(orders)
     }
 
-    fn withdraw_inner(&self, user_id: UserId, item_id: i64, quantity: i64) -> Result<()> {
-        // if quantity reaches zero - remove the record from the table for all items except funds
-        let current_quantity = self.get_user_item_quantity(user_id, item_id)?;
-        if current_quantity < quantity {
-            return Err(anyhow::anyhow!("Not enough items to withdraw"));
+    fn get_instance(&self, instance_id: i64) -> Result<ItemInstance> {
+        self.query_instances("WHERE item_instances.id = ?1", [instance_id])?
+            .pop()
+            .ok_or(Error::InstanceNotFound(instance_id))
+    }
+
+    // Queries item instances filtered by the `condition`
+    fn query_instances(
+        &self,
+        condition: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<ItemInstance>> {
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT item_instances.id, items.name, item_instances.attributes
+            FROM item_instances
+            INNER JOIN items ON item_instances.item_id = items.id
+            {condition}
+            ORDER BY item_instances.id"
+        ))?;
+        let instances = stmt
+            .query_map(params, |row| {
+                Ok(ItemInstance {
+                    id: row.get(0)?,
+                    item_name: row.get(1)?,
+                    attributes: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(instances)
+    }
+
+    // Returns the item of the instance held by the user. Instances of other users are reported as
+    // not existing
+    fn get_owned_instance_item_id(&self, user_id: UserId, instance_id: i64) -> Result<i64> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT item_id FROM item_instances WHERE id = ?1 AND owner_id = ?2")?;
+        match stmt.query_row([instance_id, user_id.0], |row| row.get(0)) {
+            Ok(item_id) => Ok(item_id),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Err(Error::InstanceNotFound(instance_id)),
+            Err(err) => Err(err.into()),
         }
+    }
 
-        // Keep the record about funds even if the balance reaches zero
-        if current_quantity > quantity || item_id == self.funds_item_id {
-            self.db.execute(
-                "UPDATE user_items SET quantity = quantity - ?3
-                WHERE user_id = ?1 AND item_id = ?2",
-                [user_id.0, item_id, quantity],
-            )?;
-        } else {
+    // Moves the instance to the user, or to the sell order if the user is not set
+    fn move_instance(&self, instance_id: i64, owner_id: Option<UserId>) -> Result<()> {
+        self.db.execute(
+            "UPDATE item_instances SET owner_id = ?2 WHERE id = ?1",
+            (instance_id, owner_id.map(|owner_id| owner_id.0)),
+        )?;
+        Ok(())
+    }
+
+    // Gives items held by the order to the user
+    fn deposit_order_items(&self, order: &SellOrderEntry, user_id: UserId) -> Result<()> {
+        if let Some(instance_id) = order.instance_id {
+            return self.move_instance(instance_id, Some(user_id));
+        }
+        for &(item_id, quantity) in &order.lines {
+            self.deposit_inner(user_id, item_id, quantity)?;
+        }
+        Ok(())
+    }
+
+    // Records the settlement of the order. A bundle is recorded as a trade per item line, each with
+    // the price of the whole bundle, and flagged, as its price isn't split between the items
+    fn record_trade(&self, order: &SellOrderEntry, unix_time: i64) -> Result<()> {
+        let bundle = order.lines.len() > 1;
+        for &(item_id, quantity) in &order.lines {
             self.db.execute(
-                "DELETE FROM user_items WHERE user_id = ?1 AND item_id = ?2",
-                [user_id.0, item_id],
+                "INSERT INTO trades (item_id, quantity, price, price_item_id, bundle, created_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                (
+                    item_id,
+                    quantity,
+                    order.price,
+                    order.price_item_id,
+                    bundle,
+                    unix_time,
+                ),
             )?;
         }
         Ok(())
     }
 
-    fn get_sell_oder_entry(&self, order_id: i64) -> Result<SellOrderEntry, rusqlite::Error> {
-        let mut stmt = self.db.prepare(
-            "SELECT
-                seller_id,
-                item_id,
-                quantity,
-                price,
-                buyer_id
-            FROM sell_orders
-            WHERE id = ?1",
+    fn delete_sell_order(&self, order_id: i64) -> Result<()> {
+        self.db.execute(
+            "DELETE FROM sell_order_items WHERE sell_order_id = ?1",
+            [order_id],
         )?;
-        stmt.query_row([order_id], |row| {
-            let buyer_id: Option<i64> = row.get(4)?;
-            Ok(SellOrderEntry {
-                seller_id: UserId(row.get(0)?),
-                item_id: row.get(1)?,
-                quantity: row.get(2)?,
-                price: row.get(3)?,
-                buyer_id: buyer_id.map(UserId),
-            })
-        })
+        self.db
+            .execute("DELETE FROM sell_orders WHERE id = ?1", [order_id])?;
+        Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use parameterized::parameterized;
-    use pretty_assertions::assert_eq;
 
-    // "2021-01-01 00:00"
-    const EXPIRATION_TIME: i64 = 1609459200;
+    // Notifies users watching the seller or the items of the new order, offline users via the
+    // mailbox. Every user is notified once, about the earliest matching watch. Sellers aren't notified
+    // about their own orders
+    fn notify_watchers(
+        &self,
+        order: &SellOrder,
+        seller_id: UserId,
+        lines: &[(i64, i64)],
+        price_item_id: i64,
+    ) -> Result<()> {
+        let mut watches = self.query_watches(
+            "WHERE watches.seller_id = ?1 AND watches.user_id != ?1",
+            [seller_id.0],
+        )?;
+        // Prices of other currencies and barters aren't comparable to watched prices in funds.
+        // The price of a bundle isn't split between its items, so bundles don't match item watches
+        if price_item_id == self.funds_item_id && lines.len() == 1 {
+            let (item_id, quantity) = lines[0];
+            watches.extend(self.query_watches(
+                "WHERE watches.item_id = ?1 AND watches.price * ?2 > ?3 AND watches.user_id != ?4",
+                [item_id, quantity, order.price, seller_id.0],
+            )?);
+        }
+        watches.sort_by_key(|entry| entry.watch.id);
+        let mut notified = HashSet::new();
+        watches.retain(|entry| notified.insert(entry.user_id.0));
+
+        for entry in watches {
+            self.notify_user(
+                entry.user_id,
+                MarketEvent::WatchTriggered {
+                    watcher: entry.username,
+                    watch: entry.watch,
+                    order: order.clone(),
+                },
+            )?;
+        }
+        Ok(())
+    }
 
-    #[test]
-    fn login() {
-        let storage = Storage::open(":memory:").unwrap();
+    // Delivers the personal event to the user's sessions, or to the mailbox if the user is offline
+    fn notify_user(&self, user_id: UserId, event: MarketEvent) -> Result<()> {
+        if self.is_online(user_id) {
+            self.send_event(Some(user_id), event);
+            return Ok(());
+        }
+        self.store_in_mailbox(user_id, &event)
+    }
 
-        for _ in 0..3 {
-            assert_eq!(
-                storage.login("test1").unwrap(),
-                User {
-                    id: UserId(1),
-                    username: "test1".into()
-                }
-            );
-            assert_eq!(
-                storage.login("test2").unwrap(),
-                User {
-                    id: UserId(2),
-                    username: "test2".into()
-                }
-            );
-            assert_eq!(
-                storage.login("test3").unwrap(),
-                User {
-                    id: UserId(3),
-                    username: "test3".into()
-                }
-            );
+    fn is_online(&self, user_id: UserId) -> bool {
+        let mut sessions = self.sessions.borrow_mut();
+        let Some(senders) = sessions.get_mut(&user_id.0) else {
+            return false;
+        };
+        senders.retain(|sender| !sender.is_closed());
+        if senders.is_empty() {
+            sessions.remove(&user_id.0);
+            return false;
         }
+        true
+    }
 
-        assert_eq!(
-            storage.login("").unwrap_err().to_string(),
-            "Username cannot be empty"
-        );
+    fn store_in_mailbox(&self, user_id: UserId, event: &MarketEvent) -> Result<()> {
+        let event = serde_json::to_string(event)
+            .map_err(|err| rusqlite::Error::ToSqlConversionFailure(err.into()))?;
+        self.db.execute(
+            "INSERT INTO mailbox (user_id, event) VALUES (?1, ?2)",
+            (user_id.0, event),
+        )?;
+        Ok(())
     }
 
-    #[test]
-    fn funds() {
-        let storage = Storage::open(":memory:").unwrap();
+    // Queries watches with their users, filtered by the `condition`
+    fn query_watches(
+        &self,
+        condition: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<WatchEntry>> {
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT watches.id, watches.user_id, users.username, items.name, watches.price, sellers.username
+            FROM watches
+            INNER JOIN users ON watches.user_id = users.id
+            LEFT JOIN items ON watches.item_id = items.id
+            LEFT JOIN users AS sellers ON watches.seller_id = sellers.id
+            {condition}
+            ORDER BY watches.id"
+        ))?;
+        let watches = stmt
+            .query_map(params, |row| {
+                let kind = match row.get::<_, Option<String>>(3)? {
+                    Some(item_name) => WatchKind::ItemBelow {
+                        item_name,
+                        price: row.get(4)?,
+                    },
+                    None => WatchKind::Seller {
+                        seller_name: row.get(5)?,
+                    },
+                };
+                Ok(WatchEntry {
+                    user_id: UserId(row.get(1)?),
+                    username: row.get(2)?,
+                    watch: Watch {
+                        id: row.get(0)?,
+                        kind,
+                    },
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(watches)
+    }
 
-        // freshly created user always has 0 funds
-        let user1 = storage.login("user1").unwrap();
-        assert_eq!(
-            storage.view_items(user1.id).unwrap(),
-            vec![("funds".into(), 0)]
-        );
+    // Item lines of the order, in the order they were listed
+    fn get_sell_order_lines(&self, order_id: i64) -> Result<Vec<(i64, i64)>> {
+        let mut stmt = self.db.prepare(
+            "SELECT item_id, quantity FROM sell_order_items WHERE sell_order_id = ?1 ORDER BY rowid",
+        )?;
+        let lines = stmt
+            .query_map([order_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(lines)
+    }
 
-        assert!(storage.deposit(user1.id, "funds", 10).is_ok());
-        assert_eq!(
-            storage.view_items(user1.id).unwrap(),
-            vec![("funds".into(), 10)]
-        );
+    fn get_offer(&self, offer_id: i64) -> Result<Offer> {
+        self.query_offers("WHERE offers.id = ?1", [offer_id])?
+            .pop()
+            .ok_or(Error::OfferNotFound(offer_id))
+    }
 
-        assert!(storage.withdraw(user1.id, "funds", 7).is_ok());
-        assert_eq!(
-            storage.view_items(user1.id).unwrap(),
-            vec![("funds".into(), 3)]
-        );
+    // Queries offers with all the details, filtered by the `condition`
+    fn query_offers(&self, condition: &str, params: impl rusqlite::Params) -> Result<Vec<Offer>> {
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT
+                offers.id,
+                senders.username,
+                recipients.username,
+                DATETIME(offers.expiration_time, 'unixepoch')
+            FROM offers
+            INNER JOIN users AS senders ON offers.sender_id = senders.id
+            INNER JOIN users AS recipients ON offers.recipient_id = recipients.id
+            {condition}
+            ORDER BY offers.id"
+        ))?;
+        let mut offers = stmt
+            .query_map(params, |row| {
+                Ok(Offer {
+                    id: row.get(0)?,
+                    sender: row.get(1)?,
+                    recipient: row.get(2)?,
+                    give: Vec::new(),
+                    want: Vec::new(),
+                    expiration_time: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
 
-        // Withdraw all funds doesn't remove the record
-        assert!(storage.withdraw(user1.id, "funds", 3).is_ok());
-        assert_eq!(
-            storage.view_items(user1.id).unwrap(),
-            vec![("funds".into(), 0)]
-        );
+        let mut stmt = self.db.prepare(
+            "SELECT offer_items.side, items.name, offer_items.quantity
+            FROM offer_items
+            INNER JOIN items ON offer_items.item_id = items.id
+            WHERE offer_items.offer_id = ?1
+            ORDER BY offer_items.rowid",
+        )?;
+        for offer in &mut offers {
+            let items = stmt
+                .query_map([offer.id], |row| {
+                    let side: String = row.get(0)?;
+                    let item = Item {
+                        name: row.get(1)?,
+                        quantity: row.get(2)?,
+                    };
+                    Ok((side, item))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            for (side, item) in items {
+                match side.as_str() {
+                    "give" => offer.give.push(item),
+                    _ => offer.want.push(item),
+                }
+            }
+        }
+        Ok(offers)
+    }
 
-        assert!(storage.deposit(user1.id, "funds", 3).is_ok());
+    fn get_offer_entry(&self, offer_id: i64) -> Result<OfferEntry> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT sender_id, recipient_id, expiration_time FROM offers WHERE id = ?1")?;
+        let (sender_id, recipient_id, expiration_time) = match stmt.query_row([offer_id], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        }) {
+            Ok(offer) => offer,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                return Err(Error::OfferNotFound(offer_id))
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut entry = OfferEntry {
+            sender_id: UserId(sender_id),
+            recipient_id: UserId(recipient_id),
+            give: Vec::new(),
+            want: Vec::new(),
+            expiration_time,
+        };
+        let mut stmt = self
+            .db
+            .prepare("SELECT side, item_id, quantity FROM offer_items WHERE offer_id = ?1")?;
+        let items = stmt
+            .query_map([offer_id], |row| {
+                Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (side, item_id, quantity) in items {
+            match side.as_str() {
+                "give" => entry.give.push((item_id, quantity)),
+                _ => entry.want.push((item_id, quantity)),
+            }
+        }
+        Ok(entry)
+    }
+
+    fn delete_offer(&self, offer_id: i64) -> Result<()> {
+        self.db
+            .execute("DELETE FROM offer_items WHERE offer_id = ?1", [offer_id])?;
+        self.db
+            .execute("DELETE FROM offers WHERE id = ?1", [offer_id])?;
+        Ok(())
+    }
+
+    fn record_transaction(
+        &self,
+        kind: TransactionKind,
+        sender_id: UserId,
+        recipient_id: UserId,
+        item_id: i64,
+        quantity: i64,
+        unix_now: i64,
+    ) -> Result<()> {
+        let kind = match kind {
+            TransactionKind::Give => "give",
+            TransactionKind::Trade => "trade",
+        };
+        self.db.execute(
+            "INSERT INTO transactions (kind, sender_id, recipient_id, item_id, quantity, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            (kind, sender_id.0, recipient_id.0, item_id, quantity, unix_now),
+        )?;
+        Ok(())
+    }
+
+    // Looks up the item by its name or alias, ignoring case and extra whitespace
+    fn get_item_id(&self, item_name: &str) -> Result<i64> {
+        let mut stmt = self.db.prepare(
+            "SELECT COALESCE(
+                (SELECT id FROM items WHERE normalized_name = ?1),
+                (SELECT item_id FROM item_aliases WHERE normalized_name = ?1)
+            )",
+        )?;
+        let item_id: Option<i64> =
+            stmt.query_row([normalize_item_name(item_name)], |row| row.get(0))?;
+        item_id.ok_or_else(|| Error::ItemNotFound {
+            item_name: item_name.to_owned(),
+            suggestions: Vec::new(),
+        })
+    }
+
+    // Same as `get_item_id`, but suggests similar names if there is no such item
+    fn find_item_id(&self, item_name: &str) -> Result<i64> {
+        match self.get_item_id(item_name) {
+            Err(Error::ItemNotFound { item_name, .. }) => Err(Error::ItemNotFound {
+                suggestions: self.suggest_item_names(&item_name)?,
+                item_name,
+            }),
+            result => result,
+        }
+    }
+
+    // Names of items whose name or alias is within a few typos from `item_name`, closest first.
+    // Swapped adjacent letters count as a single typo
+    fn suggest_item_names(&self, item_name: &str) -> Result<Vec<String>> {
+        const MAX_SUGGESTIONS: usize = 3;
+
+        let key = normalize_item_name(item_name);
+        let max_distance = (key.chars().count() / 3).max(1);
+        let mut stmt = self.db.prepare(
+            "SELECT name, normalized_name FROM items
+            UNION ALL
+            SELECT items.name, item_aliases.normalized_name
+            FROM item_aliases
+            INNER JOIN items ON item_aliases.item_id = items.id",
+        )?;
+        let mut candidates = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .filter_map(|candidate| match candidate {
+                Ok((name, normalized_name)) => {
+                    let distance = strsim::damerau_levenshtein(&key, &normalized_name);
+                    (distance <= max_distance).then_some(Ok((distance, name)))
+                }
+                Err(err) => Some(Err(err)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        candidates.sort();
+        let mut suggestions: Vec<String> = Vec::new();
+        for (_, name) in candidates {
+            if !suggestions.contains(&name) {
+                suggestions.push(name);
+            }
+        }
+        suggestions.truncate(MAX_SUGGESTIONS);
+        Ok(suggestions)
+    }
+
+    // Returns the item a user can deposit, creating it if there is no such item yet. In the strict
+    // catalog mode only items from the catalog and currencies can be deposited
+    fn get_depositable_item_id(&self, item_name: &str) -> Result<i64> {
+        let item_id = match self.get_item_id(item_name) {
+            Ok(item_id) => item_id,
+            Err(Error::ItemNotFound { .. }) if self.strict_catalog => {
+                return Err(Error::UnknownItem(item_name.to_owned()))
+            }
+            Err(Error::ItemNotFound { .. }) => self.get_or_create_item(item_name)?,
+            Err(err) => return Err(err),
+        };
+        let rules = self.get_item_rules(item_id)?;
+        if self.strict_catalog && !rules.in_catalog && !rules.is_currency {
+            return Err(Error::UnknownItem(item_name.to_owned()));
+        }
+        Ok(item_id)
+    }
+
+    // Returns the item with the given name, creating it if there is no such item yet
+    fn get_or_create_item(&self, item_name: &str) -> Result<i64> {
+        let name = tidy_item_name(item_name);
+        if name.is_empty() {
+            return Err(Error::EmptyItemName);
+        }
+        match self.get_item_id(&name) {
+            Err(Error::ItemNotFound { .. }) => {
+                self.db.execute(
+                    "INSERT INTO items (name, normalized_name) VALUES (?1, ?2)",
+                    [&name, &normalize_item_name(&name)],
+                )?;
+                Ok(self.db.last_insert_rowid())
+            }
+            result => result,
+        }
+    }
+
+    fn get_item_rules(&self, item_id: i64) -> Result<ItemRules> {
+        let mut stmt = self.db.prepare(
+            "SELECT name, is_currency, in_catalog, max_stack, tradeable FROM items WHERE id = ?1",
+        )?;
+        Ok(stmt.query_row([item_id], |row| {
+            Ok(ItemRules {
+                name: row.get(0)?,
+                is_currency: row.get(1)?,
+                in_catalog: row.get(2)?,
+                max_stack: row.get(3)?,
+                tradeable: row.get(4)?,
+            })
+        })?)
+    }
+
+    fn check_tradeable(&self, item_id: i64) -> Result<()> {
+        let rules = self.get_item_rules(item_id)?;
+        if !rules.tradeable {
+            return Err(Error::NotTradeable(rules.name));
+        }
+        Ok(())
+    }
+
+    /// Checks that the user can hold `quantity` more items, instances of the item included.
+    /// Purchases, bids, deposits and received items enforce the limit. Refunds, returned items and
+    /// payments ignore it, the user has already owned or earned them and can't refuse them
+    fn check_stack(&self, user_id: UserId, item_id: i64, quantity: i64) -> Result<()> {
+        let rules = self.get_item_rules(item_id)?;
+        if let Some(max_stack) = rules.max_stack {
+            let instances: i64 = self.db.query_row(
+                "SELECT COUNT(*) FROM item_instances WHERE owner_id = ?1 AND item_id = ?2",
+                [user_id.0, item_id],
+                |row| row.get(0),
+            )?;
+            if self.get_user_item_quantity(user_id, item_id)? + instances + quantity > max_stack {
+                return Err(Error::StackLimitExceeded {
+                    item_name: rules.name,
+                    max_stack,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn is_currency(&self, item_id: i64) -> Result<bool> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT is_currency = 1 FROM items WHERE id = ?1")?;
+        Ok(stmt.query_row([item_id], |row| row.get(0))?)
+    }
+
+    fn get_user_id(&self, username: &str) -> Result<UserId> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT id FROM users WHERE username = ?1")?;
+        match stmt.query_row([username], |row| row.get(0)) {
+            Ok(user_id) => Ok(UserId(user_id)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                Err(Error::UserNotFound(username.to_owned()))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn get_username(&self, user_id: UserId) -> Result<String> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT username FROM users WHERE id = ?1")?;
+        Ok(stmt.query_row([user_id.0], |row| row.get(0))?)
+    }
+
+    fn get_item_name(&self, item_id: i64) -> Result<String> {
+        let mut stmt = self.db.prepare("SELECT name FROM items WHERE id = ?1")?;
+        Ok(stmt.query_row([item_id], |row| row.get(0))?)
+    }
+
+    fn get_user_item_quantity(&self, user_id: UserId, item_id: i64) -> Result<i64> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT quantity FROM user_items WHERE user_id = ?1 AND item_id = ?2")?;
+        let quantity = match stmt.query_row([user_id.0, item_id], |row| row.get(0)) {
+            Ok(quantity) => quantity,
+            Err(rusqlite::Error::QueryReturnedNoRows) => 0,
+            Err(err) => Err(err)?,
+        };
+        Ok(quantity)
+    }
+
+    fn deposit_inner(&self, user_id: UserId, item_id: i64, quantity: i64) -> Result<()> {
+        self.db.execute(
+            "INSERT INTO user_items (user_id, item_id, quantity)
+            VALUES (?1, ?2, ?3)
+            ON CONFLICT (user_id, item_id) DO UPDATE SET quantity = quantity + ?3",
+            [user_id.0, item_id, quantity],
+        )?;
+        Ok(())
+    }
+
+    fn withdraw_inner(&self, user_id: UserId, item_id: i64, quantity: i64) -> Result<()> {
+        // if quantity reaches zero - remove the record from the table for all items except funds
+        let current_quantity = self.get_user_item_quantity(user_id, item_id)?;
+        if current_quantity < quantity {
+            return Err(if item_id == self.funds_item_id {
+                Error::InsufficientFunds {
+                    required: quantity,
+                    available: current_quantity,
+                }
+            } else {
+                Error::InsufficientItems {
+                    item_name: self.get_item_name(item_id)?,
+                    required: quantity,
+                    available: current_quantity,
+                }
+            });
+        }
+
+        // Keep the record about funds even if the balance reaches zero
+        if current_quantity > quantity || item_id == self.funds_item_id {
+            self.db.execute(
+                "UPDATE user_items SET quantity = quantity - ?3
+                WHERE user_id = ?1 AND item_id = ?2",
+                [user_id.0, item_id, quantity],
+            )?;
+        } else {
+            self.db.execute(
+                "DELETE FROM user_items WHERE user_id = ?1 AND item_id = ?2",
+                [user_id.0, item_id],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn get_sell_oder_entry(&self, order_id: i64) -> Result<SellOrderEntry> {
+        let mut stmt = self.db.prepare(
+            "SELECT
+                seller_id,
+                item_id,
+                quantity,
+                price,
+                price_item_id,
+                buyer_id,
+                instance_id
+            FROM sell_orders
+            WHERE id = ?1",
+        )?;
+        let entry = stmt.query_row([order_id], |row| {
+            let buyer_id: Option<i64> = row.get(5)?;
+            Ok(SellOrderEntry {
+                seller_id: UserId(row.get(0)?),
+                lines: vec![(row.get(1)?, row.get(2)?)],
+                price: row.get(3)?,
+                price_item_id: row.get(4)?,
+                instance_id: row.get(6)?,
+                buyer_id: buyer_id.map(UserId),
+            })
+        });
+        match entry {
+            Ok(mut entry) => {
+                if entry.instance_id.is_none() {
+                    entry.lines = self.get_sell_order_lines(order_id)?;
+                }
+                Ok(entry)
+            }
+            Err(rusqlite::Error::QueryReturnedNoRows) => Err(Error::OrderNotFound(order_id)),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+// Spelling of an item name as it's stored: in the canonical Unicode form, with leading, trailing
+// and repeated whitespace removed
+fn tidy_item_name(name: &str) -> String {
+    name.nfc()
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Key two item names are the same item by: the tidy name in the compatibility Unicode form and
+// lowercase, so "Sword", " sword " and "ＳＷＯＲＤ" all refer to "sword"
+fn normalize_item_name(name: &str) -> String {
+    let name: String = name.nfkc().flat_map(char::to_lowercase).collect();
+    tidy_item_name(&name)
+}
+
+// Names that are empty once normalized can't refer to any item
+fn check_item_name(item_name: &str) -> Result<()> {
+    if normalize_item_name(item_name).is_empty() {
+        return Err(Error::EmptyItemName);
+    }
+    Ok(())
+}
+
+fn did_you_mean(suggestions: &[String]) -> String {
+    match suggestions {
+        [] => String::new(),
+        [suggestion] => format!(", did you mean '{suggestion}'?"),
+        [suggestions @ .., last] => {
+            let suggestions = suggestions
+                .iter()
+                .map(|suggestion| format!("'{suggestion}'"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!(", did you mean {suggestions} or '{last}'?")
+        }
+    }
+}
+
+// Adds the column to a table created by an older version of the server
+fn add_column_if_missing(
+    db: &rusqlite::Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> rusqlite::Result<()> {
+    let exists: bool = db.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        [table, column],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        db.execute(
+            &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"),
+            (),
+        )?;
+    }
+    Ok(())
+}
+
+/// Merges items with the same normalized name into the oldest of them. Everything referring to the
+/// newer items is moved to the oldest one, quantities of the same holder are added up
+fn merge_duplicate_items(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "BEGIN;
+        CREATE TEMP TABLE merged_items AS
+          SELECT items.id AS item_id, kept.id AS kept_id
+          FROM items
+          INNER JOIN (
+            SELECT MIN(id) AS id, normalized_name FROM items GROUP BY normalized_name
+          ) AS kept ON kept.normalized_name = items.normalized_name
+          WHERE items.id != kept.id;
+
+        INSERT INTO user_items (user_id, item_id, quantity)
+        SELECT user_id, kept_id, quantity
+        FROM user_items INNER JOIN merged_items USING (item_id)
+        WHERE true
+        ON CONFLICT (user_id, item_id) DO UPDATE SET quantity = quantity + excluded.quantity;
+        DELETE FROM user_items WHERE item_id IN (SELECT item_id FROM merged_items);
+
+        INSERT INTO sell_order_items (sell_order_id, item_id, quantity)
+        SELECT sell_order_id, kept_id, quantity
+        FROM sell_order_items INNER JOIN merged_items USING (item_id)
+        WHERE true
+        ON CONFLICT (sell_order_id, item_id) DO UPDATE SET quantity = quantity + excluded.quantity;
+        DELETE FROM sell_order_items WHERE item_id IN (SELECT item_id FROM merged_items);
+
+        INSERT INTO offer_items (offer_id, side, item_id, quantity)
+        SELECT offer_id, side, kept_id, quantity
+        FROM offer_items INNER JOIN merged_items USING (item_id)
+        WHERE true
+        ON CONFLICT (offer_id, side, item_id) DO UPDATE SET quantity = quantity + excluded.quantity;
+        DELETE FROM offer_items WHERE item_id IN (SELECT item_id FROM merged_items);
+
+        UPDATE sell_orders
+        SET item_id = (SELECT kept_id FROM merged_items WHERE item_id = sell_orders.item_id)
+        WHERE item_id IN (SELECT item_id FROM merged_items);
+        UPDATE sell_orders
+        SET price_item_id = (SELECT kept_id FROM merged_items WHERE item_id = sell_orders.price_item_id)
+        WHERE price_item_id IN (SELECT item_id FROM merged_items);
+        UPDATE trades
+        SET item_id = (SELECT kept_id FROM merged_items WHERE item_id = trades.item_id)
+        WHERE item_id IN (SELECT item_id FROM merged_items);
+        UPDATE trades
+        SET price_item_id = (SELECT kept_id FROM merged_items WHERE item_id = trades.price_item_id)
+        WHERE price_item_id IN (SELECT item_id FROM merged_items);
+        UPDATE item_instances
+        SET item_id = (SELECT kept_id FROM merged_items WHERE item_id = item_instances.item_id)
+        WHERE item_id IN (SELECT item_id FROM merged_items);
+        UPDATE transactions
+        SET item_id = (SELECT kept_id FROM merged_items WHERE item_id = transactions.item_id)
+        WHERE item_id IN (SELECT item_id FROM merged_items);
+        UPDATE watches
+        SET item_id = (SELECT kept_id FROM merged_items WHERE item_id = watches.item_id)
+        WHERE item_id IN (SELECT item_id FROM merged_items);
+        UPDATE item_aliases
+        SET item_id = (SELECT kept_id FROM merged_items WHERE item_id = item_aliases.item_id)
+        WHERE item_id IN (SELECT item_id FROM merged_items);
+
+        DELETE FROM items WHERE id IN (SELECT item_id FROM merged_items);
+        DROP TABLE merged_items;
+        COMMIT;",
+    )
+}
+
+#[cfg(test)]
+#[allow(clippy::nonminimal_bool)]
+mod tests {
+    use super::*;
+    use parameterized::parameterized;
+    use pretty_assertions::assert_eq;
+
+    // "2021-01-01 00:00"
+    const EXPIRATION_TIME: i64 = 1609459200;
+
+    #[test]
+    fn login() {
+        let storage = Storage::open(":memory:").unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(
+                storage.login("test1").unwrap(),
+                User {
+                    id: UserId(1),
+                    username: "test1".into()
+                }
+            );
+            assert_eq!(
+                storage.login("test2").unwrap(),
+                User {
+                    id: UserId(2),
+                    username: "test2".into()
+                }
+            );
+            assert_eq!(
+                storage.login("test3").unwrap(),
+                User {
+                    id: UserId(3),
+                    username: "test3".into()
+                }
+            );
+        }
+
+        assert_eq!(
+            storage.login("").unwrap_err().to_string(),
+            "Username cannot be empty"
+        );
+    }
+
+    #[test]
+    fn sessions() {
+        let storage = Storage::open(":memory:").unwrap();
+
+        let user1 = storage.login("user1").unwrap();
+        let user2 = storage.login("user2").unwrap();
+        let token1 = storage.create_session(user1.id, EXPIRATION_TIME).unwrap();
+        let token2 = storage.create_session(user2.id, EXPIRATION_TIME).unwrap();
+        assert_ne!(token1, token2);
+        assert_eq!(token1.len(), 32);
+
+        assert_eq!(
+            storage
+                .get_session_user(&token1, EXPIRATION_TIME - 1)
+                .unwrap(),
+            user1
+        );
+        assert_eq!(
+            storage
+                .get_session_user(&token2, EXPIRATION_TIME - 1)
+                .unwrap(),
+            user2
+        );
+        assert!(matches!(
+            storage.get_session_user("invalid", EXPIRATION_TIME - 1),
+            Err(Error::InvalidToken)
+        ));
+
+        // tokens can't be used once expired or deleted
+        assert!(matches!(
+            storage.get_session_user(&token1, EXPIRATION_TIME),
+            Err(Error::InvalidToken)
+        ));
+        assert!(storage.delete_session(&token2).is_ok());
+        assert!(matches!(
+            storage.get_session_user(&token2, EXPIRATION_TIME - 1),
+            Err(Error::InvalidToken)
+        ));
+        assert!(matches!(
+            storage.delete_session(&token2),
+            Err(Error::InvalidToken)
+        ));
+        assert!(storage.process_expired_sessions(EXPIRATION_TIME).is_ok());
+        assert!(matches!(
+            storage.delete_session(&token1),
+            Err(Error::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn funds() {
+        let storage = Storage::open(":memory:").unwrap();
+
+        // freshly created user always has 0 funds
+        let user1 = storage.login("user1").unwrap();
+        assert_eq!(
+            storage.view_items(user1.id).unwrap(),
+            vec![("funds".into(), 0)]
+        );
+
+        assert!(storage.deposit(user1.id, "funds", 10).is_ok());
+        assert_eq!(
+            storage.view_items(user1.id).unwrap(),
+            vec![("funds".into(), 10)]
+        );
+
+        assert!(storage.withdraw(user1.id, "funds", 7).is_ok());
+        assert_eq!(
+            storage.view_items(user1.id).unwrap(),
+            vec![("funds".into(), 3)]
+        );
+
+        // Withdraw all funds doesn't remove the record
+        assert!(storage.withdraw(user1.id, "funds", 3).is_ok());
+        assert_eq!(
+            storage.view_items(user1.id).unwrap(),
+            vec![("funds".into(), 0)]
+        );
+
+        assert!(storage.deposit(user1.id, "funds", 3).is_ok());
 
         // get_or_create_user should not create new funds
         let user = storage.login("user1").unwrap();
         assert_eq!(
-            storage.view_items(user.id).unwrap(),
-            vec![("funds".into(), 3)]
+            storage.view_items(user.id).unwrap(),
+            vec![("funds".into(), 3)]
+        );
+
+        // Withdraw more than we have
+        assert!(!storage.withdraw(user.id, "funds", 10).is_ok());
+        // Deposit negative amount
+        assert!(!storage.deposit(user.id, "funds", -10).is_ok());
+        // Withdraw negative amount
+        assert!(!storage.withdraw(user.id, "funds", -10).is_ok());
+        // Deposit zero
+        assert!(!storage.deposit(user.id, "funds", 0).is_ok());
+        // Withdraw zero
+        assert!(!storage.withdraw(user.id, "funds", 0).is_ok());
+        // Nothing should change
+        assert_eq!(
+            storage.view_items(user.id).unwrap(),
+            vec![("funds".into(), 3)]
+        );
+
+        // deposit to non-existing user
+        assert!(!storage.deposit(UserId(100), "funds", 10).is_ok());
+        assert!(!storage.withdraw(UserId(100), "funds", 10).is_ok());
+
+        // and check that we can deposit and withdraw from different users
+        let user2 = storage.login("user2").unwrap();
+        assert!(storage.deposit(user2.id, "funds", 20).is_ok());
+
+        let user3 = storage.login("user3").unwrap();
+        assert!(storage.deposit(user3.id, "funds", 30).is_ok());
+
+        let user1 = storage.login("user1").unwrap();
+        assert_eq!(
+            storage.view_items(user1.id).unwrap(),
+            vec![("funds".into(), 3)]
+        );
+
+        let user2 = storage.login("user2").unwrap();
+        assert_eq!(
+            storage.view_items(user2.id).unwrap(),
+            vec![("funds".into(), 20)]
+        );
+
+        let user3 = storage.login("user3").unwrap();
+        assert_eq!(
+            storage.view_items(user3.id).unwrap(),
+            vec![("funds".into(), 30)]
+        );
+
+        // Big numbers also are ok
+        assert!(storage.deposit(user1.id, "funds", 100500).is_ok());
+        assert!(storage.withdraw(user1.id, "funds", 100400).is_ok());
+    }
+
+    #[test]
+    fn items() {
+        let storage = Storage::open(":memory:").unwrap();
+
+        let user = storage.login("user1").unwrap();
+        // freshly created user always has 0 items
+        assert_eq!(
+            storage.view_items(user.id).unwrap(),
+            vec![("funds".into(), 0)]
+        );
+
+        assert!(storage.deposit(user.id, "item1", 10).is_ok());
+        assert_eq!(
+            storage.view_items(user.id).unwrap(),
+            vec![("funds".into(), 0), ("item1".into(), 10)]
+        );
+
+        assert!(storage.deposit(user.id, "item2", 20).is_ok());
+        assert_eq!(
+            storage.view_items(user.id).unwrap(),
+            vec![
+                ("funds".into(), 0),
+                ("item1".into(), 10),
+                ("item2".into(), 20)
+            ]
+        );
+
+        assert!(storage.withdraw(user.id, "item1", 5).is_ok());
+        assert_eq!(
+            storage.view_items(user.id).unwrap(),
+            vec![
+                ("funds".into(), 0),
+                ("item1".into(), 5),
+                ("item2".into(), 20)
+            ]
+        );
+
+        assert!(storage.withdraw(user.id, "item2", 10).is_ok());
+        assert_eq!(
+            storage.view_items(user.id).unwrap(),
+            vec![
+                ("funds".into(), 0),
+                ("item1".into(), 5),
+                ("item2".into(), 10)
+            ]
+        );
+
+        // withdraw to zero should remove the record
+        assert!(storage.withdraw(user.id, "item1", 5).is_ok());
+        assert_eq!(
+            storage.view_items(user.id).unwrap(),
+            vec![("funds".into(), 0), ("item2".into(), 10)]
+        );
+
+        // login with the same username should return the same user with the same items
+        let user = storage.login("user1").unwrap();
+        assert_eq!(
+            storage.view_items(user.id).unwrap(),
+            vec![("funds".into(), 0), ("item2".into(), 10)]
+        );
+
+        // Withdraw more than we have
+        assert!(!storage.withdraw(user.id, "item2", 20).is_ok());
+
+        // Negative quantity
+        assert!(!storage.deposit(user.id, "item2", -10).is_ok());
+        assert!(!storage.withdraw(user.id, "item2", -10).is_ok());
+
+        // Zero quantity
+        assert!(!storage.deposit(user.id, "item2", 0).is_ok());
+        assert!(!storage.withdraw(user.id, "item2", 0).is_ok());
+
+        // empty item name
+        assert!(!storage.deposit(user.id, "", 10).is_ok());
+        assert!(!storage.withdraw(user.id, "", 10).is_ok());
+
+        // Nothing should change
+        assert_eq!(
+            storage.view_items(user.id).unwrap(),
+            vec![("funds".into(), 0), ("item2".into(), 10)]
+        );
+
+        // deposit to non-existing user
+        assert!(!storage.deposit(UserId(100), "item1", 10).is_ok());
+        assert!(!storage.withdraw(UserId(100), "item1", 10).is_ok());
+    }
+
+    #[parameterized(order_type = {
+        SellOrderType::Immediate,
+        SellOrderType::Auction,
+    })]
+    fn test_sell_orders_negative(order_type: SellOrderType) {
+        // workaround for resolution ambiguity caused by `parameterized` macro
+        use pretty_assertions::assert_eq;
+
+        let storage = Storage::open(":memory:").unwrap();
+
+        let user = storage.login("user").unwrap();
+        assert!(storage.deposit(user.id, "funds", 100).is_ok());
+        assert!(storage.deposit(user.id, "item1", 10).is_ok());
+        assert!(storage.deposit(user.id, "item2", 20).is_ok());
+        assert_eq!(
+            storage.view_items(user.id).unwrap(),
+            vec![
+                ("funds".into(), 100),
+                ("item1".into(), 10),
+                ("item2".into(), 20)
+            ]
+        );
+
+        // more than we have
+        assert!(storage
+            .place_sell_order(
+                order_type,
+                user.id,
+                "item1",
+                110,
+                10,
+                "funds",
+                EXPIRATION_TIME
+            )
+            .is_err());
+        // negative quantity
+        assert!(storage
+            .place_sell_order(
+                order_type,
+                user.id,
+                "item1",
+                -10,
+                10,
+                "funds",
+                EXPIRATION_TIME
+            )
+            .is_err());
+        // negative price
+        assert!(storage
+            .place_sell_order(
+                order_type,
+                user.id,
+                "item1",
+                10,
+                -10,
+                "funds",
+                EXPIRATION_TIME
+            )
+            .is_err());
+        // non-existing item
+        assert!(storage
+            .place_sell_order(
+                order_type,
+                user.id,
+                "non-existing-item",
+                1,
+                10,
+                "funds",
+                EXPIRATION_TIME
+            )
+            .is_err());
+        // non-existing user
+        assert!(storage
+            .place_sell_order(
+                order_type,
+                UserId(100),
+                "item1",
+                1,
+                10,
+                "funds",
+                EXPIRATION_TIME
+            )
+            .is_err());
+        // funds
+        assert!(storage
+            .place_sell_order(order_type, user.id, "funds", 1, 2, "funds", EXPIRATION_TIME)
+            .is_err());
+
+        // Finally, nothing should be changed
+        assert_eq!(storage.view_sell_orders(None).unwrap(), vec![]);
+    }
+
+    #[parameterized(order_type = {
+        SellOrderType::Immediate,
+        SellOrderType::Auction,
+    })]
+    fn test_sell_orders_positive(order_type: SellOrderType) {
+        // workaround for resolution ambiguity caused by `parameterized` macro
+        use pretty_assertions::assert_eq;
+
+        let storage = Storage::open(":memory:").unwrap();
+
+        let user = storage.login("user").unwrap();
+        assert!(storage.deposit(user.id, "funds", 100).is_ok());
+        assert!(storage.deposit(user.id, "item1", 10).is_ok());
+        assert!(storage.deposit(user.id, "item2", 20).is_ok());
+        assert_eq!(
+            storage.view_items(user.id).unwrap(),
+            vec![
+                ("funds".into(), 100),
+                ("item1".into(), 10),
+                ("item2".into(), 20)
+            ]
+        );
+
+        for i in 1..10 {
+            assert!(storage
+                .place_sell_order(
+                    order_type,
+                    user.id,
+                    "item1",
+                    1,
+                    10 + i,
+                    "funds",
+                    EXPIRATION_TIME
+                )
+                .is_ok());
+            assert_eq!(
+                storage.view_items(user.id).unwrap(),
+                vec![
+                    ("funds".into(), 100 - i /* fee is 1 for each order */),
+                    ("item1".into(), 10 - i),
+                    ("item2".into(), 20)
+                ]
+            );
+        }
+        assert!(storage
+            .place_sell_order(
+                order_type,
+                user.id,
+                "item2",
+                15,
+                100,
+                "funds",
+                EXPIRATION_TIME
+            )
+            .is_ok());
+        assert_eq!(
+            storage.view_items(user.id).unwrap(),
+            vec![
+                ("funds".into(), 100 - 9 - 6),
+                ("item1".into(), 1),
+                ("item2".into(), 5)
+            ]
+        );
+
+        // Item entry dissapears when quantity reaches zero
+        assert!(storage
+            .place_sell_order(
+                order_type,
+                user.id,
+                "item2",
+                5,
+                120,
+                "funds",
+                EXPIRATION_TIME + 1
+            )
+            .is_ok());
+        assert_eq!(
+            storage.view_items(user.id).unwrap(),
+            vec![("funds".into(), 100 - 9 - 6 - 7), ("item1".into(), 1)]
+        );
+
+        pretty_assertions::assert_eq!(
+            storage.view_sell_orders(None).unwrap(),
+            vec![
+                SellOrder {
+                    id: 1,
+                    seller_name: "user".into(),
+                    item_name: "item1".into(),
+                    quantity: 1,
+                    price: 11,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+                SellOrder {
+                    id: 2,
+                    seller_name: "user".into(),
+                    item_name: "item1".into(),
+                    quantity: 1,
+                    price: 12,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+                SellOrder {
+                    id: 3,
+                    seller_name: "user".into(),
+                    item_name: "item1".into(),
+                    quantity: 1,
+                    price: 13,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+                SellOrder {
+                    id: 4,
+                    seller_name: "user".into(),
+                    item_name: "item1".into(),
+                    quantity: 1,
+                    price: 14,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+                SellOrder {
+                    id: 5,
+                    seller_name: "user".into(),
+                    item_name: "item1".into(),
+                    quantity: 1,
+                    price: 15,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+                SellOrder {
+                    id: 6,
+                    seller_name: "user".into(),
+                    item_name: "item1".into(),
+                    quantity: 1,
+                    price: 16,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+                SellOrder {
+                    id: 7,
+                    seller_name: "user".into(),
+                    item_name: "item1".into(),
+                    quantity: 1,
+                    price: 17,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+                SellOrder {
+                    id: 8,
+                    seller_name: "user".into(),
+                    item_name: "item1".into(),
+                    quantity: 1,
+                    price: 18,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+                SellOrder {
+                    id: 9,
+                    seller_name: "user".into(),
+                    item_name: "item1".into(),
+                    quantity: 1,
+                    price: 19,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+                SellOrder {
+                    id: 10,
+                    seller_name: "user".into(),
+                    item_name: "item2".into(),
+                    quantity: 15,
+                    price: 100,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+                SellOrder {
+                    id: 11,
+                    seller_name: "user".into(),
+                    item_name: "item2".into(),
+                    quantity: 5,
+                    price: 120,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:01".into(), // as expected
+                    order_type,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+            ]
+        );
+
+        // cancel expired orders
+        assert!(storage.process_expired_sell_orders(EXPIRATION_TIME).is_ok());
+        assert_eq!(
+            storage.view_sell_orders(None).unwrap(),
+            vec![SellOrder {
+                id: 11,
+                seller_name: "user".into(),
+                item_name: "item2".into(),
+                quantity: 5,
+                price: 120,
+                price_item: "funds".into(),
+                expiration_time: "2021-01-01 00:00:01".into(),
+                order_type,
+                instance: None,
+                bundle: Vec::new(),
+            }]
+        );
+
+        // items are returned but fee is not
+        assert_eq!(
+            storage.view_items(user.id).unwrap(),
+            vec![
+                ("funds".into(), 100 - 9 - 6 - 7),
+                ("item1".into(), 10),
+                ("item2".into(), 15)
+            ]
+        );
+
+        // And finally, cancel the last order
+        assert!(storage
+            .process_expired_sell_orders(EXPIRATION_TIME + 2)
+            .is_ok());
+        assert_eq!(
+            storage.view_items(user.id).unwrap(),
+            vec![
+                ("funds".into(), 100 - 9 - 6 - 7),
+                ("item1".into(), 10),
+                ("item2".into(), 20)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_execute_immediate_sell_order_err() {
+        let storage = Storage::open(":memory:").unwrap();
+
+        let seller = storage.login("seller").unwrap();
+        assert!(storage.deposit(seller.id, "funds", 100).is_ok());
+        assert!(storage.deposit(seller.id, "item1", 10).is_ok());
+        assert!(storage
+            .place_sell_order(
+                SellOrderType::Immediate,
+                seller.id,
+                "item1",
+                7,
+                10,
+                "funds",
+                EXPIRATION_TIME
+            )
+            .is_ok());
+        assert!(storage
+            .place_sell_order(
+                SellOrderType::Auction,
+                seller.id,
+                "item1",
+                3,
+                11,
+                "funds",
+                EXPIRATION_TIME
+            )
+            .is_ok());
+
+        assert_eq!(
+            storage.view_sell_orders(None).unwrap(),
+            vec![
+                SellOrder {
+                    id: 1,
+                    seller_name: "seller".into(),
+                    item_name: "item1".into(),
+                    quantity: 7,
+                    price: 10,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type: SellOrderType::Immediate,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+                SellOrder {
+                    id: 2,
+                    seller_name: "seller".into(),
+                    item_name: "item1".into(),
+                    quantity: 3,
+                    price: 11,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type: SellOrderType::Auction,
+                    instance: None,
+                    bundle: Vec::new(),
+                }
+            ]
+        );
+
+        // You can't buy your own items
+        assert!(!storage
+            .execute_immediate_sell_order(seller.id, 1, EXPIRATION_TIME)
+            .is_ok());
+
+        let buyer = storage.login("buyer").unwrap();
+
+        // try to buy non-existing sell order
+        assert!(!storage
+            .execute_immediate_sell_order(buyer.id, 100, EXPIRATION_TIME)
+            .is_ok());
+
+        // try to buy from non-existing user
+        assert!(!storage
+            .execute_immediate_sell_order(UserId(100), 1, EXPIRATION_TIME)
+            .is_ok());
+
+        // try to buy without enough funds
+        assert!(!storage
+            .execute_immediate_sell_order(buyer.id, 1, EXPIRATION_TIME)
+            .is_ok());
+
+        // try to buy auction order with not enough funds
+        assert!(!storage
+            .execute_immediate_sell_order(buyer.id, 2, EXPIRATION_TIME)
+            .is_ok());
+
+        // repeat with funds
+        assert!(storage.deposit(buyer.id, "funds", 100).is_ok());
+
+        // still can't buy auction order
+        assert!(!storage
+            .execute_immediate_sell_order(buyer.id, 2, EXPIRATION_TIME)
+            .is_ok());
+
+        // while immediate order should be bought
+        assert!(storage
+            .execute_immediate_sell_order(buyer.id, 1, EXPIRATION_TIME)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_execute_immediate_sell_order_ok() {
+        let storage = Storage::open(":memory:").unwrap();
+
+        let seller = storage.login("seller").unwrap();
+        assert!(storage.deposit(seller.id, "funds", 100).is_ok());
+        assert!(storage.deposit(seller.id, "item1", 10).is_ok());
+        assert!(storage.deposit(seller.id, "item2", 20).is_ok());
+        assert_eq!(
+            storage.view_items(seller.id).unwrap(),
+            vec![
+                ("funds".into(), 100),
+                ("item1".into(), 10),
+                ("item2".into(), 20),
+            ]
+        );
+
+        assert!(storage
+            .place_sell_order(
+                SellOrderType::Immediate,
+                seller.id,
+                "item1",
+                2,
+                2,
+                "funds",
+                EXPIRATION_TIME + 1
+            )
+            .is_ok());
+
+        // sell fee is (5% + 1)
+        assert_eq!(
+            storage.view_items(seller.id).unwrap(),
+            vec![
+                ("funds".into(), 99),
+                ("item1".into(), 8),
+                ("item2".into(), 20),
+            ]
+        );
+
+        assert!(storage
+            .place_sell_order(
+                SellOrderType::Immediate,
+                seller.id,
+                "item1",
+                3,
+                3,
+                "funds",
+                EXPIRATION_TIME + 2
+            )
+            .is_ok());
+        assert!(storage
+            .place_sell_order(
+                SellOrderType::Immediate,
+                seller.id,
+                "item1",
+                4,
+                4,
+                "funds",
+                EXPIRATION_TIME + 3
+            )
+            .is_ok());
+        assert!(storage
+            .place_sell_order(
+                SellOrderType::Immediate,
+                seller.id,
+                "item1",
+                1,
+                4,
+                "funds",
+                EXPIRATION_TIME + 4
+            )
+            .is_ok());
+
+        assert!(storage
+            .place_sell_order(
+                SellOrderType::Immediate,
+                seller.id,
+                "item2",
+                5,
+                5,
+                "funds",
+                EXPIRATION_TIME + 5
+            )
+            .is_ok());
+        assert!(storage
+            .place_sell_order(
+                SellOrderType::Immediate,
+                seller.id,
+                "item2",
+                10,
+                10,
+                "funds",
+                EXPIRATION_TIME + 6
+            )
+            .is_ok());
+        assert!(storage
+            .place_sell_order(
+                SellOrderType::Immediate,
+                seller.id,
+                "item2",
+                5,
+                15,
+                "funds",
+                EXPIRATION_TIME + 7
+            )
+            .is_ok());
+
+        assert_eq!(
+            storage.view_items(seller.id).unwrap(),
+            vec![("funds".into(), 93)]
+        );
+
+        let buyer = storage.login("buyer").unwrap();
+        assert!(storage.deposit(buyer.id, "funds", 20).is_ok());
+        // 1 item1 for 4 funds
+        assert!(storage
+            .execute_immediate_sell_order(buyer.id, 4, EXPIRATION_TIME)
+            .is_ok());
+
+        // check items and funds
+        assert_eq!(
+            storage.view_items(buyer.id).unwrap(),
+            vec![("funds".into(), 16), ("item1".into(), 1)]
+        );
+        assert_eq!(
+            storage.view_items(seller.id).unwrap(),
+            vec![("funds".into(), 97)]
+        );
+
+        assert!(storage
+            .process_expired_sell_orders(EXPIRATION_TIME + 4)
+            .is_ok());
+
+        // try to buy expired order
+        assert!(!storage
+            .execute_immediate_sell_order(buyer.id, 3, EXPIRATION_TIME)
+            .is_ok());
+
+        // check items and funds
+        assert_eq!(
+            storage.view_items(buyer.id).unwrap(),
+            vec![("funds".into(), 16), ("item1".into(), 1)]
+        );
+
+        assert_eq!(
+            storage.view_items(seller.id).unwrap(),
+            vec![("funds".into(), 97), ("item1".into(), 9)]
+        );
+
+        // buy the rest
+        assert!(storage
+            .execute_immediate_sell_order(buyer.id, 5, EXPIRATION_TIME)
+            .is_ok());
+        assert!(storage
+            .execute_immediate_sell_order(buyer.id, 6, EXPIRATION_TIME)
+            .is_ok());
+        // not enough money
+        assert!(!storage
+            .execute_immediate_sell_order(buyer.id, 7, EXPIRATION_TIME)
+            .is_ok());
+
+        // check items and funds
+        assert_eq!(
+            storage.view_items(buyer.id).unwrap(),
+            vec![
+                ("funds".into(), 1),
+                ("item1".into(), 1),
+                ("item2".into(), 15)
+            ]
+        );
+        assert_eq!(
+            storage.view_items(seller.id).unwrap(),
+            vec![("funds".into(), 97 + 15), ("item1".into(), 9)]
         );
 
-        // Withdraw more than we have
-        assert!(!storage.withdraw(user.id, "funds", 10).is_ok());
-        // Deposit negative amount
-        assert!(!storage.deposit(user.id, "funds", -10).is_ok());
-        // Withdraw negative amount
-        assert!(!storage.withdraw(user.id, "funds", -10).is_ok());
-        // Deposit zero
-        assert!(!storage.deposit(user.id, "funds", 0).is_ok());
-        // Withdraw zero
-        assert!(!storage.withdraw(user.id, "funds", 0).is_ok());
-        // Nothing should change
+        // check remaining orders
         assert_eq!(
-            storage.view_items(user.id).unwrap(),
-            vec![("funds".into(), 3)]
+            storage.view_sell_orders(None).unwrap(),
+            vec![SellOrder {
+                id: 7,
+                seller_name: "seller".into(),
+                item_name: "item2".into(),
+                quantity: 5,
+                price: 15,
+                price_item: "funds".into(),
+                expiration_time: "2021-01-01 00:00:07".into(),
+                order_type: SellOrderType::Immediate,
+                instance: None,
+                bundle: Vec::new(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_place_a_bid() {
+        let storage = Storage::open(":memory:").unwrap();
+
+        let seller = storage.login("seller").unwrap();
+        assert!(storage.deposit(seller.id, "funds", 100).is_ok());
+        assert!(storage.deposit(seller.id, "item1", 10).is_ok());
+        assert!(storage.deposit(seller.id, "item2", 20).is_ok());
+        assert_eq!(
+            storage.view_items(seller.id).unwrap(),
+            vec![
+                ("funds".into(), 100),
+                ("item1".into(), 10),
+                ("item2".into(), 20),
+            ]
+        );
+        assert!(storage
+            .place_sell_order(
+                SellOrderType::Immediate,
+                seller.id,
+                "item1",
+                7,
+                10,
+                "funds",
+                EXPIRATION_TIME
+            )
+            .is_ok());
+        assert!(storage
+            .place_sell_order(
+                SellOrderType::Auction,
+                seller.id,
+                "item1",
+                3,
+                11,
+                "funds",
+                EXPIRATION_TIME
+            )
+            .is_ok());
+        assert!(storage
+            .place_sell_order(
+                SellOrderType::Auction,
+                seller.id,
+                "item2",
+                1,
+                20,
+                "funds",
+                EXPIRATION_TIME
+            )
+            .is_ok());
+        assert!(storage
+            .place_sell_order(
+                SellOrderType::Auction,
+                seller.id,
+                "item2",
+                2,
+                45,
+                "funds",
+                EXPIRATION_TIME
+            )
+            .is_ok());
+        assert!(storage
+            .place_sell_order(
+                SellOrderType::Auction,
+                seller.id,
+                "item2",
+                2,
+                50,
+                "funds",
+                EXPIRATION_TIME
+            )
+            .is_ok());
+
+        // check orders
+        assert_eq!(
+            storage.view_sell_orders(None).unwrap(),
+            vec![
+                SellOrder {
+                    id: 1,
+                    seller_name: "seller".into(),
+                    item_name: "item1".into(),
+                    quantity: 7,
+                    price: 10,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type: SellOrderType::Immediate,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+                SellOrder {
+                    id: 2,
+                    seller_name: "seller".into(),
+                    item_name: "item1".into(),
+                    quantity: 3,
+                    price: 11,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type: SellOrderType::Auction,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+                SellOrder {
+                    id: 3,
+                    seller_name: "seller".into(),
+                    item_name: "item2".into(),
+                    quantity: 1,
+                    price: 20,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type: SellOrderType::Auction,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+                SellOrder {
+                    id: 4,
+                    seller_name: "seller".into(),
+                    item_name: "item2".into(),
+                    quantity: 2,
+                    price: 45,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type: SellOrderType::Auction,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+                SellOrder {
+                    id: 5,
+                    seller_name: "seller".into(),
+                    item_name: "item2".into(),
+                    quantity: 2,
+                    price: 50,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type: SellOrderType::Auction,
+                    instance: None,
+                    bundle: Vec::new(),
+                }
+            ]
+        );
+
+        // You can't can't place a bid on your own items
+        assert!(!storage
+            .place_bid_on_auction_sell_order(seller.id, 2, 20)
+            .is_ok());
+
+        let buyer = storage.login("buyer").unwrap();
+
+        // can't place a bid on non-existing sell order
+        assert!(!storage
+            .place_bid_on_auction_sell_order(buyer.id, 100, 20)
+            .is_ok());
+
+        // can't place a bid from non-existing user
+        assert!(!storage
+            .place_bid_on_auction_sell_order(UserId(100), 2, 20)
+            .is_ok());
+
+        // can't place a bid without enough funds
+        assert!(!storage
+            .place_bid_on_auction_sell_order(buyer.id, 20, 20)
+            .is_ok());
+
+        // can't place a bid on auction order with not enough funds
+        assert!(!storage
+            .place_bid_on_auction_sell_order(buyer.id, 1, 20)
+            .is_ok());
+
+        // repeat with funds
+        assert!(storage.deposit(buyer.id, "funds", 100).is_ok());
+
+        // still can't place a bid on immediate order
+        assert!(!storage
+            .place_bid_on_auction_sell_order(buyer.id, 1, 20)
+            .is_ok());
+
+        // while it is possible to place a bid on auction order
+        assert!(storage
+            .place_bid_on_auction_sell_order(buyer.id, 2, 20)
+            .is_ok());
+
+        assert_eq!(
+            storage.view_items(buyer.id).unwrap(),
+            vec![("funds".into(), 80)]
         );
 
-        // deposit to non-existing user
-        assert!(!storage.deposit(UserId(100), "funds", 10).is_ok());
-        assert!(!storage.withdraw(UserId(100), "funds", 10).is_ok());
+        // check that bid is placed
+        assert_eq!(
+            storage.view_sell_orders(None).unwrap(),
+            vec![
+                SellOrder {
+                    id: 1,
+                    seller_name: "seller".into(),
+                    item_name: "item1".into(),
+                    quantity: 7,
+                    price: 10,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type: SellOrderType::Immediate,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+                SellOrder {
+                    id: 2,
+                    seller_name: "seller".into(),
+                    item_name: "item1".into(),
+                    quantity: 3,
+                    price: 20, // a bid was made!
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type: SellOrderType::Auction,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+                SellOrder {
+                    id: 3,
+                    seller_name: "seller".into(),
+                    item_name: "item2".into(),
+                    quantity: 1,
+                    price: 20,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type: SellOrderType::Auction,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+                SellOrder {
+                    id: 4,
+                    seller_name: "seller".into(),
+                    item_name: "item2".into(),
+                    quantity: 2,
+                    price: 45,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type: SellOrderType::Auction,
+                    instance: None,
+                    bundle: Vec::new(),
+                },
+                SellOrder {
+                    id: 5,
+                    seller_name: "seller".into(),
+                    item_name: "item2".into(),
+                    quantity: 2,
+                    price: 50,
+                    price_item: "funds".into(),
+                    expiration_time: "2021-01-01 00:00:00".into(),
+                    order_type: SellOrderType::Auction,
+                    instance: None,
+                    bundle: Vec::new(),
+                }
+            ]
+        );
+
+        // but you can't repeat a bid
+        assert!(!storage
+            .place_bid_on_auction_sell_order(buyer.id, 2, 20)
+            .is_ok());
+
+        let another_buyer = storage.login("another buyer").unwrap();
+        assert!(storage.deposit(another_buyer.id, "funds", 100).is_ok());
+
+        // and you can't lower previous bid
+        assert!(!storage
+            .place_bid_on_auction_sell_order(another_buyer.id, 2, 19)
+            .is_ok());
+
+        // but you can increase it, but not greater than funds allow
+        assert!(!storage
+            .place_bid_on_auction_sell_order(another_buyer.id, 2, 121)
+            .is_ok());
+        assert!(storage
+            .place_bid_on_auction_sell_order(another_buyer.id, 2, 21)
+            .is_ok());
 
-        // and check that we can deposit and withdraw from different users
-        let user2 = storage.login("user2").unwrap();
-        assert!(storage.deposit(user2.id, "funds", 20).is_ok());
+        assert!(storage
+            .place_bid_on_auction_sell_order(another_buyer.id, 3, 25)
+            .is_ok());
+        assert!(storage
+            .place_bid_on_auction_sell_order(another_buyer.id, 4, 50)
+            .is_ok());
 
-        let user3 = storage.login("user3").unwrap();
-        assert!(storage.deposit(user3.id, "funds", 30).is_ok());
+        assert!(storage
+            .place_bid_on_auction_sell_order(buyer.id, 3, 27)
+            .is_ok());
 
-        let user1 = storage.login("user1").unwrap();
         assert_eq!(
-            storage.view_items(user1.id).unwrap(),
-            vec![("funds".into(), 3)]
+            storage.view_items(seller.id).unwrap(),
+            vec![("funds".into(), 90), ("item2".into(), 20 - 1 - 2 - 2)]
         );
-
-        let user2 = storage.login("user2").unwrap();
         assert_eq!(
-            storage.view_items(user2.id).unwrap(),
-            vec![("funds".into(), 20)]
+            storage.view_items(buyer.id).unwrap(),
+            vec![("funds".into(), 100 - 27)]
         );
-
-        let user3 = storage.login("user3").unwrap();
         assert_eq!(
-            storage.view_items(user3.id).unwrap(),
-            vec![("funds".into(), 30)]
+            storage.view_items(another_buyer.id).unwrap(),
+            vec![("funds".into(), 100 - 21 - 50)]
         );
 
-        // Big numbers also are ok
-        assert!(storage.deposit(user1.id, "funds", 100500).is_ok());
-        assert!(storage.withdraw(user1.id, "funds", 100400).is_ok());
+        // and finally process expired orders - make them real
+        assert!(storage
+            .process_expired_sell_orders(EXPIRATION_TIME + 1)
+            .is_ok());
+        assert_eq!(
+            storage.view_items(seller.id).unwrap(),
+            vec![
+                ("funds".into(), 90 + 27 + 21 + 50),
+                ("item1".into(), 7),
+                ("item2".into(), 20 - 5 + 2)
+            ]
+        );
+        assert_eq!(
+            storage.view_items(buyer.id).unwrap(),
+            vec![("funds".into(), 100 - 27), ("item2".into(), 1),]
+        );
+        assert_eq!(
+            storage.view_items(another_buyer.id).unwrap(),
+            vec![
+                ("funds".into(), 100 - 21 - 50),
+                ("item1".into(), 3),
+                ("item2".into(), 2)
+            ]
+        );
     }
 
     #[test]
-    fn items() {
+    fn test_error_codes() {
         let storage = Storage::open(":memory:").unwrap();
 
-        let user = storage.login("user1").unwrap();
-        // freshly created user always has 0 items
+        let seller = storage.login("seller").unwrap();
+        let buyer = storage.login("buyer").unwrap();
+        assert!(storage.deposit(seller.id, "funds", 10).is_ok());
+        assert!(storage.deposit(seller.id, "item1", 10).is_ok());
+        assert!(storage
+            .place_sell_order(
+                SellOrderType::Auction,
+                seller.id,
+                "item1",
+                1,
+                10,
+                "funds",
+                EXPIRATION_TIME
+            )
+            .is_ok());
+
+        let error_code = |result: Result<()>| u32::from(result.unwrap_err().code());
+        assert_eq!(error_code(storage.login("").map(|_| ())), 101);
+        assert_eq!(error_code(storage.deposit(buyer.id, "", 1)), 102);
+        assert_eq!(error_code(storage.deposit(buyer.id, "item1", 0)), 103);
+        assert_eq!(error_code(storage.withdraw(buyer.id, "item2", 1)), 105);
+        assert_eq!(error_code(storage.withdraw(buyer.id, "funds", 1)), 106);
+        assert_eq!(error_code(storage.withdraw(buyer.id, "item1", 1)), 107);
         assert_eq!(
-            storage.view_items(user.id).unwrap(),
-            vec![("funds".into(), 0)]
+            error_code(storage.execute_immediate_sell_order(buyer.id, 2, EXPIRATION_TIME)),
+            109
         );
-
-        assert!(storage.deposit(user.id, "item1", 10).is_ok());
         assert_eq!(
-            storage.view_items(user.id).unwrap(),
-            vec![("funds".into(), 0), ("item1".into(), 10)]
+            error_code(storage.execute_immediate_sell_order(buyer.id, 1, EXPIRATION_TIME)),
+            110
         );
-
-        assert!(storage.deposit(user.id, "item2", 20).is_ok());
         assert_eq!(
-            storage.view_items(user.id).unwrap(),
-            vec![
-                ("funds".into(), 0),
-                ("item1".into(), 10),
-                ("item2".into(), 20)
-            ]
+            error_code(storage.place_bid_on_auction_sell_order(seller.id, 1, 20)),
+            112
         );
-
-        assert!(storage.withdraw(user.id, "item1", 5).is_ok());
         assert_eq!(
-            storage.view_items(user.id).unwrap(),
-            vec![
-                ("funds".into(), 0),
-                ("item1".into(), 5),
-                ("item2".into(), 20)
-            ]
+            error_code(storage.place_bid_on_auction_sell_order(buyer.id, 1, 10)),
+            113
+        );
+        assert_eq!(
+            error_code(
+                storage
+                    .place_sell_order(
+                        SellOrderType::Immediate,
+                        seller.id,
+                        "funds",
+                        1,
+                        1,
+                        "funds",
+                        0
+                    )
+                    .map(|_| ())
+            ),
+            108
+        );
+        assert_eq!(
+            storage
+                .withdraw(seller.id, "item1", 10)
+                .unwrap_err()
+                .to_string(),
+            "Not enough item1(s): 10 required, but only 9 available"
         );
+    }
+
+    #[test]
+    fn test_market_events() {
+        let storage = Storage::open(":memory:").unwrap();
+        let mut events = storage.subscribe();
+
+        let seller = storage.login("seller").unwrap();
+        let buyer = storage.login("buyer").unwrap();
+        assert!(storage.deposit(seller.id, "funds", 10).is_ok());
+        assert!(storage.deposit(seller.id, "item1", 10).is_ok());
+        assert!(storage.deposit(buyer.id, "funds", 100).is_ok());
+
+        let order = |id, quantity, price, order_type| SellOrder {
+            id,
+            seller_name: "seller".into(),
+            item_name: "item1".into(),
+            quantity,
+            price,
+            price_item: "funds".into(),
+            expiration_time: "2021-01-01 00:00:00".into(),
+            order_type,
+            instance: None,
+            bundle: Vec::new(),
+        };
+
+        assert!(storage
+            .place_sell_order(
+                SellOrderType::Immediate,
+                seller.id,
+                "item1",
+                2,
+                10,
+                "funds",
+                EXPIRATION_TIME
+            )
+            .is_ok());
+        assert!(storage
+            .place_sell_order(
+                SellOrderType::Auction,
+                seller.id,
+                "item1",
+                3,
+                10,
+                "funds",
+                EXPIRATION_TIME
+            )
+            .is_ok());
+        assert!(storage
+            .execute_immediate_sell_order(buyer.id, 1, EXPIRATION_TIME)
+            .is_ok());
+        assert!(storage
+            .place_bid_on_auction_sell_order(buyer.id, 2, 20)
+            .is_ok());
+        // failed operations don't produce events
+        assert!(storage
+            .place_bid_on_auction_sell_order(buyer.id, 2, 15)
+            .is_err());
+        assert!(storage.process_expired_sell_orders(EXPIRATION_TIME).is_ok());
 
-        assert!(storage.withdraw(user.id, "item2", 10).is_ok());
         assert_eq!(
-            storage.view_items(user.id).unwrap(),
+            std::iter::from_fn(|| events.try_recv().ok()).collect::<Vec<_>>(),
             vec![
-                ("funds".into(), 0),
-                ("item1".into(), 5),
-                ("item2".into(), 10)
+                MarketEvent::OrderPlaced {
+                    order: order(1, 2, 10, SellOrderType::Immediate)
+                },
+                MarketEvent::OrderPlaced {
+                    order: order(2, 3, 10, SellOrderType::Auction)
+                },
+                MarketEvent::OrderBought {
+                    order: order(1, 2, 10, SellOrderType::Immediate)
+                },
+                MarketEvent::BidPlaced {
+                    order: order(2, 3, 20, SellOrderType::Auction)
+                },
+                MarketEvent::OrderExpired {
+                    order: order(2, 3, 20, SellOrderType::Auction)
+                },
             ]
         );
+    }
 
-        // withdraw to zero should remove the record
-        assert!(storage.withdraw(user.id, "item1", 5).is_ok());
+    #[test]
+    fn test_cancel_sell_order() {
+        let storage = Storage::open(":memory:").unwrap();
+        let mut events = storage.subscribe();
+
+        let seller = storage.login("seller").unwrap();
+        let buyer = storage.login("buyer").unwrap();
+        assert!(storage.deposit(seller.id, "funds", 10).is_ok());
+        assert!(storage.deposit(seller.id, "item1", 10).is_ok());
+        assert!(storage.deposit(buyer.id, "funds", 100).is_ok());
+        for order_type in [
+            SellOrderType::Immediate,
+            SellOrderType::Auction,
+            SellOrderType::Auction,
+        ] {
+            assert!(storage
+                .place_sell_order(
+                    order_type,
+                    seller.id,
+                    "item1",
+                    2,
+                    10,
+                    "funds",
+                    EXPIRATION_TIME
+                )
+                .is_ok());
+        }
+        assert!(storage
+            .place_bid_on_auction_sell_order(buyer.id, 3, 20)
+            .is_ok());
+
+        let error_code = |result: Result<()>| u32::from(result.unwrap_err().code());
+        assert_eq!(error_code(storage.cancel_sell_order(seller.id, 4)), 109);
+        assert_eq!(error_code(storage.cancel_sell_order(buyer.id, 1)), 115);
+        assert_eq!(error_code(storage.cancel_sell_order(seller.id, 3)), 116);
+
+        // Items are returned, fees are not
+        assert!(storage.cancel_sell_order(seller.id, 1).is_ok());
+        assert!(storage.cancel_sell_order(seller.id, 2).is_ok());
+        assert_eq!(error_code(storage.cancel_sell_order(seller.id, 1)), 109);
         assert_eq!(
-            storage.view_items(user.id).unwrap(),
-            vec![("funds".into(), 0), ("item2".into(), 10)]
+            storage.view_items(seller.id).unwrap(),
+            vec![("funds".into(), 10 - 3), ("item1".into(), 10 - 2)]
         );
-
-        // login with the same username should return the same user with the same items
-        let user = storage.login("user1").unwrap();
         assert_eq!(
-            storage.view_items(user.id).unwrap(),
-            vec![("funds".into(), 0), ("item2".into(), 10)]
+            storage
+                .view_sell_orders(None)
+                .unwrap()
+                .into_iter()
+                .map(|order| order.id)
+                .collect::<Vec<_>>(),
+            vec![3]
         );
 
-        // Withdraw more than we have
-        assert!(!storage.withdraw(user.id, "item2", 20).is_ok());
+        let cancelled = std::iter::from_fn(|| events.try_recv().ok())
+            .filter_map(|event| match event {
+                MarketEvent::OrderCancelled { order } => Some(order.id),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(cancelled, vec![1, 2]);
+    }
 
-        // Negative quantity
-        assert!(!storage.deposit(user.id, "item2", -10).is_ok());
-        assert!(!storage.withdraw(user.id, "item2", -10).is_ok());
+    #[test]
+    fn test_nested_savepoints() {
+        let storage = Storage::open(":memory:").unwrap();
+        let mut events = storage.subscribe();
+        let seller = storage.login("seller").unwrap();
+        assert!(storage.deposit(seller.id, "funds", 10).is_ok());
+        assert!(storage.deposit(seller.id, "item1", 10).is_ok());
 
-        // Zero quantity
-        assert!(!storage.deposit(user.id, "item2", 0).is_ok());
-        assert!(!storage.withdraw(user.id, "item2", 0).is_ok());
+        // Rolled back together with the events
+        {
+            let _savepoint = storage.savepoint().unwrap();
+            assert!(storage
+                .place_sell_order(
+                    SellOrderType::Immediate,
+                    seller.id,
+                    "item1",
+                    2,
+                    10,
+                    "funds",
+                    EXPIRATION_TIME
+                )
+                .is_ok());
+        }
+        assert!(events.try_recv().is_err());
+        assert_eq!(storage.view_sell_orders(None).unwrap(), vec![]);
 
-        // empty item name
-        assert!(!storage.deposit(user.id, "", 10).is_ok());
-        assert!(!storage.withdraw(user.id, "", 10).is_ok());
+        // Events are delivered once the outermost savepoint is committed
+        let outer = storage.savepoint().unwrap();
+        let inner = storage.savepoint().unwrap();
+        assert!(storage
+            .place_sell_order(
+                SellOrderType::Immediate,
+                seller.id,
+                "item1",
+                2,
+                10,
+                "funds",
+                EXPIRATION_TIME
+            )
+            .is_ok());
+        inner.commit().unwrap();
+        assert!(events.try_recv().is_err());
+        outer.commit().unwrap();
+        assert!(matches!(
+            events.try_recv(),
+            Ok(MarketEvent::OrderPlaced { order }) if order.quantity == 2
+        ));
+        assert_eq!(
+            storage.view_items(seller.id).unwrap(),
+            vec![("funds".into(), 10 - 1), ("item1".into(), 10 - 2)]
+        );
+    }
 
-        // Nothing should change
+    #[test]
+    fn test_request_results() {
+        let storage = Storage::open(":memory:").unwrap();
+        let user1 = storage.login("user1").unwrap();
+        let user2 = storage.login("user2").unwrap();
+
+        assert_eq!(storage.get_request_result(user1.id, "1").unwrap(), None);
+        assert!(storage
+            .save_request_result(user1.id, "1", "result", EXPIRATION_TIME)
+            .is_ok());
         assert_eq!(
-            storage.view_items(user.id).unwrap(),
-            vec![("funds".into(), 0), ("item2".into(), 10)]
+            storage.get_request_result(user1.id, "1").unwrap(),
+            Some("result".into())
         );
+        // Ids are unique per user
+        assert_eq!(storage.get_request_result(user2.id, "1").unwrap(), None);
+        assert!(storage
+            .save_request_result(user1.id, "1", "another result", EXPIRATION_TIME)
+            .is_err());
 
-        // deposit to non-existing user
-        assert!(!storage.deposit(UserId(100), "item1", 10).is_ok());
-        assert!(!storage.withdraw(UserId(100), "item1", 10).is_ok());
+        // Old results are forgotten
+        assert!(storage
+            .save_request_result(user2.id, "2", "result", EXPIRATION_TIME + 24 * 60 * 60)
+            .is_ok());
+        assert_eq!(storage.get_request_result(user1.id, "1").unwrap(), None);
     }
 
-    #[parameterized(order_type = {
-        SellOrderType::Immediate,
-        SellOrderType::Auction,
-    })]
-    fn test_sell_orders_negative(order_type: SellOrderType) {
-        // workaround for resolution ambiguity caused by `parameterized` macro
-        use pretty_assertions::assert_eq;
+    #[test]
+    fn test_give() {
+        let mut storage = Storage::open(":memory:").unwrap();
+        storage.set_daily_give_limit(Some(5));
 
-        let storage = Storage::open(":memory:").unwrap();
+        let sender = storage.login("sender").unwrap();
+        let recipient = storage.login("recipient").unwrap();
+        let mut events = storage.connect(recipient.id);
+        assert!(storage.deposit(sender.id, "item1", 10).is_ok());
+
+        assert!(storage
+            .give(sender.id, "recipient", "item1", 3, EXPIRATION_TIME)
+            .is_ok());
+        assert_eq!(
+            storage.view_items(sender.id).unwrap(),
+            vec![("funds".into(), 0), ("item1".into(), 7)]
+        );
+        assert_eq!(
+            storage.view_items(recipient.id).unwrap(),
+            vec![("funds".into(), 0), ("item1".into(), 3)]
+        );
+        assert_eq!(
+            events.try_recv().unwrap(),
+            MarketEvent::ItemsReceived {
+                sender: "sender".into(),
+                recipient: "recipient".into(),
+                item_name: "item1".into(),
+                quantity: 3
+            }
+        );
 
-        let user = storage.login("user").unwrap();
-        assert!(storage.deposit(user.id, "funds", 100).is_ok());
-        assert!(storage.deposit(user.id, "item1", 10).is_ok());
-        assert!(storage.deposit(user.id, "item2", 20).is_ok());
+        let error_code = |result: Result<()>| u32::from(result.unwrap_err().code());
         assert_eq!(
-            storage.view_items(user.id).unwrap(),
-            vec![
-                ("funds".into(), 100),
-                ("item1".into(), 10),
-                ("item2".into(), 20)
-            ]
+            error_code(storage.give(sender.id, "nobody", "item1", 1, EXPIRATION_TIME)),
+            117
+        );
+        assert_eq!(
+            error_code(storage.give(sender.id, "sender", "item1", 1, EXPIRATION_TIME)),
+            118
+        );
+        assert_eq!(
+            error_code(storage.give(sender.id, "recipient", "  ", 1, EXPIRATION_TIME)),
+            102
+        );
+        assert_eq!(
+            error_code(storage.give(sender.id, "recipient", "item2", 1, EXPIRATION_TIME)),
+            105
+        );
+        assert_eq!(
+            error_code(storage.give(sender.id, "recipient", "funds", 1, EXPIRATION_TIME)),
+            106
+        );
+        assert_eq!(
+            storage
+                .give(sender.id, "recipient", "item1", 3, EXPIRATION_TIME + 1)
+                .unwrap_err()
+                .to_string(),
+            "Daily limit of 5 item1(s) to give is exceeded, only 2 more can be given today"
         );
+        // failed transfers change nothing
+        assert!(events.try_recv().is_err());
 
-        // more than we have
-        assert!(storage
-            .place_sell_order(order_type, user.id, "item1", 110, 10, EXPIRATION_TIME)
-            .is_err());
-        // negative quantity
-        assert!(storage
-            .place_sell_order(order_type, user.id, "item1", -10, 10, EXPIRATION_TIME)
-            .is_err());
-        // negative price
+        // The limit is per sender and item, and frees up a day later
+        assert!(storage.deposit(recipient.id, "item2", 5).is_ok());
         assert!(storage
-            .place_sell_order(order_type, user.id, "item1", 10, -10, EXPIRATION_TIME)
-            .is_err());
-        // non-existing item
+            .give(recipient.id, "sender", "item2", 5, EXPIRATION_TIME)
+            .is_ok());
         assert!(storage
-            .place_sell_order(
-                order_type,
-                user.id,
-                "non-existing-item",
-                1,
-                10,
-                EXPIRATION_TIME
+            .give(
+                sender.id,
+                "recipient",
+                "item1",
+                5,
+                EXPIRATION_TIME + 24 * 60 * 60
             )
-            .is_err());
-        // non-existing user
-        assert!(storage
-            .place_sell_order(order_type, UserId(100), "item1", 1, 10, EXPIRATION_TIME)
-            .is_err());
-        // funds
-        assert!(storage
-            .place_sell_order(order_type, user.id, "funds", 1, 2, EXPIRATION_TIME)
-            .is_err());
+            .is_ok());
 
-        // Finally, nothing should be changed
-        assert_eq!(storage.view_sell_orders().unwrap(), vec![]);
+        let transfer =
+            |sender: &str, recipient: &str, item_name: &str, quantity, time: &str| Transaction {
+                kind: TransactionKind::Give,
+                sender: sender.into(),
+                recipient: recipient.into(),
+                item_name: item_name.into(),
+                quantity,
+                time: time.into(),
+            };
+        assert_eq!(
+            storage.view_transactions(recipient.id).unwrap(),
+            vec![
+                transfer("sender", "recipient", "item1", 3, "2021-01-01 00:00:00"),
+                transfer("recipient", "sender", "item2", 5, "2021-01-01 00:00:00"),
+                transfer("sender", "recipient", "item1", 5, "2021-01-02 00:00:00"),
+            ]
+        );
     }
 
-    #[parameterized(order_type = {
-        SellOrderType::Immediate,
-        SellOrderType::Auction,
-    })]
-    fn test_sell_orders_positive(order_type: SellOrderType) {
-        // workaround for resolution ambiguity caused by `parameterized` macro
-        use pretty_assertions::assert_eq;
-
-        let storage = Storage::open(":memory:").unwrap();
+    #[test]
+    fn test_offers() {
+        let mut storage = Storage::open(":memory:").unwrap();
+
+        let sender = storage.login("sender").unwrap();
+        let recipient = storage.login("recipient").unwrap();
+        let other = storage.login("other").unwrap();
+        // the sender is offline and finds notifications in the mailbox
+        let mut events = storage.connect(recipient.id);
+        assert!(storage.deposit(sender.id, "item1", 10).is_ok());
+        assert!(storage.deposit(recipient.id, "item2", 5).is_ok());
+
+        let item = |name: &str, quantity| Item {
+            name: name.into(),
+            quantity,
+        };
+        let error_code = |result: Result<i64>| u32::from(result.unwrap_err().code());
+        assert_eq!(
+            error_code(storage.place_offer(sender.id, "recipient", &[], &[], EXPIRATION_TIME)),
+            120
+        );
+        assert_eq!(
+            error_code(storage.place_offer(
+                sender.id,
+                "recipient",
+                &[item("item1", 11)],
+                &[item("item2", 1)],
+                EXPIRATION_TIME
+            )),
+            107
+        );
 
-        let user = storage.login("user").unwrap();
-        assert!(storage.deposit(user.id, "funds", 100).is_ok());
-        assert!(storage.deposit(user.id, "item1", 10).is_ok());
-        assert!(storage.deposit(user.id, "item2", 20).is_ok());
+        // duplicate items are merged
+        let offer_id = storage
+            .place_offer(
+                sender.id,
+                "recipient",
+                &[item("item1", 2), item("item1", 1)],
+                &[item("item2", 5)],
+                EXPIRATION_TIME,
+            )
+            .unwrap();
+        // offered items are held until the offer is finished
         assert_eq!(
-            storage.view_items(user.id).unwrap(),
-            vec![
-                ("funds".into(), 100),
-                ("item1".into(), 10),
-                ("item2".into(), 20)
-            ]
+            storage.view_items(sender.id).unwrap(),
+            vec![("funds".into(), 0), ("item1".into(), 7)]
+        );
+        let offer = Offer {
+            id: offer_id,
+            sender: "sender".into(),
+            recipient: "recipient".into(),
+            give: vec![item("item1", 3)],
+            want: vec![item("item2", 5)],
+            expiration_time: "2021-01-01 00:00:00".into(),
+        };
+        assert_eq!(
+            storage.view_offers(recipient.id).unwrap(),
+            vec![offer.clone()]
+        );
+        assert_eq!(storage.view_offers(other.id).unwrap(), vec![]);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            MarketEvent::OfferReceived {
+                offer: offer.clone()
+            }
         );
 
-        for i in 1..10 {
-            assert!(storage
-                .place_sell_order(order_type, user.id, "item1", 1, 10 + i, EXPIRATION_TIME)
-                .is_ok());
-            assert_eq!(
-                storage.view_items(user.id).unwrap(),
-                vec![
-                    ("funds".into(), 100 - i /* fee is 1 for each order */),
-                    ("item1".into(), 10 - i),
-                    ("item2".into(), 20)
-                ]
-            );
-        }
+        assert_eq!(
+            u32::from(
+                storage
+                    .accept_offer(sender.id, offer_id, EXPIRATION_TIME - 1)
+                    .unwrap_err()
+                    .code()
+            ),
+            122
+        );
+        assert!(storage.decline_offer(other.id, offer_id).is_err());
+        // the offer can't be accepted once expired, even before the items are returned
+        assert_eq!(
+            storage
+                .accept_offer(recipient.id, offer_id, EXPIRATION_TIME)
+                .unwrap_err()
+                .code(),
+            ErrorCode::OfferNotFound
+        );
         assert!(storage
-            .place_sell_order(order_type, user.id, "item2", 15, 100, EXPIRATION_TIME)
+            .accept_offer(recipient.id, offer_id, EXPIRATION_TIME - 1)
             .is_ok());
         assert_eq!(
-            storage.view_items(user.id).unwrap(),
+            storage.view_items(sender.id).unwrap(),
             vec![
-                ("funds".into(), 100 - 9 - 6),
-                ("item1".into(), 1),
+                ("funds".into(), 0),
+                ("item1".into(), 7),
                 ("item2".into(), 5)
             ]
         );
+        assert_eq!(
+            storage.view_items(recipient.id).unwrap(),
+            vec![("funds".into(), 0), ("item1".into(), 3)]
+        );
+        assert_eq!(events.try_recv().ok(), None);
+        assert_eq!(
+            storage.view_mailbox(sender.id).unwrap(),
+            vec![MarketEvent::OfferAccepted { offer }]
+        );
+        assert_eq!(
+            storage
+                .view_transactions(sender.id)
+                .unwrap()
+                .iter()
+                .map(|transaction| transaction.kind)
+                .collect::<Vec<_>>(),
+            vec![TransactionKind::Trade, TransactionKind::Trade]
+        );
+        assert_eq!(
+            u32::from(
+                storage
+                    .decline_offer(recipient.id, offer_id)
+                    .unwrap_err()
+                    .code()
+            ),
+            121
+        );
 
-        // Item entry dissapears when quantity reaches zero
+        // declined and expired offers return items to the sender
+        let offer_id = storage
+            .place_offer(
+                sender.id,
+                "recipient",
+                &[item("item1", 7)],
+                &[item("item2", 1)],
+                EXPIRATION_TIME,
+            )
+            .unwrap();
+        assert!(storage.decline_offer(recipient.id, offer_id).is_ok());
         assert!(storage
-            .place_sell_order(order_type, user.id, "item2", 5, 120, EXPIRATION_TIME + 1)
+            .place_offer(
+                sender.id,
+                "recipient",
+                &[item("item1", 7)],
+                &[item("item2", 1)],
+                EXPIRATION_TIME,
+            )
             .is_ok());
+        assert!(storage.process_expired_offers(EXPIRATION_TIME - 1).is_ok());
+        assert_eq!(storage.view_offers(sender.id).unwrap().len(), 1);
+        assert!(storage.process_expired_offers(EXPIRATION_TIME).is_ok());
+        assert_eq!(storage.view_offers(sender.id).unwrap(), vec![]);
+        let events = storage.view_mailbox(sender.id).unwrap();
+        assert!(matches!(
+            events.as_slice(),
+            [
+                MarketEvent::OfferDeclined { declined_by, .. },
+                MarketEvent::OfferExpired { .. }
+            ] if declined_by == "recipient"
+        ));
         assert_eq!(
-            storage.view_items(user.id).unwrap(),
-            vec![("funds".into(), 100 - 9 - 6 - 7), ("item1".into(), 1)]
+            storage.view_items(sender.id).unwrap(),
+            vec![
+                ("funds".into(), 0),
+                ("item1".into(), 7),
+                ("item2".into(), 5)
+            ]
         );
 
-        pretty_assertions::assert_eq!(
-            storage.view_sell_orders().unwrap(),
+        // the wanted items must still fit into the sender's stack when the offer is accepted
+        let catalog = vec![CatalogItem {
+            name: "item2".into(),
+            display_name: None,
+            category: None,
+            description: String::new(),
+            max_stack: Some(6),
+            tradeable: true,
+            aliases: Vec::new(),
+        }];
+        assert!(storage.set_catalog(&catalog, false).is_ok());
+        assert!(storage.deposit(recipient.id, "item2", 1).is_ok());
+        let offer_id = storage
+            .place_offer(
+                sender.id,
+                "recipient",
+                &[item("item1", 1)],
+                &[item("item2", 1)],
+                EXPIRATION_TIME,
+            )
+            .unwrap();
+        assert!(storage.deposit(sender.id, "item2", 1).is_ok());
+        assert_eq!(
+            storage
+                .accept_offer(recipient.id, offer_id, EXPIRATION_TIME - 1)
+                .unwrap_err()
+                .code(),
+            ErrorCode::StackLimitExceeded
+        );
+        assert_eq!(storage.view_offers(sender.id).unwrap().len(), 1);
+        assert_eq!(
+            storage.view_items(recipient.id).unwrap(),
             vec![
-                SellOrder {
-                    id: 1,
-                    seller_name: "user".into(),
-                    item_name: "item1".into(),
-                    quantity: 1,
-                    price: 11,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type,
-                },
-                SellOrder {
-                    id: 2,
-                    seller_name: "user".into(),
-                    item_name: "item1".into(),
-                    quantity: 1,
-                    price: 12,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type,
-                },
-                SellOrder {
-                    id: 3,
-                    seller_name: "user".into(),
-                    item_name: "item1".into(),
-                    quantity: 1,
-                    price: 13,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type,
-                },
-                SellOrder {
-                    id: 4,
-                    seller_name: "user".into(),
-                    item_name: "item1".into(),
-                    quantity: 1,
-                    price: 14,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type,
-                },
-                SellOrder {
-                    id: 5,
-                    seller_name: "user".into(),
-                    item_name: "item1".into(),
-                    quantity: 1,
-                    price: 15,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type,
-                },
-                SellOrder {
-                    id: 6,
-                    seller_name: "user".into(),
-                    item_name: "item1".into(),
-                    quantity: 1,
-                    price: 16,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type,
-                },
-                SellOrder {
-                    id: 7,
-                    seller_name: "user".into(),
-                    item_name: "item1".into(),
-                    quantity: 1,
-                    price: 17,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type,
-                },
-                SellOrder {
-                    id: 8,
-                    seller_name: "user".into(),
-                    item_name: "item1".into(),
-                    quantity: 1,
-                    price: 18,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type,
-                },
-                SellOrder {
-                    id: 9,
-                    seller_name: "user".into(),
-                    item_name: "item1".into(),
-                    quantity: 1,
-                    price: 19,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type,
-                },
-                SellOrder {
-                    id: 10,
-                    seller_name: "user".into(),
-                    item_name: "item2".into(),
-                    quantity: 15,
-                    price: 100,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type,
-                },
-                SellOrder {
-                    id: 11,
-                    seller_name: "user".into(),
-                    item_name: "item2".into(),
-                    quantity: 5,
-                    price: 120,
-                    expiration_time: "2021-01-01 00:00:01".into(), // as expected
-                    order_type,
-                },
+                ("funds".into(), 0),
+                ("item1".into(), 3),
+                ("item2".into(), 1)
             ]
         );
+    }
+
+    #[test]
+    fn test_barter_orders() {
+        let storage = Storage::open(":memory:").unwrap();
+        let seller = storage.login("seller").unwrap();
+        let buyer = storage.login("buyer").unwrap();
+        assert!(storage.deposit(seller.id, "funds", 10).is_ok());
+        assert!(storage.deposit(seller.id, "ore", 100).is_ok());
+        assert!(storage.deposit(seller.id, "gem", 1).is_ok());
+        assert!(storage.deposit(buyer.id, "gem", 10).is_ok());
+
+        let error_code = |result: Result<i64>| u32::from(result.unwrap_err().code());
+        assert_eq!(
+            error_code(storage.place_sell_order(
+                SellOrderType::Immediate,
+                seller.id,
+                "ore",
+                1,
+                1,
+                "ore",
+                EXPIRATION_TIME
+            )),
+            123
+        );
+        assert_eq!(
+            error_code(storage.place_sell_order(
+                SellOrderType::Immediate,
+                seller.id,
+                "ore",
+                1,
+                1,
+                "diamond",
+                EXPIRATION_TIME
+            )),
+            105
+        );
+
+        // the fee of barter orders is 1 funds regardless of the price
+        let immediate_order_id = storage
+            .place_sell_order(
+                SellOrderType::Immediate,
+                seller.id,
+                "ore",
+                50,
+                300,
+                "gem",
+                EXPIRATION_TIME,
+            )
+            .unwrap();
+        let auction_order_id = storage
+            .place_sell_order(
+                SellOrderType::Auction,
+                seller.id,
+                "ore",
+                50,
+                2,
+                "gem",
+                EXPIRATION_TIME,
+            )
+            .unwrap();
+        // returned to the seller on expiry together with the payment in gems
+        assert!(storage
+            .place_sell_order(
+                SellOrderType::Immediate,
+                seller.id,
+                "gem",
+                1,
+                5,
+                "funds",
+                EXPIRATION_TIME,
+            )
+            .is_ok());
+        assert_eq!(
+            storage
+                .get_sell_order(immediate_order_id)
+                .unwrap()
+                .to_string(),
+            "#1: seller is selling 50 ore(s) for 300 gem(s) until 2021-01-01 00:00:00"
+        );
+        assert_eq!(
+            storage.view_items(seller.id).unwrap(),
+            vec![("funds".into(), 10 - 1 - 1 - 1)]
+        );
+
+        assert_eq!(
+            u32::from(
+                storage
+                    .execute_immediate_sell_order(buyer.id, immediate_order_id, EXPIRATION_TIME)
+                    .unwrap_err()
+                    .code()
+            ),
+            107
+        );
+        assert_eq!(
+            storage
+                .place_bid_on_auction_sell_order(buyer.id, auction_order_id, 2)
+                .unwrap_err()
+                .to_string(),
+            "Bid must be higher than the current price of 2 gem(s)"
+        );
+        assert!(storage
+            .place_bid_on_auction_sell_order(buyer.id, auction_order_id, 4)
+            .is_ok());
+        assert_eq!(
+            storage.view_items(buyer.id).unwrap(),
+            vec![("funds".into(), 0), ("gem".into(), 6)]
+        );
 
-        // cancel expired orders
         assert!(storage.process_expired_sell_orders(EXPIRATION_TIME).is_ok());
         assert_eq!(
-            storage.view_sell_orders().unwrap(),
-            vec![SellOrder {
-                id: 11,
-                seller_name: "user".into(),
-                item_name: "item2".into(),
-                quantity: 5,
-                price: 120,
-                expiration_time: "2021-01-01 00:00:01".into(),
-                order_type,
-            }]
+            storage.view_items(seller.id).unwrap(),
+            vec![("funds".into(), 7), ("ore".into(), 50), ("gem".into(), 5)]
+        );
+        assert_eq!(
+            storage.view_items(buyer.id).unwrap(),
+            vec![("funds".into(), 0), ("ore".into(), 50), ("gem".into(), 6)]
         );
+    }
 
-        // items are returned but fee is not
+    #[test]
+    fn test_currencies() {
+        let mut storage = Storage::open(":memory:").unwrap();
+        let user = storage.login("user").unwrap();
+        let other = storage.login("other").unwrap();
+        assert!(storage
+            .set_currencies(&["gold".into(), "gem".into()])
+            .is_ok());
+        assert_eq!(
+            storage.view_currencies().unwrap(),
+            vec!["funds".to_owned(), "gold".into(), "gem".into()]
+        );
+
+        assert!(storage.deposit(user.id, "ore", 10).is_ok());
+        assert_eq!(
+            u32::from(
+                storage
+                    .set_exchange_rates(vec!["ore/gold=1".parse().unwrap()])
+                    .unwrap_err()
+                    .code()
+            ),
+            124
+        );
+        assert!(storage
+            .set_exchange_rates(vec!["gem/gold=100".parse().unwrap()])
+            .is_ok());
+
+        // the fee is taken in the currency of the order
+        assert!(storage.deposit(user.id, "gold", 10).is_ok());
+        let order_id = storage
+            .place_sell_order(
+                SellOrderType::Auction,
+                user.id,
+                "ore",
+                10,
+                100,
+                "gold",
+                EXPIRATION_TIME,
+            )
+            .unwrap();
         assert_eq!(
             storage.view_items(user.id).unwrap(),
-            vec![
-                ("funds".into(), 100 - 9 - 6 - 7),
-                ("item1".into(), 10),
-                ("item2".into(), 15)
-            ]
+            vec![("funds".into(), 0), ("gold".into(), 4)]
         );
 
-        // And finally, cancel the last order
+        assert!(storage.deposit(other.id, "gem", 2).is_ok());
+        assert_eq!(
+            u32::from(
+                storage
+                    .exchange(other.id, 1, "gold", "gem")
+                    .unwrap_err()
+                    .code()
+            ),
+            125
+        );
+        assert_eq!(storage.exchange(other.id, 1, "gem", "gold").unwrap(), 100);
+        // currencies are matched regardless of the spelling
+        assert_eq!(storage.exchange(other.id, 1, " GEM", "Gold").unwrap(), 100);
+        assert_eq!(
+            storage
+                .exchange(other.id, 1, "gems", "gold")
+                .unwrap_err()
+                .code(),
+            ErrorCode::ExchangeNotListed
+        );
         assert!(storage
-            .process_expired_sell_orders(EXPIRATION_TIME + 2)
+            .place_bid_on_auction_sell_order(other.id, order_id, 150)
             .is_ok());
+        assert!(storage.process_expired_sell_orders(EXPIRATION_TIME).is_ok());
         assert_eq!(
             storage.view_items(user.id).unwrap(),
-            vec![
-                ("funds".into(), 100 - 9 - 6 - 7),
-                ("item1".into(), 10),
-                ("item2".into(), 20)
-            ]
+            vec![("funds".into(), 0), ("gold".into(), 154)]
+        );
+        assert_eq!(
+            storage.view_items(other.id).unwrap(),
+            vec![("funds".into(), 0), ("gold".into(), 50), ("ore".into(), 10)]
         );
+
+        // currencies that are not configured anymore are regular items
+        assert!(storage.set_currencies(&[]).is_ok());
+        assert_eq!(storage.view_currencies().unwrap(), vec!["funds".to_owned()]);
     }
 
     #[test]
-    fn test_execute_immediate_sell_order_err() {
-        let storage = Storage::open(":memory:").unwrap();
+    fn test_catalog() {
+        let mut storage = Storage::open(":memory:").unwrap();
+        let user = storage.login("user").unwrap();
+        let other = storage.login("other").unwrap();
+        assert!(storage.deposit(user.id, "Swrod", 1).is_ok());
+
+        let item = |name: &str, category: &str, max_stack, tradeable| CatalogItem {
+            name: name.into(),
+            display_name: None,
+            category: Some(category.into()),
+            description: String::new(),
+            max_stack,
+            tradeable,
+            aliases: Vec::new(),
+        };
+        let catalog = vec![
+            item("Sword", "weapons", None, true),
+            item("ore", "resources", Some(10), true),
+            item("medal", "rewards", None, false),
+        ];
+        assert!(storage.set_catalog(&catalog, true).is_ok());
+        assert_eq!(storage.view_catalog().unwrap(), catalog);
+
+        let error_code = |result: Result<()>| u32::from(result.unwrap_err().code());
+        // unknown items are rejected in the strict mode, even if they were deposited before
+        assert_eq!(error_code(storage.deposit(user.id, "Swrod", 1)), 126);
+        assert_eq!(error_code(storage.deposit(user.id, "Axe", 1)), 126);
+        assert!(storage.deposit(user.id, "funds", 100).is_ok());
+        assert!(storage.deposit(user.id, "Sword", 2).is_ok());
+        assert!(storage.deposit(user.id, "medal", 1).is_ok());
 
-        let seller = storage.login("seller").unwrap();
-        assert!(storage.deposit(seller.id, "funds", 100).is_ok());
-        assert!(storage.deposit(seller.id, "item1", 10).is_ok());
-        assert!(storage
+        assert_eq!(
+            storage.deposit(user.id, "ore", 11).unwrap_err().to_string(),
+            "At most 10 ore(s) can be held by a user"
+        );
+        assert!(storage.deposit(user.id, "ore", 10).is_ok());
+        assert!(storage.deposit(other.id, "ore", 10).is_ok());
+        assert_eq!(
+            error_code(storage.give(user.id, "other", "ore", 1, EXPIRATION_TIME)),
+            127
+        );
+        assert_eq!(
+            error_code(storage.give(user.id, "other", "medal", 1, EXPIRATION_TIME)),
+            128
+        );
+        assert_eq!(
+            u32::from(
+                storage
+                    .place_sell_order(
+                        SellOrderType::Immediate,
+                        user.id,
+                        "medal",
+                        1,
+                        10,
+                        "funds",
+                        EXPIRATION_TIME
+                    )
+                    .unwrap_err()
+                    .code()
+            ),
+            128
+        );
+
+        // orders can be filtered by category
+        let ore_order_id = storage
             .place_sell_order(
                 SellOrderType::Immediate,
-                seller.id,
-                "item1",
-                7,
+                user.id,
+                "ore",
+                5,
                 10,
-                EXPIRATION_TIME
+                "funds",
+                EXPIRATION_TIME,
             )
-            .is_ok());
+            .unwrap();
         assert!(storage
             .place_sell_order(
-                SellOrderType::Auction,
-                seller.id,
-                "item1",
-                3,
-                11,
-                EXPIRATION_TIME
+                SellOrderType::Immediate,
+                user.id,
+                "Sword",
+                1,
+                10,
+                "funds",
+                EXPIRATION_TIME,
             )
             .is_ok());
+        let orders = storage.view_sell_orders(Some("weapons")).unwrap();
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].item_name, "Sword");
+        assert_eq!(storage.view_sell_orders(Some("rewards")).unwrap(), vec![]);
+        assert_eq!(storage.view_sell_orders(None).unwrap().len(), 2);
+
+        // buyers can't exceed the stack limit either
+        assert!(storage.deposit(other.id, "funds", 100).is_ok());
+        assert_eq!(
+            error_code(storage.execute_immediate_sell_order(
+                other.id,
+                ore_order_id,
+                EXPIRATION_TIME
+            )),
+            127
+        );
+
+        // without the catalog any item can be deposited again
+        assert!(storage.set_catalog(&[], false).is_ok());
+        assert_eq!(storage.view_catalog().unwrap(), vec![]);
+        assert!(storage.deposit(user.id, "Axe", 1).is_ok());
+        assert!(storage.deposit(user.id, "ore", 100).is_ok());
+    }
+
+    #[test]
+    fn test_item_names() {
+        let mut storage = Storage::open(":memory:").unwrap();
+        let user = storage.login("user").unwrap();
+        assert!(storage.deposit(user.id, "funds", 100).is_ok());
+
+        // the first spelling of an item is kept, others refer to the same item
+        assert!(storage.deposit(user.id, " Iron  Sword", 1).is_ok());
+        assert!(storage.deposit(user.id, "iron sword ", 1).is_ok());
+        assert!(storage.deposit(user.id, "IRON SWORD", 1).is_ok());
+        assert!(storage.deposit(user.id, "ＩＲＯＮ ＳＷＯＲＤ", 1).is_ok());
+        assert_eq!(
+            storage.view_items(user.id).unwrap(),
+            vec![("funds".into(), 100), ("Iron Sword".into(), 4)]
+        );
+        assert_eq!(
+            storage.deposit(user.id, "   ", 1).unwrap_err().code(),
+            ErrorCode::EmptyItemName
+        );
+        assert_eq!(
+            storage
+                .withdraw(user.id, " \u{3000}", 1)
+                .unwrap_err()
+                .code(),
+            ErrorCode::EmptyItemName
+        );
+
+        // similar names are suggested when there is no such item
+        assert!(storage.deposit(user.id, "ore", 1).is_ok());
+        assert!(storage.deposit(user.id, "orb", 1).is_ok());
+        assert_eq!(
+            storage
+                .withdraw(user.id, "iron swrod", 1)
+                .unwrap_err()
+                .to_string(),
+            "No such item 'iron swrod', did you mean 'Iron Sword'?"
+        );
+        assert_eq!(
+            storage
+                .place_sell_order(
+                    SellOrderType::Immediate,
+                    user.id,
+                    "orr",
+                    1,
+                    10,
+                    "funds",
+                    EXPIRATION_TIME
+                )
+                .unwrap_err()
+                .to_string(),
+            "No such item 'orr', did you mean 'orb' or 'ore'?"
+        );
+        assert_eq!(
+            storage.withdraw(user.id, "axe", 1).unwrap_err().to_string(),
+            "No such item 'axe'"
+        );
+        assert_eq!(
+            storage
+                .place_sell_order(
+                    SellOrderType::Immediate,
+                    user.id,
+                    "ORE",
+                    1,
+                    10,
+                    "ore",
+                    EXPIRATION_TIME
+                )
+                .unwrap_err()
+                .code(),
+            ErrorCode::SamePriceItem
+        );
+
+        // aliases defined in the catalog refer to the item
+        let catalog = vec![CatalogItem {
+            name: "iron sword".into(),
+            display_name: None,
+            category: None,
+            description: String::new(),
+            max_stack: None,
+            tradeable: true,
+            aliases: vec!["Blade".into(), "sword".into()],
+        }];
+        assert!(storage.set_catalog(&catalog, false).is_ok());
+        assert_eq!(
+            storage.view_catalog().unwrap()[0].aliases,
+            vec!["Blade".to_owned(), "sword".to_owned()]
+        );
+        assert!(storage.withdraw(user.id, "blade", 1).is_ok());
+        assert!(storage.deposit(user.id, "SWORD", 2).is_ok());
+        assert_eq!(
+            storage.view_items(user.id).unwrap()[1],
+            ("Iron Sword".into(), 5)
+        );
+        assert_eq!(
+            storage
+                .withdraw(user.id, "blaed", 1)
+                .unwrap_err()
+                .to_string(),
+            "No such item 'blaed', did you mean 'Iron Sword'?"
+        );
+
+        // an alias can't be the name of another item
+        let catalog = vec![CatalogItem {
+            aliases: vec!["Ore".into()],
+            ..catalog[0].clone()
+        }];
+        assert_eq!(
+            storage
+                .set_catalog(&catalog, false)
+                .unwrap_err()
+                .to_string(),
+            "Alias 'Ore' is already used by item 'ore'"
+        );
+
+        // exchanged currencies are resolved by aliases and respect the stack limit
+        assert!(storage
+            .set_currencies(&["gold".into(), "gem".into()])
+            .is_ok());
+        let catalog = vec![CatalogItem {
+            name: "gold".into(),
+            max_stack: Some(150),
+            aliases: vec!["gp".into()],
+            ..catalog[0].clone()
+        }];
+        assert!(storage.set_catalog(&catalog, false).is_ok());
+        assert!(storage
+            .set_exchange_rates(vec!["gem/gold=100".parse().unwrap()])
+            .is_ok());
+        assert!(storage.deposit(user.id, "gem", 2).is_ok());
+        assert_eq!(storage.exchange(user.id, 1, "Gem", "GP").unwrap(), 100);
+        assert_eq!(
+            storage
+                .exchange(user.id, 1, "gem", "gold")
+                .unwrap_err()
+                .code(),
+            ErrorCode::StackLimitExceeded
+        );
+        assert_eq!(
+            storage.view_items(user.id).unwrap()[4..],
+            [("gold".into(), 100), ("gem".into(), 1)]
+        );
+    }
 
+    #[test]
+    fn test_merge_duplicate_items() {
+        // a database created before item names were normalized
+        let path = std::env::temp_dir().join(format!("auction-house-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let db = rusqlite::Connection::open(path).unwrap();
+        db.execute_batch(
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, username TEXT NOT NULL UNIQUE) STRICT;
+            CREATE TABLE items (id INTEGER PRIMARY KEY, name TEXT NOT NULL UNIQUE) STRICT;
+            CREATE TABLE user_items (
+                user_id INTEGER NOT NULL,
+                item_id INTEGER NOT NULL,
+                quantity INTEGER NOT NULL CHECK(quantity >= 0),
+                PRIMARY KEY (user_id, item_id)
+            ) STRICT;
+            CREATE TABLE sell_orders (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                seller_id INTEGER NOT NULL,
+                item_id INTEGER NOT NULL,
+                quantity INTEGER NOT NULL CHECK(quantity > 0),
+                price INTEGER NOT NULL CHECK(price > 0),
+                expiration_time INTEGER NOT NULL,
+                buyer_id INTEGER
+            ) STRICT;
+            INSERT INTO users (id, username) VALUES (1, 'alice'), (2, 'bob');
+            INSERT INTO items (id, name) VALUES (1, 'funds'), (2, 'Sword'), (3, 'sword '), (4, 'SWORD');
+            INSERT INTO user_items (user_id, item_id, quantity)
+            VALUES (1, 1, 10), (1, 2, 1), (1, 3, 2), (2, 4, 3);
+            INSERT INTO sell_orders (seller_id, item_id, quantity, price, expiration_time, buyer_id)
+            VALUES (1, 3, 1, 5, 4102444800, 1);",
+        )
+        .unwrap();
+        drop(db);
+
+        let storage = Storage::open(path).unwrap();
+        let alice = storage.login("alice").unwrap();
+        let bob = storage.login("bob").unwrap();
         assert_eq!(
-            storage.view_sell_orders().unwrap(),
-            vec![
-                SellOrder {
-                    id: 1,
-                    seller_name: "seller".into(),
-                    item_name: "item1".into(),
-                    quantity: 7,
-                    price: 10,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type: SellOrderType::Immediate,
-                },
-                SellOrder {
-                    id: 2,
-                    seller_name: "seller".into(),
-                    item_name: "item1".into(),
-                    quantity: 3,
-                    price: 11,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type: SellOrderType::Auction,
-                }
-            ]
+            storage.view_items(alice.id).unwrap(),
+            vec![("funds".into(), 10), ("Sword".into(), 3)]
+        );
+        assert_eq!(
+            storage.view_items(bob.id).unwrap(),
+            vec![("Sword".into(), 3)]
         );
+        let sell_orders = storage.view_sell_orders(None).unwrap();
+        assert_eq!(sell_orders.len(), 1);
+        assert_eq!(sell_orders[0].item_name, "Sword");
 
-        // You can't buy your own items
-        assert!(!storage.execute_immediate_sell_order(seller.id, 1).is_ok());
+        // every spelling refers to the merged item
+        assert!(storage.withdraw(bob.id, "sword", 3).is_ok());
+        assert!(storage
+            .execute_immediate_sell_order(bob.id, sell_orders[0].id, EXPIRATION_TIME)
+            .is_err());
+        assert!(storage.deposit(bob.id, "funds", 5).is_ok());
+        assert!(storage
+            .execute_immediate_sell_order(bob.id, sell_orders[0].id, EXPIRATION_TIME)
+            .is_ok());
+        assert_eq!(
+            storage.view_items(bob.id).unwrap(),
+            vec![("funds".into(), 0), ("Sword".into(), 1)]
+        );
+        drop(storage);
+
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{path}{suffix}"));
+        }
+    }
 
+    #[test]
+    fn test_item_instances() {
+        let mut storage = Storage::open(":memory:").unwrap();
+        let seller = storage.login("seller").unwrap();
         let buyer = storage.login("buyer").unwrap();
+        assert!(storage.deposit(seller.id, "funds", 100).is_ok());
+        assert!(storage.deposit(buyer.id, "funds", 100).is_ok());
 
-        // try to buy non-existing sell order
-        assert!(!storage.execute_immediate_sell_order(buyer.id, 100).is_ok());
+        let sharp = serde_json::json!({"durability": 80, "enchantments": ["fire"]});
+        let blunt = serde_json::json!({"durability": 5});
+        let instance = |id, attributes: &serde_json::Value| ItemInstance {
+            id,
+            item_name: "Sword".into(),
+            attributes: attributes.clone(),
+        };
+        assert_eq!(
+            storage
+                .deposit_instance(seller.id, "Sword", &sharp)
+                .unwrap(),
+            instance(1, &sharp)
+        );
+        // instances of the same item are not merged
+        assert_eq!(
+            storage
+                .deposit_instance(seller.id, "sword", &blunt)
+                .unwrap(),
+            instance(2, &blunt)
+        );
+        assert_eq!(
+            storage.view_instances(seller.id).unwrap(),
+            vec![instance(1, &sharp), instance(2, &blunt)]
+        );
+        assert_eq!(
+            storage.view_items(seller.id).unwrap(),
+            vec![("funds".into(), 100)]
+        );
 
-        // try to buy from non-existing user
-        assert!(!storage.execute_immediate_sell_order(UserId(100), 1).is_ok());
+        let error_code = |result: Result<()>| result.unwrap_err().code();
+        assert_eq!(
+            storage
+                .deposit_instance(seller.id, "Sword", &serde_json::json!(80))
+                .unwrap_err()
+                .code(),
+            ErrorCode::InvalidAttributes
+        );
+        assert_eq!(
+            error_code(storage.withdraw_instance(buyer.id, 2)),
+            ErrorCode::InstanceNotFound
+        );
+        assert!(storage.withdraw_instance(seller.id, 2).is_ok());
+        assert_eq!(
+            error_code(storage.withdraw_instance(seller.id, 2)),
+            ErrorCode::InstanceNotFound
+        );
 
-        // try to buy without enough funds
-        assert!(!storage.execute_immediate_sell_order(buyer.id, 1).is_ok());
+        // buyers see the attributes of the sold instance
+        let order_id = storage
+            .place_instance_sell_order(
+                SellOrderType::Immediate,
+                seller.id,
+                1,
+                50,
+                "funds",
+                EXPIRATION_TIME,
+            )
+            .unwrap();
+        let orders = storage.view_sell_orders(None).unwrap();
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].quantity, 1);
+        assert_eq!(orders[0].instance, Some(instance(1, &sharp)));
+        // the instance is held by the order
+        assert_eq!(storage.view_instances(seller.id).unwrap(), vec![]);
+        assert_eq!(
+            error_code(storage.withdraw_instance(seller.id, 1)),
+            ErrorCode::InstanceNotFound
+        );
 
-        // try to buy auction order with not enough funds
-        assert!(!storage.execute_immediate_sell_order(buyer.id, 2).is_ok());
+        assert!(storage
+            .execute_immediate_sell_order(buyer.id, order_id, EXPIRATION_TIME)
+            .is_ok());
+        assert_eq!(
+            storage.view_instances(buyer.id).unwrap(),
+            vec![instance(1, &sharp)]
+        );
+        assert_eq!(
+            storage.view_items(seller.id).unwrap(),
+            vec![("funds".into(), 100 - 3 + 50)]
+        );
+        assert_eq!(
+            storage
+                .place_instance_sell_order(
+                    SellOrderType::Immediate,
+                    seller.id,
+                    1,
+                    50,
+                    "funds",
+                    EXPIRATION_TIME,
+                )
+                .unwrap_err()
+                .code(),
+            ErrorCode::InstanceNotFound
+        );
 
-        // repeat with funds
-        assert!(storage.deposit(buyer.id, "funds", 100).is_ok());
+        // cancelled orders return the instance
+        let order_id = storage
+            .place_instance_sell_order(
+                SellOrderType::Auction,
+                buyer.id,
+                1,
+                10,
+                "funds",
+                EXPIRATION_TIME,
+            )
+            .unwrap();
+        assert!(storage.cancel_sell_order(buyer.id, order_id).is_ok());
+        assert_eq!(
+            storage.view_instances(buyer.id).unwrap(),
+            vec![instance(1, &sharp)]
+        );
 
-        // still can't buy auction order
-        assert!(!storage.execute_immediate_sell_order(buyer.id, 2).is_ok());
+        // expired auctions move the instance to the highest bidder
+        let order_id = storage
+            .place_instance_sell_order(
+                SellOrderType::Auction,
+                buyer.id,
+                1,
+                10,
+                "funds",
+                EXPIRATION_TIME,
+            )
+            .unwrap();
+        assert!(storage
+            .place_bid_on_auction_sell_order(seller.id, order_id, 20)
+            .is_ok());
+        assert!(storage.process_expired_sell_orders(EXPIRATION_TIME).is_ok());
+        assert_eq!(storage.view_instances(buyer.id).unwrap(), vec![]);
+        assert_eq!(
+            storage.view_instances(seller.id).unwrap(),
+            vec![instance(1, &sharp)]
+        );
 
-        // while immediate order should be bought
-        assert!(storage.execute_immediate_sell_order(buyer.id, 1).is_ok());
+        // instances count towards the stack limit of the item
+        let catalog = vec![CatalogItem {
+            name: "Sword".into(),
+            display_name: None,
+            category: None,
+            description: String::new(),
+            max_stack: Some(1),
+            tradeable: true,
+            aliases: Vec::new(),
+        }];
+        assert!(storage.set_catalog(&catalog, false).is_ok());
+        assert_eq!(
+            storage
+                .deposit_instance(seller.id, "Sword", &blunt)
+                .unwrap_err()
+                .code(),
+            ErrorCode::StackLimitExceeded
+        );
+        assert_eq!(
+            error_code(storage.deposit(seller.id, "Sword", 1)),
+            ErrorCode::StackLimitExceeded
+        );
     }
 
     #[test]
-    fn test_execute_immediate_sell_order_ok() {
+    fn test_bundle_orders() {
         let storage = Storage::open(":memory:").unwrap();
-
         let seller = storage.login("seller").unwrap();
+        let buyer = storage.login("buyer").unwrap();
         assert!(storage.deposit(seller.id, "funds", 100).is_ok());
-        assert!(storage.deposit(seller.id, "item1", 10).is_ok());
-        assert!(storage.deposit(seller.id, "item2", 20).is_ok());
+        assert!(storage.deposit(seller.id, "Sword", 2).is_ok());
+        assert!(storage.deposit(seller.id, "Shield", 1).is_ok());
+        assert!(storage.deposit(seller.id, "arrow", 100).is_ok());
+        assert!(storage.deposit(buyer.id, "funds", 200).is_ok());
+
+        let item = |name: &str, quantity| Item {
+            name: name.into(),
+            quantity,
+        };
+        let bundle = vec![item("Sword", 1), item("Shield", 1), item("arrow", 50)];
+        let error_code = |result: Result<i64>| result.unwrap_err().code();
+        assert_eq!(
+            error_code(storage.place_bundle_sell_order(
+                SellOrderType::Immediate,
+                seller.id,
+                &[],
+                100,
+                "funds",
+                EXPIRATION_TIME,
+            )),
+            ErrorCode::EmptyBundle
+        );
+        assert_eq!(
+            error_code(storage.place_bundle_sell_order(
+                SellOrderType::Immediate,
+                seller.id,
+                &[item("Sword", 1), item("Shield", 2)],
+                100,
+                "funds",
+                EXPIRATION_TIME,
+            )),
+            ErrorCode::InsufficientItems
+        );
+        assert_eq!(
+            error_code(storage.place_bundle_sell_order(
+                SellOrderType::Immediate,
+                seller.id,
+                &[item("Sword", 1), item("funds", 1)],
+                100,
+                "funds",
+                EXPIRATION_TIME,
+            )),
+            ErrorCode::FundsNotTradable
+        );
+        // nothing is taken from the seller by failed orders
         assert_eq!(
             storage.view_items(seller.id).unwrap(),
             vec![
                 ("funds".into(), 100),
-                ("item1".into(), 10),
-                ("item2".into(), 20),
+                ("Sword".into(), 2),
+                ("Shield".into(), 1),
+                ("arrow".into(), 100)
             ]
         );
 
-        assert!(storage
-            .place_sell_order(
+        let order_id = storage
+            .place_bundle_sell_order(
                 SellOrderType::Immediate,
                 seller.id,
-                "item1",
-                2,
-                2,
-                EXPIRATION_TIME + 1
+                &bundle,
+                100,
+                "funds",
+                EXPIRATION_TIME,
             )
-            .is_ok());
-
-        // sell fee is (5% + 1)
+            .unwrap();
+        let order = storage.get_sell_order(order_id).unwrap();
+        assert_eq!(order.bundle, bundle);
+        assert_eq!(
+            order.to_string(),
+            "#1: seller is selling a bundle of Sword, Shield, 50 arrow(s) for 100 funds until 2021-01-01 00:00:00"
+        );
+        // all items are held by the order
         assert_eq!(
             storage.view_items(seller.id).unwrap(),
             vec![
-                ("funds".into(), 99),
-                ("item1".into(), 8),
-                ("item2".into(), 20),
+                ("funds".into(), 100 - 6),
+                ("Sword".into(), 1),
+                ("arrow".into(), 50)
             ]
         );
 
         assert!(storage
-            .place_sell_order(
+            .execute_immediate_sell_order(buyer.id, order_id, EXPIRATION_TIME)
+            .is_ok());
+        assert_eq!(
+            storage.view_items(buyer.id).unwrap(),
+            vec![
+                ("funds".into(), 100),
+                ("Sword".into(), 1),
+                ("Shield".into(), 1),
+                ("arrow".into(), 50)
+            ]
+        );
+        assert_eq!(storage.view_sell_orders(None).unwrap(), vec![]);
+
+        // cancelled bundles return all items, repeated items are merged into a single line
+        let order_id = storage
+            .place_bundle_sell_order(
                 SellOrderType::Immediate,
-                seller.id,
-                "item1",
-                3,
-                3,
-                EXPIRATION_TIME + 2
+                buyer.id,
+                &[item("arrow", 20), item("Shield", 1), item("arrow", 30)],
+                50,
+                "funds",
+                EXPIRATION_TIME,
             )
-            .is_ok());
+            .unwrap();
+        assert_eq!(
+            storage.get_sell_order(order_id).unwrap().bundle,
+            vec![item("arrow", 50), item("Shield", 1)]
+        );
+        assert!(storage.cancel_sell_order(buyer.id, order_id).is_ok());
+        assert_eq!(
+            storage.view_items(buyer.id).unwrap(),
+            vec![
+                ("funds".into(), 100 - 3),
+                ("Sword".into(), 1),
+                ("Shield".into(), 1),
+                ("arrow".into(), 50)
+            ]
+        );
+
+        // expired auctions move all items to the highest bidder
+        let order_id = storage
+            .place_bundle_sell_order(
+                SellOrderType::Auction,
+                buyer.id,
+                &[item("Sword", 1), item("Shield", 1)],
+                10,
+                "funds",
+                EXPIRATION_TIME,
+            )
+            .unwrap();
+        // and unsold ones return them to the seller
         assert!(storage
-            .place_sell_order(
+            .place_bundle_sell_order(
                 SellOrderType::Immediate,
                 seller.id,
-                "item1",
-                4,
-                4,
-                EXPIRATION_TIME + 3
+                &[item("arrow", 10), item("Sword", 1)],
+                10,
+                "funds",
+                EXPIRATION_TIME,
             )
             .is_ok());
         assert!(storage
-            .place_sell_order(
-                SellOrderType::Immediate,
-                seller.id,
-                "item1",
-                1,
-                4,
-                EXPIRATION_TIME + 4
-            )
+            .place_bid_on_auction_sell_order(seller.id, order_id, 20)
             .is_ok());
+        assert!(storage.process_expired_sell_orders(EXPIRATION_TIME).is_ok());
+        assert_eq!(
+            storage.view_items(buyer.id).unwrap(),
+            vec![("funds".into(), 97 - 1 + 20), ("arrow".into(), 50)]
+        );
+        assert_eq!(
+            storage.view_items(seller.id).unwrap(),
+            vec![
+                ("funds".into(), 94 + 100 - 1 - 20),
+                ("Sword".into(), 2),
+                ("Shield".into(), 1),
+                ("arrow".into(), 50)
+            ]
+        );
+        assert_eq!(storage.view_sell_orders(None).unwrap(), vec![]);
+    }
 
-        assert!(storage
-            .place_sell_order(
+    #[test]
+    fn test_price_history() {
+        let storage = Storage::open(":memory:").unwrap();
+        let seller = storage.login("seller").unwrap();
+        let buyer = storage.login("buyer").unwrap();
+        assert!(storage.deposit(seller.id, "funds", 100).is_ok());
+        assert!(storage.deposit(seller.id, "arrow", 1000).is_ok());
+        assert!(storage.deposit(seller.id, "bow", 2).is_ok());
+        assert!(storage.deposit(buyer.id, "funds", 1000).is_ok());
+        assert!(storage.deposit(buyer.id, "gem", 1).is_ok());
+
+        let no_stats = ItemStats {
+            item_name: "arrow".into(),
+            last_trade: None,
+            volume_24h: 0,
+            volume_7d: 0,
+            vwap_7d: None,
+            min_price_7d: None,
+            max_price_7d: None,
+            active_listings: 0,
+        };
+        assert_eq!(
+            storage.item_stats("Arrow", EXPIRATION_TIME).unwrap(),
+            no_stats
+        );
+        assert_eq!(
+            storage.suggest_price("arrow", 10, EXPIRATION_TIME).unwrap(),
+            None
+        );
+        assert_eq!(
+            storage
+                .item_stats("arow", EXPIRATION_TIME)
+                .unwrap_err()
+                .code(),
+            ErrorCode::ItemNotFound
+        );
+
+        let sell = |order_type, quantity, price, price_item: &str, expiration_time| {
+            storage
+                .place_sell_order(
+                    order_type,
+                    seller.id,
+                    "arrow",
+                    quantity,
+                    price,
+                    price_item,
+                    expiration_time,
+                )
+                .unwrap()
+        };
+        // trades older than 7 days are only the last trade
+        for (quantity, price, unix_now) in [
+            (10, 50, EXPIRATION_TIME - 8 * DAY_SECONDS),
+            (10, 100, EXPIRATION_TIME - 2 * DAY_SECONDS),
+            (20, 300, EXPIRATION_TIME - 3600),
+        ] {
+            let order_id = sell(
                 SellOrderType::Immediate,
-                seller.id,
-                "item2",
-                5,
-                5,
-                EXPIRATION_TIME + 5
-            )
+                quantity,
+                price,
+                "funds",
+                EXPIRATION_TIME,
+            );
+            assert!(storage
+                .execute_immediate_sell_order(buyer.id, order_id, unix_now)
+                .is_ok());
+        }
+        // settled auctions are recorded at their expiration time
+        let order_id = sell(SellOrderType::Auction, 10, 10, "funds", EXPIRATION_TIME);
+        assert!(storage
+            .place_bid_on_auction_sell_order(buyer.id, order_id, 200)
             .is_ok());
+        // barters and bundles are recorded, but not counted
+        let order_id = sell(SellOrderType::Immediate, 5, 1, "gem", EXPIRATION_TIME);
         assert!(storage
-            .place_sell_order(
+            .execute_immediate_sell_order(buyer.id, order_id, EXPIRATION_TIME)
+            .is_ok());
+        let bundle = [
+            Item {
+                name: "arrow".into(),
+                quantity: 10,
+            },
+            Item {
+                name: "bow".into(),
+                quantity: 1,
+            },
+        ];
+        let order_id = storage
+            .place_bundle_sell_order(
                 SellOrderType::Immediate,
                 seller.id,
-                "item2",
+                &bundle,
                 10,
-                10,
-                EXPIRATION_TIME + 6
+                "funds",
+                EXPIRATION_TIME,
             )
+            .unwrap();
+        assert!(storage
+            .execute_immediate_sell_order(buyer.id, order_id, EXPIRATION_TIME - 1)
             .is_ok());
         assert!(storage
-            .place_sell_order(
+            .place_bundle_sell_order(
                 SellOrderType::Immediate,
                 seller.id,
-                "item2",
-                5,
-                15,
-                EXPIRATION_TIME + 7
+                &bundle,
+                10,
+                "funds",
+                EXPIRATION_TIME + DAY_SECONDS,
             )
             .is_ok());
+        sell(
+            SellOrderType::Immediate,
+            10,
+            10,
+            "funds",
+            EXPIRATION_TIME + DAY_SECONDS,
+        );
+        assert!(storage.process_expired_sell_orders(EXPIRATION_TIME).is_ok());
 
         assert_eq!(
-            storage.view_items(seller.id).unwrap(),
-            vec![("funds".into(), 93)]
+            storage.item_stats("arrow", EXPIRATION_TIME).unwrap(),
+            ItemStats {
+                last_trade: Some(Trade {
+                    quantity: 10,
+                    price: 200,
+                    time: "2021-01-01 00:00:00".into(),
+                }),
+                volume_24h: 20 + 10,
+                volume_7d: 10 + 20 + 10,
+                vwap_7d: Some(15.0),
+                min_price_7d: Some(10.0),
+                max_price_7d: Some(20.0),
+                active_listings: 2,
+                ..no_stats.clone()
+            }
         );
-
-        let buyer = storage.login("buyer").unwrap();
-        assert!(storage.deposit(buyer.id, "funds", 20).is_ok());
-        // 1 item1 for 4 funds
-        assert!(storage.execute_immediate_sell_order(buyer.id, 4).is_ok());
-
-        // check items and funds
+        // median of 10, 15 and 20 funds per arrow
         assert_eq!(
-            storage.view_items(buyer.id).unwrap(),
-            vec![("funds".into(), 16), ("item1".into(), 1)]
+            storage.suggest_price("arrow", 10, EXPIRATION_TIME).unwrap(),
+            Some(150)
         );
         assert_eq!(
-            storage.view_items(seller.id).unwrap(),
-            vec![("funds".into(), 97)]
+            storage.suggest_price("arrow", 1, EXPIRATION_TIME).unwrap(),
+            Some(15)
+        );
+        assert_eq!(
+            storage
+                .suggest_price("arrow", 0, EXPIRATION_TIME)
+                .unwrap_err()
+                .code(),
+            ErrorCode::InvalidQuantity
+        );
+        // the bundle is a flagged trade per item line, each with the price of the whole bundle
+        assert_eq!(
+            storage
+                .db
+                .query_row(
+                    "SELECT COUNT(*), SUM(quantity) FROM trades WHERE bundle = 1 AND price = 10",
+                    [],
+                    |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+                )
+                .unwrap(),
+            (2, 11)
         );
 
-        assert!(storage
-            .process_expired_sell_orders(EXPIRATION_TIME + 4)
-            .is_ok());
+        let unix_later = EXPIRATION_TIME + 30 * DAY_SECONDS;
+        let stats = storage.item_stats("arrow", unix_later).unwrap();
+        assert_eq!(stats.last_trade.unwrap().price, 200);
+        assert_eq!(stats.volume_7d, 0);
+        assert_eq!(stats.vwap_7d, None);
+        assert_eq!(storage.suggest_price("arrow", 1, unix_later).unwrap(), None);
+    }
 
-        // try to buy expired order
-        assert!(!storage.execute_immediate_sell_order(buyer.id, 3).is_ok());
+    #[test]
+    fn test_watches() {
+        let storage = Storage::open(":memory:").unwrap();
+        let seller = storage.login("seller").unwrap();
+        let watcher = storage.login("watcher").unwrap();
+        assert!(storage.deposit(seller.id, "funds", 100).is_ok());
+        assert!(storage.deposit(seller.id, "arrow", 100).is_ok());
+        assert!(storage.deposit(seller.id, "gem", 10).is_ok());
+        assert!(storage.deposit(watcher.id, "funds", 100).is_ok());
 
-        // check items and funds
+        let item_watch = WatchKind::ItemBelow {
+            item_name: "arrow".into(),
+            price: 2,
+        };
+        let seller_watch = WatchKind::Seller {
+            seller_name: "seller".into(),
+        };
         assert_eq!(
-            storage.view_items(buyer.id).unwrap(),
-            vec![("funds".into(), 16), ("item1".into(), 1)]
+            storage.add_watch(watcher.id, &item_watch).unwrap(),
+            Watch {
+                id: 1,
+                kind: item_watch.clone(),
+            }
         );
-
         assert_eq!(
-            storage.view_items(seller.id).unwrap(),
-            vec![("funds".into(), 97), ("item1".into(), 9)]
+            storage
+                .add_watch(
+                    watcher.id,
+                    &WatchKind::ItemBelow {
+                        item_name: "arow".into(),
+                        price: 2,
+                    }
+                )
+                .unwrap_err()
+                .code(),
+            ErrorCode::ItemNotFound
+        );
+        assert_eq!(
+            storage
+                .add_watch(
+                    watcher.id,
+                    &WatchKind::Seller {
+                        seller_name: "nobody".into(),
+                    }
+                )
+                .unwrap_err()
+                .code(),
+            ErrorCode::UserNotFound
         );
 
-        // buy the rest
-        assert!(storage.execute_immediate_sell_order(buyer.id, 5).is_ok());
-        assert!(storage.execute_immediate_sell_order(buyer.id, 6).is_ok());
-        // not enough money
-        assert!(!storage.execute_immediate_sell_order(buyer.id, 7).is_ok());
-
-        // check items and funds
+        let sell = |item_name: &str, quantity, price, price_item: &str| {
+            storage
+                .place_sell_order(
+                    SellOrderType::Immediate,
+                    seller.id,
+                    item_name,
+                    quantity,
+                    price,
+                    price_item,
+                    EXPIRATION_TIME,
+                )
+                .unwrap()
+        };
+        // 20 arrows for 40 funds is not below 2 funds per arrow, barters are not compared
+        sell("arrow", 20, 40, "funds");
+        sell("arrow", 20, 1, "gem");
+        let order_id = sell("arrow", 20, 39, "funds");
+        // offline watchers find notifications in the mailbox
+        let events = storage.view_mailbox(watcher.id).unwrap();
         assert_eq!(
-            storage.view_items(buyer.id).unwrap(),
+            events,
+            vec![MarketEvent::WatchTriggered {
+                watcher: "watcher".into(),
+                watch: Watch {
+                    id: 1,
+                    kind: item_watch.clone(),
+                },
+                order: storage.get_sell_order(order_id).unwrap(),
+            }]
+        );
+        assert_eq!(
+            events[0].to_string(),
+            "watched arrow below 2 funds - #3: seller is selling 20 arrow(s) for 39 funds until 2021-01-01 00:00:00"
+        );
+        assert_eq!(storage.view_mailbox(watcher.id).unwrap(), vec![]);
+        // sellers aren't notified about own orders
+        assert!(storage.add_watch(seller.id, &item_watch).is_ok());
+        sell("arrow", 1, 1, "funds");
+        assert_eq!(storage.view_mailbox(seller.id).unwrap(), vec![]);
+        assert_eq!(storage.view_mailbox(watcher.id).unwrap().len(), 1);
+
+        // online watchers get notifications pushed
+        let seller_watch = storage.add_watch(watcher.id, &seller_watch).unwrap();
+        assert_eq!(
+            storage.view_watchlist(watcher.id).unwrap(),
             vec![
-                ("funds".into(), 1),
-                ("item1".into(), 1),
-                ("item2".into(), 15)
+                Watch {
+                    id: 1,
+                    kind: item_watch.clone(),
+                },
+                seller_watch.clone()
             ]
         );
+        let mut events = storage.connect(watcher.id);
+        let order_id = sell("gem", 1, 10, "funds");
         assert_eq!(
-            storage.view_items(seller.id).unwrap(),
-            vec![("funds".into(), 97 + 15), ("item1".into(), 9)]
+            events.try_recv().unwrap(),
+            MarketEvent::WatchTriggered {
+                watcher: "watcher".into(),
+                watch: seller_watch.clone(),
+                order: storage.get_sell_order(order_id).unwrap(),
+            }
         );
+        assert_eq!(storage.view_mailbox(watcher.id).unwrap(), vec![]);
+        // the watcher goes offline once the session is closed
+        drop(events);
 
-        // check remaining orders
+        // an order matching several watches of a user is delivered once, about the earliest watch
+        let order_id = sell("arrow", 1, 1, "funds");
         assert_eq!(
-            storage.view_sell_orders().unwrap(),
-            vec![SellOrder {
-                id: 7,
-                seller_name: "seller".into(),
-                item_name: "item2".into(),
-                quantity: 5,
-                price: 15,
-                expiration_time: "2021-01-01 00:00:07".into(),
-                order_type: SellOrderType::Immediate,
+            storage.view_mailbox(watcher.id).unwrap(),
+            vec![MarketEvent::WatchTriggered {
+                watcher: "watcher".into(),
+                watch: Watch {
+                    id: 1,
+                    kind: item_watch.clone(),
+                },
+                order: storage.get_sell_order(order_id).unwrap(),
             }]
         );
-    }
-
-    #[test]
-    fn test_place_a_bid() {
-        let storage = Storage::open(":memory:").unwrap();
 
-        let seller = storage.login("seller").unwrap();
-        assert!(storage.deposit(seller.id, "funds", 100).is_ok());
-        assert!(storage.deposit(seller.id, "item1", 10).is_ok());
-        assert!(storage.deposit(seller.id, "item2", 20).is_ok());
-        assert_eq!(
-            storage.view_items(seller.id).unwrap(),
-            vec![
-                ("funds".into(), 100),
-                ("item1".into(), 10),
-                ("item2".into(), 20),
-            ]
-        );
-        assert!(storage
-            .place_sell_order(
+        // the price of a bundle isn't compared with item watches, 10 arrows would be below 2 funds each
+        let hunter = storage.login("hunter").unwrap();
+        assert!(storage.add_watch(hunter.id, &item_watch).is_ok());
+        let bundle_id = storage
+            .place_bundle_sell_order(
                 SellOrderType::Immediate,
                 seller.id,
-                "item1",
-                7,
-                10,
-                EXPIRATION_TIME
-            )
-            .is_ok());
-        assert!(storage
-            .place_sell_order(
-                SellOrderType::Auction,
-                seller.id,
-                "item1",
-                3,
-                11,
-                EXPIRATION_TIME
+                &[
+                    Item {
+                        name: "arrow".into(),
+                        quantity: 10,
+                    },
+                    Item {
+                        name: "gem".into(),
+                        quantity: 1,
+                    },
+                ],
+                15,
+                "funds",
+                EXPIRATION_TIME,
             )
-            .is_ok());
-        assert!(storage
+            .unwrap();
+        assert_eq!(storage.view_mailbox(hunter.id).unwrap(), vec![]);
+        assert_eq!(
+            storage.view_mailbox(watcher.id).unwrap(),
+            vec![MarketEvent::WatchTriggered {
+                watcher: "watcher".into(),
+                watch: seller_watch.clone(),
+                order: storage.get_sell_order(bundle_id).unwrap(),
+            }]
+        );
+        assert!(storage.remove_watch(hunter.id, 4).is_ok());
+
+        // removed watches aren't triggered
+        assert_eq!(
+            storage.remove_watch(seller.id, 3).unwrap_err().code(),
+            ErrorCode::WatchNotFound
+        );
+        assert!(storage.remove_watch(watcher.id, 3).is_ok());
+        assert!(storage.remove_watch(watcher.id, 1).is_ok());
+        assert_eq!(
+            storage.remove_watch(watcher.id, 1).unwrap_err().code(),
+            ErrorCode::WatchNotFound
+        );
+        sell("arrow", 1, 1, "funds");
+        assert_eq!(storage.view_mailbox(watcher.id).unwrap(), vec![]);
+        assert_eq!(storage.view_watchlist(watcher.id).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_bids_and_reserved_items() {
+        let storage = Storage::open(":memory:").unwrap();
+        let seller = storage.login("seller").unwrap();
+        let bidder = storage.login("bidder").unwrap();
+        assert!(storage.deposit(seller.id, "funds", 100).is_ok());
+        assert!(storage.deposit(seller.id, "Sword", 1).is_ok());
+        assert!(storage.deposit(seller.id, "Shield", 1).is_ok());
+        assert!(storage.deposit(seller.id, "arrow", 50).is_ok());
+        assert!(storage.deposit(bidder.id, "funds", 100).is_ok());
+        assert!(storage.deposit(bidder.id, "gem", 5).is_ok());
+
+        let sword_order = storage
             .place_sell_order(
                 SellOrderType::Auction,
                 seller.id,
-                "item2",
+                "Sword",
                 1,
-                20,
-                EXPIRATION_TIME
+                10,
+                "funds",
+                EXPIRATION_TIME,
             )
-            .is_ok());
-        assert!(storage
+            .unwrap();
+        let arrow_order = storage
             .place_sell_order(
                 SellOrderType::Auction,
                 seller.id,
-                "item2",
-                2,
-                45,
-                EXPIRATION_TIME
+                "arrow",
+                20,
+                1,
+                "gem",
+                EXPIRATION_TIME - 1,
             )
-            .is_ok());
-        assert!(storage
-            .place_sell_order(
-                SellOrderType::Auction,
+            .unwrap();
+        storage
+            .place_bundle_sell_order(
+                SellOrderType::Immediate,
                 seller.id,
-                "item2",
-                2,
-                50,
-                EXPIRATION_TIME
+                &[
+                    Item {
+                        name: "Shield".into(),
+                        quantity: 1,
+                    },
+                    Item {
+                        name: "arrow".into(),
+                        quantity: 10,
+                    },
+                ],
+                30,
+                "funds",
+                EXPIRATION_TIME,
             )
-            .is_ok());
-
-        // check orders
+            .unwrap();
+        assert_eq!(storage.view_bids(bidder.id).unwrap(), vec![]);
         assert_eq!(
-            storage.view_sell_orders().unwrap(),
+            storage.view_reserved_items(seller.id).unwrap(),
             vec![
-                SellOrder {
-                    id: 1,
-                    seller_name: "seller".into(),
-                    item_name: "item1".into(),
-                    quantity: 7,
-                    price: 10,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type: SellOrderType::Immediate,
-                },
-                SellOrder {
-                    id: 2,
-                    seller_name: "seller".into(),
-                    item_name: "item1".into(),
-                    quantity: 3,
-                    price: 11,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type: SellOrderType::Auction,
-                },
-                SellOrder {
-                    id: 3,
-                    seller_name: "seller".into(),
-                    item_name: "item2".into(),
-                    quantity: 1,
-                    price: 20,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type: SellOrderType::Auction,
-                },
-                SellOrder {
-                    id: 4,
-                    seller_name: "seller".into(),
-                    item_name: "item2".into(),
-                    quantity: 2,
-                    price: 45,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type: SellOrderType::Auction,
-                },
-                SellOrder {
-                    id: 5,
-                    seller_name: "seller".into(),
-                    item_name: "item2".into(),
-                    quantity: 2,
-                    price: 50,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type: SellOrderType::Auction,
-                }
+                ("Sword".into(), 0, 1),
+                ("Shield".into(), 0, 1),
+                ("arrow".into(), 0, 30)
             ]
         );
 
-        // You can't can't place a bid on your own items
-        assert!(!storage
-            .place_bid_on_auction_sell_order(seller.id, 2, 20)
-            .is_ok());
-
-        let buyer = storage.login("buyer").unwrap();
-
-        // can't place a bid on non-existing sell order
-        assert!(!storage
-            .place_bid_on_auction_sell_order(buyer.id, 100, 20)
+        assert!(storage
+            .place_bid_on_auction_sell_order(bidder.id, sword_order, 20)
             .is_ok());
-
-        // can't place a bid from non-existing user
-        assert!(!storage
-            .place_bid_on_auction_sell_order(UserId(100), 2, 20)
+        assert!(storage
+            .place_bid_on_auction_sell_order(bidder.id, arrow_order, 2)
             .is_ok());
+        // the order expiring first is the first
+        assert_eq!(
+            storage.view_bids(bidder.id).unwrap(),
+            vec![
+                storage.get_sell_order(arrow_order).unwrap(),
+                storage.get_sell_order(sword_order).unwrap()
+            ]
+        );
+        assert_eq!(
+            storage.view_items(bidder.id).unwrap(),
+            vec![("funds".into(), 80), ("gem".into(), 3)]
+        );
+        assert_eq!(
+            storage.view_reserved_items(bidder.id).unwrap(),
+            vec![("funds".into(), 20, 0), ("gem".into(), 2, 0)]
+        );
+        assert_eq!(storage.view_bids(seller.id).unwrap(), vec![]);
 
-        // can't place a bid without enough funds
-        assert!(!storage
-            .place_bid_on_auction_sell_order(buyer.id, 20, 20)
+        // the bid is no longer escrowed once outbid
+        let another_bidder = storage.login("another bidder").unwrap();
+        assert!(storage.deposit(another_bidder.id, "funds", 100).is_ok());
+        assert!(storage
+            .place_bid_on_auction_sell_order(another_bidder.id, sword_order, 25)
             .is_ok());
+        assert_eq!(
+            storage.view_bids(bidder.id).unwrap(),
+            vec![storage.get_sell_order(arrow_order).unwrap()]
+        );
+        assert_eq!(
+            storage.view_reserved_items(bidder.id).unwrap(),
+            vec![("gem".into(), 2, 0)]
+        );
+        assert_eq!(
+            storage.view_items(bidder.id).unwrap(),
+            vec![("funds".into(), 100), ("gem".into(), 3)]
+        );
 
-        // can't place a bid on auction order with not enough funds
-        assert!(!storage
-            .place_bid_on_auction_sell_order(buyer.id, 1, 20)
-            .is_ok());
+        // nothing is held once the orders are settled
+        assert!(storage.process_expired_sell_orders(EXPIRATION_TIME).is_ok());
+        assert_eq!(storage.view_bids(bidder.id).unwrap(), vec![]);
+        assert_eq!(storage.view_reserved_items(bidder.id).unwrap(), vec![]);
+        assert_eq!(storage.view_reserved_items(seller.id).unwrap(), vec![]);
+    }
 
-        // repeat with funds
+    #[test]
+    fn test_expiration_stack_limit() {
+        let mut storage = Storage::open(":memory:").unwrap();
+        let seller = storage.login("seller").unwrap();
+        let buyer = storage.login("buyer").unwrap();
+        let catalog = vec![CatalogItem {
+            name: "ore".into(),
+            display_name: None,
+            category: None,
+            description: String::new(),
+            max_stack: Some(10),
+            tradeable: true,
+            aliases: Vec::new(),
+        }];
+        assert!(storage.set_catalog(&catalog, false).is_ok());
+        assert!(storage.deposit(seller.id, "funds", 100).is_ok());
+        assert!(storage.deposit(seller.id, "ore", 10).is_ok());
         assert!(storage.deposit(buyer.id, "funds", 100).is_ok());
+        assert!(storage.deposit(buyer.id, "ore", 6).is_ok());
 
-        // still can't place a bid on immediate order
-        assert!(!storage
-            .place_bid_on_auction_sell_order(buyer.id, 1, 20)
-            .is_ok());
+        let sell = |order_type, quantity| {
+            storage
+                .place_sell_order(
+                    order_type,
+                    seller.id,
+                    "ore",
+                    quantity,
+                    10,
+                    "funds",
+                    EXPIRATION_TIME,
+                )
+                .unwrap()
+        };
+        let auction_id = sell(SellOrderType::Auction, 5);
+        sell(SellOrderType::Immediate, 5);
 
-        // while it is possible to place a bid on auction order
+        // the won items must fit when bidding
+        assert_eq!(
+            storage
+                .place_bid_on_auction_sell_order(buyer.id, auction_id, 20)
+                .unwrap_err()
+                .code(),
+            ErrorCode::StackLimitExceeded
+        );
+        assert!(storage.withdraw(buyer.id, "ore", 1).is_ok());
         assert!(storage
-            .place_bid_on_auction_sell_order(buyer.id, 2, 20)
+            .place_bid_on_auction_sell_order(buyer.id, auction_id, 20)
             .is_ok());
 
+        // expired orders are settled even if the held items no longer fit
+        assert!(storage.deposit(buyer.id, "ore", 3).is_ok());
+        assert!(storage.deposit(seller.id, "ore", 10).is_ok());
+        let mut events = storage.subscribe();
+        assert!(storage.process_expired_sell_orders(EXPIRATION_TIME).is_ok());
+        assert!(matches!(
+            events.try_recv(),
+            Ok(MarketEvent::OrderExpired { order }) if order.id == auction_id
+        ));
+        assert!(events.try_recv().is_ok());
+        assert_eq!(storage.view_reserved_items(seller.id).unwrap(), vec![]);
+        assert_eq!(storage.view_bids(buyer.id).unwrap(), vec![]);
         assert_eq!(
             storage.view_items(buyer.id).unwrap(),
-            vec![("funds".into(), 80)]
+            vec![("funds".into(), 80), ("ore".into(), 13)]
         );
-
-        // check that bid is placed
         assert_eq!(
-            storage.view_sell_orders().unwrap(),
-            vec![
-                SellOrder {
-                    id: 1,
-                    seller_name: "seller".into(),
-                    item_name: "item1".into(),
-                    quantity: 7,
-                    price: 10,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type: SellOrderType::Immediate,
-                },
-                SellOrder {
-                    id: 2,
-                    seller_name: "seller".into(),
-                    item_name: "item1".into(),
-                    quantity: 3,
-                    price: 20, // a bid was made!
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type: SellOrderType::Auction,
-                },
-                SellOrder {
-                    id: 3,
-                    seller_name: "seller".into(),
-                    item_name: "item2".into(),
-                    quantity: 1,
-                    price: 20,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type: SellOrderType::Auction,
-                },
-                SellOrder {
-                    id: 4,
-                    seller_name: "seller".into(),
-                    item_name: "item2".into(),
-                    quantity: 2,
-                    price: 45,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type: SellOrderType::Auction,
-                },
-                SellOrder {
-                    id: 5,
-                    seller_name: "seller".into(),
-                    item_name: "item2".into(),
-                    quantity: 2,
-                    price: 50,
-                    expiration_time: "2021-01-01 00:00:00".into(),
-                    order_type: SellOrderType::Auction,
-                }
-            ]
+            storage.view_items(seller.id).unwrap(),
+            vec![("funds".into(), 118), ("ore".into(), 15)]
         );
+    }
 
-        // but you can't repeat a bid
-        assert!(!storage
-            .place_bid_on_auction_sell_order(buyer.id, 2, 20)
-            .is_ok());
-
-        let another_buyer = storage.login("another buyer").unwrap();
-        assert!(storage.deposit(another_buyer.id, "funds", 100).is_ok());
+    #[test]
+    fn test_refund_stack_limit() {
+        let mut storage = Storage::open(":memory:").unwrap();
+        let seller = storage.login("seller").unwrap();
+        let buyer = storage.login("buyer").unwrap();
+        let bidder = storage.login("bidder").unwrap();
+        let catalog = ["ore", "gold"].map(|name| CatalogItem {
+            name: name.into(),
+            display_name: None,
+            category: None,
+            description: String::new(),
+            max_stack: Some(10),
+            tradeable: true,
+            aliases: Vec::new(),
+        });
+        assert!(storage.set_catalog(&catalog, false).is_ok());
+        assert!(storage.set_currencies(&["gold".into()]).is_ok());
+        assert!(storage.deposit(seller.id, "funds", 100).is_ok());
+        assert!(storage.deposit(seller.id, "gold", 10).is_ok());
+        assert!(storage.deposit(seller.id, "ore", 10).is_ok());
+        assert!(storage.deposit(buyer.id, "gold", 10).is_ok());
+        assert!(storage.deposit(bidder.id, "gold", 10).is_ok());
+
+        let sell = |order_type, price| {
+            storage
+                .place_sell_order(
+                    order_type,
+                    seller.id,
+                    "ore",
+                    5,
+                    price,
+                    "gold",
+                    EXPIRATION_TIME,
+                )
+                .unwrap()
+        };
+        let cancelled_id = sell(SellOrderType::Immediate, 5);
+        let immediate_id = sell(SellOrderType::Immediate, 5);
+        assert!(storage.deposit(seller.id, "ore", 5).is_ok());
+        let auction_id = sell(SellOrderType::Auction, 1);
 
-        // and you can't lower previous bid
-        assert!(!storage
-            .place_bid_on_auction_sell_order(another_buyer.id, 2, 19)
-            .is_ok());
+        // cancelled items are returned even if they don't fit into the seller's stack
+        assert!(storage.deposit(seller.id, "ore", 10).is_ok());
+        assert!(storage.cancel_sell_order(seller.id, cancelled_id).is_ok());
 
-        // but you can increase it, but not greater than funds allow
-        assert!(!storage
-            .place_bid_on_auction_sell_order(another_buyer.id, 2, 121)
-            .is_ok());
+        // and so is the payment, the fees have left 7 gold to the seller
+        assert!(storage.deposit(seller.id, "gold", 3).is_ok());
         assert!(storage
-            .place_bid_on_auction_sell_order(another_buyer.id, 2, 21)
+            .execute_immediate_sell_order(buyer.id, immediate_id, EXPIRATION_TIME)
             .is_ok());
+        assert_eq!(
+            storage.view_items(seller.id).unwrap(),
+            vec![
+                ("funds".into(), 100),
+                ("ore".into(), 15),
+                ("gold".into(), 15)
+            ]
+        );
 
+        // the outbid user gets the bid back even if their stack is full
         assert!(storage
-            .place_bid_on_auction_sell_order(another_buyer.id, 3, 25)
-            .is_ok());
-        assert!(storage
-            .place_bid_on_auction_sell_order(another_buyer.id, 4, 50)
+            .place_bid_on_auction_sell_order(bidder.id, auction_id, 2)
             .is_ok());
-
+        assert!(storage.deposit(bidder.id, "gold", 2).is_ok());
         assert!(storage
-            .place_bid_on_auction_sell_order(buyer.id, 3, 27)
+            .place_bid_on_auction_sell_order(buyer.id, auction_id, 3)
             .is_ok());
-
-        assert_eq!(
-            storage.view_items(seller.id).unwrap(),
-            vec![("funds".into(), 90), ("item2".into(), 20 - 1 - 2 - 2)]
-        );
         assert_eq!(
-            storage.view_items(buyer.id).unwrap(),
-            vec![("funds".into(), 100 - 27)]
+            storage.view_items(bidder.id).unwrap(),
+            vec![("funds".into(), 0), ("gold".into(), 12)]
         );
+
+        // wanted items must fit into the sender's stack when the offer is placed
+        let offer = |storage: &Storage, want| {
+            storage.place_offer(
+                buyer.id,
+                "bidder",
+                &[Item {
+                    name: "ore".into(),
+                    quantity: 1,
+                }],
+                &[Item {
+                    name: "gold".into(),
+                    quantity: want,
+                }],
+                EXPIRATION_TIME,
+            )
+        };
         assert_eq!(
-            storage.view_items(another_buyer.id).unwrap(),
-            vec![("funds".into(), 100 - 21 - 50)]
+            offer(&storage, 9).unwrap_err().code(),
+            ErrorCode::StackLimitExceeded
         );
+        let accepted_id = offer(&storage, 1).unwrap();
+        let declined_id = offer(&storage, 1).unwrap();
+        let expired_id = offer(&storage, 1).unwrap();
 
-        // and finally process expired orders - make them real
+        // returned items are always accepted
         assert!(storage
-            .process_expired_sell_orders(EXPIRATION_TIME + 1)
+            .accept_offer(bidder.id, accepted_id, EXPIRATION_TIME - 1)
             .is_ok());
-        assert_eq!(
-            storage.view_items(seller.id).unwrap(),
-            vec![
-                ("funds".into(), 90 + 27 + 21 + 50),
-                ("item1".into(), 7),
-                ("item2".into(), 20 - 5 + 2)
-            ]
-        );
+        assert!(storage.deposit(buyer.id, "gold", 7).is_ok());
+        assert!(storage.deposit(buyer.id, "ore", 8).is_ok());
+        assert!(storage.decline_offer(bidder.id, declined_id).is_ok());
+        assert!(storage.process_expired_offers(EXPIRATION_TIME).is_ok());
+        assert_eq!(storage.view_offers(buyer.id).unwrap(), vec![]);
+        assert!(storage.get_offer(expired_id).is_err());
         assert_eq!(
             storage.view_items(buyer.id).unwrap(),
-            vec![("funds".into(), 100 - 27), ("item2".into(), 1),]
-        );
-        assert_eq!(
-            storage.view_items(another_buyer.id).unwrap(),
-            vec![
-                ("funds".into(), 100 - 21 - 50),
-                ("item1".into(), 3),
-                ("item2".into(), 2)
-            ]
+            vec![("funds".into(), 0), ("ore".into(), 12), ("gold".into(), 10)]
         );
     }
 }
@@ -0,0 +1,143 @@
+//! WebSocket gateway. Every text message is a single command of the TCP protocol and is answered
+//! with a single text message. Market events the session is subscribed to (`subscribe orders`)
+//! are pushed as separate text messages.
+//!
+//! Browsers can't set headers on WebSocket requests, so the session token may be passed
+//! as the `token` query parameter: `/ws?token=<token>`.
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        State, WebSocketUpgrade,
+    },
+    response::IntoResponse,
+};
+
+use crate::{
+    commands::CommandsProcessor,
+    http::{AuthUser, SharedStorage},
+    storage::User,
+};
+
+pub(crate) async fn handler(
+    State(storage): State<SharedStorage>,
+    AuthUser(user): AuthUser,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| session(socket, user, storage))
+}
+
+async fn session(mut socket: WebSocket, user: User, storage: SharedStorage) {
+    println!("{user:?} connected via WebSocket");
+    let mut processor = CommandsProcessor::new(user.clone(), storage.clone());
+
+    loop {
+        let response = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(request))) => {
+                    let response = processor.process_request(request.trim()).await;
+                    processor.encode(response)
+                }
+                // Pings are answered by axum automatically
+                Some(Ok(Message::Binary(_) | Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+            },
+            event = processor.next_event() => event,
+        };
+
+        if socket.send(Message::Text(response.into())).await.is_err() {
+            break;
+        }
+    }
+    println!("WebSocket connection with {user:?} closed by client");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures_util::{SinkExt, StreamExt};
+    use pretty_assertions::assert_eq;
+    use tokio::{net::TcpListener, sync::Mutex};
+    use tokio_tungstenite::tungstenite;
+
+    use crate::storage::Storage;
+
+    #[tokio::test]
+    async fn websocket_session() {
+        let storage = Arc::new(Mutex::new(Storage::open(":memory:").unwrap()));
+        let (seller_token, buyer_token) = {
+            let storage = storage.lock().await;
+            let seller = storage.login("seller").unwrap();
+            storage.deposit(seller.id, "funds", 10).unwrap();
+            storage.deposit(seller.id, "Sword", 1).unwrap();
+            storage.deposit(seller.id, "Shield", 1).unwrap();
+            let buyer = storage.login("buyer").unwrap();
+            (
+                storage.create_session(seller.id, i64::MAX).unwrap(),
+                storage.create_session(buyer.id, i64::MAX).unwrap(),
+            )
+        };
+
+        let listener = TcpListener::bind(("localhost", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = crate::http::router(storage);
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        // Unauthorized connections are rejected
+        assert!(tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+            .await
+            .is_err());
+
+        let (mut buyer, _) =
+            tokio_tungstenite::connect_async(format!("ws://{addr}/ws?token={buyer_token}"))
+                .await
+                .unwrap();
+        let (mut seller, _) =
+            tokio_tungstenite::connect_async(format!("ws://{addr}/ws?token={seller_token}"))
+                .await
+                .unwrap();
+
+        async fn recv(
+            socket: &mut tokio_tungstenite::WebSocketStream<
+                tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+            >,
+        ) -> String {
+            match socket.next().await.unwrap().unwrap() {
+                tungstenite::Message::Text(text) => text.to_string(),
+                message => panic!("Unexpected message: {message:?}"),
+            }
+        }
+
+        buyer.send("whoami".into()).await.unwrap();
+        assert_eq!(recv(&mut buyer).await, "buyer");
+        buyer.send("subscribe orders Sword".into()).await.unwrap();
+        assert_eq!(
+            recv(&mut buyer).await,
+            "Successfully subscribed to Sword orders"
+        );
+
+        seller.send("sell auction Shield 10".into()).await.unwrap();
+        assert_eq!(
+            recv(&mut seller).await,
+            "Successfully placed auction sell order for 1 Shield(s)"
+        );
+        seller.send("format json".into()).await.unwrap();
+        recv(&mut seller).await;
+        seller.send("sell Sword 20".into()).await.unwrap();
+        assert!(recv(&mut seller).await.starts_with(r#"{"ok":true"#));
+
+        // Only the Sword order is pushed to the buyer
+        let event = recv(&mut buyer).await;
+        assert!(
+            event.starts_with("Event: order placed - #2: seller is selling a Sword for 20 funds"),
+            "{event}"
+        );
+
+        buyer.send("format json".into()).await.unwrap();
+        recv(&mut buyer).await;
+        buyer.send("buy 2".into()).await.unwrap();
+        let response: serde_json::Value = serde_json::from_str(&recv(&mut buyer).await).unwrap();
+        assert_eq!(response["error_code"], 106);
+    }
+}